@@ -0,0 +1,87 @@
+//! Benchmarks the cost of computing note commitment tree nodes during block scanning, by
+//! scanning a dense block with and without commitment tracking enabled.
+
+use std::convert::Infallible;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use group::ff::{Field, PrimeField};
+use rand_core::{OsRng, RngCore};
+use zcash_client_backend::{
+    proto::compact_formats::{ChainMetadata, CompactBlock, CompactSaplingOutput, CompactTx},
+    scanning::{scan_block_with_config, Nullifiers, ScanConfig, ScanningKeys},
+};
+use zcash_primitives::{consensus::Network, zip32::AccountId};
+
+/// The number of transactions in the benchmark block, each containing a single output.
+const OUTPUTS_PER_BLOCK: usize = 1000;
+
+/// Constructs a block containing outputs with random (but validly encoded) note commitments.
+fn dense_block(rng: &mut impl RngCore) -> CompactBlock {
+    let vtx = (0..OUTPUTS_PER_BLOCK)
+        .map(|i| {
+            let mut hash = vec![0; 32];
+            rng.fill_bytes(&mut hash);
+            CompactTx {
+                index: i as u64,
+                hash,
+                outputs: vec![CompactSaplingOutput {
+                    cmu: bls12_381::Scalar::random(&mut *rng)
+                        .to_repr()
+                        .as_ref()
+                        .to_owned(),
+                    ephemeral_key: vec![0; 32],
+                    ciphertext: vec![0; 52],
+                }],
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    CompactBlock {
+        height: 1,
+        hash: vec![1; 32],
+        prev_hash: vec![0; 32],
+        vtx,
+        chain_metadata: Some(ChainMetadata {
+            sapling_commitment_tree_size: OUTPUTS_PER_BLOCK as u32,
+            orchard_commitment_tree_size: 0,
+        }),
+        ..Default::default()
+    }
+}
+
+fn bench_commitment_tracking(c: &mut Criterion) {
+    let mut rng = OsRng;
+    let network = Network::TestNetwork;
+    let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+    let nullifiers = Nullifiers::empty();
+    let block = dense_block(&mut rng);
+
+    let mut group = c.benchmark_group("commitment-tracking");
+    group.throughput(Throughput::Elements(OUTPUTS_PER_BLOCK as u64));
+
+    for track_commitments in [true, false] {
+        let config = ScanConfig {
+            track_commitments,
+            ..Default::default()
+        };
+        group.bench_function(BenchmarkId::from_parameter(track_commitments), |b| {
+            b.iter(|| {
+                scan_block_with_config(
+                    &network,
+                    block.clone(),
+                    &scanning_keys,
+                    &nullifiers,
+                    None,
+                    config,
+                )
+                .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_commitment_tracking);
+criterion_main!(benches);
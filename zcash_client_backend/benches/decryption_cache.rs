@@ -0,0 +1,131 @@
+//! Benchmarks the savings from caching negative trial decryption results when a scan range is
+//! rescanned after a key has been added to a wallet.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use group::{
+    ff::{Field, PrimeField},
+    Group, GroupEncoding,
+};
+use rand_core::{OsRng, RngCore};
+use zcash_client_backend::{
+    proto::compact_formats::{ChainMetadata, CompactBlock, CompactSaplingOutput, CompactTx},
+    scanning::{
+        scan_block_with_config, DecryptionCache, Nullifiers, ScanConfig, ScanOptions, ScanningKeys,
+    },
+};
+use zcash_keys::keys::{UnifiedFullViewingKey, UnifiedSpendingKey};
+use zcash_primitives::{consensus::Network, zip32::AccountId};
+
+/// The number of blocks in the benchmark scan range.
+const BLOCK_COUNT: u32 = 1_000;
+
+/// The number of transactions in each block, each containing a single output.
+const OUTPUTS_PER_BLOCK: usize = 10;
+
+/// The number of accounts whose keys were used for the initial scan of the range.
+const INITIAL_ACCOUNT_COUNT: u32 = 3;
+
+/// Constructs a block containing outputs that are not decryptable by any of the wallet's keys.
+fn fake_block(height: u32, rng: &mut impl RngCore) -> CompactBlock {
+    let vtx = (0..OUTPUTS_PER_BLOCK)
+        .map(|i| {
+            let mut hash = vec![0; 32];
+            rng.fill_bytes(&mut hash);
+            CompactTx {
+                index: i as u64,
+                hash,
+                outputs: vec![CompactSaplingOutput {
+                    cmu: bls12_381::Scalar::random(&mut *rng)
+                        .to_repr()
+                        .as_ref()
+                        .to_owned(),
+                    ephemeral_key: jubjub::SubgroupPoint::random(&mut *rng).to_bytes().to_vec(),
+                    ciphertext: vec![0; 52],
+                }],
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    CompactBlock {
+        height: height.into(),
+        hash: vec![1; 32],
+        prev_hash: vec![0; 32],
+        vtx,
+        chain_metadata: Some(ChainMetadata {
+            sapling_commitment_tree_size: height * OUTPUTS_PER_BLOCK as u32,
+            orchard_commitment_tree_size: 0,
+        }),
+        ..Default::default()
+    }
+}
+
+fn account_ufvk(network: &Network, index: u32) -> (AccountId, UnifiedFullViewingKey) {
+    let account = AccountId::try_from(index).unwrap();
+    let usk = UnifiedSpendingKey::from_seed(network, &[0u8; 32], account).unwrap();
+    (account, usk.to_unified_full_viewing_key())
+}
+
+fn bench_decryption_cache(c: &mut Criterion) {
+    let mut rng = OsRng;
+    let network = Network::TestNetwork;
+    let initial_keys = ScanningKeys::from_account_ufvks(
+        (0..INITIAL_ACCOUNT_COUNT).map(|i| account_ufvk(&network, i)),
+    );
+    let rescan_keys = ScanningKeys::from_account_ufvks(
+        (0..=INITIAL_ACCOUNT_COUNT).map(|i| account_ufvk(&network, i)),
+    );
+    let nullifiers = Nullifiers::empty();
+    let blocks = (1..=BLOCK_COUNT)
+        .map(|height| fake_block(height, &mut rng))
+        .collect::<Vec<_>>();
+
+    // Populate the cache by scanning the range with the initial keys.
+    let mut initial_cache = DecryptionCache::new();
+    for block in &blocks {
+        scan_block_with_config(
+            &network,
+            block.clone(),
+            &initial_keys,
+            &nullifiers,
+            None,
+            ScanOptions::new(ScanConfig::default()).with_decryption_cache(&mut initial_cache),
+        )
+        .unwrap();
+    }
+
+    let mut group = c.benchmark_group("decryption-cache");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(BLOCK_COUNT.into()));
+
+    for (name, cache) in [
+        ("uncached", DecryptionCache::new()),
+        ("cached", initial_cache),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || cache.clone(),
+                |mut cache| {
+                    for block in &blocks {
+                        scan_block_with_config(
+                            &network,
+                            block.clone(),
+                            &rescan_keys,
+                            &nullifiers,
+                            None,
+                            ScanOptions::new(ScanConfig::default())
+                                .with_decryption_cache(&mut cache),
+                        )
+                        .unwrap();
+                    }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_decryption_cache);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+//! Benchmarks the matching of spent nullifiers against the wallet's tracked nullifiers during
+//! block scanning.
+//!
+//! Run once with default features (constant-time matching) and once with
+//! `--features fast-nullifier-lookup` (hash-based matching) to compare the two strategies.
+//! The `nullifier-tracking` group measures the construction of the tracked nullifier set,
+//! which includes building the hash index when `fast-nullifier-lookup` is enabled.
+
+use std::convert::Infallible;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand_core::{OsRng, RngCore};
+use zcash_client_backend::{
+    proto::compact_formats::{ChainMetadata, CompactBlock, CompactSaplingSpend, CompactTx},
+    scanning::{scan_block, Nullifiers, ScanningKeys},
+};
+use zcash_primitives::{consensus::Network, zip32::AccountId};
+
+/// The number of transactions in the benchmark block, each containing a single spend.
+const SPENDS_PER_BLOCK: usize = 100;
+
+fn random_nf(rng: &mut impl RngCore) -> sapling::Nullifier {
+    let mut nf = [0; 32];
+    rng.fill_bytes(&mut nf);
+    sapling::Nullifier(nf)
+}
+
+/// Constructs a block in which every tenth spend reveals one of the given tracked nullifiers,
+/// and the remaining spends reveal nullifiers unknown to the wallet.
+fn fake_block(tracked: &[(AccountId, sapling::Nullifier)], rng: &mut impl RngCore) -> CompactBlock {
+    let vtx = (0..SPENDS_PER_BLOCK)
+        .map(|i| {
+            let nf = if i % 10 == 0 {
+                tracked[i % tracked.len()].1
+            } else {
+                random_nf(rng)
+            };
+            let mut hash = vec![0; 32];
+            rng.fill_bytes(&mut hash);
+            CompactTx {
+                index: i as u64,
+                hash,
+                spends: vec![CompactSaplingSpend { nf: nf.0.to_vec() }],
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    CompactBlock {
+        height: 1,
+        hash: vec![1; 32],
+        prev_hash: vec![0; 32],
+        vtx,
+        chain_metadata: Some(ChainMetadata {
+            sapling_commitment_tree_size: 0,
+            orchard_commitment_tree_size: 0,
+        }),
+        ..Default::default()
+    }
+}
+
+fn bench_nullifier_matching(c: &mut Criterion) {
+    let mut rng = OsRng;
+    let network = Network::TestNetwork;
+    let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+
+    let mut group = c.benchmark_group("nullifier-matching");
+    group.throughput(Throughput::Elements(SPENDS_PER_BLOCK as u64));
+
+    for tracked_count in [1_000, 10_000, 100_000] {
+        let tracked = (0..tracked_count)
+            .map(|_| (AccountId::ZERO, random_nf(&mut rng)))
+            .collect::<Vec<_>>();
+        let block = fake_block(&tracked, &mut rng);
+        let nullifiers = Nullifiers::new(
+            tracked,
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+
+        group.bench_function(BenchmarkId::from_parameter(tracked_count), |b| {
+            b.iter(|| {
+                scan_block(&network, block.clone(), &scanning_keys, &nullifiers, None).unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_nullifier_tracking(c: &mut Criterion) {
+    let mut rng = OsRng;
+
+    let mut group = c.benchmark_group("nullifier-tracking");
+
+    for tracked_count in [1_000, 10_000, 100_000] {
+        let tracked = (0..tracked_count)
+            .map(|_| (AccountId::ZERO, random_nf(&mut rng)))
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Elements(tracked_count as u64));
+        group.bench_function(BenchmarkId::from_parameter(tracked_count), |b| {
+            b.iter(|| {
+                Nullifiers::new(
+                    tracked.clone(),
+                    #[cfg(feature = "orchard")]
+                    vec![],
+                )
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_nullifier_matching, bench_nullifier_tracking);
+criterion_main!(benches);
@@ -0,0 +1,103 @@
+//! Benchmarks the savings from preparing incoming viewing keys once for an entire scan range,
+//! rather than for each transaction that is scanned.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use group::{
+    ff::{Field, PrimeField},
+    Group, GroupEncoding,
+};
+use rand_core::{OsRng, RngCore};
+use zcash_client_backend::{
+    proto::compact_formats::{ChainMetadata, CompactBlock, CompactSaplingOutput, CompactTx},
+    scanning::{
+        scan_block, scan_block_with_config, Nullifiers, ScanConfig, ScanOptions, ScanningKeys,
+    },
+};
+use zcash_keys::keys::UnifiedSpendingKey;
+use zcash_primitives::{consensus::Network, zip32::AccountId};
+
+/// The number of blocks in the benchmark scan range.
+const BLOCK_COUNT: u32 = 10_000;
+
+/// The number of accounts whose keys are used for scanning.
+const ACCOUNT_COUNT: u32 = 4;
+
+/// Constructs a block containing a single transaction with one output that is not
+/// decryptable by any of the wallet's keys.
+fn fake_block(height: u32, rng: &mut impl RngCore) -> CompactBlock {
+    let mut hash = vec![0; 32];
+    rng.fill_bytes(&mut hash);
+    let tx = CompactTx {
+        index: 0,
+        hash,
+        outputs: vec![CompactSaplingOutput {
+            cmu: bls12_381::Scalar::random(&mut *rng)
+                .to_repr()
+                .as_ref()
+                .to_owned(),
+            ephemeral_key: jubjub::SubgroupPoint::random(&mut *rng).to_bytes().to_vec(),
+            ciphertext: vec![0; 52],
+        }],
+        ..Default::default()
+    };
+
+    CompactBlock {
+        height: height.into(),
+        hash: vec![1; 32],
+        prev_hash: vec![0; 32],
+        vtx: vec![tx],
+        chain_metadata: Some(ChainMetadata {
+            sapling_commitment_tree_size: height,
+            orchard_commitment_tree_size: 0,
+        }),
+        ..Default::default()
+    }
+}
+
+fn bench_prepared_ivks(c: &mut Criterion) {
+    let mut rng = OsRng;
+    let network = Network::TestNetwork;
+    let scanning_keys = ScanningKeys::from_account_ufvks((0..ACCOUNT_COUNT).map(|i| {
+        let account = AccountId::try_from(i).unwrap();
+        let usk = UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account).unwrap();
+        (account, usk.to_unified_full_viewing_key())
+    }));
+    let nullifiers = Nullifiers::empty();
+    let blocks = (1..=BLOCK_COUNT)
+        .map(|height| fake_block(height, &mut rng))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("prepared-ivks");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(BLOCK_COUNT.into()));
+
+    group.bench_function("per-transaction", |b| {
+        b.iter(|| {
+            for block in &blocks {
+                scan_block(&network, block.clone(), &scanning_keys, &nullifiers, None).unwrap();
+            }
+        })
+    });
+
+    group.bench_function("prepared-once", |b| {
+        b.iter(|| {
+            let prepared_ivks = scanning_keys.to_prepared_ivks();
+            for block in &blocks {
+                scan_block_with_config(
+                    &network,
+                    block.clone(),
+                    &scanning_keys,
+                    &nullifiers,
+                    None,
+                    ScanOptions::new(ScanConfig::default()).with_prepared_ivks(&prepared_ivks),
+                )
+                .unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_prepared_ivks);
+criterion_main!(benches);
@@ -56,14 +56,19 @@
 //! [`propose_shielding`]: crate::data_api::wallet::propose_shielding
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
     hash::Hash,
     io,
     num::{NonZeroU32, TryFromIntError},
+    ops::Range,
 };
 
-use incrementalmerkletree::{frontier::Frontier, Retention};
+use incrementalmerkletree::{
+    frontier::{CommitmentTree, Frontier},
+    Hashable, Position, Retention,
+};
+use sapling::note_encryption::Zip212Enforcement;
 use secrecy::SecretVec;
 use shardtree::{error::ShardTreeError, store::ShardStore, ShardTree};
 
@@ -73,7 +78,10 @@ use crate::{
     decrypt::DecryptedOutput,
     keys::{UnifiedAddressRequest, UnifiedFullViewingKey, UnifiedSpendingKey},
     proto::service::TreeState,
-    wallet::{Note, NoteId, ReceivedNote, Recipient, WalletTransparentOutput, WalletTx},
+    wallet::{
+        Note, NoteId, ReceivedNote, Recipient, WalletSaplingOutput, WalletSaplingSpend,
+        WalletTransparentOutput, WalletTx,
+    },
     ShieldedProtocol,
 };
 use zcash_primitives::{
@@ -86,6 +94,9 @@ use zcash_primitives::{
     },
 };
 
+#[cfg(feature = "orchard")]
+use crate::wallet::{WalletOrchardOutput, WalletOrchardSpend};
+
 #[cfg(feature = "transparent-inputs")]
 use {
     crate::wallet::TransparentAddressMetadata,
@@ -697,6 +708,21 @@ impl BlockMetadata {
         }
     }
 
+    /// Constructs the [`BlockMetadata`] for the given scanned block, using the final sizes of its
+    /// note commitment trees.
+    ///
+    /// The result is suitable for use as the prior block metadata when scanning the block that
+    /// follows it.
+    pub fn from_scanned_block<A>(block: &ScannedBlock<A>) -> Self {
+        Self {
+            block_height: block.block_height,
+            block_hash: block.block_hash,
+            sapling_tree_size: Some(block.sapling.final_tree_size),
+            #[cfg(feature = "orchard")]
+            orchard_tree_size: Some(block.orchard.final_tree_size),
+        }
+    }
+
     /// Returns the block height.
     pub fn block_height(&self) -> BlockHeight {
         self.block_height
@@ -729,14 +755,14 @@ impl BlockMetadata {
 pub struct ScannedBundles<NoteCommitment, NF> {
     final_tree_size: u32,
     commitments: Vec<(NoteCommitment, Retention<BlockHeight>)>,
-    nullifier_map: Vec<(TxId, u16, Vec<NF>)>,
+    nullifier_map: Vec<(TxId, u16, Vec<(usize, NF)>)>,
 }
 
 impl<NoteCommitment, NF> ScannedBundles<NoteCommitment, NF> {
     pub(crate) fn new(
         final_tree_size: u32,
         commitments: Vec<(NoteCommitment, Retention<BlockHeight>)>,
-        nullifier_map: Vec<(TxId, u16, Vec<NF>)>,
+        nullifier_map: Vec<(TxId, u16, Vec<(usize, NF)>)>,
     ) -> Self {
         Self {
             final_tree_size,
@@ -755,8 +781,11 @@ impl<NoteCommitment, NF> ScannedBundles<NoteCommitment, NF> {
     /// The returned tuple is keyed by both transaction ID and the index of the transaction within
     /// the block, so that either the txid or the combination of the block hash available from
     /// [`ScannedBlock::block_hash`] and returned transaction index may be used to uniquely
-    /// identify the transaction, depending upon the needs of the caller.
-    pub fn nullifier_map(&self) -> &[(TxId, u16, Vec<NF>)] {
+    /// identify the transaction, depending upon the needs of the caller. Each nullifier is
+    /// paired with the index of the spend (for Sapling) or action (for Orchard) that revealed it
+    /// within its transaction, corresponding to the index recorded for spends that are linked
+    /// to the wallet's notes during scanning.
+    pub fn nullifier_map(&self) -> &[(TxId, u16, Vec<(usize, NF)>)] {
         &self.nullifier_map
     }
 
@@ -765,14 +794,313 @@ impl<NoteCommitment, NF> ScannedBundles<NoteCommitment, NF> {
     pub fn commitments(&self) -> &[(NoteCommitment, Retention<BlockHeight>)] {
         &self.commitments
     }
+
+    /// Returns `true` if the block contained no note commitments and revealed no nullifiers in
+    /// this bundle's shielded protocol, as is the case (for example) for all blocks prior to
+    /// the protocol's activation.
+    pub fn is_empty(&self) -> bool {
+        self.commitments.is_empty()
+            && self
+                .nullifier_map
+                .iter()
+                .all(|(_, _, nullifiers)| nullifiers.is_empty())
+    }
+
+    /// Returns the positions in the note commitment tree of the commitments in this block
+    /// that are marked for retention, i.e. the positions of the notes for which the wallet
+    /// must maintain witnesses.
+    pub fn marked_positions(&self) -> Vec<Position> {
+        let start_position = u64::from(self.final_tree_size) - self.commitments.len() as u64;
+        self.commitments
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, retention))| retention.is_marked())
+            .map(|(i, _)| Position::from(start_position + i as u64))
+            .collect()
+    }
+
+    /// Returns the positions of the marked commitments in this block (see
+    /// [`marked_positions`]), coalesced into maximal ranges of adjacent positions.
+    ///
+    /// This may be used as a hint by note commitment tree implementations that can mark a
+    /// contiguous range of positions more efficiently than marking each position individually,
+    /// such as when consecutive outputs of a transaction were all received by the wallet.
+    ///
+    /// [`marked_positions`]: Self::marked_positions
+    pub fn marked_ranges(&self) -> Vec<Range<Position>> {
+        let mut ranges: Vec<Range<Position>> = vec![];
+        for position in self.marked_positions() {
+            match ranges.last_mut() {
+                Some(range) if range.end == position => range.end = position + 1,
+                _ => ranges.push(position..position + 1),
+            }
+        }
+        ranges
+    }
+
+    /// Returns the range of note commitment tree positions of the commitments in this block,
+    /// or `None` if the block contains no note commitments.
+    ///
+    /// The end of the range is the [`final_tree_size`] of the tree. `None` is also returned
+    /// if note commitments were not tracked during scanning (see
+    /// [`ScanConfig::track_commitments`]), as the commitments are then absent.
+    ///
+    /// [`final_tree_size`]: Self::final_tree_size
+    /// [`ScanConfig::track_commitments`]: crate::scanning::ScanConfig::track_commitments
+    pub fn position_range(&self) -> Option<Range<Position>> {
+        if self.commitments.is_empty() {
+            None
+        } else {
+            let end = u64::from(self.final_tree_size);
+            let start = end - self.commitments.len() as u64;
+            Some(Position::from(start)..Position::from(end))
+        }
+    }
+
+    /// Returns the note commitments of this block partitioned into segments, each of which
+    /// ends with a commitment that is retained as a checkpoint.
+    ///
+    /// The commitments of a scanned block end with the block's checkpoint, so this returns a
+    /// single segment, or no segments if the block contains no note commitments (or if they
+    /// were not tracked). See [`ScannedRange::sapling_checkpoint_segments`] for the segments of
+    /// a range of blocks.
+    pub fn checkpoint_segments(&self) -> Vec<&[(NoteCommitment, Retention<BlockHeight>)]> {
+        checkpoint_segments(&self.commitments)
+    }
+}
+
+/// Partitions the given note commitments into segments, each of which ends with a commitment
+/// that is retained as a checkpoint. Any commitments that follow the last checkpoint form a
+/// final segment.
+fn checkpoint_segments<NoteCommitment>(
+    commitments: &[(NoteCommitment, Retention<BlockHeight>)],
+) -> Vec<&[(NoteCommitment, Retention<BlockHeight>)]> {
+    commitments
+        .split_inclusive(|(_, retention)| matches!(retention, Retention::Checkpoint { .. }))
+        .collect()
+}
+
+impl<NoteCommitment, NF> Default for ScannedBundles<NoteCommitment, NF> {
+    /// Returns the bundles for a block with no activity in a shielded protocol whose note
+    /// commitment tree is empty.
+    fn default() -> Self {
+        Self {
+            final_tree_size: 0,
+            commitments: vec![],
+            nullifier_map: vec![],
+        }
+    }
+}
+
+/// Errors that can occur when applying the note commitments of a [`ScannedBundles`] value to a
+/// note commitment tree via [`apply_to_frontier`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrontierError {
+    /// The size of the tree did not match the position of the first note commitment in the
+    /// scanned bundles; the bundles must be applied to the tree in block order without gaps.
+    StartPositionMismatch { tree_size: u64, start_position: u64 },
+    /// The tree does not have room for all of the note commitments in the scanned bundles.
+    TreeFull,
+}
+
+/// Appends the note commitments of the given scanned bundles to a note commitment tree.
+///
+/// The tree must be the treestate as of the end of the block preceding the block from which
+/// `bundles` was obtained; if its size does not match the position of the first commitment in
+/// `bundles`, an error is returned and the tree is left unmodified. The tree is also left
+/// unmodified if it does not have room for all of the commitments.
+///
+/// A [`CommitmentTree`] retains only the frontier of the tree, so the [`Retention`] of each
+/// commitment affects neither which nodes are appended nor the resulting root; callers that need
+/// to maintain witnesses for marked notes or to roll back to checkpoints should instead insert
+/// the commitments into a [`ShardTree`], for example via [`ScannedBlock::into_commitments`].
+///
+/// [`CommitmentTree`]: incrementalmerkletree::frontier::CommitmentTree
+pub fn apply_to_frontier<H: Hashable + Clone, NF, const DEPTH: u8>(
+    bundles: &ScannedBundles<H, NF>,
+    frontier: &mut CommitmentTree<H, DEPTH>,
+) -> Result<(), FrontierError> {
+    let tree_size = frontier.size() as u64;
+    let start_position = u64::from(bundles.final_tree_size) - bundles.commitments.len() as u64;
+    if tree_size != start_position {
+        return Err(FrontierError::StartPositionMismatch {
+            tree_size,
+            start_position,
+        });
+    }
+    if u64::from(bundles.final_tree_size) > 1u64 << DEPTH {
+        return Err(FrontierError::TreeFull);
+    }
+
+    for (node, _) in &bundles.commitments {
+        frontier
+            .append(node.clone())
+            .map_err(|_| FrontierError::TreeFull)?;
+    }
+
+    Ok(())
+}
+
+/// Appends the Sapling note commitments of the given scanned bundles to the Sapling note
+/// commitment tree (see [`apply_to_frontier`]), and returns the anchor of the resulting tree.
+///
+/// The returned anchor is the Sapling anchor as of the end of the block from which `bundles`
+/// was obtained. As with [`apply_to_frontier`], the tree is left unmodified if an error is
+/// returned, so an anchor is never computed from a tree that is out of step with the scanned
+/// blocks.
+pub fn apply_and_root<NF>(
+    bundles: &ScannedBundles<sapling::Node, NF>,
+    frontier: &mut sapling::CommitmentTree,
+) -> Result<sapling::Anchor, FrontierError> {
+    apply_to_frontier(bundles, frontier)?;
+    Ok(frontier.root().into())
+}
+
+/// The spends and received notes of a single shielded pool within a [`ScannedBlock`],
+/// partitioned by whether they were already known to the caller. See [`diff_scanned`].
+///
+/// Each entry is paired with the [`TxId`] of the transaction in which it was detected.
+pub struct PoolDiff<'a, S, O> {
+    new_spends: Vec<(TxId, &'a S)>,
+    known_spends: Vec<(TxId, &'a S)>,
+    new_outputs: Vec<(TxId, &'a O)>,
+    known_outputs: Vec<(TxId, &'a O)>,
+}
+
+impl<'a, S, O> PoolDiff<'a, S, O> {
+    fn partition(
+        transactions: impl Iterator<Item = (TxId, &'a [S], &'a [O])>,
+        spend_is_known: impl Fn(&S) -> bool,
+        output_is_known: impl Fn(&O) -> bool,
+    ) -> Self {
+        let mut diff = PoolDiff {
+            new_spends: vec![],
+            known_spends: vec![],
+            new_outputs: vec![],
+            known_outputs: vec![],
+        };
+        for (txid, spends, outputs) in transactions {
+            for spend in spends {
+                if spend_is_known(spend) {
+                    diff.known_spends.push((txid, spend));
+                } else {
+                    diff.new_spends.push((txid, spend));
+                }
+            }
+            for output in outputs {
+                if output_is_known(output) {
+                    diff.known_outputs.push((txid, output));
+                } else {
+                    diff.new_outputs.push((txid, output));
+                }
+            }
+        }
+        diff
+    }
+
+    /// Returns the spends whose nullifiers were not already known.
+    pub fn new_spends(&self) -> &[(TxId, &'a S)] {
+        &self.new_spends
+    }
+
+    /// Returns the spends whose nullifiers were already known.
+    pub fn known_spends(&self) -> &[(TxId, &'a S)] {
+        &self.known_spends
+    }
+
+    /// Returns the received notes whose note commitments were not already known.
+    pub fn new_outputs(&self) -> &[(TxId, &'a O)] {
+        &self.new_outputs
+    }
+
+    /// Returns the received notes whose note commitments were already known.
+    pub fn known_outputs(&self) -> &[(TxId, &'a O)] {
+        &self.known_outputs
+    }
+
+    /// Returns `true` if no spends or received notes were newly discovered.
+    pub fn is_unchanged(&self) -> bool {
+        self.new_spends.is_empty() && self.new_outputs.is_empty()
+    }
+}
+
+/// The result of comparing a [`ScannedBlock`] against the wallet data that was previously
+/// stored for the same block. See [`diff_scanned`].
+pub struct ScanDiff<'a, A> {
+    sapling: PoolDiff<'a, WalletSaplingSpend<A>, WalletSaplingOutput<A>>,
+    #[cfg(feature = "orchard")]
+    orchard: PoolDiff<'a, WalletOrchardSpend<A>, WalletOrchardOutput<A>>,
+}
+
+impl<'a, A> ScanDiff<'a, A> {
+    /// Returns the partitioned Sapling spends and received notes.
+    pub fn sapling(&self) -> &PoolDiff<'a, WalletSaplingSpend<A>, WalletSaplingOutput<A>> {
+        &self.sapling
+    }
+
+    /// Returns the partitioned Orchard spends and received notes.
+    #[cfg(feature = "orchard")]
+    pub fn orchard(&self) -> &PoolDiff<'a, WalletOrchardSpend<A>, WalletOrchardOutput<A>> {
+        &self.orchard
+    }
+
+    /// Returns `true` if no spends or received notes were newly discovered in any pool.
+    pub fn is_unchanged(&self) -> bool {
+        #[cfg(feature = "orchard")]
+        let orchard_unchanged = self.orchard.is_unchanged();
+        #[cfg(not(feature = "orchard"))]
+        let orchard_unchanged = true;
+
+        self.sapling.is_unchanged() && orchard_unchanged
+    }
+}
+
+/// Partitions the spends and received notes of a [`ScannedBlock`] into those that are newly
+/// discovered and those that were already known, for example from a previous scan of the same
+/// block range.
+///
+/// A spend is already known if the byte encoding of its nullifier is in `known_nullifiers`,
+/// and a received note is already known if the byte encoding of its note commitment (its
+/// `cmu` or `cmx`) is in `known_commitments`. The diff is computed purely from the contents of
+/// `block`, without performing any trial decryption, so that incremental rescans can skip
+/// writing data that the wallet already has.
+pub fn diff_scanned<'a, A>(
+    block: &'a ScannedBlock<A>,
+    known_nullifiers: &HashSet<[u8; 32]>,
+    known_commitments: &HashSet<[u8; 32]>,
+) -> ScanDiff<'a, A> {
+    ScanDiff {
+        sapling: PoolDiff::partition(
+            block
+                .transactions
+                .iter()
+                .map(|tx| (tx.txid(), tx.sapling_spends(), tx.sapling_outputs())),
+            |spend| known_nullifiers.contains(&spend.nf().0),
+            |output| known_commitments.contains(&output.note().cmu().to_bytes()),
+        ),
+        #[cfg(feature = "orchard")]
+        orchard: PoolDiff::partition(
+            block
+                .transactions
+                .iter()
+                .map(|tx| (tx.txid(), tx.orchard_spends(), tx.orchard_outputs())),
+            |spend| known_nullifiers.contains(&spend.nf().to_bytes()),
+            |output| {
+                known_commitments.contains(
+                    &orchard::note::ExtractedNoteCommitment::from(output.note().commitment())
+                        .to_bytes(),
+                )
+            },
+        ),
+    }
 }
 
-/// A struct used to return the vectors of note commitments for a [`ScannedBlock`]
-/// as owned values.
+/// A struct used to return the vectors of note commitments for a [`ScannedBlock`] or
+/// [`ScannedRange`] as owned values.
 pub struct ScannedBlockCommitments {
-    /// The ordered vector of note commitments for Sapling outputs of the block.
+    /// The ordered vector of note commitments for Sapling outputs of the block or range.
     pub sapling: Vec<(sapling::Node, Retention<BlockHeight>)>,
-    /// The ordered vector of note commitments for Orchard outputs of the block.
+    /// The ordered vector of note commitments for Orchard outputs of the block or range.
     /// Present only when the `orchard` feature is enabled.
     #[cfg(feature = "orchard")]
     pub orchard: Vec<(orchard::tree::MerkleHashOrchard, Retention<BlockHeight>)>,
@@ -787,9 +1115,12 @@ pub struct ScannedBlock<A> {
     block_hash: BlockHash,
     block_time: u32,
     transactions: Vec<WalletTx<A>>,
+    zip212_enforcement: Zip212Enforcement,
     sapling: ScannedBundles<sapling::Node, sapling::Nullifier>,
     #[cfg(feature = "orchard")]
     orchard: ScannedBundles<orchard::tree::MerkleHashOrchard, orchard::note::Nullifier>,
+    source_id: Option<u64>,
+    key_hits: Option<HashMap<(A, Option<zip32::Scope>), usize>>,
 }
 
 impl<A> ScannedBlock<A> {
@@ -799,6 +1130,7 @@ impl<A> ScannedBlock<A> {
         block_hash: BlockHash,
         block_time: u32,
         transactions: Vec<WalletTx<A>>,
+        zip212_enforcement: Zip212Enforcement,
         sapling: ScannedBundles<sapling::Node, sapling::Nullifier>,
         #[cfg(feature = "orchard")] orchard: ScannedBundles<
             orchard::tree::MerkleHashOrchard,
@@ -810,12 +1142,64 @@ impl<A> ScannedBlock<A> {
             block_hash,
             block_time,
             transactions,
+            zip212_enforcement,
             sapling,
             #[cfg(feature = "orchard")]
             orchard,
+            source_id: None,
+            key_hits: None,
         }
     }
 
+    /// Consumes `self` and returns the transactions from this block that are relevant to the
+    /// wallet.
+    pub(crate) fn into_transactions(self) -> Vec<WalletTx<A>> {
+        self.transactions
+    }
+
+    /// Records the identifier of the source from which the scanned block was obtained.
+    pub(crate) fn with_source_id(mut self, source_id: u64) -> Self {
+        self.source_id = Some(source_id);
+        self
+    }
+
+    /// Records the number of outputs in this block that were decrypted by the key for each
+    /// account and scope.
+    pub(crate) fn with_key_hits(mut self) -> Self
+    where
+        A: Copy + Eq + Hash,
+    {
+        let mut key_hits = HashMap::new();
+        for tx in &self.transactions {
+            let recipients = tx
+                .sapling_outputs()
+                .iter()
+                .map(|output| (*output.account_id(), output.recipient_key_scope()));
+            #[cfg(feature = "orchard")]
+            let recipients = recipients.chain(
+                tx.orchard_outputs()
+                    .iter()
+                    .map(|output| (*output.account_id(), output.recipient_key_scope())),
+            );
+            for recipient in recipients {
+                *key_hits.entry(recipient).or_insert(0) += 1;
+            }
+        }
+        self.key_hits = Some(key_hits);
+        self
+    }
+
+    /// Records the given numbers of outputs in this block that were decrypted by the key for
+    /// each account and scope.
+    #[cfg(feature = "unstable-serialization")]
+    pub(crate) fn with_key_hit_counts(
+        mut self,
+        key_hits: HashMap<(A, Option<zip32::Scope>), usize>,
+    ) -> Self {
+        self.key_hits = Some(key_hits);
+        self
+    }
+
     /// Returns the height of the block that was scanned.
     pub fn height(&self) -> BlockHeight {
         self.block_height
@@ -836,6 +1220,76 @@ impl<A> ScannedBlock<A> {
         &self.transactions
     }
 
+    /// Returns the txids of the transactions from this block that are relevant to the wallet,
+    /// in the order in which they appear in the block.
+    ///
+    /// These are the transactions for which a wallet may wish to retrieve full transaction
+    /// data.
+    pub fn matched_txids(&self) -> Vec<TxId> {
+        self.transactions.iter().map(|tx| tx.txid()).collect()
+    }
+
+    /// Returns the identifier of the source from which the scanned block was obtained, if one
+    /// was provided via [`ScanConfig::source_id`].
+    ///
+    /// The source identifier is preserved by the serialized form of a [`ScannedBlock`] (see
+    /// `serialization::scanned_block`, under the `unstable-serialization` feature flag), except
+    /// when reading data written in version 1 of the format, which predates it.
+    ///
+    /// [`ScanConfig::source_id`]: crate::scanning::ScanConfig::source_id
+    pub fn source_id(&self) -> Option<u64> {
+        self.source_id
+    }
+
+    /// Returns the number of outputs in this block that were decrypted by the key for each
+    /// account and scope, if [`ScanConfig::track_key_hits`] was set when the block was scanned.
+    ///
+    /// Outputs decrypted by a key that is not associated with a ZIP 32 scope (such as a bare
+    /// incoming viewing key) are counted under a scope of `None`. Keys that did not decrypt any
+    /// output in this block are absent from the map. Like the source identifier, these counts
+    /// are preserved by the serialized form of a [`ScannedBlock`] other than version 1.
+    ///
+    /// [`ScanConfig::track_key_hits`]: crate::scanning::ScanConfig::track_key_hits
+    pub fn key_hits(&self) -> Option<&HashMap<(A, Option<zip32::Scope>), usize>> {
+        self.key_hits.as_ref()
+    }
+
+    /// Returns the ZIP 212 enforcement policy that was applied when trial-decrypting the
+    /// Sapling outputs of this block.
+    pub fn zip212_enforcement(&self) -> Zip212Enforcement {
+        self.zip212_enforcement
+    }
+
+    /// Returns the total value of the notes received by each account in this block, summed
+    /// across the Sapling and (if the `orchard` feature is enabled) Orchard pools.
+    ///
+    /// The totals include notes that were detected as change. Returns an error if the total for
+    /// any account would exceed `MAX_MONEY`.
+    pub fn received_by_account(&self) -> Result<HashMap<A, NonNegativeAmount>, BalanceError>
+    where
+        A: Copy + Eq + Hash,
+    {
+        let mut totals: HashMap<A, NonNegativeAmount> = HashMap::new();
+        let mut add = |account: A, value: u64| -> Result<(), BalanceError> {
+            let value = NonNegativeAmount::from_u64(value)?;
+            let total = totals.entry(account).or_insert(NonNegativeAmount::ZERO);
+            *total = (*total + value).ok_or(BalanceError::Overflow)?;
+            Ok(())
+        };
+
+        for tx in &self.transactions {
+            for output in tx.sapling_outputs() {
+                add(*output.account_id(), output.note().value().inner())?;
+            }
+            #[cfg(feature = "orchard")]
+            for output in tx.orchard_outputs() {
+                add(*output.account_id(), output.note().value().inner())?;
+            }
+        }
+
+        Ok(totals)
+    }
+
     /// Returns the Sapling note commitment tree and nullifier data for the block.
     pub fn sapling(&self) -> &ScannedBundles<sapling::Node, sapling::Nullifier> {
         &self.sapling
@@ -861,12 +1315,117 @@ impl<A> ScannedBlock<A> {
 
     /// Returns the [`BlockMetadata`] corresponding to the scanned block.
     pub fn to_block_metadata(&self) -> BlockMetadata {
-        BlockMetadata {
-            block_height: self.block_height,
-            block_hash: self.block_hash,
-            sapling_tree_size: Some(self.sapling.final_tree_size),
+        BlockMetadata::from_scanned_block(self)
+    }
+}
+
+/// The information relevant to this wallet that has been extracted from a contiguous range of
+/// [`CompactBlock`]s, with the note commitments of all blocks in the range merged into a single
+/// stream per shielded protocol.
+///
+/// Within each merged stream, the last note commitment of each block is a checkpoint identified
+/// by that block's height, so the stream may be inserted directly into the note commitment tree.
+/// A block that contains no note commitments for a given protocol has no checkpoint in that
+/// protocol's stream.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub struct ScannedRange<A> {
+    blocks: Vec<BlockMetadata>,
+    transactions: Vec<(BlockHeight, WalletTx<A>)>,
+    sapling: Vec<(sapling::Node, Retention<BlockHeight>)>,
+    #[cfg(feature = "orchard")]
+    orchard: Vec<(orchard::tree::MerkleHashOrchard, Retention<BlockHeight>)>,
+}
+
+impl<A> ScannedRange<A> {
+    /// Constructs a new `ScannedRange` containing no blocks.
+    pub(crate) fn empty() -> Self {
+        Self {
+            blocks: vec![],
+            transactions: vec![],
+            sapling: vec![],
+            #[cfg(feature = "orchard")]
+            orchard: vec![],
+        }
+    }
+
+    /// Appends the contents of the given block, which must directly follow the last block in
+    /// the range, to the range.
+    pub(crate) fn push_block(&mut self, block: ScannedBlock<A>) {
+        self.blocks.push(block.to_block_metadata());
+        let height = block.block_height;
+        self.transactions
+            .extend(block.transactions.into_iter().map(|tx| (height, tx)));
+        // Each block's commitments already end with that block's checkpoint.
+        self.sapling.extend(block.sapling.commitments);
+        #[cfg(feature = "orchard")]
+        self.orchard.extend(block.orchard.commitments);
+    }
+
+    /// Returns the metadata for each block in the range, in order of increasing height.
+    pub fn block_metadata(&self) -> &[BlockMetadata] {
+        &self.blocks
+    }
+
+    /// Returns the range of heights of the blocks that were scanned, or `None` if the range is
+    /// empty.
+    pub fn block_range(&self) -> Option<Range<BlockHeight>> {
+        self.blocks
+            .first()
+            .zip(self.blocks.last())
+            .map(|(first, last)| Range {
+                start: first.block_height(),
+                end: last.block_height() + 1,
+            })
+    }
+
+    /// Returns the transactions in the range that are relevant to the wallet, each paired with
+    /// the height of the block in which it was mined.
+    pub fn transactions(&self) -> &[(BlockHeight, WalletTx<A>)] {
+        &self.transactions
+    }
+
+    /// Returns the merged, ordered list of Sapling note commitments to be added to the note
+    /// commitment tree.
+    pub fn sapling_commitments(&self) -> &[(sapling::Node, Retention<BlockHeight>)] {
+        &self.sapling
+    }
+
+    /// Returns the merged, ordered list of Orchard note commitments to be added to the note
+    /// commitment tree.
+    #[cfg(feature = "orchard")]
+    pub fn orchard_commitments(
+        &self,
+    ) -> &[(orchard::tree::MerkleHashOrchard, Retention<BlockHeight>)] {
+        &self.orchard
+    }
+
+    /// Returns the merged Sapling note commitments of the range, partitioned into segments that
+    /// each end with the checkpoint of a block.
+    ///
+    /// Each segment advances the note commitment tree from one checkpoint to the next, and so is
+    /// a natural unit for a tree backend to apply atomically (for example, in a single database
+    /// transaction). Blocks that contain no Sapling note commitments do not produce a segment.
+    pub fn sapling_checkpoint_segments(&self) -> Vec<&[(sapling::Node, Retention<BlockHeight>)]> {
+        checkpoint_segments(&self.sapling)
+    }
+
+    /// Returns the merged Orchard note commitments of the range, partitioned into segments that
+    /// each end with the checkpoint of a block (see [`Self::sapling_checkpoint_segments`]).
+    #[cfg(feature = "orchard")]
+    pub fn orchard_checkpoint_segments(
+        &self,
+    ) -> Vec<&[(orchard::tree::MerkleHashOrchard, Retention<BlockHeight>)]> {
+        checkpoint_segments(&self.orchard)
+    }
+
+    /// Consumes `self` and returns the merged lists of Sapling and Orchard note commitments for
+    /// the range as an owned value.
+    pub fn into_commitments(self) -> ScannedBlockCommitments {
+        ScannedBlockCommitments {
+            sapling: self.sapling,
             #[cfg(feature = "orchard")]
-            orchard_tree_size: Some(self.orchard.final_tree_size),
+            orchard: self.orchard,
         }
     }
 }
@@ -7,9 +7,14 @@
 use zcash_primitives::{
     consensus::{self, BlockHeight},
     memo::MemoBytes,
-    transaction::fees::{
-        transparent,
-        zip317::{FeeError as Zip317FeeError, FeeRule as Zip317FeeRule},
+    transaction::{
+        components::amount::NonNegativeAmount,
+        fees::{
+            transparent,
+            zip317::{
+                FeeError as Zip317FeeError, FeeRule as Zip317FeeRule, GRACE_ACTIONS, MARGINAL_FEE,
+            },
+        },
     },
 };
 
@@ -161,6 +166,37 @@ impl ChangeStrategy for SingleOutputChangeStrategy {
     }
 }
 
+/// The classification of a transaction's fee relative to the ZIP 317 conventional fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeClass {
+    /// The fee is less than the conventional fee.
+    BelowConventional,
+    /// The fee is equal to the conventional fee.
+    Conventional,
+    /// The fee is greater than the conventional fee.
+    AboveConventional,
+}
+
+/// Classifies the fee paid by a transaction having the given number of logical actions relative
+/// to the [ZIP 317 conventional fee].
+///
+/// This may be used to flag transactions that were created with a misconfigured fee policy,
+/// using the fee and logical action count determined during scanning (see
+/// [`WalletTx::computed_fee`] and [`WalletTx::logical_actions`]).
+///
+/// [ZIP 317 conventional fee]: https://zips.z.cash/zip-0317#fee-calculation
+/// [`WalletTx::computed_fee`]: crate::wallet::WalletTx::computed_fee
+/// [`WalletTx::logical_actions`]: crate::wallet::WalletTx::logical_actions
+pub fn classify_fee(tx_fee: NonNegativeAmount, logical_actions: usize) -> FeeClass {
+    // If the conventional fee would exceed the maximum representable amount, then any valid
+    // fee is below it.
+    match MARGINAL_FEE * std::cmp::max(GRACE_ACTIONS, logical_actions) {
+        Some(conventional_fee) if tx_fee == conventional_fee => FeeClass::Conventional,
+        Some(conventional_fee) if tx_fee > conventional_fee => FeeClass::AboveConventional,
+        _ => FeeClass::BelowConventional,
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -175,7 +211,7 @@ mod tests {
         },
     };
 
-    use super::SingleOutputChangeStrategy;
+    use super::{classify_fee, FeeClass, SingleOutputChangeStrategy};
     use crate::{
         data_api::wallet::input_selection::SaplingPayment,
         fees::{
@@ -185,6 +221,39 @@ mod tests {
         ShieldedProtocol,
     };
 
+    #[test]
+    fn fee_classification() {
+        // Transactions with fewer logical actions than the grace allowance pay the minimum fee.
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(10000), 0),
+            FeeClass::Conventional
+        );
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(10000), 2),
+            FeeClass::Conventional
+        );
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(5000), 1),
+            FeeClass::BelowConventional
+        );
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(15000), 3),
+            FeeClass::Conventional
+        );
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(10000), 3),
+            FeeClass::BelowConventional
+        );
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(20000), 3),
+            FeeClass::AboveConventional
+        );
+        assert_eq!(
+            classify_fee(NonNegativeAmount::const_from_u64(20000), usize::MAX),
+            FeeClass::BelowConventional
+        );
+    }
+
     #[test]
     fn change_without_dust() {
         let change_strategy = SingleOutputChangeStrategy::new(
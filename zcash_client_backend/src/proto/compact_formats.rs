@@ -74,6 +74,36 @@ pub struct CompactTx {
     pub outputs: ::prost::alloc::vec::Vec<CompactSaplingOutput>,
     #[prost(message, repeated, tag = "6")]
     pub actions: ::prost::alloc::vec::Vec<CompactOrchardAction>,
+    /// `CompactTxIn` values corresponding to the `vin` entries of the full transaction.
+    /// This field is only populated by servers that provide transparent transaction data.
+    #[prost(message, repeated, tag = "7")]
+    pub vin: ::prost::alloc::vec::Vec<CompactTxIn>,
+    /// The transparent outputs created by the transaction. This field is only populated
+    /// by servers that provide transparent transaction data.
+    #[prost(message, repeated, tag = "8")]
+    pub vout: ::prost::alloc::vec::Vec<TxOut>,
+}
+/// A compact representation of a transparent transaction input.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CompactTxIn {
+    /// The id of the transaction that created the output being spent.
+    #[prost(bytes = "vec", tag = "1")]
+    pub prevout_txid: ::prost::alloc::vec::Vec<u8>,
+    /// The index of the output being spent in that transaction's `vout`.
+    #[prost(uint32, tag = "2")]
+    pub prevout_index: u32,
+}
+/// A transparent output created by a transaction.
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TxOut {
+    /// The value of the output, in zatoshis.
+    #[prost(uint64, tag = "1")]
+    pub value: u64,
+    /// The script that must be satisfied to spend this output.
+    #[prost(bytes = "vec", tag = "2")]
+    pub script_pub_key: ::prost::alloc::vec::Vec<u8>,
 }
 /// A compact representation of a [Sapling Spend](<https://zips.z.cash/protocol/protocol.pdf#spendencodingandconsensus>).
 ///
@@ -278,10 +278,23 @@ impl<Item: Task> Task for WithUsageTask<Item> {
     }
 }
 
+/// Counters for the batches that a [`BatchRunner`] has submitted to the threadpool.
+///
+/// These are shared between the runner and its batches, so that they can be read without
+/// contending with the worker threads.
+#[derive(Default)]
+struct BatchCounters {
+    /// The number of batches that have been submitted but have not yet completed.
+    pending: AtomicUsize,
+    /// The number of batches that have completed.
+    completed: AtomicUsize,
+}
+
 /// A batch of outputs to trial decrypt.
 pub(crate) struct Batch<IvkTag, D: BatchDomain, Output, Dec: Decryptor<D, Output>> {
     tags: Vec<IvkTag>,
     ivks: Vec<D::IncomingViewingKey>,
+    counters: Arc<BatchCounters>,
     /// We currently store outputs and repliers as parallel vectors, because
     /// [`batch::try_note_decryption`] accepts a slice of domain/output pairs
     /// rather than a value that implements `IntoIterator`, and therefore we
@@ -332,11 +345,16 @@ where
     Dec: Decryptor<D, Output>,
 {
     /// Constructs a new batch.
-    fn new(tags: Vec<IvkTag>, ivks: Vec<D::IncomingViewingKey>) -> Self {
+    fn new(
+        tags: Vec<IvkTag>,
+        ivks: Vec<D::IncomingViewingKey>,
+        counters: Arc<BatchCounters>,
+    ) -> Self {
         assert_eq!(tags.len(), ivks.len());
         Self {
             tags,
             ivks,
+            counters,
             outputs: vec![],
             repliers: vec![],
         }
@@ -366,6 +384,7 @@ where
         let Self {
             tags,
             ivks,
+            counters,
             outputs,
             repliers,
         } = self;
@@ -374,22 +393,30 @@ where
 
         let decryption_results = Dec::batch_decrypt(&tags, &ivks, &outputs);
         for (decryption_result, OutputReplier(replier)) in
-            decryption_results.into_iter().zip(repliers.into_iter())
+            decryption_results.into_iter().zip(repliers.iter())
         {
-            // If `decryption_result` is `None` then we will just drop `replier`,
-            // indicating to the parent `BatchRunner` that this output was not for us.
+            // If `decryption_result` is `None` then we send nothing; once `replier` is
+            // dropped, this indicates to the parent `BatchRunner` that this output was not
+            // for us.
             if let Some(value) = decryption_result {
                 let result = OutputIndex {
                     output_index: replier.output_index,
                     value,
                 };
 
+                // The receiver may have been discarded (or the `BatchRunner` dropped) before
+                // the batch finished; the results for other outputs must still be sent.
                 if replier.value.send(result).is_err() {
-                    tracing::debug!("BatchRunner was dropped before batch finished");
-                    break;
+                    tracing::debug!("Result receiver was dropped before batch finished");
                 }
             }
         }
+
+        // Update the counters before dropping the repliers, so that a caller that has
+        // collected the results of this batch observes it as completed.
+        counters.completed.fetch_add(1, Ordering::Relaxed);
+        counters.pending.fetch_sub(1, Ordering::Relaxed);
+        drop(repliers);
     }
 }
 
@@ -453,6 +480,8 @@ where
     running_tasks: T,
     // Receivers for the results of the running batches.
     pending_results: HashMap<ResultKey, BatchReceiver<IvkTag, D, Dec::Memo>>,
+    // Counters for the batches that have been submitted to the threadpool.
+    counters: Arc<BatchCounters>,
 }
 
 impl<IvkTag, D, Output, Dec, T> DynamicUsage for BatchRunner<IvkTag, D, Output, Dec, T>
@@ -501,13 +530,40 @@ where
         ivks: impl Iterator<Item = (IvkTag, D::IncomingViewingKey)>,
     ) -> Self {
         let (tags, ivks) = ivks.unzip();
+        let counters = Arc::new(BatchCounters::default());
         Self {
             batch_size_threshold,
-            acc: Batch::new(tags, ivks),
+            acc: Batch::new(tags, ivks, counters.clone()),
             running_tasks: T::new(),
             pending_results: HashMap::default(),
+            counters,
         }
     }
+
+    /// Returns the number of batches that have been submitted to the threadpool and have not
+    /// yet completed.
+    ///
+    /// This does not include the batch that is currently being accumulated.
+    pub(crate) fn pending_tasks(&self) -> usize {
+        self.counters.pending.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of batches that have completed since this runner was constructed.
+    pub(crate) fn completed_batches(&self) -> usize {
+        self.counters.completed.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pending_result_count(&self) -> usize {
+        self.pending_results.len()
+    }
+
+    /// Discards the receivers for any uncollected results of outputs that were added with the
+    /// given block tag.
+    pub(crate) fn discard_results(&mut self, block_tag: &BlockHash) {
+        self.pending_results
+            .retain(|ResultKey(tag, _), _| tag != block_tag);
+    }
 }
 
 impl<IvkTag, D, Output, Dec, T> BatchRunner<IvkTag, D, Output, Dec, T>
@@ -553,8 +609,13 @@ where
     /// Subsequent calls to `Self::add_outputs` will be accumulated into a new batch.
     pub(crate) fn flush(&mut self) {
         if !self.acc.is_empty() {
-            let mut batch = Batch::new(self.acc.tags.clone(), self.acc.ivks.clone());
+            let mut batch = Batch::new(
+                self.acc.tags.clone(),
+                self.acc.ivks.clone(),
+                self.counters.clone(),
+            );
             mem::swap(&mut batch, &mut self.acc);
+            self.counters.pending.fetch_add(1, Ordering::Relaxed);
             self.running_tasks.run_task(batch);
         }
     }
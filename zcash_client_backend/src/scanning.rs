@@ -4,39 +4,75 @@ use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt::{self, Debug};
 use std::hash::Hash;
+use std::ops::Range;
 
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20,
+};
 use incrementalmerkletree::{Position, Retention};
 use sapling::{
-    note_encryption::{CompactOutputDescription, SaplingDomain},
+    note_encryption::{CompactOutputDescription, SaplingDomain, Zip212Enforcement},
     SaplingIvk,
 };
-use subtle::{ConditionallySelectable, ConstantTimeEq, CtOption};
+use subtle::ConditionallySelectable;
 
 use zcash_keys::keys::UnifiedFullViewingKey;
-use zcash_note_encryption::{batch, BatchDomain, Domain, ShieldedOutput, COMPACT_NOTE_SIZE};
+use zcash_note_encryption::{
+    batch, BatchDomain, Domain, EphemeralKeyBytes, NotePlaintextBytes, ShieldedOutput,
+    COMPACT_NOTE_SIZE, NOTE_PLAINTEXT_SIZE,
+};
 use zcash_primitives::{
-    consensus::{self, BlockHeight, NetworkUpgrade},
-    transaction::{components::sapling::zip212_enforcement, TxId},
+    block::BlockHash,
+    consensus::{self, BlockHeight, NetworkType, NetworkUpgrade},
+    transaction::{
+        components::{amount::NonNegativeAmount, sapling::zip212_enforcement},
+        fees::zip317,
+        TxId,
+    },
 };
 use zip32::Scope;
 
 use crate::{
-    data_api::{BlockMetadata, ScannedBlock, ScannedBundles},
-    proto::compact_formats::CompactBlock,
+    data_api::{BlockMetadata, ScannedBlock, ScannedBundles, ScannedRange},
+    proto::compact_formats::{self, CompactBlock, CompactTx},
     scan::{Batch, BatchRunner, CompactDecryptor, DecryptedOutput, Tasks},
-    wallet::{WalletOutput, WalletSpend, WalletTx},
+    wallet::{
+        compute_nullifier, WalletOutput, WalletSaplingOutput, WalletSaplingSpend, WalletSpend,
+        WalletTx,
+    },
     ShieldedProtocol,
 };
 
 #[cfg(feature = "orchard")]
-use orchard::{
-    note_encryption::{CompactAction, OrchardDomain},
-    tree::MerkleHashOrchard,
+use {
+    crate::wallet::WalletOrchardSpend,
+    orchard::{
+        note_encryption::{CompactAction, OrchardDomain},
+        tree::MerkleHashOrchard,
+    },
 };
 
 #[cfg(not(feature = "orchard"))]
 use std::marker::PhantomData;
 
+#[cfg(not(feature = "fast-nullifier-lookup"))]
+use subtle::{ConstantTimeEq, CtOption};
+
+#[cfg(feature = "tokio")]
+use futures_util::stream::{self, Stream, StreamExt};
+
+#[cfg(feature = "transparent-inputs")]
+use {
+    crate::wallet::WalletTransparentOutput,
+    zcash_primitives::{
+        legacy::{Script, TransparentAddress},
+        transaction::components::{OutPoint, TxOut},
+    },
+};
+
+pub mod rate;
+
 /// A key that can be used to perform trial decryption and nullifier
 /// computation for a [`CompactSaplingOutput`] or [`CompactOrchardAction`].
 ///
@@ -69,6 +105,16 @@ pub trait ScanningKeyOps<D: Domain, AccountId, Nf> {
     /// IVK-based implementations of this trait cannot successfully derive
     /// nullifiers, in which this function will always return `None`.
     fn nf(&self, note: &D::Note, note_position: Position) -> Option<Nf>;
+
+    /// Returns the range of block heights within which this key should be used for trial
+    /// decryption, or `None` if the key should be used for blocks at all heights.
+    ///
+    /// Outputs in blocks outside of this range will not be attributed to this key. See
+    /// [`HeightBoundedKey`] for a wrapper that can be used to restrict any key to a range of
+    /// heights, for example because it has been rotated out of use.
+    fn valid_heights(&self) -> Option<Range<BlockHeight>> {
+        None
+    }
 }
 
 impl<D: Domain, AccountId, Nf, K: ScanningKeyOps<D, AccountId, Nf>> ScanningKeyOps<D, AccountId, Nf>
@@ -89,6 +135,10 @@ impl<D: Domain, AccountId, Nf, K: ScanningKeyOps<D, AccountId, Nf>> ScanningKeyO
     fn nf(&self, note: &D::Note, note_position: Position) -> Option<Nf> {
         (*self).nf(note, note_position)
     }
+
+    fn valid_heights(&self) -> Option<Range<BlockHeight>> {
+        (*self).valid_heights()
+    }
 }
 
 impl<D: Domain, AccountId, Nf> ScanningKeyOps<D, AccountId, Nf>
@@ -109,6 +159,52 @@ impl<D: Domain, AccountId, Nf> ScanningKeyOps<D, AccountId, Nf>
     fn nf(&self, note: &D::Note, note_position: Position) -> Option<Nf> {
         self.as_ref().nf(note, note_position)
     }
+
+    fn valid_heights(&self) -> Option<Range<BlockHeight>> {
+        self.as_ref().valid_heights()
+    }
+}
+
+/// A scanning key that is only used for trial decryption of outputs in blocks within a given
+/// range of heights.
+///
+/// This is useful for keys that have been rotated out of use: restricting such a key to the
+/// heights at which it was in use avoids unnecessary trial decryption work, and prevents funds
+/// received after the rotation from being attributed to the retired key.
+pub struct HeightBoundedKey<K> {
+    key: K,
+    valid_heights: Range<BlockHeight>,
+}
+
+impl<K> HeightBoundedKey<K> {
+    /// Constructs a key that is used only for blocks with heights in `valid_heights`.
+    pub fn new(key: K, valid_heights: Range<BlockHeight>) -> Self {
+        Self { key, valid_heights }
+    }
+}
+
+impl<D: Domain, AccountId, Nf, K: ScanningKeyOps<D, AccountId, Nf>> ScanningKeyOps<D, AccountId, Nf>
+    for HeightBoundedKey<K>
+{
+    fn prepare(&self) -> D::IncomingViewingKey {
+        self.key.prepare()
+    }
+
+    fn account_id(&self) -> &AccountId {
+        self.key.account_id()
+    }
+
+    fn key_scope(&self) -> Option<Scope> {
+        self.key.key_scope()
+    }
+
+    fn nf(&self, note: &D::Note, note_position: Position) -> Option<Nf> {
+        self.key.nf(note, note_position)
+    }
+
+    fn valid_heights(&self) -> Option<Range<BlockHeight>> {
+        Some(self.valid_heights.clone())
+    }
 }
 
 /// An incoming viewing key, paired with an optional nullifier key and key source metadata.
@@ -127,7 +223,9 @@ impl<AccountId> ScanningKeyOps<SaplingDomain, AccountId, sapling::Nullifier>
     }
 
     fn nf(&self, note: &sapling::Note, position: Position) -> Option<sapling::Nullifier> {
-        self.nk.as_ref().map(|key| note.nf(key, position.into()))
+        self.nk
+            .as_ref()
+            .map(|key| compute_nullifier(key, note, position))
     }
 
     fn account_id(&self) -> &AccountId {
@@ -192,6 +290,8 @@ pub struct ScanningKeys<AccountId, IvkTag> {
         IvkTag,
         Box<dyn ScanningKeyOps<OrchardDomain, AccountId, orchard::note::Nullifier>>,
     >,
+    #[cfg(feature = "transparent-inputs")]
+    transparent: HashSet<TransparentAddress>,
 }
 
 impl<AccountId, IvkTag> ScanningKeys<AccountId, IvkTag> {
@@ -210,6 +310,8 @@ impl<AccountId, IvkTag> ScanningKeys<AccountId, IvkTag> {
             sapling,
             #[cfg(feature = "orchard")]
             orchard,
+            #[cfg(feature = "transparent-inputs")]
+            transparent: HashSet::new(),
         }
     }
 
@@ -219,9 +321,27 @@ impl<AccountId, IvkTag> ScanningKeys<AccountId, IvkTag> {
             sapling: HashMap::new(),
             #[cfg(feature = "orchard")]
             orchard: HashMap::new(),
+            #[cfg(feature = "transparent-inputs")]
+            transparent: HashSet::new(),
         }
     }
 
+    /// Adds the given transparent addresses to the set of addresses for which received
+    /// transparent outputs will be detected.
+    ///
+    /// Transparent outputs can only be detected when scanning compact blocks obtained from a
+    /// server that includes transparent outputs in each [`CompactTx`].
+    ///
+    /// [`CompactTx`]: crate::proto::compact_formats::CompactTx
+    #[cfg(feature = "transparent-inputs")]
+    pub fn with_transparent_addresses(
+        mut self,
+        addresses: impl IntoIterator<Item = TransparentAddress>,
+    ) -> Self {
+        self.transparent.extend(addresses);
+        self
+    }
+
     /// Returns the Sapling keys to be used for incoming note detection.
     pub fn sapling(
         &self,
@@ -238,6 +358,163 @@ impl<AccountId, IvkTag> ScanningKeys<AccountId, IvkTag> {
     {
         &self.orchard
     }
+
+    /// Returns the transparent addresses to be used for received transparent output detection.
+    #[cfg(feature = "transparent-inputs")]
+    pub fn transparent(&self) -> &HashSet<TransparentAddress> {
+        &self.transparent
+    }
+
+    /// Prepares the incoming viewing keys of this set of scanning keys for use in trial
+    /// decryption.
+    ///
+    /// Preparing a key has a non-trivial cost, which is otherwise incurred for every transaction
+    /// that is scanned. Callers scanning many blocks with the same keys should prepare them once
+    /// and provide the result to [`ScanOptions::with_prepared_ivks`].
+    pub fn to_prepared_ivks(&self) -> PreparedIvks<IvkTag>
+    where
+        IvkTag: Clone,
+    {
+        PreparedIvks {
+            sapling: self
+                .sapling
+                .iter()
+                .map(|(tag, key)| (tag.clone(), key.prepare()))
+                .unzip(),
+            #[cfg(feature = "orchard")]
+            orchard: self
+                .orchard
+                .iter()
+                .map(|(tag, key)| (tag.clone(), key.prepare()))
+                .unzip(),
+        }
+    }
+}
+
+/// The incoming viewing keys of a [`ScanningKeys`], prepared for use in trial decryption.
+///
+/// This is obtained from [`ScanningKeys::to_prepared_ivks`], and must only be used to scan with
+/// the scanning keys from which it was obtained.
+pub struct PreparedIvks<IvkTag> {
+    sapling: (
+        Vec<IvkTag>,
+        Vec<sapling::note_encryption::PreparedIncomingViewingKey>,
+    ),
+    #[cfg(feature = "orchard")]
+    orchard: (Vec<IvkTag>, Vec<orchard::keys::PreparedIncomingViewingKey>),
+}
+
+/// A record of the outputs that could not be decrypted by a set of scanning keys, used to avoid
+/// repeating trial decryption of those outputs when blocks are rescanned.
+///
+/// Each output is identified by its ephemeral public key and note commitment, and is recorded
+/// along with the keys (identified by their tags) with which it was trial-decrypted. When a
+/// block is rescanned with the cache (see [`ScanOptions::with_decryption_cache`]), each output that was previously
+/// found to be undecryptable is only trial-decrypted with the keys that have been added since,
+/// such as those of a newly imported account.
+///
+/// The cache relies upon each tag always identifying the same key. If any key is removed from
+/// the scanning keys, the cache is invalidated; callers that replace the key associated with an
+/// existing tag must call [`DecryptionCache::clear`].
+#[derive(Clone)]
+pub struct DecryptionCache<IvkTag> {
+    sapling: PoolDecryptionCache<IvkTag>,
+    #[cfg(feature = "orchard")]
+    orchard: PoolDecryptionCache<IvkTag>,
+}
+
+impl<IvkTag> DecryptionCache<IvkTag> {
+    /// Constructs a new empty cache.
+    pub fn new() -> Self {
+        Self {
+            sapling: PoolDecryptionCache::new(),
+            #[cfg(feature = "orchard")]
+            orchard: PoolDecryptionCache::new(),
+        }
+    }
+
+    /// Returns the number of outputs that are recorded as undecryptable.
+    pub fn len(&self) -> usize {
+        let len = self.sapling.undecryptable.len();
+        #[cfg(feature = "orchard")]
+        let len = len + self.orchard.undecryptable.len();
+        len
+    }
+
+    /// Returns whether no outputs are recorded as undecryptable.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Discards all of the cached results.
+    pub fn clear(&mut self) {
+        self.sapling.clear();
+        #[cfg(feature = "orchard")]
+        self.orchard.clear();
+    }
+}
+
+impl<IvkTag> Default for DecryptionCache<IvkTag> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The outputs of a single shielded pool that could not be decrypted by a set of scanning keys.
+#[derive(Clone)]
+struct PoolDecryptionCache<IvkTag> {
+    /// The successive sets of keys with which outputs have been trial-decrypted. Each set
+    /// contains all of the keys of the set preceding it.
+    generations: Vec<HashSet<IvkTag>>,
+    /// The outputs that could not be decrypted, keyed by ephemeral public key and note
+    /// commitment, along with the index of the generation of keys with which each was last
+    /// trial-decrypted.
+    undecryptable: HashMap<([u8; 32], [u8; 32]), usize>,
+}
+
+impl<IvkTag> PoolDecryptionCache<IvkTag> {
+    fn new() -> Self {
+        Self {
+            generations: vec![],
+            undecryptable: HashMap::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generations.clear();
+        self.undecryptable.clear();
+    }
+}
+
+impl<IvkTag: Clone + Hash + Eq> PoolDecryptionCache<IvkTag> {
+    /// Returns the index of the generation corresponding to the given set of keys, adding a new
+    /// generation if the keys differ from those of the latest generation.
+    ///
+    /// If any of the keys of the latest generation is absent from the given keys, the cache is
+    /// cleared.
+    fn generation<'a>(&mut self, tags: impl Iterator<Item = &'a IvkTag>) -> usize
+    where
+        IvkTag: 'a,
+    {
+        let tags = tags.cloned().collect::<HashSet<_>>();
+        match self.generations.last() {
+            Some(latest) if latest == &tags => (),
+            Some(latest) if latest.is_subset(&tags) => self.generations.push(tags),
+            _ => {
+                self.clear();
+                self.generations.push(tags);
+            }
+        }
+        self.generations.len() - 1
+    }
+
+    /// Returns the keys with which the given output has already been found to be undecryptable,
+    /// or `None` if the output is not cached.
+    fn tried_keys(&self, output: &([u8; 32], [u8; 32])) -> Option<&HashSet<IvkTag>> {
+        self.undecryptable
+            .get(output)
+            .map(|generation| &self.generations[*generation])
+    }
 }
 
 impl<AccountId: Copy + Eq + Hash + 'static> ScanningKeys<AccountId, (AccountId, Scope)> {
@@ -293,15 +570,38 @@ impl<AccountId: Copy + Eq + Hash + 'static> ScanningKeys<AccountId, (AccountId,
             sapling,
             #[cfg(feature = "orchard")]
             orchard,
+            #[cfg(feature = "transparent-inputs")]
+            transparent: HashSet::new(),
         }
     }
 }
 
 /// The set of nullifiers being tracked by a wallet.
+///
+/// The values of the notes corresponding to the tracked nullifiers may optionally be provided,
+/// in which case they are used to compute the fees of transactions in which all of the inputs
+/// are known to the wallet (see [`WalletTx::computed_fee`]).
+///
+/// [`WalletTx::computed_fee`]: crate::wallet::WalletTx::computed_fee
+#[derive(Clone)]
 pub struct Nullifiers<AccountId> {
     sapling: Vec<(AccountId, sapling::Nullifier)>,
     #[cfg(feature = "orchard")]
     orchard: Vec<(AccountId, orchard::note::Nullifier)>,
+    sapling_values: HashMap<[u8; 32], NonNegativeAmount>,
+    #[cfg(feature = "orchard")]
+    orchard_values: HashMap<[u8; 32], NonNegativeAmount>,
+    sapling_bloom: Option<NullifierBloom>,
+    #[cfg(feature = "orchard")]
+    orchard_bloom: Option<NullifierBloom>,
+    /// The position in `sapling` of the first entry for each tracked nullifier.
+    #[cfg(feature = "fast-nullifier-lookup")]
+    sapling_index: HashMap<[u8; 32], usize>,
+    /// The position in `orchard` of the first entry for each tracked nullifier.
+    #[cfg(all(feature = "orchard", feature = "fast-nullifier-lookup"))]
+    orchard_index: HashMap<[u8; 32], usize>,
+    #[cfg(feature = "transparent-inputs")]
+    transparent: HashMap<([u8; 32], u32), AccountId>,
 }
 
 impl<AccountId> Nullifiers<AccountId> {
@@ -311,19 +611,119 @@ impl<AccountId> Nullifiers<AccountId> {
             sapling: vec![],
             #[cfg(feature = "orchard")]
             orchard: vec![],
+            sapling_values: HashMap::new(),
+            #[cfg(feature = "orchard")]
+            orchard_values: HashMap::new(),
+            sapling_bloom: None,
+            #[cfg(feature = "orchard")]
+            orchard_bloom: None,
+            #[cfg(feature = "fast-nullifier-lookup")]
+            sapling_index: HashMap::new(),
+            #[cfg(all(feature = "orchard", feature = "fast-nullifier-lookup"))]
+            orchard_index: HashMap::new(),
+            #[cfg(feature = "transparent-inputs")]
+            transparent: HashMap::new(),
         }
     }
 
     /// Construct a nullifier set from its constituent parts.
-    pub(crate) fn new(
+    pub fn new(
         sapling: Vec<(AccountId, sapling::Nullifier)>,
         #[cfg(feature = "orchard")] orchard: Vec<(AccountId, orchard::note::Nullifier)>,
     ) -> Self {
         Self {
+            #[cfg(feature = "fast-nullifier-lookup")]
+            sapling_index: nullifier_index(&sapling, |nf| nf.0),
+            #[cfg(all(feature = "orchard", feature = "fast-nullifier-lookup"))]
+            orchard_index: nullifier_index(&orchard, |nf| nf.to_bytes()),
             sapling,
             #[cfg(feature = "orchard")]
             orchard,
+            sapling_values: HashMap::new(),
+            #[cfg(feature = "orchard")]
+            orchard_values: HashMap::new(),
+            sapling_bloom: None,
+            #[cfg(feature = "orchard")]
+            orchard_bloom: None,
+            #[cfg(feature = "transparent-inputs")]
+            transparent: HashMap::new(),
+        }
+    }
+
+    /// Records the outpoints of unspent transparent outputs received by each account, so that
+    /// transactions spending them can be recognized as spending the wallet's transparent funds
+    /// (see [`WalletTx::is_shielding`]).
+    ///
+    /// [`WalletTx::is_shielding`]: crate::wallet::WalletTx::is_shielding
+    #[cfg(feature = "transparent-inputs")]
+    pub fn with_transparent_outpoints(
+        mut self,
+        outpoints: impl IntoIterator<Item = (AccountId, OutPoint)>,
+    ) -> Self {
+        self.transparent.extend(
+            outpoints
+                .into_iter()
+                .map(|(account_id, outpoint)| ((*outpoint.hash(), outpoint.n()), account_id)),
+        );
+        self
+    }
+
+    /// Returns the account that received the transparent output spent by the given input, if
+    /// its outpoint is tracked.
+    #[cfg(feature = "transparent-inputs")]
+    fn transparent_spender(&self, txin: &compact_formats::CompactTxIn) -> Option<&AccountId> {
+        let prevout_txid: [u8; 32] = txin.prevout_txid[..].try_into().ok()?;
+        self.transparent.get(&(prevout_txid, txin.prevout_index))
+    }
+
+    /// Records the values of the Sapling notes corresponding to the given nullifiers.
+    pub fn with_sapling_values(
+        mut self,
+        values: impl IntoIterator<Item = (sapling::Nullifier, NonNegativeAmount)>,
+    ) -> Self {
+        self.sapling_values
+            .extend(values.into_iter().map(|(nf, value)| (nf.0, value)));
+        self
+    }
+
+    /// Records the values of the Orchard notes corresponding to the given nullifiers.
+    #[cfg(feature = "orchard")]
+    pub fn with_orchard_values(
+        mut self,
+        values: impl IntoIterator<Item = (orchard::note::Nullifier, NonNegativeAmount)>,
+    ) -> Self {
+        self.orchard_values
+            .extend(values.into_iter().map(|(nf, value)| (nf.to_bytes(), value)));
+        self
+    }
+
+    /// Uses the given Bloom filter to cheaply reject Sapling spends that cannot match any
+    /// tracked nullifier, before the constant-time comparison against every tracked nullifier
+    /// is performed.
+    ///
+    /// The currently tracked Sapling nullifiers, and any that are subsequently added to this
+    /// set during scanning, are inserted into the filter. The filter has no effect when the
+    /// `fast-nullifier-lookup` feature is enabled.
+    ///
+    /// Note that whether or not a spend passes the filter may be observable via timing; spends
+    /// that pass the filter are likely to be spends of the wallet's notes.
+    pub fn with_sapling_bloom(mut self, mut bloom: NullifierBloom) -> Self {
+        for (_, nf) in &self.sapling {
+            bloom.insert(&nf.0);
+        }
+        self.sapling_bloom = Some(bloom);
+        self
+    }
+
+    /// Uses the given Bloom filter to cheaply reject Orchard spends that cannot match any
+    /// tracked nullifier. See [`Self::with_sapling_bloom`] for details.
+    #[cfg(feature = "orchard")]
+    pub fn with_orchard_bloom(mut self, mut bloom: NullifierBloom) -> Self {
+        for (_, nf) in &self.orchard {
+            bloom.insert(&nf.to_bytes());
         }
+        self.orchard_bloom = Some(bloom);
+        self
     }
 
     /// Returns the Sapling nullifiers for notes that the wallet is tracking.
@@ -340,7 +740,14 @@ impl<AccountId> Nullifiers<AccountId> {
     /// Discards Sapling nullifiers from the tracked nullifier set, retaining only those that
     /// satisfy the given predicate.
     pub(crate) fn retain_sapling(&mut self, f: impl Fn(&(AccountId, sapling::Nullifier)) -> bool) {
+        #[cfg(feature = "fast-nullifier-lookup")]
+        let len = self.sapling.len();
         self.sapling.retain(f);
+        // Removing entries shifts the positions of those that follow them.
+        #[cfg(feature = "fast-nullifier-lookup")]
+        if self.sapling.len() != len {
+            self.sapling_index = nullifier_index(&self.sapling, |nf| nf.0);
+        }
     }
 
     /// Adds the given nullifiers to the tracked nullifier set.
@@ -348,7 +755,16 @@ impl<AccountId> Nullifiers<AccountId> {
         &mut self,
         nfs: impl IntoIterator<Item = (AccountId, sapling::Nullifier)>,
     ) {
-        self.sapling.extend(nfs);
+        #[cfg(feature = "fast-nullifier-lookup")]
+        let start = self.sapling.len();
+        let bloom = &mut self.sapling_bloom;
+        self.sapling.extend(nfs.into_iter().inspect(|(_, nf)| {
+            if let Some(bloom) = bloom {
+                bloom.insert(&nf.0);
+            }
+        }));
+        #[cfg(feature = "fast-nullifier-lookup")]
+        extend_nullifier_index(&mut self.sapling_index, &self.sapling, start, |nf| nf.0);
     }
 
     #[cfg(feature = "orchard")]
@@ -356,7 +772,13 @@ impl<AccountId> Nullifiers<AccountId> {
         &mut self,
         f: impl Fn(&(AccountId, orchard::note::Nullifier)) -> bool,
     ) {
+        #[cfg(feature = "fast-nullifier-lookup")]
+        let len = self.orchard.len();
         self.orchard.retain(f);
+        #[cfg(feature = "fast-nullifier-lookup")]
+        if self.orchard.len() != len {
+            self.orchard_index = nullifier_index(&self.orchard, |nf| nf.to_bytes());
+        }
     }
 
     #[cfg(feature = "orchard")]
@@ -364,7 +786,137 @@ impl<AccountId> Nullifiers<AccountId> {
         &mut self,
         nfs: impl IntoIterator<Item = (AccountId, orchard::note::Nullifier)>,
     ) {
-        self.orchard.extend(nfs);
+        #[cfg(feature = "fast-nullifier-lookup")]
+        let start = self.orchard.len();
+        let bloom = &mut self.orchard_bloom;
+        self.orchard.extend(nfs.into_iter().inspect(|(_, nf)| {
+            if let Some(bloom) = bloom {
+                bloom.insert(&nf.to_bytes());
+            }
+        }));
+        #[cfg(feature = "fast-nullifier-lookup")]
+        extend_nullifier_index(&mut self.orchard_index, &self.orchard, start, |nf| {
+            nf.to_bytes()
+        });
+    }
+
+    /// Updates the tracked nullifier set to reflect the contents of the given scanned block,
+    /// discarding the nullifiers of notes that were spent in the block and adding the
+    /// nullifiers of newly received notes for which the nullifier could be computed.
+    ///
+    /// When scanning a range of blocks with [`scan_block`], call this with the result of each
+    /// block before scanning the next, so that spends of notes received earlier in the same
+    /// range are detected.
+    pub fn update_for_block(&mut self, scanned_block: &ScannedBlock<AccountId>)
+    where
+        AccountId: Copy,
+    {
+        let sapling_spent_nf: Vec<&sapling::Nullifier> = scanned_block
+            .transactions()
+            .iter()
+            .flat_map(|tx| tx.sapling_spends().iter().map(|spend| spend.nf()))
+            .collect();
+        self.retain_sapling(|(_, nf)| !sapling_spent_nf.contains(&nf));
+        for nf in sapling_spent_nf {
+            self.sapling_values.remove(&nf.0);
+        }
+        self.extend_sapling(scanned_block.transactions().iter().flat_map(|tx| {
+            tx.sapling_outputs()
+                .iter()
+                .flat_map(|out| out.nf().into_iter().map(|nf| (*out.account_id(), *nf)))
+        }));
+        self.sapling_values
+            .extend(scanned_block.transactions().iter().flat_map(|tx| {
+                tx.sapling_outputs().iter().flat_map(|out| {
+                    out.nf().into_iter().flat_map(|nf| {
+                        NonNegativeAmount::from_u64(out.note().value().inner())
+                            .ok()
+                            .map(|value| (nf.0, value))
+                    })
+                })
+            }));
+
+        #[cfg(feature = "orchard")]
+        {
+            let orchard_spent_nf: Vec<&orchard::note::Nullifier> = scanned_block
+                .transactions()
+                .iter()
+                .flat_map(|tx| tx.orchard_spends().iter().map(|spend| spend.nf()))
+                .collect();
+
+            self.retain_orchard(|(_, nf)| !orchard_spent_nf.contains(&nf));
+            for nf in orchard_spent_nf {
+                self.orchard_values.remove(&nf.to_bytes());
+            }
+            self.extend_orchard(scanned_block.transactions().iter().flat_map(|tx| {
+                tx.orchard_outputs()
+                    .iter()
+                    .flat_map(|out| out.nf().into_iter().map(|nf| (*out.account_id(), *nf)))
+            }));
+            self.orchard_values
+                .extend(scanned_block.transactions().iter().flat_map(|tx| {
+                    tx.orchard_outputs().iter().flat_map(|out| {
+                        out.nf().into_iter().flat_map(|nf| {
+                            NonNegativeAmount::from_u64(out.note().value().inner())
+                                .ok()
+                                .map(|value| (nf.to_bytes(), value))
+                        })
+                    })
+                }));
+        }
+    }
+}
+
+/// A Bloom filter over the byte encodings of tracked nullifiers.
+///
+/// A filter may report that a nullifier it does not contain is present (with a false-positive
+/// rate of roughly 1% when no more than the expected number of nullifiers have been inserted),
+/// but never reports that an inserted nullifier is absent. It can therefore be used via
+/// [`Nullifiers::with_sapling_bloom`] to skip the comparison of most unrelated spends against
+/// the tracked nullifier set.
+///
+/// Nullifiers are pseudorandom, so the filter derives its bit indices directly from the bytes
+/// of the nullifier rather than hashing it.
+#[derive(Clone, Debug)]
+pub struct NullifierBloom {
+    bits: Vec<u64>,
+}
+
+impl NullifierBloom {
+    /// The number of bit indices derived from each nullifier.
+    const HASH_COUNT: u64 = 7;
+
+    /// Constructs an empty filter sized for the given number of nullifiers.
+    pub fn new(expected_nullifiers: usize) -> Self {
+        // Roughly 9.6 bits per element yields a 1% false-positive rate with 7 hash functions.
+        let bit_count = (expected_nullifiers.max(1) * 10)
+            .next_power_of_two()
+            .max(64);
+        Self {
+            bits: vec![0; bit_count / 64],
+        }
+    }
+
+    fn bit_indices(&self, nf: &[u8; 32]) -> impl Iterator<Item = usize> {
+        let bit_count = self.bits.len() as u64 * 64;
+        let h1 = u64::from_le_bytes(nf[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(nf[8..16].try_into().unwrap()) | 1;
+        (0..Self::HASH_COUNT)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+
+    /// Inserts the byte encoding of a nullifier into the filter.
+    pub fn insert(&mut self, nf: &[u8; 32]) {
+        for i in self.bit_indices(nf) {
+            self.bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+
+    /// Returns `false` if the nullifier with the given byte encoding has definitely not been
+    /// inserted into the filter, or `true` if it may have been.
+    pub fn may_contain(&self, nf: &[u8; 32]) -> bool {
+        self.bit_indices(nf)
+            .all(|i| self.bits[i / 64] & (1 << (i % 64)) != 0)
     }
 }
 
@@ -402,9 +954,16 @@ pub enum ScanError {
     /// The size of the note commitment tree for the given protocol was not provided as part of a
     /// [`CompactBlock`] being scanned, making it impossible to construct the nullifier for a
     /// detected note.
+    ///
+    /// `metadata_absent` is `true` if the block was provided without any chain metadata at all,
+    /// which indicates that the source of the block (e.g. `lightwalletd`) does not track note
+    /// commitment tree sizes; in this case, retrying with the same source will not succeed. It is
+    /// `false` if chain metadata was present, but contained only default (zero) tree sizes for a
+    /// block that has outputs in the given protocol's pool.
     TreeSizeUnknown {
         protocol: ShieldedProtocol,
         at_height: BlockHeight,
+        metadata_absent: bool,
     },
 
     /// We were provided chain metadata for a block containing note commitment tree metadata
@@ -414,6 +973,124 @@ pub enum ScanError {
         protocol: ShieldedProtocol,
         at_height: BlockHeight,
     },
+
+    /// The index of a transaction within the block being scanned could not be represented as
+    /// a `u16`.
+    TxIndexOverflow {
+        at_height: BlockHeight,
+        txid: TxId,
+        index: u64,
+    },
+
+    /// The number of outputs (for Sapling) or actions (for Orchard) in the block being scanned,
+    /// or the resulting note commitment tree size, could not be represented as a `u32`.
+    OutputCountOverflow {
+        protocol: ShieldedProtocol,
+        at_height: BlockHeight,
+    },
+
+    /// An output was decrypted by the batch runner using an incoming viewing key that does not
+    /// correspond to any of the scanning keys provided to the scanner. This indicates that the
+    /// set of keys used to prime the batch runner has diverged from the set of keys used to
+    /// scan the block, for example because an account was added partway through a scan.
+    UnknownIvkTag {
+        protocol: ShieldedProtocol,
+        at_height: BlockHeight,
+        txid: TxId,
+        output_index: usize,
+    },
+
+    /// A transparent output in the block being scanned could not be decoded from its compact
+    /// representation.
+    TransparentOutputInvalid {
+        at_height: BlockHeight,
+        txid: TxId,
+        index: usize,
+    },
+
+    /// The consensus parameters used for scanning specify an NU5 activation height that is not
+    /// preceded by Sapling activation. This indicates misconfigured parameters (for example, for
+    /// a custom regtest network), which would otherwise result in incorrect note commitment tree
+    /// sizes.
+    InconsistentActivationHeights {
+        at_height: BlockHeight,
+        sapling_activation: Option<BlockHeight>,
+        nu5_activation: BlockHeight,
+    },
+
+    /// Connecting the proposed new block to the wallet's view of the chain would require
+    /// rewinding more than the permitted number of blocks. `depth` is the number of blocks that
+    /// would need to be rewound, or a lower bound on that number if the fork point lies below
+    /// the blocks known to the caller.
+    ReorgTooDeep { at_height: BlockHeight, depth: u32 },
+
+    /// A spend in the block being scanned revealed a nullifier that the wallet is tracking for
+    /// more than one account. This indicates that the wallet's store of notes or keys has been
+    /// corrupted, since a note can only belong to a single account. This error is only returned
+    /// if [`ScanConfig::reject_duplicate_nullifiers`] is set.
+    DuplicateTrackedNullifier {
+        protocol: ShieldedProtocol,
+        at_height: BlockHeight,
+        txid: TxId,
+        index: usize,
+    },
+
+    /// The metadata provided for the block preceding the block being scanned did not include
+    /// the size of the note commitment tree for a shielded protocol that was active as of that
+    /// block, and the block being scanned has no chain metadata from which the tree size could
+    /// be determined instead. This indicates that the metadata was misconfigured; for example, it
+    /// may have been constructed without an Orchard tree size for a block after NU5 activation.
+    MissingPoolMetadata {
+        protocol: ShieldedProtocol,
+        at_height: BlockHeight,
+    },
+
+    /// The block being scanned contains more shielded outputs (Sapling outputs and Orchard
+    /// actions combined) than permitted by [`ScanConfig::max_outputs_per_block`]. This indicates
+    /// that the block was fabricated by the source from which it was obtained, since no block
+    /// that is valid under the consensus rules can contain that many outputs.
+    BlockTooLarge {
+        at_height: BlockHeight,
+        output_count: usize,
+    },
+
+    /// The note commitment tree position computed for a Sapling output when rescanning a block
+    /// (see [`ScanOptions::with_expected_positions`]) differs from the position that the caller
+    /// expected. `computed` is `None` if the output was not detected by any of the scanning
+    /// keys.
+    PositionMismatch {
+        at_height: BlockHeight,
+        txid: TxId,
+        output_index: usize,
+        expected: Position,
+        computed: Option<Position>,
+    },
+
+    /// Appending the outputs of the block being scanned to the note commitment tree for the
+    /// given protocol would grow the tree beyond the limit set by
+    /// [`ScanConfig::max_sapling_tree_size`]. This allows a wallet that has frozen a pool to
+    /// detect a block that would nonetheless add notes to it.
+    TreeSizeExceeded {
+        protocol: ShieldedProtocol,
+        limit: u64,
+        at_height: BlockHeight,
+    },
+
+    /// The nullifier revealed by a compact Sapling spend or compact Orchard action could not be
+    /// decoded from its compact representation.
+    NullifierInvalid {
+        protocol: ShieldedProtocol,
+        at_height: BlockHeight,
+        txid: TxId,
+        index: usize,
+    },
+
+    /// An error was encountered while scanning a block that was obtained from the source with
+    /// the given identifier (see [`ScanConfig::source_id`]).
+    FromSource {
+        source_id: u64,
+        error: Box<ScanError>,
+    },
 }
 
 impl ScanError {
@@ -427,6 +1104,19 @@ impl ScanError {
             TreeSizeMismatch { .. } => true,
             TreeSizeUnknown { .. } => false,
             TreeSizeInvalid { .. } => false,
+            TxIndexOverflow { .. } => false,
+            OutputCountOverflow { .. } => false,
+            UnknownIvkTag { .. } => false,
+            TransparentOutputInvalid { .. } => false,
+            InconsistentActivationHeights { .. } => false,
+            ReorgTooDeep { .. } => true,
+            DuplicateTrackedNullifier { .. } => false,
+            MissingPoolMetadata { .. } => false,
+            BlockTooLarge { .. } => false,
+            PositionMismatch { .. } => false,
+            TreeSizeExceeded { .. } => false,
+            NullifierInvalid { .. } => false,
+            FromSource { error, .. } => error.is_continuity_error(),
         }
     }
 
@@ -440,8 +1130,105 @@ impl ScanError {
             TreeSizeMismatch { at_height, .. } => *at_height,
             TreeSizeUnknown { at_height, .. } => *at_height,
             TreeSizeInvalid { at_height, .. } => *at_height,
+            TxIndexOverflow { at_height, .. } => *at_height,
+            OutputCountOverflow { at_height, .. } => *at_height,
+            UnknownIvkTag { at_height, .. } => *at_height,
+            TransparentOutputInvalid { at_height, .. } => *at_height,
+            InconsistentActivationHeights { at_height, .. } => *at_height,
+            ReorgTooDeep { at_height, .. } => *at_height,
+            DuplicateTrackedNullifier { at_height, .. } => *at_height,
+            MissingPoolMetadata { at_height, .. } => *at_height,
+            BlockTooLarge { at_height, .. } => *at_height,
+            PositionMismatch { at_height, .. } => *at_height,
+            TreeSizeExceeded { at_height, .. } => *at_height,
+            NullifierInvalid { at_height, .. } => *at_height,
+            FromSource { error, .. } => error.at_height(),
+        }
+    }
+
+    /// Returns a stable, machine-readable identifier for the kind of this error.
+    ///
+    /// Unlike the [`Display`] representation of the error, the returned codes will not
+    /// change between releases, and so are suitable for aggregation (e.g. in telemetry).
+    ///
+    /// [`Display`]: fmt::Display
+    pub fn code(&self) -> &'static str {
+        use ScanError::*;
+        match self {
+            EncodingInvalid { .. } => "encoding-invalid",
+            PrevHashMismatch { .. } => "prev-hash-mismatch",
+            BlockHeightDiscontinuity { .. } => "block-height-discontinuity",
+            TreeSizeMismatch { .. } => "tree-size-mismatch",
+            TreeSizeUnknown { .. } => "tree-size-unknown",
+            TreeSizeInvalid { .. } => "tree-size-invalid",
+            TxIndexOverflow { .. } => "tx-index-overflow",
+            OutputCountOverflow { .. } => "output-count-overflow",
+            UnknownIvkTag { .. } => "unknown-ivk-tag",
+            TransparentOutputInvalid { .. } => "transparent-output-invalid",
+            InconsistentActivationHeights { .. } => "inconsistent-activation-heights",
+            ReorgTooDeep { .. } => "reorg-too-deep",
+            DuplicateTrackedNullifier { .. } => "duplicate-tracked-nullifier",
+            MissingPoolMetadata { .. } => "missing-pool-metadata",
+            BlockTooLarge { .. } => "block-too-large",
+            PositionMismatch { .. } => "position-mismatch",
+            TreeSizeExceeded { .. } => "tree-size-exceeded",
+            NullifierInvalid { .. } => "nullifier-invalid",
+            FromSource { error, .. } => error.code(),
+        }
+    }
+
+    /// Attaches the identifier of the source from which the block being scanned was obtained
+    /// to this error.
+    ///
+    /// If this error is already attributed to a source, that source is replaced.
+    pub fn with_source(self, source_id: u64) -> Self {
+        let error = match self {
+            ScanError::FromSource { error, .. } => error,
+            other => Box::new(other),
+        };
+        ScanError::FromSource { source_id, error }
+    }
+
+    /// Returns the identifier of the source from which the block that could not be scanned was
+    /// obtained, if known.
+    pub fn source_id(&self) -> Option<u64> {
+        match self {
+            ScanError::FromSource { source_id, .. } => Some(*source_id),
+            _ => None,
         }
     }
+
+    /// Returns a value that displays this error along with the network on which it occurred,
+    /// such that messages logged by a backend serving multiple networks can be distinguished.
+    pub fn display_with_network(&self, network: NetworkType) -> impl fmt::Display + '_ {
+        ScanErrorOnNetwork {
+            error: self,
+            network,
+        }
+    }
+}
+
+/// The [`fmt::Display`] implementation returned by [`ScanError::display_with_network`].
+struct ScanErrorOnNetwork<'a> {
+    error: &'a ScanError,
+    network: NetworkType,
+}
+
+impl fmt::Display for ScanErrorOnNetwork<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = self.error.to_string();
+        let network = match self.network {
+            NetworkType::Main => "mainnet",
+            NetworkType::Test => "testnet",
+            NetworkType::Regtest => "regtest",
+        };
+        write!(
+            f,
+            "{} on {}",
+            message.strip_suffix('.').unwrap_or(&message),
+            network
+        )
+    }
 }
 
 impl fmt::Display for ScanError {
@@ -464,13 +1251,336 @@ impl fmt::Display for ScanError {
             TreeSizeMismatch { protocol, at_height, given, computed } => {
                 write!(f, "The {:?} note commitment tree size provided by a compact block did not match the expected size at height {}; given {}, expected {}", protocol, at_height, given, computed)
             }
-            TreeSizeUnknown { protocol, at_height } => {
-                write!(f, "Unable to determine {:?} note commitment tree size at height {}", protocol, at_height)
+            TreeSizeUnknown { protocol, at_height, metadata_absent } => {
+                write!(f, "Unable to determine {:?} note commitment tree size at height {}", protocol, at_height)?;
+                if *metadata_absent {
+                    write!(f, "; no chain metadata was provided")
+                } else {
+                    write!(f, "; chain metadata contained only default values")
+                }
             }
             TreeSizeInvalid { protocol, at_height } => {
                 write!(f, "Received invalid (potentially default) {:?} note commitment tree size metadata at height {}", protocol, at_height)
             }
-        }
+            TxIndexOverflow { at_height, txid, index } => {
+                write!(f, "Transaction {} at height {} has index {}, which exceeds the maximum supported transaction index", txid, at_height, index)
+            }
+            OutputCountOverflow { protocol, at_height } => {
+                write!(f, "The number of {:?} outputs in the block at height {} exceeds the maximum supported note commitment tree size", protocol, at_height)
+            }
+            UnknownIvkTag { protocol, at_height, txid, output_index } => {
+                write!(f, "{:?} output {} of transaction {} at height {} was decrypted with a key that is not among the scanning keys", protocol, output_index, txid, at_height)
+            }
+            TransparentOutputInvalid { at_height, txid, index } => {
+                write!(f, "Transparent output {} of transaction {} at height {} could not be decoded", index, txid, at_height)
+            }
+            InconsistentActivationHeights { at_height, sapling_activation, nu5_activation } => {
+                write!(f, "Unable to scan block at height {}: NU5 activates at height {}, ", at_height, nu5_activation)?;
+                match sapling_activation {
+                    Some(h) => write!(f, "before Sapling activation at height {}", h),
+                    None => write!(f, "but no Sapling activation height is set"),
+                }
+            }
+            ReorgTooDeep { at_height, depth } => {
+                write!(f, "Connecting the block at height {} would require rewinding at least {} blocks", at_height, depth)
+            }
+            DuplicateTrackedNullifier { protocol, at_height, txid, index } => {
+                write!(f, "The nullifier revealed by {:?} spend {} of transaction {} at height {} is tracked for more than one account", protocol, index, txid, at_height)
+            }
+            MissingPoolMetadata { protocol, at_height } => {
+                write!(f, "The metadata for the block preceding height {} does not include the {:?} note commitment tree size", at_height, protocol)
+            }
+            BlockTooLarge { at_height, output_count } => {
+                write!(f, "The block at height {} contains {} shielded outputs, which exceeds the maximum permitted number of outputs per block", at_height, output_count)
+            }
+            PositionMismatch { at_height, txid, output_index, expected, computed } => match computed {
+                Some(computed) => write!(f, "Sapling output {} of transaction {} at height {} was expected at note commitment tree position {:?}, but scanning placed it at position {:?}", output_index, txid, at_height, expected, computed),
+                None => write!(f, "Sapling output {} of transaction {} at height {} was expected at note commitment tree position {:?}, but it was not detected by scanning", output_index, txid, at_height, expected),
+            },
+            TreeSizeExceeded { protocol, limit, at_height } => {
+                write!(f, "The block at height {} would grow the {:?} note commitment tree beyond the maximum permitted size of {}", at_height, protocol, limit)
+            }
+            NullifierInvalid { protocol, at_height, txid, index } => {
+                write!(f, "The nullifier revealed by {:?} spend {} of transaction {} at height {} could not be decoded", protocol, index, txid, at_height)
+            }
+            FromSource { source_id, error } => {
+                write!(f, "{} (block obtained from source {})", error, source_id)
+            }
+        }
+    }
+}
+
+/// An error encountered while scanning a block, together with identifying information about
+/// the block that was being scanned.
+///
+/// This is returned by [`scan_block_detailed`], so that callers may log diagnostic context for
+/// a block that could not be scanned.
+#[derive(Clone, Debug)]
+pub struct BlockScanFailure {
+    block_height: BlockHeight,
+    block_hash: BlockHash,
+    block_time: u32,
+    error: ScanError,
+}
+
+impl BlockScanFailure {
+    /// Returns the height of the block that could not be scanned.
+    pub fn block_height(&self) -> BlockHeight {
+        self.block_height
+    }
+
+    /// Returns the hash of the block that could not be scanned.
+    pub fn block_hash(&self) -> BlockHash {
+        self.block_hash
+    }
+
+    /// Returns the block time of the block that could not be scanned, as a Unix timestamp in
+    /// seconds.
+    pub fn block_time(&self) -> u32 {
+        self.block_time
+    }
+
+    /// Returns the error that caused scanning to fail.
+    pub fn error(&self) -> &ScanError {
+        &self.error
+    }
+
+    /// Consumes `self` and returns the error that caused scanning to fail.
+    pub fn into_error(self) -> ScanError {
+        self.error
+    }
+}
+
+impl fmt::Display for BlockScanFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to scan block {} (hash {}, time {}): {}",
+            self.block_height, self.block_hash, self.block_time, self.error
+        )
+    }
+}
+
+/// Options that control how a block is scanned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScanConfig {
+    /// Whether to compute the note commitments of the block's shielded outputs, for insertion
+    /// into the wallet's note commitment trees.
+    ///
+    /// When `false`, the [`ScannedBundles::commitments`] of the resulting [`ScannedBlock`] are
+    /// empty, and the hashing required to compute tree nodes from the outputs' note commitments
+    /// is skipped. Received notes are still detected and their note commitment tree positions
+    /// are still reported, but witnesses for them cannot be constructed from the scan results.
+    /// This is useful for wallets that only need to determine whether they have received
+    /// funds.
+    pub track_commitments: bool,
+    /// The value below which received notes are considered to be dust, because they would cost
+    /// more to spend than they are worth.
+    ///
+    /// Received outputs with values below this threshold are flagged (see
+    /// [`WalletOutput::below_dust_threshold`]). Defaults to the ZIP 317 marginal fee, which is
+    /// the fee incurred by spending a single note.
+    pub dust_threshold: NonNegativeAmount,
+    /// Whether to return [`ScanError::DuplicateTrackedNullifier`] if a spend in the block
+    /// reveals a nullifier that is tracked for more than one account.
+    ///
+    /// When `false`, such a spend is attributed to the first matching account in the tracked
+    /// nullifier set. Checking for duplicates requires an additional pass over the tracked
+    /// nullifiers for each spend that is detected.
+    pub reject_duplicate_nullifiers: bool,
+    /// Whether to require the block's `prev_hash` to match the hash of the prior block.
+    ///
+    /// When `false`, the block's `prev_hash` is not inspected, so blocks that omit it (as are
+    /// served by some regtest environments) can be scanned; the block's height must still
+    /// directly follow that of the prior block. This should only be disabled when the blocks
+    /// are obtained from a trusted source, as chain reorganizations cannot then be detected.
+    pub require_prev_hash: bool,
+    /// An opaque identifier for the source (for example, the peer or `lightwalletd` server)
+    /// from which the block was obtained.
+    ///
+    /// When set, the identifier is recorded on the resulting [`ScannedBlock`] (see
+    /// [`ScannedBlock::source_id`]) and on any error encountered while scanning the block (see
+    /// [`ScanError::with_source`]), so that wallets that download blocks from multiple sources
+    /// can identify a source that provided an invalid block.
+    pub source_id: Option<u64>,
+    /// The maximum number of shielded outputs (Sapling outputs and Orchard actions combined)
+    /// that a block may contain, or `None` for no limit.
+    ///
+    /// A block that exceeds this limit is rejected with [`ScanError::BlockTooLarge`] before any
+    /// of its outputs are decoded, so that a malicious source cannot exhaust the scanner's
+    /// memory by serving a block containing an enormous number of fabricated outputs. Defaults
+    /// to [`DEFAULT_MAX_OUTPUTS_PER_BLOCK`].
+    pub max_outputs_per_block: Option<usize>,
+    /// Whether to count the outputs in the block that were decrypted by the key for each
+    /// account and scope.
+    ///
+    /// When `true`, the counts are available from [`ScannedBlock::key_hits`]. This allows a
+    /// wallet with many viewing keys to identify the keys that are actually receiving funds.
+    pub track_key_hits: bool,
+    /// The maximum size of the Sapling note commitment tree after the block has been scanned,
+    /// or `None` for no limit.
+    ///
+    /// A block whose Sapling outputs would grow the tree beyond this size is rejected with
+    /// [`ScanError::TreeSizeExceeded`]. This may be used by a wallet that has frozen the Sapling
+    /// pool (for example, after migrating its funds to Orchard) to assert that the Sapling tree
+    /// does not grow any further.
+    pub max_sapling_tree_size: Option<u64>,
+    /// Whether to trial-decrypt the block's Sapling outputs.
+    ///
+    /// When `false`, no Sapling outputs are detected, but the Sapling note commitment tree size
+    /// is still advanced by the block's outputs (and their note commitments are still tracked
+    /// if [`Self::track_commitments`] is set), so that the positions of notes detected in later
+    /// scans remain correct. Spends of tracked Sapling nullifiers are still detected.
+    pub scan_sapling: bool,
+    /// Whether to trial-decrypt the block's Orchard actions.
+    ///
+    /// This behaves in the same manner as [`Self::scan_sapling`]. It may be disabled by a wallet
+    /// restoring accounts that predate NU5, to avoid trial decryption of Orchard actions that
+    /// cannot have been sent to it. This has no effect if the `orchard` feature is not enabled.
+    pub scan_orchard: bool,
+    /// Whether the source of the block populates the transparent inputs and outputs (the `vin`
+    /// and `vout` fields) of its compact transactions.
+    ///
+    /// Compact transactions do not indicate whether their transparent data has been omitted, so
+    /// a transaction's fee (see [`WalletTx::computed_fee`]) can only be computed if the source
+    /// is known to provide it; otherwise, the value of any transparent outputs would be counted
+    /// as part of the fee. Defaults to `false`, in which case no fees are computed.
+    pub transparent_data_available: bool,
+}
+
+/// The default value of [`ScanConfig::max_outputs_per_block`].
+///
+/// A block that is valid under the consensus rules is at most 2 MB in size, and each Sapling
+/// output or Orchard action occupies more than 800 bytes of it, so no valid block can contain
+/// more than about 2,500 shielded outputs. This limit leaves ample margin above that bound.
+pub const DEFAULT_MAX_OUTPUTS_PER_BLOCK: usize = 10_000;
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            track_commitments: true,
+            dust_threshold: zip317::MARGINAL_FEE,
+            reject_duplicate_nullifiers: false,
+            require_prev_hash: true,
+            source_id: None,
+            max_outputs_per_block: Some(DEFAULT_MAX_OUTPUTS_PER_BLOCK),
+            track_key_hits: false,
+            max_sapling_tree_size: None,
+            scan_sapling: true,
+            scan_orchard: true,
+            transparent_data_available: false,
+        }
+    }
+}
+
+/// The inputs to [`scan_block_with_config`] that are specific to an individual scan, in
+/// addition to the [`ScanConfig`] that controls how the block is scanned.
+///
+/// A [`ScanConfig`] may be provided wherever a `ScanOptions` is expected, in which case none of
+/// the optional inputs are supplied. The Sapling note commitment tree leaves of the scanned
+/// block are constructed using the [`CommitmentHasher`] `H`.
+pub struct ScanOptions<'a, IvkTag, H = SaplingCommitmentHasher> {
+    config: ScanConfig,
+    prepared_ivks: Option<&'a PreparedIvks<IvkTag>>,
+    decryption_cache: Option<&'a mut DecryptionCache<IvkTag>>,
+    tx_range: Option<Range<usize>>,
+    expected_positions: Option<&'a HashMap<TxId, Vec<(usize, Position)>>>,
+    hasher: PhantomData<H>,
+}
+
+impl<'a, IvkTag> ScanOptions<'a, IvkTag> {
+    /// Constructs the options for a scan performed according to the given [`ScanConfig`].
+    pub fn new(config: ScanConfig) -> Self {
+        Self {
+            config,
+            prepared_ivks: None,
+            decryption_cache: None,
+            tx_range: None,
+            expected_positions: None,
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<'a, IvkTag, H> ScanOptions<'a, IvkTag, H> {
+    /// Scans using incoming viewing keys that have already been prepared for trial decryption.
+    ///
+    /// `prepared_ivks` must have been obtained from the scanning keys with which the block is
+    /// scanned, using [`ScanningKeys::to_prepared_ivks`]. This avoids repeating the preparation
+    /// of each key for every transaction that is scanned; keys that are restricted to a range of
+    /// heights (see [`ScanningKeyOps::valid_heights`]) are prepared as needed for blocks outside
+    /// of that range.
+    pub fn with_prepared_ivks(mut self, prepared_ivks: &'a PreparedIvks<IvkTag>) -> Self {
+        self.prepared_ivks = Some(prepared_ivks);
+        self
+    }
+
+    /// Uses the given [`DecryptionCache`] to avoid repeating the trial decryption of outputs that
+    /// have previously been found to be undecryptable.
+    ///
+    /// Outputs that cannot be decrypted by any of the scanning keys are recorded in the cache, so
+    /// that when the block is subsequently rescanned, only keys that have since been added to the
+    /// scanning keys are tried against those outputs. This makes rescanning a range of blocks
+    /// after importing a key substantially cheaper. Outputs are only recorded if all of the
+    /// scanning keys are valid at the height of the block (see [`ScanningKeyOps::valid_heights`]).
+    pub fn with_decryption_cache(
+        mut self,
+        decryption_cache: &'a mut DecryptionCache<IvkTag>,
+    ) -> Self {
+        self.decryption_cache = Some(decryption_cache);
+        self
+    }
+
+    /// Scans only the transactions of the block having indices within `tx_range`.
+    ///
+    /// This allows the trial decryption of a very large block to be distributed across multiple
+    /// workers, each of which scans a disjoint range of the block's transactions. Transactions
+    /// outside of `tx_range` are not trial-decrypted and their spends are not checked against the
+    /// provided nullifiers, but their note commitments are included in the returned
+    /// [`ScannedBlock`] (as ephemeral commitments), so that the note commitment tree positions of
+    /// detected notes are the same as they would be in a scan of the entire block.
+    pub fn with_tx_range(mut self, tx_range: Range<usize>) -> Self {
+        self.tx_range = Some(tx_range);
+        self
+    }
+
+    /// Checks that the note commitment tree positions of the detected Sapling outputs match
+    /// those that the caller expects.
+    ///
+    /// `expected` maps the ID of each transaction to the indices of its Sapling outputs that the
+    /// caller has previously received, along with the position that was recorded for each. This
+    /// allows a wallet that is repairing its witnesses to confirm that a rescan of a historical
+    /// block reproduces the positions it has stored. The scan returns
+    /// [`ScanError::PositionMismatch`] for the first expected output (in transaction and then
+    /// output order) that was either not detected, or was detected at a different position.
+    pub fn with_expected_positions(
+        mut self,
+        expected: &'a HashMap<TxId, Vec<(usize, Position)>>,
+    ) -> Self {
+        self.expected_positions = Some(expected);
+        self
+    }
+
+    /// Constructs the Sapling note commitment tree leaves of the scanned block using the
+    /// [`CommitmentHasher`] `H2`.
+    ///
+    /// This is intended for testing; production callers should use the default of
+    /// [`SaplingCommitmentHasher`].
+    pub fn with_hasher<H2: CommitmentHasher>(self) -> ScanOptions<'a, IvkTag, H2> {
+        ScanOptions {
+            config: self.config,
+            prepared_ivks: self.prepared_ivks,
+            decryption_cache: self.decryption_cache,
+            tx_range: self.tx_range,
+            expected_positions: self.expected_positions,
+            hasher: PhantomData,
+        }
+    }
+}
+
+impl<'a, IvkTag> From<ScanConfig> for ScanOptions<'a, IvkTag> {
+    fn from(config: ScanConfig) -> Self {
+        Self::new(config)
     }
 }
 
@@ -479,6 +1589,10 @@ impl fmt::Display for ScanError {
 /// Returns a vector of [`WalletTx`]s decryptable by any of the given keys. If an output is
 /// decrypted by a full viewing key, the nullifiers of that output will also be computed.
 ///
+/// The outputs of each returned [`WalletTx`] are ordered by their index within the
+/// transaction, independent of the order in which the scanning keys are iterated or of how
+/// many keys matched outputs of the transaction.
+///
 /// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
 /// [`WalletTx`]: crate::wallet::WalletTx
 pub fn scan_block<P, AccountId, IvkTag>(
@@ -491,743 +1605,3185 @@ pub fn scan_block<P, AccountId, IvkTag>(
 where
     P: consensus::Parameters + Send + 'static,
     AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
-    IvkTag: Copy + std::hash::Hash + Eq + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
 {
-    scan_block_with_runners::<_, _, _, (), ()>(
+    scan_block_with_runners::<_, _, _, _, (), ()>(
         params,
         block,
         scanning_keys,
         nullifiers,
         prior_block_metadata,
+        ScanConfig::default(),
         None,
     )
 }
 
-type TaggedSaplingBatch<IvkTag> = Batch<
-    IvkTag,
-    SaplingDomain,
-    sapling::note_encryption::CompactOutputDescription,
-    CompactDecryptor,
->;
-type TaggedSaplingBatchRunner<IvkTag, Tasks> = BatchRunner<
-    IvkTag,
-    SaplingDomain,
-    sapling::note_encryption::CompactOutputDescription,
-    CompactDecryptor,
-    Tasks,
->;
-
-#[cfg(feature = "orchard")]
-type TaggedOrchardBatch<IvkTag> =
-    Batch<IvkTag, OrchardDomain, orchard::note_encryption::CompactAction, CompactDecryptor>;
-#[cfg(feature = "orchard")]
-type TaggedOrchardBatchRunner<IvkTag, Tasks> = BatchRunner<
-    IvkTag,
-    OrchardDomain,
-    orchard::note_encryption::CompactAction,
-    CompactDecryptor,
-    Tasks,
->;
-
-pub(crate) trait SaplingTasks<IvkTag>: Tasks<TaggedSaplingBatch<IvkTag>> {}
-impl<IvkTag, T: Tasks<TaggedSaplingBatch<IvkTag>>> SaplingTasks<IvkTag> for T {}
-
-#[cfg(not(feature = "orchard"))]
-pub(crate) trait OrchardTasks<IvkTag> {}
-#[cfg(not(feature = "orchard"))]
-impl<IvkTag, T> OrchardTasks<IvkTag> for T {}
+/// A strategy for constructing the Sapling note commitment tree leaves that are returned as
+/// part of a [`ScannedBlock`].
+///
+/// This exists so that test harnesses and fuzzers can exercise the scanning logic with
+/// instrumented or alternative leaf constructions via [`ScanOptions::with_hasher`]. Scans use
+/// [`SaplingCommitmentHasher`] unless another hasher is specified.
+pub trait CommitmentHasher {
+    /// Returns the note commitment tree leaf for the given Sapling note commitment.
+    fn sapling_node(cmu: &sapling::note::ExtractedNoteCommitment) -> sapling::Node;
+}
 
-#[cfg(feature = "orchard")]
-pub(crate) trait OrchardTasks<IvkTag>: Tasks<TaggedOrchardBatch<IvkTag>> {}
-#[cfg(feature = "orchard")]
-impl<IvkTag, T: Tasks<TaggedOrchardBatch<IvkTag>>> OrchardTasks<IvkTag> for T {}
+/// The [`CommitmentHasher`] used by the Zcash protocol, which constructs leaves via
+/// [`sapling::Node::from_cmu`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SaplingCommitmentHasher;
 
-pub(crate) struct BatchRunners<IvkTag, TS: SaplingTasks<IvkTag>, TO: OrchardTasks<IvkTag>> {
-    sapling: TaggedSaplingBatchRunner<IvkTag, TS>,
-    #[cfg(feature = "orchard")]
-    orchard: TaggedOrchardBatchRunner<IvkTag, TO>,
-    #[cfg(not(feature = "orchard"))]
-    orchard: PhantomData<TO>,
+impl CommitmentHasher for SaplingCommitmentHasher {
+    #[inline]
+    fn sapling_node(cmu: &sapling::note::ExtractedNoteCommitment) -> sapling::Node {
+        sapling::Node::from_cmu(cmu)
+    }
 }
 
-impl<IvkTag, TS, TO> BatchRunners<IvkTag, TS, TO>
+/// Scans a [`CompactBlock`] with a set of [`ScanningKeys`], as with [`scan_block`], using the
+/// given [`ScanConfig`] or [`ScanOptions`].
+///
+/// To scan for the notes of accounts identified by their unified full viewing keys, construct
+/// the scanning keys with [`ScanningKeys::from_account_ufvks`]. Trial decryption is then
+/// performed for every shielded protocol for which a UFVK has a component key, in a single pass
+/// over the block, so that the resulting [`WalletTx`]s contain the outputs received in all
+/// pools. A received note is marked as change if its account spent a note (in any pool) in the
+/// same transaction; for example, Orchard change resulting from a Sapling spend is detected as
+/// change. Callers scanning a sequence of blocks should construct the scanning keys once, rather
+/// than for each block.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn scan_block_with_config<'a, H, P, AccountId, IvkTag>(
+    params: &P,
+    block: CompactBlock,
+    scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+    nullifiers: &Nullifiers<AccountId>,
+    prior_block_metadata: Option<&BlockMetadata>,
+    options: impl Into<ScanOptions<'a, IvkTag, H>>,
+) -> Result<ScannedBlock<AccountId>, ScanError>
 where
-    IvkTag: Clone + Send + 'static,
-    TS: SaplingTasks<IvkTag>,
-    TO: OrchardTasks<IvkTag>,
+    H: CommitmentHasher,
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
 {
-    pub(crate) fn for_keys<AccountId>(
-        batch_size_threshold: usize,
-        scanning_keys: &ScanningKeys<AccountId, IvkTag>,
-    ) -> Self {
-        BatchRunners {
-            sapling: BatchRunner::new(
-                batch_size_threshold,
-                scanning_keys
-                    .sapling()
-                    .iter()
-                    .map(|(id, key)| (id.clone(), key.prepare())),
-            ),
-            #[cfg(feature = "orchard")]
-            orchard: BatchRunner::new(
-                batch_size_threshold,
-                scanning_keys
-                    .orchard()
-                    .iter()
-                    .map(|(id, key)| (id.clone(), key.prepare())),
-            ),
-            #[cfg(not(feature = "orchard"))]
-            orchard: PhantomData,
-        }
-    }
-
-    pub(crate) fn flush(&mut self) {
-        self.sapling.flush();
-        #[cfg(feature = "orchard")]
-        self.orchard.flush();
-    }
-
-    #[tracing::instrument(skip_all, fields(height = block.height))]
-    pub(crate) fn add_block<P>(&mut self, params: &P, block: CompactBlock) -> Result<(), ScanError>
-    where
-        P: consensus::Parameters + Send + 'static,
-        IvkTag: Copy + Send + 'static,
-    {
-        let block_hash = block.hash();
-        let block_height = block.height();
-        let zip212_enforcement = zip212_enforcement(params, block_height);
-
-        for tx in block.vtx.into_iter() {
-            let txid = tx.txid();
-
-            self.sapling.add_outputs(
-                block_hash,
-                txid,
-                |_| SaplingDomain::new(zip212_enforcement),
-                &tx.outputs
-                    .iter()
-                    .enumerate()
-                    .map(|(i, output)| {
-                        CompactOutputDescription::try_from(output).map_err(|_| {
-                            ScanError::EncodingInvalid {
-                                at_height: block_height,
-                                txid,
-                                pool_type: ShieldedProtocol::Sapling,
-                                index: i,
-                            }
-                        })
-                    })
-                    .collect::<Result<Vec<_>, _>>()?,
-            );
-
-            #[cfg(feature = "orchard")]
-            self.orchard.add_outputs(
-                block_hash,
-                txid,
-                |action| OrchardDomain::for_nullifier(action.nullifier()),
-                &tx.actions
-                    .iter()
-                    .enumerate()
-                    .map(|(i, action)| {
-                        CompactAction::try_from(action).map_err(|_| ScanError::EncodingInvalid {
-                            at_height: block_height,
-                            txid,
-                            pool_type: ShieldedProtocol::Sapling,
-                            index: i,
-                        })
-                    })
-                    .collect::<Result<Vec<_>, _>>()?,
-            );
-        }
-
-        Ok(())
-    }
+    scan_block_with_runners::<_, _, _, _, (), ()>(
+        params,
+        block,
+        scanning_keys,
+        nullifiers,
+        prior_block_metadata,
+        options,
+        None,
+    )
 }
 
-#[tracing::instrument(skip_all, fields(height = block.height))]
-pub(crate) fn scan_block_with_runners<P, AccountId, IvkTag, TS, TO>(
+/// Scans a [`CompactBlock`] with a set of [`ScanningKeys`], as with [`scan_block_with_config`]
+/// using the given [`ScanConfig`] or [`ScanOptions`].
+///
+/// If scanning fails, the returned [`BlockScanFailure`] identifies the block by its height,
+/// hash, and block time in addition to providing the underlying [`ScanError`].
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn scan_block_detailed<'a, H, P, AccountId, IvkTag>(
     params: &P,
     block: CompactBlock,
     scanning_keys: &ScanningKeys<AccountId, IvkTag>,
     nullifiers: &Nullifiers<AccountId>,
     prior_block_metadata: Option<&BlockMetadata>,
-    mut batch_runners: Option<&mut BatchRunners<IvkTag, TS, TO>>,
-) -> Result<ScannedBlock<AccountId>, ScanError>
+    options: impl Into<ScanOptions<'a, IvkTag, H>>,
+) -> Result<ScannedBlock<AccountId>, BlockScanFailure>
 where
+    H: CommitmentHasher,
     P: consensus::Parameters + Send + 'static,
     AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
-    IvkTag: Copy + std::hash::Hash + Eq + Send + 'static,
-    TS: SaplingTasks<IvkTag> + Sync,
-    TO: OrchardTasks<IvkTag> + Sync,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
 {
-    fn check_hash_continuity(
-        block: &CompactBlock,
-        prior_block_metadata: Option<&BlockMetadata>,
-    ) -> Option<ScanError> {
-        if let Some(prev) = prior_block_metadata {
-            if block.height() != prev.block_height() + 1 {
-                return Some(ScanError::BlockHeightDiscontinuity {
-                    prev_height: prev.block_height(),
-                    new_height: block.height(),
-                });
-            }
+    let block_height = block.height();
+    let block_hash = block.hash();
+    let block_time = block.time;
+    scan_block_with_config(
+        params,
+        block,
+        scanning_keys,
+        nullifiers,
+        prior_block_metadata,
+        options,
+    )
+    .map_err(|error| BlockScanFailure {
+        block_height,
+        block_hash,
+        block_time,
+        error,
+    })
+}
 
-            if block.prev_hash() != prev.block_hash() {
-                return Some(ScanError::PrevHashMismatch {
-                    at_height: block.height(),
-                });
-            }
-        }
+/// Scans a single unmined (mempool) [`CompactTx`] with a set of [`ScanningKeys`], returning a
+/// [`WalletTx`] if the transaction is relevant to the wallet.
+///
+/// `target_height` is the height of the block in which the transaction is expected to be
+/// mined (typically one more than the height of the current chain tip); it determines the
+/// ZIP 212 enforcement policy that is applied in trial decryption.
+///
+/// No note commitment tree bookkeeping is performed: the positions of the received notes will
+/// not be known until the transaction is mined, so each returned output reports that it has
+/// not been mined (see [`WalletOutput::is_mined`]) and carries no nullifier. The transaction
+/// must be rescanned once it has been mined in order to obtain this information. Spends of the
+/// tracked nullifiers are detected as usual. This allows a wallet to display pending incoming
+/// funds optimistically.
+///
+/// The transaction is scanned according to the given [`ScanConfig`], except that note
+/// commitments are never tracked and no previous block hash is required.
+pub fn scan_mempool_tx<P, AccountId, IvkTag>(
+    params: &P,
+    target_height: BlockHeight,
+    tx: CompactTx,
+    scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+    nullifiers: &Nullifiers<AccountId>,
+    config: ScanConfig,
+) -> Result<Option<WalletTx<AccountId>>, ScanError>
+where
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+{
+    // The transaction is scanned as the only transaction of a placeholder block at the target
+    // height, following a block with empty note commitment trees.
+    let block = CompactBlock {
+        height: target_height.into(),
+        vtx: vec![CompactTx { index: 0, ..tx }],
+        ..Default::default()
+    };
+    let prior_block_metadata = BlockMetadata::from_parts(
+        target_height.saturating_sub(1),
+        BlockHash([0; 32]),
+        Some(0),
+        #[cfg(feature = "orchard")]
+        Some(0),
+    );
+    let config = ScanConfig {
+        track_commitments: false,
+        require_prev_hash: false,
+        ..config
+    };
 
-        None
+    let scanned_block = scan_block_with_config(
+        params,
+        block,
+        scanning_keys,
+        nullifiers,
+        Some(&prior_block_metadata),
+        config,
+    )?;
+
+    Ok(scanned_block
+        .into_transactions()
+        .into_iter()
+        .next()
+        .map(WalletTx::into_unmined))
+}
+
+/// Scans a sequence of contiguous [`CompactBlock`]s with a set of [`ScanningKeys`], returning
+/// the results as a single [`ScannedRange`].
+///
+/// Each block is scanned as by [`scan_block_with_config`] using the given [`ScanConfig`], with
+/// the metadata of each scanned block used as the prior block metadata for the next. The
+/// tracked nullifier set is updated after each block (see [`Nullifiers::update_for_block`]),
+/// so that spends of notes received earlier in the range are detected; on return it reflects
+/// the state of the wallet as of the end of the range.
+/// The incoming viewing keys of `scanning_keys` are prepared once for the entire range.
+///
+/// The note commitments of the scanned blocks are merged into a single stream per shielded
+/// protocol, in which the last commitment of each block is checkpointed at that block's height.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn scan_blocks<P, AccountId, IvkTag>(
+    params: &P,
+    blocks: impl IntoIterator<Item = CompactBlock>,
+    scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+    nullifiers: &mut Nullifiers<AccountId>,
+    prior_block_metadata: Option<&BlockMetadata>,
+    config: ScanConfig,
+) -> Result<ScannedRange<AccountId>, ScanError>
+where
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Default + Copy + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+{
+    let prepared_ivks = scanning_keys.to_prepared_ivks();
+    let mut scanned_range = ScannedRange::empty();
+    let mut prior_block_metadata = prior_block_metadata.copied();
+    for block in blocks {
+        let scanned_block = scan_block_with_config(
+            params,
+            block,
+            scanning_keys,
+            nullifiers,
+            prior_block_metadata.as_ref(),
+            ScanOptions::new(config).with_prepared_ivks(&prepared_ivks),
+        )?;
+        nullifiers.update_for_block(&scanned_block);
+        prior_block_metadata = Some(scanned_block.to_block_metadata());
+        scanned_range.push_block(scanned_block);
     }
 
-    if let Some(scan_error) = check_hash_continuity(&block, prior_block_metadata) {
-        return Err(scan_error);
+    Ok(scanned_range)
+}
+
+/// The spends of tracked notes detected by [`scan_block_spends_only`].
+#[derive(Clone, Debug)]
+pub struct ScannedSpends<AccountId> {
+    block_height: BlockHeight,
+    block_hash: BlockHash,
+    sapling_spends: Vec<(TxId, WalletSaplingSpend<AccountId>)>,
+    sapling_nullifier_map: Vec<(TxId, u16, Vec<(usize, sapling::Nullifier)>)>,
+    #[cfg(feature = "orchard")]
+    orchard_spends: Vec<(TxId, WalletOrchardSpend<AccountId>)>,
+    #[cfg(feature = "orchard")]
+    orchard_nullifier_map: Vec<(TxId, u16, Vec<(usize, orchard::note::Nullifier)>)>,
+}
+
+impl<AccountId> ScannedSpends<AccountId> {
+    /// Returns the height of the scanned block.
+    pub fn block_height(&self) -> BlockHeight {
+        self.block_height
     }
 
-    let cur_height = block.height();
-    let cur_hash = block.hash();
-    let zip212_enforcement = zip212_enforcement(params, cur_height);
+    /// Returns the hash of the scanned block.
+    pub fn block_hash(&self) -> BlockHash {
+        self.block_hash
+    }
 
-    let mut sapling_commitment_tree_size = prior_block_metadata
-        .and_then(|m| m.sapling_tree_size())
-        .map_or_else(
-            || {
-                block.chain_metadata.as_ref().map_or_else(
-                    || {
-                        // If we're below Sapling activation, or Sapling activation is not set, the tree size is zero
-                        params
-                            .activation_height(NetworkUpgrade::Sapling)
-                            .map_or_else(
-                                || Ok(0),
-                                |sapling_activation| {
-                                    if cur_height < sapling_activation {
-                                        Ok(0)
-                                    } else {
-                                        Err(ScanError::TreeSizeUnknown {
-                                            protocol: ShieldedProtocol::Sapling,
-                                            at_height: cur_height,
-                                        })
-                                    }
-                                },
-                            )
-                    },
-                    |m| {
-                        let sapling_output_count: u32 = block
-                            .vtx
-                            .iter()
-                            .map(|tx| tx.outputs.len())
-                            .sum::<usize>()
-                            .try_into()
-                            .expect("Sapling output count cannot exceed a u32");
+    /// Returns the spends of tracked Sapling notes in the block, along with the ID of the
+    /// transaction in which each spend occurred.
+    pub fn sapling_spends(&self) -> &[(TxId, WalletSaplingSpend<AccountId>)] {
+        &self.sapling_spends
+    }
 
-                        // The default for m.sapling_commitment_tree_size is zero, so we need to check
-                        // that the subtraction will not underflow; if it would do so, we were given
-                        // invalid chain metadata for a block with Sapling outputs.
-                        m.sapling_commitment_tree_size
-                            .checked_sub(sapling_output_count)
-                            .ok_or(ScanError::TreeSizeInvalid {
-                                protocol: ShieldedProtocol::Sapling,
-                                at_height: cur_height,
-                            })
-                    },
-                )
-            },
-            Ok,
-        )?;
+    /// Returns the Sapling nullifiers revealed in the block that did not match any tracked
+    /// nullifier, as described by [`ScannedBundles::nullifier_map`].
+    pub fn sapling_nullifier_map(&self) -> &[(TxId, u16, Vec<(usize, sapling::Nullifier)>)] {
+        &self.sapling_nullifier_map
+    }
 
+    /// Returns the spends of tracked Orchard notes in the block, along with the ID of the
+    /// transaction in which each spend occurred.
     #[cfg(feature = "orchard")]
-    let mut orchard_commitment_tree_size = prior_block_metadata
-        .and_then(|m| m.orchard_tree_size())
-        .map_or_else(
-            || {
-                block.chain_metadata.as_ref().map_or_else(
-                    || {
-                        // If we're below Orchard activation, or Orchard activation is not set, the tree size is zero
-                        params.activation_height(NetworkUpgrade::Nu5).map_or_else(
-                            || Ok(0),
-                            |orchard_activation| {
-                                if cur_height < orchard_activation {
-                                    Ok(0)
-                                } else {
-                                    Err(ScanError::TreeSizeUnknown {
-                                        protocol: ShieldedProtocol::Orchard,
-                                        at_height: cur_height,
-                                    })
-                                }
-                            },
-                        )
-                    },
-                    |m| {
-                        let orchard_action_count: u32 = block
-                            .vtx
-                            .iter()
-                            .map(|tx| tx.actions.len())
-                            .sum::<usize>()
-                            .try_into()
-                            .expect("Orchard action count cannot exceed a u32");
+    pub fn orchard_spends(&self) -> &[(TxId, WalletOrchardSpend<AccountId>)] {
+        &self.orchard_spends
+    }
 
-                        // The default for m.orchard_commitment_tree_size is zero, so we need to check
-                        // that the subtraction will not underflow; if it would do so, we were given
-                        // invalid chain metadata for a block with Orchard actions.
-                        m.orchard_commitment_tree_size
-                            .checked_sub(orchard_action_count)
-                            .ok_or(ScanError::TreeSizeInvalid {
-                                protocol: ShieldedProtocol::Orchard,
-                                at_height: cur_height,
-                            })
-                    },
-                )
-            },
-            Ok,
-        )?;
+    /// Returns the Orchard nullifiers revealed in the block that did not match any tracked
+    /// nullifier, as described by [`ScannedBundles::nullifier_map`].
+    #[cfg(feature = "orchard")]
+    pub fn orchard_nullifier_map(&self) -> &[(TxId, u16, Vec<(usize, orchard::note::Nullifier)>)] {
+        &self.orchard_nullifier_map
+    }
 
-    let compact_block_tx_count = block.vtx.len();
-    let mut wtxs: Vec<WalletTx<AccountId>> = vec![];
-    let mut sapling_nullifier_map = Vec::with_capacity(block.vtx.len());
-    let mut sapling_note_commitments: Vec<(sapling::Node, Retention<BlockHeight>)> = vec![];
+    /// Returns `true` if any tracked note was spent in the block.
+    pub fn has_spends(&self) -> bool {
+        let has_spends = !self.sapling_spends.is_empty();
+        #[cfg(feature = "orchard")]
+        let has_spends = has_spends || !self.orchard_spends.is_empty();
+        has_spends
+    }
+}
+
+/// Scans a [`CompactBlock`] for spends of the notes whose nullifiers are tracked in
+/// `nullifiers`, without performing any trial decryption.
+///
+/// This is substantially cheaper than [`scan_block`], as neither trial decryption nor the
+/// computation of note commitment tree nodes is performed. It is intended for lightweight
+/// checks of whether any of a wallet's notes have been spent; it does not detect received
+/// notes, and does not check the continuity of the block with respect to any prior block.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn scan_block_spends_only<AccountId>(
+    block: &CompactBlock,
+    nullifiers: &Nullifiers<AccountId>,
+) -> Result<ScannedSpends<AccountId>, ScanError>
+where
+    AccountId: Default + ConditionallySelectable,
+{
+    let block_height = block.height();
+    let block_hash = block.hash();
 
+    let find_sapling_account = sapling_account_finder(nullifiers);
     #[cfg(feature = "orchard")]
-    let mut orchard_nullifier_map = Vec::with_capacity(block.vtx.len());
+    let find_orchard_account = orchard_account_finder(nullifiers);
+
+    let mut sapling_spends = vec![];
+    let mut sapling_nullifier_map = Vec::with_capacity(block.vtx.len());
     #[cfg(feature = "orchard")]
-    let mut orchard_note_commitments: Vec<(MerkleHashOrchard, Retention<BlockHeight>)> = vec![];
+    let mut orchard_spends = vec![];
+    #[cfg(feature = "orchard")]
+    let mut orchard_nullifier_map = Vec::with_capacity(block.vtx.len());
 
-    for (tx_idx, tx) in block.vtx.into_iter().enumerate() {
+    for tx in &block.vtx {
         let txid = tx.txid();
-        let tx_index =
-            u16::try_from(tx.index).expect("Cannot fit more than 2^16 transactions in a block");
+        let tx_index = u16::try_from(tx.index).map_err(|_| ScanError::TxIndexOverflow {
+            at_height: block_height,
+            txid,
+            index: tx.index,
+        })?;
 
-        let (sapling_spends, sapling_unlinked_nullifiers) = find_spent(
+        let (spends, unlinked_nullifiers) = find_spent(
             &tx.spends,
-            &nullifiers.sapling,
-            |spend| {
-                spend.nf().expect(
-                    "Could not deserialize nullifier for spend from protobuf representation.",
-                )
+            &find_sapling_account,
+            |index, spend| {
+                spend.nf().map_err(|_| ScanError::NullifierInvalid {
+                    protocol: ShieldedProtocol::Sapling,
+                    at_height: block_height,
+                    txid,
+                    index,
+                })
             },
             WalletSpend::from_parts,
-        );
-
-        sapling_nullifier_map.push((txid, tx_index, sapling_unlinked_nullifiers));
+        )?;
+        sapling_spends.extend(spends.into_iter().map(|spend| (txid, spend)));
+        sapling_nullifier_map.push((txid, tx_index, unlinked_nullifiers));
 
         #[cfg(feature = "orchard")]
-        let orchard_spends = {
-            let (orchard_spends, orchard_unlinked_nullifiers) = find_spent(
+        {
+            let (spends, unlinked_nullifiers) = find_spent(
                 &tx.actions,
-                &nullifiers.orchard,
-                |spend| {
-                    spend.nf().expect(
-                        "Could not deserialize nullifier for spend from protobuf representation.",
-                    )
+                &find_orchard_account,
+                |index, spend| {
+                    spend.nf().map_err(|_| ScanError::NullifierInvalid {
+                        protocol: ShieldedProtocol::Orchard,
+                        at_height: block_height,
+                        txid,
+                        index,
+                    })
                 },
                 WalletSpend::from_parts,
-            );
-            orchard_nullifier_map.push((txid, tx_index, orchard_unlinked_nullifiers));
-            orchard_spends
-        };
+            )?;
+            orchard_spends.extend(spends.into_iter().map(|spend| (txid, spend)));
+            orchard_nullifier_map.push((txid, tx_index, unlinked_nullifiers));
+        }
+    }
 
-        // Collect the set of accounts that were spent from in this transaction
-        let spent_from_accounts = sapling_spends.iter().map(|spend| spend.account_id());
+    Ok(ScannedSpends {
+        block_height,
+        block_hash,
+        sapling_spends,
+        sapling_nullifier_map,
         #[cfg(feature = "orchard")]
-        let spent_from_accounts =
-            spent_from_accounts.chain(orchard_spends.iter().map(|spend| spend.account_id()));
-        let spent_from_accounts = spent_from_accounts.copied().collect::<HashSet<_>>();
+        orchard_spends,
+        #[cfg(feature = "orchard")]
+        orchard_nullifier_map,
+    })
+}
 
-        let (sapling_outputs, mut sapling_nc) = find_received(
-            cur_height,
-            compact_block_tx_count,
-            txid,
-            tx_idx,
-            sapling_commitment_tree_size,
-            &scanning_keys.sapling,
-            &spent_from_accounts,
-            &tx.outputs
-                .iter()
-                .enumerate()
-                .map(|(i, output)| {
-                    Ok((
-                        SaplingDomain::new(zip212_enforcement),
-                        CompactOutputDescription::try_from(output).map_err(|_| {
-                            ScanError::EncodingInvalid {
-                                at_height: cur_height,
-                                txid,
-                                pool_type: ShieldedProtocol::Sapling,
-                                index: i,
-                            }
-                        })?,
-                    ))
+/// Returns the nullifiers revealed by the Sapling spends of each transaction in a
+/// [`CompactBlock`], along with the ID and index within the block of that transaction.
+///
+/// No trial decryption or note commitment tree hashing is performed, so this is considerably
+/// cheaper than [`scan_block_spends_only`]. It is intended for services that notify wallets of
+/// spends without any knowledge of the wallets' keys or notes. Transactions without Sapling
+/// spends are included with an empty nullifier list, so that the result covers every
+/// transaction in the block.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn extract_nullifier_map(
+    block: &CompactBlock,
+) -> Result<Vec<(TxId, u16, Vec<sapling::Nullifier>)>, ScanError> {
+    extract_nullifiers(block, |tx, txid| {
+        tx.spends
+            .iter()
+            .enumerate()
+            .map(|(index, spend)| {
+                spend.nf().map_err(|_| ScanError::NullifierInvalid {
+                    protocol: ShieldedProtocol::Sapling,
+                    at_height: block.height(),
+                    txid,
+                    index,
                 })
-                .collect::<Result<Vec<_>, _>>()?,
-            batch_runners
-                .as_mut()
-                .map(|runners| |txid| runners.sapling.collect_results(cur_hash, txid)),
-            |output| sapling::Node::from_cmu(&output.cmu),
-        );
-        sapling_note_commitments.append(&mut sapling_nc);
-        let has_sapling = !(sapling_spends.is_empty() && sapling_outputs.is_empty());
+            })
+            .collect()
+    })
+}
 
-        #[cfg(feature = "orchard")]
-        let (orchard_outputs, mut orchard_nc) = find_received(
-            cur_height,
-            compact_block_tx_count,
-            txid,
-            tx_idx,
-            orchard_commitment_tree_size,
-            &scanning_keys.orchard,
-            &spent_from_accounts,
-            &tx.actions
-                .iter()
-                .enumerate()
-                .map(|(i, action)| {
-                    let action = CompactAction::try_from(action).map_err(|_| {
-                        ScanError::EncodingInvalid {
-                            at_height: cur_height,
-                            txid,
-                            pool_type: ShieldedProtocol::Orchard,
-                            index: i,
-                        }
-                    })?;
-                    Ok((OrchardDomain::for_nullifier(action.nullifier()), action))
+/// Returns the nullifiers revealed by the Orchard actions of each transaction in a
+/// [`CompactBlock`], along with the ID and index within the block of that transaction.
+///
+/// This is the Orchard equivalent of [`extract_nullifier_map`].
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+#[cfg(feature = "orchard")]
+pub fn extract_orchard_nullifier_map(
+    block: &CompactBlock,
+) -> Result<Vec<(TxId, u16, Vec<orchard::note::Nullifier>)>, ScanError> {
+    extract_nullifiers(block, |tx, txid| {
+        tx.actions
+            .iter()
+            .enumerate()
+            .map(|(index, action)| {
+                action.nf().map_err(|_| ScanError::NullifierInvalid {
+                    protocol: ShieldedProtocol::Orchard,
+                    at_height: block.height(),
+                    txid,
+                    index,
                 })
-                .collect::<Result<Vec<_>, _>>()?,
-            batch_runners
-                .as_mut()
-                .map(|runners| |txid| runners.orchard.collect_results(cur_hash, txid)),
-            |output| MerkleHashOrchard::from_cmx(&output.cmx()),
-        );
-        #[cfg(feature = "orchard")]
-        orchard_note_commitments.append(&mut orchard_nc);
-
-        #[cfg(feature = "orchard")]
-        let has_orchard = !(orchard_spends.is_empty() && orchard_outputs.is_empty());
-        #[cfg(not(feature = "orchard"))]
-        let has_orchard = false;
+            })
+            .collect()
+    })
+}
 
-        if has_sapling || has_orchard {
-            wtxs.push(WalletTx::new(
+fn extract_nullifiers<Nf>(
+    block: &CompactBlock,
+    tx_nullifiers: impl Fn(&CompactTx, TxId) -> Result<Vec<Nf>, ScanError>,
+) -> Result<Vec<(TxId, u16, Vec<Nf>)>, ScanError> {
+    block
+        .vtx
+        .iter()
+        .map(|tx| {
+            let txid = tx.txid();
+            let tx_index = u16::try_from(tx.index).map_err(|_| ScanError::TxIndexOverflow {
+                at_height: block.height(),
                 txid,
-                tx_index as usize,
-                sapling_spends,
-                sapling_outputs,
-                #[cfg(feature = "orchard")]
-                orchard_spends,
-                #[cfg(feature = "orchard")]
-                orchard_outputs,
-            ));
-        }
+                index: tx.index,
+            })?;
+            Ok((txid, tx_index, tx_nullifiers(tx, txid)?))
+        })
+        .collect()
+}
 
-        sapling_commitment_tree_size +=
-            u32::try_from(tx.outputs.len()).expect("Sapling output count cannot exceed a u32");
-        #[cfg(feature = "orchard")]
-        {
-            orchard_commitment_tree_size +=
-                u32::try_from(tx.actions.len()).expect("Orchard action count cannot exceed a u32");
-        }
-    }
+/// The information required to decrypt a single Sapling output without the recipient's incoming
+/// viewing key, as used by [`scan_block_with_shared_secrets`].
+///
+/// A recipient (or the sender) of a note may disclose this to an auditor as proof of payment,
+/// without revealing any key that could be used to decrypt other outputs. The shared secret may
+/// be obtained by the recipient via [`Domain::ka_agree_dec`], or by the sender via
+/// [`Domain::ka_agree_enc`]. The recipient's payment address must be disclosed along with it,
+/// because the note plaintext does not include the recipient's diversified transmission key.
+#[derive(Debug)]
+pub struct SaplingOutputDisclosure<AccountId> {
+    account_id: AccountId,
+    recipient: sapling::PaymentAddress,
+    shared_secret: sapling::keys::SharedSecret,
+}
 
-    if let Some(chain_meta) = block.chain_metadata {
-        if chain_meta.sapling_commitment_tree_size != sapling_commitment_tree_size {
-            return Err(ScanError::TreeSizeMismatch {
-                protocol: ShieldedProtocol::Sapling,
-                at_height: cur_height,
-                given: chain_meta.sapling_commitment_tree_size,
-                computed: sapling_commitment_tree_size,
-            });
+impl<AccountId> SaplingOutputDisclosure<AccountId> {
+    /// Constructs a new disclosure of an output received at `recipient`, which will be attributed
+    /// to the given account once decrypted.
+    pub fn new(
+        account_id: AccountId,
+        recipient: sapling::PaymentAddress,
+        shared_secret: sapling::keys::SharedSecret,
+    ) -> Self {
+        Self {
+            account_id,
+            recipient,
+            shared_secret,
         }
+    }
 
-        #[cfg(feature = "orchard")]
-        if chain_meta.orchard_commitment_tree_size != orchard_commitment_tree_size {
-            return Err(ScanError::TreeSizeMismatch {
-                protocol: ShieldedProtocol::Orchard,
-                at_height: cur_height,
-                given: chain_meta.orchard_commitment_tree_size,
-                computed: orchard_commitment_tree_size,
-            });
-        }
+    /// Returns the account to which the decrypted output will be attributed.
+    pub fn account_id(&self) -> &AccountId {
+        &self.account_id
     }
 
-    Ok(ScannedBlock::from_parts(
-        cur_height,
-        cur_hash,
-        block.time,
-        wtxs,
-        ScannedBundles::new(
-            sapling_commitment_tree_size,
-            sapling_note_commitments,
-            sapling_nullifier_map,
-        ),
-        #[cfg(feature = "orchard")]
-        ScannedBundles::new(
-            orchard_commitment_tree_size,
-            orchard_note_commitments,
-            orchard_nullifier_map,
-        ),
-    ))
+    /// Returns the payment address at which the disclosed output was received.
+    pub fn recipient(&self) -> &sapling::PaymentAddress {
+        &self.recipient
+    }
 }
 
-/// Check for spent notes. The comparison against known-unspent nullifiers is done
-/// in constant time.
-fn find_spent<
-    AccountId: ConditionallySelectable + Default,
-    Spend,
-    Nf: ConstantTimeEq + Copy,
-    WS,
->(
-    spends: &[Spend],
-    nullifiers: &[(AccountId, Nf)],
-    extract_nf: impl Fn(&Spend) -> Nf,
-    construct_wallet_spend: impl Fn(usize, Nf, AccountId) -> WS,
-) -> (Vec<WS>, Vec<Nf>) {
-    // TODO: this is O(|nullifiers| * |notes|); does using constant-time operations here really
-    // make sense?
-    let mut found_spent = vec![];
-    let mut unlinked_nullifiers = Vec::with_capacity(spends.len());
-    for (index, spend) in spends.iter().enumerate() {
-        let spend_nf = extract_nf(spend);
+/// Decrypts the Sapling outputs of a [`CompactBlock`] that are identified by `disclosures`, using
+/// the disclosed shared secret for each output in place of trial decryption.
+///
+/// Each disclosure is keyed by the ID of the transaction containing the output and the index of
+/// the output within that transaction's Sapling bundle. The decrypted notes are returned as the
+/// same [`WalletSaplingOutput`]s that [`scan_block`] would produce for the recipient, except that
+/// their nullifiers are not computed (as doing so requires the nullifier deriving key) and they
+/// are never classified as change. A disclosed output is omitted from the result if it cannot be
+/// decrypted with the disclosed secret, or if the decrypted note does not match the output's note
+/// commitment.
+///
+/// Outputs are decrypted according to the [ZIP 212] enforcement policy in effect at the height
+/// of the block, so that outputs which use the original note plaintext format are accepted
+/// before the end of the ZIP 212 grace period.
+///
+/// The positions of the returned notes in the Sapling note commitment tree are derived from the
+/// block's chain metadata, which must be present.
+///
+/// [ZIP 212]: https://zips.z.cash/zip-0212
+pub fn scan_block_with_shared_secrets<P, AccountId>(
+    params: &P,
+    block: &CompactBlock,
+    mut disclosures: HashMap<(TxId, usize), SaplingOutputDisclosure<AccountId>>,
+    config: ScanConfig,
+) -> Result<Vec<(TxId, WalletSaplingOutput<AccountId>)>, ScanError>
+where
+    P: consensus::Parameters,
+{
+    check_output_count(block, &config)?;
+
+    let block_height = block.height();
+    let zip212_enforcement = zip212_enforcement(params, block_height);
+
+    let chain_metadata = block
+        .chain_metadata
+        .as_ref()
+        .ok_or(ScanError::TreeSizeUnknown {
+            protocol: ShieldedProtocol::Sapling,
+            at_height: block_height,
+            metadata_absent: true,
+        })?;
+    let sapling_output_count = u32::try_from(block_output_counts(block).0).map_err(|_| {
+        ScanError::OutputCountOverflow {
+            protocol: ShieldedProtocol::Sapling,
+            at_height: block_height,
+        }
+    })?;
+    let mut note_commitment_tree_position = Position::from(u64::from(
+        chain_metadata
+            .sapling_commitment_tree_size
+            .checked_sub(sapling_output_count)
+            .ok_or(ScanError::TreeSizeInvalid {
+                protocol: ShieldedProtocol::Sapling,
+                at_height: block_height,
+            })?,
+    ));
 
-        // Find whether any tracked nullifier that matches this spend, and produce a
-        // WalletShieldedSpend in constant time.
-        let ct_spend = nullifiers
-            .iter()
-            .map(|&(account, nf)| CtOption::new(account, nf.ct_eq(&spend_nf)))
-            .fold(
-                CtOption::new(AccountId::default(), 0.into()),
-                |first, next| CtOption::conditional_select(&next, &first, first.is_some()),
-            )
-            .map(|account| construct_wallet_spend(index, spend_nf, account));
+    let mut outputs = vec![];
+    for tx in &block.vtx {
+        let txid = tx.txid();
+        for (output_idx, output) in tx.outputs.iter().enumerate() {
+            if let Some(disclosure) = disclosures.remove(&(txid, output_idx)) {
+                let ephemeral_key =
+                    output
+                        .ephemeral_key()
+                        .map_err(|_| ScanError::EncodingInvalid {
+                            at_height: block_height,
+                            txid,
+                            pool_type: ShieldedProtocol::Sapling,
+                            index: output_idx,
+                        })?;
+
+                if let Some(note) = decrypt_with_shared_secret(
+                    zip212_enforcement,
+                    &disclosure.recipient,
+                    disclosure.shared_secret,
+                    &ephemeral_key,
+                    output,
+                ) {
+                    let below_dust_threshold =
+                        note.value().inner() < u64::from(config.dust_threshold);
+                    outputs.push((
+                        txid,
+                        WalletOutput::from_parts(
+                            output_idx,
+                            ephemeral_key,
+                            note,
+                            false,
+                            below_dust_threshold,
+                            note_commitment_tree_position,
+                            None,
+                            disclosure.account_id,
+                            None,
+                        ),
+                    ));
+                }
+            }
 
-        if let Some(spend) = ct_spend.into() {
-            found_spent.push(spend);
-        } else {
-            // This nullifier didn't match any we are currently tracking; save it in
-            // case it matches an earlier block range we haven't scanned yet.
-            unlinked_nullifiers.push(spend_nf);
+            note_commitment_tree_position = note_commitment_tree_position + 1;
         }
     }
 
-    (found_spent, unlinked_nullifiers)
+    Ok(outputs)
 }
 
-#[allow(clippy::too_many_arguments)]
-#[allow(clippy::type_complexity)]
-fn find_received<
-    AccountId: Copy + Eq + Hash,
-    D: BatchDomain,
-    Nf,
-    IvkTag: Copy + std::hash::Hash + Eq + Send + 'static,
-    SK: ScanningKeyOps<D, AccountId, Nf>,
-    Output: ShieldedOutput<D, COMPACT_NOTE_SIZE>,
-    NoteCommitment,
->(
-    block_height: BlockHeight,
-    block_tx_count: usize,
+/// Decrypts the compact note plaintext of a Sapling output using the shared secret agreed
+/// between its sender and `recipient`, returning the note if the plaintext is well-formed and
+/// consistent with the output's note commitment.
+fn decrypt_with_shared_secret(
+    zip212_enforcement: Zip212Enforcement,
+    recipient: &sapling::PaymentAddress,
+    shared_secret: sapling::keys::SharedSecret,
+    ephemeral_key: &EphemeralKeyBytes,
+    output: &compact_formats::CompactSaplingOutput,
+) -> Option<sapling::Note> {
+    let ciphertext: &[u8; COMPACT_NOTE_SIZE] = output.ciphertext[..].try_into().ok()?;
+    let key = SaplingDomain::kdf(shared_secret, ephemeral_key);
+
+    // `zcash_note_encryption` only performs compact decryption for a holder of an incoming
+    // viewing key, so we apply the keystream here. The compact plaintext is the prefix of the
+    // note plaintext, which is encrypted with the keystream beginning at the second ChaCha20
+    // block. The memo is not needed to parse the note, and is left zeroed.
+    let mut plaintext = NotePlaintextBytes([0; NOTE_PLAINTEXT_SIZE]);
+    plaintext.0[..COMPACT_NOTE_SIZE].copy_from_slice(ciphertext);
+    let mut keystream = ChaCha20::new(key.as_ref().into(), [0u8; 12][..].into());
+    keystream.seek(64);
+    keystream.apply_keystream(&mut plaintext.0[..COMPACT_NOTE_SIZE]);
+
+    // The recipient's diversified transmission key is known, so the plaintext may be parsed as
+    // it would be by the sender. This accepts both the original and the ZIP 212 plaintext
+    // formats, as permitted by the given enforcement policy.
+    let (note, to) = SaplingDomain::new(zip212_enforcement)
+        .parse_note_plaintext_without_memo_ovk(recipient.pk_d(), &plaintext)?;
+
+    // Compact decryption is not authenticated, so the note must be checked against the
+    // recipient and the commitment in the output.
+    (&to == recipient && note.cmu().to_bytes()[..] == output.cmu[..]).then_some(note)
+}
+
+/// The result of trial decryption of a single shielded output, as reported by
+/// [`scan_block_explain`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputScanDiagnostic<IvkTag> {
+    protocol: ShieldedProtocol,
     txid: TxId,
-    tx_idx: usize,
-    commitment_tree_size: u32,
-    keys: &HashMap<IvkTag, SK>,
-    spent_from_accounts: &HashSet<AccountId>,
-    decoded: &[(D, Output)],
-    batch_results: Option<
-        impl FnOnce(TxId) -> HashMap<(TxId, usize), DecryptedOutput<IvkTag, D, ()>>,
-    >,
-    extract_note_commitment: impl Fn(&Output) -> NoteCommitment,
-) -> (
-    Vec<WalletOutput<D::Note, Nf, AccountId>>,
-    Vec<(NoteCommitment, Retention<BlockHeight>)>,
-) {
-    // Check for incoming notes while incrementing tree and witnesses
-    let (decrypted_opts, decrypted_len) = if let Some(collect_results) = batch_results {
-        let mut decrypted = collect_results(txid);
-        let decrypted_len = decrypted.len();
-        (
-            (0..decoded.len())
-                .map(|i| {
-                    decrypted
-                        .remove(&(txid, i))
-                        .map(|d_out| (d_out.ivk_tag, d_out.note))
-                })
-                .collect::<Vec<_>>(),
-            decrypted_len,
-        )
-    } else {
-        let mut ivks = Vec::with_capacity(keys.len());
-        let mut ivk_lookup = Vec::with_capacity(keys.len());
-        for (key_id, key) in keys.iter() {
-            ivks.push(key.prepare());
-            ivk_lookup.push(key_id);
-        }
+    output_index: usize,
+    encoding_valid: bool,
+    epk_valid: bool,
+    decrypted_by: Option<IvkTag>,
+    zip212_rejected: bool,
+}
 
-        let mut decrypted_len = 0;
-        (
-            batch::try_compact_note_decryption(&ivks, decoded)
-                .into_iter()
-                .map(|v| {
-                    v.map(|((note, _), ivk_idx)| {
-                        decrypted_len += 1;
-                        (*ivk_lookup[ivk_idx], note)
-                    })
-                })
-                .collect::<Vec<_>>(),
-            decrypted_len,
-        )
-    };
+impl<IvkTag> OutputScanDiagnostic<IvkTag> {
+    /// Returns the shielded protocol of the output.
+    pub fn protocol(&self) -> ShieldedProtocol {
+        self.protocol
+    }
 
-    let mut shielded_outputs = Vec::with_capacity(decrypted_len);
-    let mut note_commitments = Vec::with_capacity(decoded.len());
-    for (output_idx, ((_, output), decrypted_note)) in
-        decoded.iter().zip(decrypted_opts).enumerate()
-    {
-        // Collect block note commitments
-        let node = extract_note_commitment(output);
-        let is_checkpoint = output_idx + 1 == decoded.len() && tx_idx + 1 == block_tx_count;
-        let retention = match (decrypted_note.is_some(), is_checkpoint) {
-            (is_marked, true) => Retention::Checkpoint {
-                id: block_height,
-                is_marked,
-            },
-            (true, false) => Retention::Marked,
-            (false, false) => Retention::Ephemeral,
-        };
+    /// Returns the ID of the transaction containing the output.
+    pub fn txid(&self) -> TxId {
+        self.txid
+    }
 
-        if let Some((key_id, note)) = decrypted_note {
-            let key = keys
-                .get(&key_id)
-                .expect("Key is available for decrypted output");
+    /// Returns the index of the output within its transaction's bundle for
+    /// [`Self::protocol`].
+    pub fn output_index(&self) -> usize {
+        self.output_index
+    }
 
-            // A note is marked as "change" if the account that received it
-            // also spent notes in the same transaction. This will catch,
-            // for instance:
-            // - Change created by spending fractions of notes.
-            // - Notes created by consolidation transactions.
-            // - Notes sent from one account to itself.
-            let is_change = spent_from_accounts.contains(key.account_id());
-            let note_commitment_tree_position = Position::from(u64::from(
-                commitment_tree_size + u32::try_from(output_idx).unwrap(),
-            ));
-            let nf = key.nf(&note, note_commitment_tree_position);
+    /// Returns `false` if the output could not be parsed from its compact encoding, in which
+    /// case no decryption was attempted.
+    pub fn encoding_valid(&self) -> bool {
+        self.encoding_valid
+    }
 
-            shielded_outputs.push(WalletOutput::from_parts(
-                output_idx,
-                output.ephemeral_key(),
-                note,
-                is_change,
-                note_commitment_tree_position,
-                nf,
-                *key.account_id(),
-                key.key_scope(),
-            ));
-        }
+    /// Returns whether the output's ephemeral public key is a valid encoding of a curve point.
+    ///
+    /// An output with an invalid ephemeral key cannot be decrypted by any key.
+    pub fn epk_valid(&self) -> bool {
+        self.epk_valid
+    }
 
-        note_commitments.push((node, retention))
+    /// Returns the tag of the key that decrypted the output, or `None` if the output could not
+    /// be decrypted by any of the keys that are valid at the block's height.
+    pub fn decrypted_by(&self) -> Option<&IvkTag> {
+        self.decrypted_by.as_ref()
     }
 
-    (shielded_outputs, note_commitments)
+    /// Returns `true` if the output could not be decrypted, but would have been decrypted had
+    /// ZIP 212 enforcement not applied at the block's height; that is, the note plaintext lead
+    /// byte is not permitted at that height.
+    ///
+    /// This is always `false` for Orchard outputs, to which ZIP 212 always applies.
+    pub fn zip212_rejected(&self) -> bool {
+        self.zip212_rejected
+    }
 }
 
-#[cfg(test)]
-mod tests {
+/// Explains the result of trial decryption of every shielded output in a [`CompactBlock`] with
+/// the given keys.
+///
+/// This is a diagnostic tool for determining why an expected note was not detected by
+/// [`scan_block`]; it performs trial decryption with each key in turn, and should not be used
+/// for scanning. As in [`scan_block`], keys that are not valid at the block's height (see
+/// [`ScanningKeyOps::valid_heights`]) are not used.
+///
+/// One [`OutputScanDiagnostic`] is returned for each output in the block, ordered by
+/// transaction; within each transaction, the Sapling outputs are followed by the Orchard
+/// actions, each in order of output index.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn scan_block_explain<P, AccountId, IvkTag>(
+    params: &P,
+    block: &CompactBlock,
+    scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+) -> Vec<OutputScanDiagnostic<IvkTag>>
+where
+    P: consensus::Parameters,
+    IvkTag: Clone,
+{
+    let block_height = block.height();
+    let zip212_enforcement = zip212_enforcement(params, block_height);
 
-    use std::convert::Infallible;
+    let sapling_keys = valid_prepared_keys(&scanning_keys.sapling, block_height);
+    #[cfg(feature = "orchard")]
+    let orchard_keys = valid_prepared_keys(&scanning_keys.orchard, block_height);
 
-    use group::{
-        ff::{Field, PrimeField},
-        GroupEncoding,
-    };
-    use incrementalmerkletree::{Position, Retention};
-    use rand_core::{OsRng, RngCore};
-    use sapling::{
-        constants::SPENDING_KEY_GENERATOR,
-        note_encryption::{sapling_note_encryption, SaplingDomain},
-        util::generate_random_rseed,
-        value::NoteValue,
-        zip32::DiversifiableFullViewingKey,
-        Nullifier,
-    };
-    use zcash_keys::keys::UnifiedSpendingKey;
-    use zcash_note_encryption::{Domain, COMPACT_NOTE_SIZE};
-    use zcash_primitives::{
-        block::BlockHash,
-        consensus::{BlockHeight, Network},
-        memo::MemoBytes,
-        transaction::components::{amount::NonNegativeAmount, sapling::zip212_enforcement},
-        zip32::AccountId,
+    // Outputs that fail decryption are retried during the grace period, in which either
+    // plaintext lead byte is accepted, in order to detect rejection by ZIP 212 enforcement.
+    let sapling_lenient_domain = if zip212_enforcement == Zip212Enforcement::GracePeriod {
+        None
+    } else {
+        Some(|_: &CompactOutputDescription| SaplingDomain::new(Zip212Enforcement::GracePeriod))
     };
 
-    use crate::{
-        data_api::BlockMetadata,
-        proto::compact_formats::{
-            self as compact, CompactBlock, CompactSaplingOutput, CompactSaplingSpend, CompactTx,
-        },
-        scanning::{BatchRunners, ScanningKeys},
-    };
+    let mut diagnostics = vec![];
+    for tx in &block.vtx {
+        let txid = tx.txid();
 
-    use super::{scan_block, scan_block_with_runners, Nullifiers};
+        diagnostics.extend(explain_outputs(
+            ShieldedProtocol::Sapling,
+            txid,
+            &sapling_keys,
+            tx.outputs
+                .iter()
+                .map(|output| CompactOutputDescription::try_from(output).ok()),
+            |_| SaplingDomain::new(zip212_enforcement),
+            sapling_lenient_domain,
+        ));
 
-    fn random_compact_tx(mut rng: impl RngCore) -> CompactTx {
-        let fake_nf = {
-            let mut nf = vec![0; 32];
-            rng.fill_bytes(&mut nf);
-            nf
-        };
-        let fake_cmu = {
-            let fake_cmu = bls12_381::Scalar::random(&mut rng);
-            fake_cmu.to_repr().as_ref().to_owned()
-        };
-        let fake_epk = {
-            let mut buffer = [0; 64];
-            rng.fill_bytes(&mut buffer);
-            let fake_esk = jubjub::Fr::from_bytes_wide(&buffer);
-            let fake_epk = SPENDING_KEY_GENERATOR * fake_esk;
-            fake_epk.to_bytes().to_vec()
-        };
-        let cspend = CompactSaplingSpend { nf: fake_nf };
-        let cout = CompactSaplingOutput {
-            cmu: fake_cmu,
-            ephemeral_key: fake_epk,
-            ciphertext: vec![0; COMPACT_NOTE_SIZE],
-        };
-        let mut ctx = CompactTx::default();
-        let mut txid = vec![0; 32];
-        rng.fill_bytes(&mut txid);
-        ctx.hash = txid;
-        ctx.spends.push(cspend);
-        ctx.outputs.push(cout);
-        ctx
+        #[cfg(feature = "orchard")]
+        diagnostics.extend(explain_outputs(
+            ShieldedProtocol::Orchard,
+            txid,
+            &orchard_keys,
+            tx.actions
+                .iter()
+                .map(|action| CompactAction::try_from(action).ok()),
+            |action| OrchardDomain::for_nullifier(action.nullifier()),
+            None::<fn(&CompactAction) -> OrchardDomain>,
+        ));
     }
 
-    /// Create a fake CompactBlock at the given height, with a transaction containing a
-    /// single spend of the given nullifier and a single output paying the given address.
-    /// Returns the CompactBlock.
-    ///
-    /// Set `initial_tree_sizes` to `None` to simulate a `CompactBlock` retrieved
-    /// from a `lightwalletd` that is not currently tracking note commitment tree sizes.
-    fn fake_compact_block(
-        height: BlockHeight,
-        prev_hash: BlockHash,
-        nf: Nullifier,
-        dfvk: &DiversifiableFullViewingKey,
-        value: NonNegativeAmount,
-        tx_after: bool,
-        initial_tree_sizes: Option<(u32, u32)>,
-    ) -> CompactBlock {
-        let zip212_enforcement = zip212_enforcement(&Network::TestNetwork, height);
-        let to = dfvk.default_address().1;
-
-        // Create a fake Note for the account
-        let mut rng = OsRng;
-        let rseed = generate_random_rseed(zip212_enforcement, &mut rng);
-        let note = sapling::Note::from_parts(to, NoteValue::from_raw(value.into()), rseed);
-        let encryptor = sapling_note_encryption(
-            Some(dfvk.fvk().ovk),
-            note.clone(),
-            *MemoBytes::empty().as_array(),
-            &mut rng,
-        );
-        let cmu = note.cmu().to_bytes().to_vec();
-        let ephemeral_key = SaplingDomain::epk_bytes(encryptor.epk()).0.to_vec();
-        let enc_ciphertext = encryptor.encrypt_note_plaintext();
+    diagnostics
+}
 
+/// Prepares the keys in `keys` that are valid at the given height, for use by
+/// [`scan_block_explain`].
+fn valid_prepared_keys<D: Domain, AccountId, Nf, IvkTag: Clone>(
+    keys: &HashMap<IvkTag, Box<dyn ScanningKeyOps<D, AccountId, Nf>>>,
+    block_height: BlockHeight,
+) -> Vec<(IvkTag, D::IncomingViewingKey)> {
+    keys.iter()
+        .filter(|(_, key)| {
+            key.valid_heights()
+                .map_or(true, |range| range.contains(&block_height))
+        })
+        .map(|(tag, key)| (tag.clone(), key.prepare()))
+        .collect()
+}
+
+/// Attempts to decrypt each of the given outputs with each of the given keys, for use by
+/// [`scan_block_explain`]. Outputs that could not be parsed are given as `None`.
+fn explain_outputs<D: Domain, Output: ShieldedOutput<D, COMPACT_NOTE_SIZE>, IvkTag: Clone>(
+    protocol: ShieldedProtocol,
+    txid: TxId,
+    keys: &[(IvkTag, D::IncomingViewingKey)],
+    outputs: impl Iterator<Item = Option<Output>>,
+    domain: impl Fn(&Output) -> D,
+    lenient_domain: Option<impl Fn(&Output) -> D>,
+) -> Vec<OutputScanDiagnostic<IvkTag>> {
+    outputs
+        .enumerate()
+        .map(|(output_index, output)| {
+            let mut diagnostic = OutputScanDiagnostic {
+                protocol,
+                txid,
+                output_index,
+                encoding_valid: output.is_some(),
+                epk_valid: false,
+                decrypted_by: None,
+                zip212_rejected: false,
+            };
+
+            if let Some(output) = output {
+                let try_decrypt = |domain: D| {
+                    keys.iter()
+                        .find(|(_, ivk)| {
+                            zcash_note_encryption::try_compact_note_decryption(
+                                &domain, ivk, &output,
+                            )
+                            .is_some()
+                        })
+                        .map(|(tag, _)| tag.clone())
+                };
+
+                diagnostic.epk_valid = D::epk(&output.ephemeral_key()).is_some();
+                diagnostic.decrypted_by = try_decrypt(domain(&output));
+                diagnostic.zip212_rejected = diagnostic.decrypted_by.is_none()
+                    && lenient_domain
+                        .as_ref()
+                        .map_or(false, |lenient| try_decrypt(lenient(&output)).is_some());
+            }
+
+            diagnostic
+        })
+        .collect()
+}
+
+/// Scans a [`CompactBlock`] for spends of tracked notes, matching the spends against the
+/// nullifiers of each account in isolation.
+///
+/// Unlike [`scan_block_spends_only`], which attributes a spend to the first account in a
+/// single combined nullifier set that tracks its nullifier, this checks each spend against
+/// each account's own nullifiers, and returns the [`ScannedSpends`] for each account that
+/// appears in either `sapling` or `orchard` as though its nullifiers were the only ones being
+/// tracked. A nullifier tracked by one account therefore never causes a spend to be attributed
+/// to another. Each account's nullifiers are searched in constant time, unless the
+/// `fast-nullifier-lookup` feature is enabled.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn scan_block_spends_by_account<AccountId>(
+    block: &CompactBlock,
+    sapling: &HashMap<AccountId, Vec<sapling::Nullifier>>,
+    #[cfg(feature = "orchard")] orchard: &HashMap<AccountId, Vec<orchard::note::Nullifier>>,
+) -> Result<HashMap<AccountId, ScannedSpends<AccountId>>, ScanError>
+where
+    AccountId: Default + ConditionallySelectable + Eq + Hash,
+{
+    let accounts = sapling.keys().copied().collect::<HashSet<_>>();
+    #[cfg(feature = "orchard")]
+    let accounts = accounts
+        .into_iter()
+        .chain(orchard.keys().copied())
+        .collect::<HashSet<_>>();
+
+    accounts
+        .into_iter()
+        .map(|account| {
+            let nullifiers = Nullifiers::new(
+                sapling.get(&account).map_or_else(Vec::new, |nfs| {
+                    nfs.iter().map(|nf| (account, *nf)).collect()
+                }),
+                #[cfg(feature = "orchard")]
+                orchard.get(&account).map_or_else(Vec::new, |nfs| {
+                    nfs.iter().map(|nf| (account, *nf)).collect()
+                }),
+            );
+            Ok((account, scan_block_spends_only(block, &nullifiers)?))
+        })
+        .collect()
+}
+
+/// Returns the number of leaves that scanning the given block will append to the Sapling and
+/// Orchard note commitment trees, respectively.
+///
+/// This is the same count that [`scan_block`] subtracts from the block's chain metadata in
+/// order to determine the tree sizes at the start of the block, and so it may be used to check
+/// the consistency of that metadata (and to detect conditions that would result in
+/// [`ScanError::TreeSizeInvalid`]) without performing any trial decryption. Counts that do not
+/// fit in a `u32` are saturated to `u32::MAX`; scanning such a block will fail with
+/// [`ScanError::OutputCountOverflow`].
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub fn block_tree_growth(block: &CompactBlock) -> (u32, u32) {
+    let (sapling_output_count, orchard_action_count) = block_output_counts(block);
+    (
+        u32::try_from(sapling_output_count).unwrap_or(u32::MAX),
+        u32::try_from(orchard_action_count).unwrap_or(u32::MAX),
+    )
+}
+
+fn block_output_counts(block: &CompactBlock) -> (usize, usize) {
+    block.vtx.iter().fold((0, 0), |(outputs, actions), tx| {
+        (outputs + tx.outputs.len(), actions + tx.actions.len())
+    })
+}
+
+/// Returns [`ScanError::BlockTooLarge`] if the block contains more shielded outputs than are
+/// permitted by [`ScanConfig::max_outputs_per_block`].
+fn check_output_count(block: &CompactBlock, config: &ScanConfig) -> Result<(), ScanError> {
+    if let Some(max_outputs) = config.max_outputs_per_block {
+        let (sapling_output_count, orchard_action_count) = block_output_counts(block);
+        let output_count = sapling_output_count.saturating_add(orchard_action_count);
+        if output_count > max_outputs {
+            return Err(ScanError::BlockTooLarge {
+                at_height: block.height(),
+                output_count,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// A stateful scanner that scans a sequence of [`CompactBlock`]s in chain order.
+///
+/// A `BlockScanner` tracks the [`BlockMetadata`] of the most recently scanned block and uses
+/// it to check the continuity of each subsequent block, so that callers do not need to thread
+/// `prior_block_metadata` through successive calls to [`scan_block`] themselves. The tracked
+/// nullifier set is likewise updated after each block, so that spends of notes received
+/// earlier in the sequence are detected.
+///
+/// [`CompactBlock`]: crate::proto::compact_formats::CompactBlock
+pub struct BlockScanner<P, AccountId, IvkTag>
+where
+    IvkTag: Clone + Send + 'static,
+{
+    params: P,
+    scanning_keys: ScanningKeys<AccountId, IvkTag>,
+    nullifiers: Nullifiers<AccountId>,
+    prior_block_metadata: Option<BlockMetadata>,
+    config: ScanConfig,
+    prepared_ivks: Option<PreparedIvks<IvkTag>>,
+    batch_runners: Option<BatchRunners<IvkTag, (), ()>>,
+}
+
+impl<P, AccountId, IvkTag> BlockScanner<P, AccountId, IvkTag>
+where
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+{
+    /// Constructs a new `BlockScanner` that scans each block using the given [`ScanConfig`].
+    ///
+    /// `prior_block_metadata` should describe the block immediately preceding the first block
+    /// that will be scanned, if known. If `multithreaded` is `true`, trial decryption of each
+    /// block's outputs will be performed in parallel on the global threadpool.
+    pub fn new(
+        params: P,
+        scanning_keys: ScanningKeys<AccountId, IvkTag>,
+        nullifiers: Nullifiers<AccountId>,
+        prior_block_metadata: Option<BlockMetadata>,
+        config: ScanConfig,
+        multithreaded: bool,
+    ) -> Self {
+        let batch_runners = multithreaded.then(|| BatchRunners::for_keys(100, &scanning_keys));
+        // Keys only need to be prepared here if trial decryption is not performed by the batch
+        // runners, which prepare their own keys.
+        let prepared_ivks = (!multithreaded).then(|| scanning_keys.to_prepared_ivks());
+        Self {
+            params,
+            scanning_keys,
+            nullifiers,
+            prior_block_metadata,
+            config,
+            prepared_ivks,
+            batch_runners,
+        }
+    }
+
+    /// Returns the metadata for the most recently scanned block, or the metadata provided at
+    /// construction if no blocks have yet been scanned.
+    pub fn prior_block_metadata(&self) -> Option<&BlockMetadata> {
+        self.prior_block_metadata.as_ref()
+    }
+
+    /// Returns the set of nullifiers currently being tracked by this scanner.
+    pub fn nullifiers(&self) -> &Nullifiers<AccountId> {
+        &self.nullifiers
+    }
+
+    /// Returns the number of batches of trial decryptions that have been submitted to the
+    /// global threadpool and have not yet completed.
+    ///
+    /// This is always zero if the scanner was constructed with `multithreaded` set to `false`.
+    pub fn pending_tasks(&self) -> usize {
+        self.batch_runners
+            .as_ref()
+            .map_or(0, |runners| runners.pending_tasks())
+    }
+
+    /// Returns the number of batches of trial decryptions that have completed.
+    ///
+    /// This is always zero if the scanner was constructed with `multithreaded` set to `false`.
+    pub fn completed_batches(&self) -> usize {
+        self.batch_runners
+            .as_ref()
+            .map_or(0, |runners| runners.completed_batches())
+    }
+
+    /// Scans the given block, which must be the successor of the most recently scanned block.
+    ///
+    /// On success, the scanner's block metadata and nullifier set are updated to reflect the
+    /// scanned block. On failure, the scanner's state is left unchanged.
+    ///
+    /// If the scanner is multithreaded, the trial decryptions for the block's outputs must be
+    /// completed before this returns, so they cannot be batched together with those of
+    /// subsequent blocks; use [`Self::scan_batch`] to scan several blocks at once.
+    pub fn scan_next(&mut self, block: CompactBlock) -> Result<ScannedBlock<AccountId>, ScanError>
+    where
+        AccountId: Copy,
+    {
+        Ok(self
+            .scan_batch(vec![block])?
+            .pop()
+            .expect("A scanned block is returned for each input block."))
+    }
+
+    /// Scans the given sequence of consecutive blocks, the first of which must be the successor
+    /// of the most recently scanned block.
+    ///
+    /// If the scanner is multithreaded, the outputs of all of the blocks are submitted for
+    /// trial decryption together, so that the batches of trial decryptions are not limited to
+    /// the outputs of a single block.
+    ///
+    /// On success, the scanned blocks are returned in order, and the scanner's block metadata
+    /// and nullifier set are updated to reflect the last of them. On failure, the scanner's
+    /// state is left unchanged, and no results are returned for any of the blocks.
+    pub fn scan_batch(
+        &mut self,
+        blocks: Vec<CompactBlock>,
+    ) -> Result<Vec<ScannedBlock<AccountId>>, ScanError>
+    where
+        AccountId: Copy,
+    {
+        // Check the blocks before any of their outputs are enqueued for batch trial decryption,
+        // so that the batch runners do not accumulate the outputs of blocks that are rejected.
+        let mut prev = self
+            .prior_block_metadata
+            .as_ref()
+            .map(|m| (m.block_height(), m.block_hash()));
+        for block in &blocks {
+            if let Some(scan_error) =
+                check_hash_continuity(block, prev, self.config.require_prev_hash)
+            {
+                return Err(scan_error);
+            }
+            check_output_count(block, &self.config)?;
+            prev = Some((block.height(), block.hash()));
+        }
+
+        let block_hashes = blocks.iter().map(|block| block.hash()).collect::<Vec<_>>();
+        let result = self.scan_batch_inner(blocks);
+
+        // Any results that were not collected (because an error occurred before the block to
+        // which they belong was scanned) will never be collected.
+        if result.is_err() {
+            if let Some(runners) = self.batch_runners.as_mut() {
+                for block_hash in &block_hashes {
+                    runners.discard_block(block_hash);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Returns the number of transactions for which trial decryption results have been
+    /// requested but not collected.
+    #[cfg(test)]
+    fn pending_result_count(&self) -> usize {
+        self.batch_runners
+            .as_ref()
+            .map_or(0, |runners| runners.pending_result_count())
+    }
+
+    fn scan_batch_inner(
+        &mut self,
+        blocks: Vec<CompactBlock>,
+    ) -> Result<Vec<ScannedBlock<AccountId>>, ScanError>
+    where
+        AccountId: Copy,
+    {
+        if let Some(runners) = self.batch_runners.as_mut() {
+            let enqueued = blocks
+                .iter()
+                .try_for_each(|block| runners.add_block(&self.params, block));
+            runners.flush();
+            enqueued?;
+        }
+
+        // The tracked nullifiers are only updated once every block has been scanned; the
+        // blocks after the first are scanned against an updated copy of them.
+        let mut updated_nullifiers: Option<Nullifiers<AccountId>> = None;
+        let mut prior_block_metadata = self.prior_block_metadata;
+        let mut scanned_blocks = Vec::with_capacity(blocks.len());
+        for block in blocks {
+            if let Some(last) = scanned_blocks.last() {
+                updated_nullifiers
+                    .get_or_insert_with(|| self.nullifiers.clone())
+                    .update_for_block(last);
+            }
+
+            let mut options = ScanOptions::new(self.config);
+            if let Some(prepared_ivks) = self.prepared_ivks.as_ref() {
+                options = options.with_prepared_ivks(prepared_ivks);
+            }
+            let scanned_block = scan_block_with_runners(
+                &self.params,
+                block,
+                &self.scanning_keys,
+                updated_nullifiers.as_ref().unwrap_or(&self.nullifiers),
+                prior_block_metadata.as_ref(),
+                options,
+                self.batch_runners.as_mut(),
+            )?;
+            prior_block_metadata = Some(scanned_block.to_block_metadata());
+            scanned_blocks.push(scanned_block);
+        }
+
+        if let Some(nullifiers) = updated_nullifiers {
+            self.nullifiers = nullifiers;
+        }
+        if let Some(last) = scanned_blocks.last() {
+            self.nullifiers.update_for_block(last);
+        }
+        self.prior_block_metadata = prior_block_metadata;
+
+        Ok(scanned_blocks)
+    }
+}
+
+/// The number of blocks that [`feed_blocks`] reads from its input stream ahead of the block
+/// being scanned, and the number of scanned blocks that it buffers ahead of their consumption.
+#[cfg(feature = "tokio")]
+const FEED_BLOCKS_LOOKAHEAD: usize = 8;
+
+/// Scans a stream of blocks on the `tokio` blocking thread pool, returning a stream of the
+/// results of scanning each block.
+///
+/// The [`BlockScanner`] that scans the blocks is constructed by `make_scanner` on a blocking
+/// thread, and is retained there for the lifetime of the returned stream, because its scanning
+/// keys cannot be sent between threads. Each block is scanned with [`BlockScanner::scan_next`];
+/// if the scanner is multithreaded, trial decryption is additionally parallelized on the global
+/// `rayon` thread pool. This allows blocks obtained by asynchronous download code to be scanned
+/// without blocking the async runtime.
+///
+/// Blocks are read from `blocks` by a separate task, so that the download of subsequent blocks
+/// overlaps with the scanning of earlier ones. Up to a small, fixed number of blocks are read
+/// ahead of the block being scanned, and up to the same number of results are buffered ahead of
+/// their consumption from the returned stream.
+///
+/// The returned stream ends when the stream of blocks ends, or after the first error is
+/// returned.
+///
+/// # Panics
+///
+/// Panics if called outside of the context of a `tokio` runtime.
+#[cfg(feature = "tokio")]
+pub fn feed_blocks<P, AccountId, IvkTag, S>(
+    make_scanner: impl FnOnce() -> BlockScanner<P, AccountId, IvkTag> + Send + 'static,
+    mut blocks: S,
+) -> impl Stream<Item = Result<ScannedBlock<AccountId>, ScanError>>
+where
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Copy + Default + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+    S: Stream<Item = CompactBlock> + Unpin + Send + 'static,
+{
+    let (block_tx, mut block_rx) = tokio::sync::mpsc::channel(FEED_BLOCKS_LOOKAHEAD);
+    let (result_tx, result_rx) = tokio::sync::mpsc::channel(FEED_BLOCKS_LOOKAHEAD);
+
+    tokio::spawn(async move {
+        while let Some(block) = blocks.next().await {
+            if block_tx.send(block).await.is_err() {
+                // The scanner has stopped.
+                break;
+            }
+        }
+    });
+
+    tokio::task::spawn_blocking(move || {
+        let mut scanner = make_scanner();
+        while let Some(block) = block_rx.blocking_recv() {
+            let result = scanner.scan_next(block);
+            let is_err = result.is_err();
+            if result_tx.blocking_send(result).is_err() || is_err {
+                // Either the returned stream has been dropped, or no further blocks can be
+                // scanned.
+                break;
+            }
+        }
+    });
+
+    stream::unfold(result_rx, |mut result_rx| async move {
+        let result = result_rx.recv().await?;
+        Some((result, result_rx))
+    })
+}
+
+type TaggedSaplingBatch<IvkTag> = Batch<
+    IvkTag,
+    SaplingDomain,
+    sapling::note_encryption::CompactOutputDescription,
+    CompactDecryptor,
+>;
+type TaggedSaplingBatchRunner<IvkTag, Tasks> = BatchRunner<
+    IvkTag,
+    SaplingDomain,
+    sapling::note_encryption::CompactOutputDescription,
+    CompactDecryptor,
+    Tasks,
+>;
+
+#[cfg(feature = "orchard")]
+type TaggedOrchardBatch<IvkTag> =
+    Batch<IvkTag, OrchardDomain, orchard::note_encryption::CompactAction, CompactDecryptor>;
+#[cfg(feature = "orchard")]
+type TaggedOrchardBatchRunner<IvkTag, Tasks> = BatchRunner<
+    IvkTag,
+    OrchardDomain,
+    orchard::note_encryption::CompactAction,
+    CompactDecryptor,
+    Tasks,
+>;
+
+pub(crate) trait SaplingTasks<IvkTag>: Tasks<TaggedSaplingBatch<IvkTag>> {}
+impl<IvkTag, T: Tasks<TaggedSaplingBatch<IvkTag>>> SaplingTasks<IvkTag> for T {}
+
+#[cfg(not(feature = "orchard"))]
+pub(crate) trait OrchardTasks<IvkTag> {}
+#[cfg(not(feature = "orchard"))]
+impl<IvkTag, T> OrchardTasks<IvkTag> for T {}
+
+#[cfg(feature = "orchard")]
+pub(crate) trait OrchardTasks<IvkTag>: Tasks<TaggedOrchardBatch<IvkTag>> {}
+#[cfg(feature = "orchard")]
+impl<IvkTag, T: Tasks<TaggedOrchardBatch<IvkTag>>> OrchardTasks<IvkTag> for T {}
+
+/// The batch runners used to perform trial decryption of the outputs of each shielded
+/// protocol.
+///
+/// The runners do not own any threads of their own: each batch, whichever protocol it belongs
+/// to, is spawned as a FIFO task on the global `rayon` thread pool (see [`Tasks::run_task`]).
+/// The number of threads used for trial decryption is therefore bounded by the size of that
+/// pool regardless of how many protocols are active, and a block whose outputs are dominated
+/// by a single protocol still has its batches spread across all of the pool's threads.
+pub(crate) struct BatchRunners<IvkTag, TS: SaplingTasks<IvkTag>, TO: OrchardTasks<IvkTag>> {
+    sapling: TaggedSaplingBatchRunner<IvkTag, TS>,
+    #[cfg(feature = "orchard")]
+    orchard: TaggedOrchardBatchRunner<IvkTag, TO>,
+    #[cfg(not(feature = "orchard"))]
+    orchard: PhantomData<TO>,
+}
+
+impl<IvkTag, TS, TO> BatchRunners<IvkTag, TS, TO>
+where
+    IvkTag: Clone + Send + 'static,
+    TS: SaplingTasks<IvkTag>,
+    TO: OrchardTasks<IvkTag>,
+{
+    pub(crate) fn for_keys<AccountId>(
+        batch_size_threshold: usize,
+        scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+    ) -> Self {
+        BatchRunners {
+            sapling: BatchRunner::new(
+                batch_size_threshold,
+                scanning_keys
+                    .sapling()
+                    .iter()
+                    .map(|(id, key)| (id.clone(), key.prepare())),
+            ),
+            #[cfg(feature = "orchard")]
+            orchard: BatchRunner::new(
+                batch_size_threshold,
+                scanning_keys
+                    .orchard()
+                    .iter()
+                    .map(|(id, key)| (id.clone(), key.prepare())),
+            ),
+            #[cfg(not(feature = "orchard"))]
+            orchard: PhantomData,
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.sapling.flush();
+        #[cfg(feature = "orchard")]
+        self.orchard.flush();
+    }
+
+    /// Returns the number of batches across all runners that have been submitted for trial
+    /// decryption and have not yet completed.
+    pub(crate) fn pending_tasks(&self) -> usize {
+        let pending = self.sapling.pending_tasks();
+        #[cfg(feature = "orchard")]
+        let pending = pending + self.orchard.pending_tasks();
+        pending
+    }
+
+    /// Returns the number of batches across all runners that have completed.
+    pub(crate) fn completed_batches(&self) -> usize {
+        let completed = self.sapling.completed_batches();
+        #[cfg(feature = "orchard")]
+        let completed = completed + self.orchard.completed_batches();
+        completed
+    }
+
+    #[cfg(test)]
+    pub(crate) fn pending_result_count(&self) -> usize {
+        let count = self.sapling.pending_result_count();
+        #[cfg(feature = "orchard")]
+        let count = count + self.orchard.pending_result_count();
+        count
+    }
+
+    /// Discards the receivers for any results of trial decryption of the given block's outputs
+    /// that have not been collected.
+    ///
+    /// This must be called if a block that has been added to the runners will not be scanned
+    /// (for example, because scanning of an earlier block failed), so that its results are not
+    /// retained indefinitely.
+    pub(crate) fn discard_block(&mut self, block_tag: &BlockHash) {
+        self.sapling.discard_results(block_tag);
+        #[cfg(feature = "orchard")]
+        self.orchard.discard_results(block_tag);
+    }
+
+    #[tracing::instrument(skip_all, fields(height = block.height))]
+    pub(crate) fn add_block<P>(&mut self, params: &P, block: &CompactBlock) -> Result<(), ScanError>
+    where
+        P: consensus::Parameters + Send + 'static,
+        IvkTag: Clone + Send + 'static,
+    {
+        let block_hash = block.hash();
+        let block_height = block.height();
+        let zip212_enforcement = zip212_enforcement(params, block_height);
+
+        for tx in block.vtx.iter() {
+            let txid = tx.txid();
+
+            self.sapling.add_outputs(
+                block_hash,
+                txid,
+                |_| SaplingDomain::new(zip212_enforcement),
+                &tx.outputs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, output)| {
+                        CompactOutputDescription::try_from(output).map_err(|_| {
+                            ScanError::EncodingInvalid {
+                                at_height: block_height,
+                                txid,
+                                pool_type: ShieldedProtocol::Sapling,
+                                index: i,
+                            }
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+
+            #[cfg(feature = "orchard")]
+            self.orchard.add_outputs(
+                block_hash,
+                txid,
+                |action| OrchardDomain::for_nullifier(action.nullifier()),
+                &tx.actions
+                    .iter()
+                    .enumerate()
+                    .map(|(i, action)| {
+                        CompactAction::try_from(action).map_err(|_| ScanError::EncodingInvalid {
+                            at_height: block_height,
+                            txid,
+                            pool_type: ShieldedProtocol::Orchard,
+                            index: i,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that the note commitment tree size of each shielded protocol that was active as of the
+/// block preceding `block` can be determined, either from the prior block's metadata or from the
+/// chain metadata of `block` itself.
+///
+/// The tree size of a protocol that was not yet active is zero, and so may be omitted. Wallets
+/// may also hold prior block metadata that predates the recording of a protocol's tree size; in
+/// that case the tree size is determined from the block's chain metadata, and so is only
+/// required to be present in the prior block metadata if the block has no chain metadata.
+fn check_prior_pool_metadata<P: consensus::Parameters>(
+    params: &P,
+    prior_block_metadata: &BlockMetadata,
+    block: &CompactBlock,
+) -> Result<(), ScanError> {
+    if block.chain_metadata.is_some() {
+        return Ok(());
+    }
+
+    let at_height = block.height();
+    let check = |tree_size: Option<u32>, upgrade, protocol| {
+        let active = params
+            .activation_height(upgrade)
+            .map_or(false, |activation| {
+                prior_block_metadata.block_height() >= activation
+            });
+        if active && tree_size.is_none() {
+            Err(ScanError::MissingPoolMetadata {
+                protocol,
+                at_height,
+            })
+        } else {
+            Ok(())
+        }
+    };
+
+    check(
+        prior_block_metadata.sapling_tree_size(),
+        NetworkUpgrade::Sapling,
+        ShieldedProtocol::Sapling,
+    )?;
+    #[cfg(feature = "orchard")]
+    check(
+        prior_block_metadata.orchard_tree_size(),
+        NetworkUpgrade::Nu5,
+        ShieldedProtocol::Orchard,
+    )?;
+
+    Ok(())
+}
+
+/// Returns the number of [ZIP 317] logical actions in the given compact transaction, treating
+/// each of its transparent inputs and outputs as a standard P2PKH input or output.
+///
+/// [ZIP 317]: https://zips.z.cash/zip-0317
+fn logical_actions(tx: &CompactTx) -> usize {
+    std::cmp::max(tx.vin.len(), tx.vout.len())
+        + std::cmp::max(tx.spends.len(), tx.outputs.len())
+        + tx.actions.len()
+}
+
+/// Returns the size of a note commitment tree of the given size after `count` note commitments
+/// have been appended to it.
+///
+/// Tree sizes are accumulated as `u64` values, matching the domain of [`Position`]; they are
+/// narrowed to the `u32` representation used by the chain metadata only via
+/// [`narrow_tree_size`].
+fn grow_tree_size(
+    size: u64,
+    count: usize,
+    protocol: ShieldedProtocol,
+    at_height: BlockHeight,
+) -> Result<u64, ScanError> {
+    u64::try_from(count)
+        .ok()
+        .and_then(|count| size.checked_add(count))
+        .ok_or(ScanError::OutputCountOverflow {
+            protocol,
+            at_height,
+        })
+}
+
+/// Converts an accumulated note commitment tree size to the `u32` representation used by the
+/// chain metadata, returning [`ScanError::OutputCountOverflow`] if it is out of range.
+fn narrow_tree_size(
+    size: u64,
+    protocol: ShieldedProtocol,
+    at_height: BlockHeight,
+) -> Result<u32, ScanError> {
+    u32::try_from(size).map_err(|_| ScanError::OutputCountOverflow {
+        protocol,
+        at_height,
+    })
+}
+
+/// Checks that the given block directly follows the block with the given height and hash, if
+/// any.
+fn check_hash_continuity(
+    block: &CompactBlock,
+    prev: Option<(BlockHeight, BlockHash)>,
+    require_prev_hash: bool,
+) -> Option<ScanError> {
+    if let Some((prev_height, prev_hash)) = prev {
+        if block.height() != prev_height + 1 {
+            return Some(ScanError::BlockHeightDiscontinuity {
+                prev_height,
+                new_height: block.height(),
+            });
+        }
+
+        if require_prev_hash && block.prev_hash() != prev_hash {
+            return Some(ScanError::PrevHashMismatch {
+                at_height: block.height(),
+            });
+        }
+    }
+
+    None
+}
+
+/// Checks the internal continuity of a sequence of compact blocks without performing any trial
+/// decryption.
+///
+/// Each block must have a height one greater than that of the block before it, and its
+/// `prev_hash` must equal the hash of the block before it. If `prior` is provided, the first
+/// block must likewise directly follow the block it describes. This allows a range of blocks
+/// obtained from a light wallet server to be rejected before any scanning work is performed
+/// for it.
+pub fn validate_chain(
+    blocks: &[CompactBlock],
+    prior: Option<&BlockMetadata>,
+) -> Result<(), ScanError> {
+    let mut prev = prior.map(|m| (m.block_height(), m.block_hash()));
+    for block in blocks {
+        if let Some(scan_error) = check_hash_continuity(block, prev, true) {
+            return Err(scan_error);
+        }
+        prev = Some((block.height(), block.hash()));
+    }
+
+    Ok(())
+}
+
+/// Determines how far the wallet would need to rewind in order to connect the given block to
+/// its view of the chain, and checks that this does not exceed `max_reorg_depth` blocks.
+///
+/// `recent_blocks` should contain the metadata for the most recent blocks that the wallet has
+/// scanned, the highest of which is taken to be the wallet's chain tip; it may be provided in
+/// any order. The block is connected at the height below its own, at which the wallet's block
+/// must have a hash equal to the block's `prev_hash`. A block that directly follows the chain
+/// tip thus requires no rewinding.
+///
+/// Returns:
+/// - [`ScanError::ReorgTooDeep`] if the fork point is (or, if it lies below the provided
+///   blocks, must be) more than `max_reorg_depth` blocks below the chain tip.
+/// - [`ScanError::PrevHashMismatch`] if the block does not connect to any of the provided
+///   blocks, but the fork point may still lie within `max_reorg_depth` blocks of the tip. The
+///   caller should obtain blocks from a lower height in order to locate the fork point.
+/// - [`ScanError::BlockHeightDiscontinuity`] if the block is above the successor of the chain
+///   tip.
+pub fn detect_reorg(
+    block: &CompactBlock,
+    recent_blocks: &[BlockMetadata],
+    max_reorg_depth: u32,
+) -> Result<(), ScanError> {
+    let tip_height = match recent_blocks.iter().map(|m| m.block_height()).max() {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+
+    let at_height = block.height();
+    if at_height > tip_height + 1 {
+        return Err(ScanError::BlockHeightDiscontinuity {
+            prev_height: tip_height,
+            new_height: at_height,
+        });
+    }
+
+    // The number of blocks above the block's parent that would need to be rewound.
+    let depth = u32::from(tip_height) + 1 - u32::from(at_height);
+    let connected = recent_blocks
+        .iter()
+        .any(|m| m.block_height() + 1 == at_height && m.block_hash() == block.prev_hash());
+
+    if connected {
+        if depth > max_reorg_depth {
+            Err(ScanError::ReorgTooDeep { at_height, depth })
+        } else {
+            Ok(())
+        }
+    } else if depth + 1 > max_reorg_depth {
+        // The fork point is below the block's parent.
+        Err(ScanError::ReorgTooDeep {
+            at_height,
+            depth: depth + 1,
+        })
+    } else {
+        Err(ScanError::PrevHashMismatch { at_height })
+    }
+}
+
+#[tracing::instrument(skip_all, fields(height = block.height))]
+pub(crate) fn scan_block_with_runners<'a, H, P, AccountId, IvkTag, TS, TO>(
+    params: &P,
+    block: CompactBlock,
+    scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+    nullifiers: &Nullifiers<AccountId>,
+    prior_block_metadata: Option<&BlockMetadata>,
+    options: impl Into<ScanOptions<'a, IvkTag, H>>,
+    batch_runners: Option<&mut BatchRunners<IvkTag, TS, TO>>,
+) -> Result<ScannedBlock<AccountId>, ScanError>
+where
+    H: CommitmentHasher,
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+    TS: SaplingTasks<IvkTag> + Sync,
+    TO: OrchardTasks<IvkTag> + Sync,
+{
+    let options = options.into();
+    let config = options.config;
+    let expected_positions = options.expected_positions.map(|expected| {
+        let tx_order = block
+            .vtx
+            .iter()
+            .map(|tx| (tx.txid(), tx.index))
+            .collect::<HashMap<_, _>>();
+        (expected, tx_order)
+    });
+    let result = scan_block_contents(
+        params,
+        block,
+        scanning_keys,
+        nullifiers,
+        prior_block_metadata,
+        options,
+        batch_runners,
+    );
+
+    let result = match expected_positions {
+        Some((expected, tx_order)) => result.and_then(|scanned_block| {
+            check_positions(&scanned_block, expected, &tx_order)?;
+            Ok(scanned_block)
+        }),
+        None => result,
+    };
+
+    let result = if config.track_key_hits {
+        result.map(|scanned_block| scanned_block.with_key_hits())
+    } else {
+        result
+    };
+
+    match config.source_id {
+        Some(source_id) => result
+            .map(|scanned_block| scanned_block.with_source_id(source_id))
+            .map_err(|e| e.with_source(source_id)),
+        None => result,
+    }
+}
+
+/// Checks that the Sapling outputs of `scanned_block` were detected at the `expected` note
+/// commitment tree positions, where `tx_order` maps the ID of each transaction in the block to
+/// its index within the block.
+fn check_positions<AccountId>(
+    scanned_block: &ScannedBlock<AccountId>,
+    expected: &HashMap<TxId, Vec<(usize, Position)>>,
+    tx_order: &HashMap<TxId, u64>,
+) -> Result<(), ScanError> {
+    let computed = scanned_block
+        .transactions()
+        .iter()
+        .flat_map(|tx| {
+            tx.sapling_outputs()
+                .iter()
+                .map(move |output| ((tx.txid(), output.index()), output))
+        })
+        .map(|(key, output)| (key, output.note_commitment_tree_position()))
+        .collect::<HashMap<_, _>>();
+
+    // Check the expected positions in block order, so that the reported mismatch does not
+    // depend upon the iteration order of `expected`.
+    let mut expected = expected
+        .iter()
+        .flat_map(|(txid, outputs)| {
+            outputs
+                .iter()
+                .map(move |(output_index, position)| (*txid, *output_index, *position))
+        })
+        .collect::<Vec<_>>();
+    expected
+        .sort_by_key(|(txid, output_index, _)| (tx_order.get(txid).copied(), *txid, *output_index));
+
+    for (txid, output_index, expected) in expected {
+        let computed = computed.get(&(txid, output_index)).copied();
+        if computed != Some(expected) {
+            return Err(ScanError::PositionMismatch {
+                at_height: scanned_block.height(),
+                txid,
+                output_index,
+                expected,
+                computed,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn scan_block_contents<H, P, AccountId, IvkTag, TS, TO>(
+    params: &P,
+    block: CompactBlock,
+    scanning_keys: &ScanningKeys<AccountId, IvkTag>,
+    nullifiers: &Nullifiers<AccountId>,
+    prior_block_metadata: Option<&BlockMetadata>,
+    options: ScanOptions<'_, IvkTag, H>,
+    mut batch_runners: Option<&mut BatchRunners<IvkTag, TS, TO>>,
+) -> Result<ScannedBlock<AccountId>, ScanError>
+where
+    H: CommitmentHasher,
+    P: consensus::Parameters + Send + 'static,
+    AccountId: Default + Eq + Hash + ConditionallySelectable + Send + 'static,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+    TS: SaplingTasks<IvkTag> + Sync,
+    TO: OrchardTasks<IvkTag> + Sync,
+{
+    let ScanOptions {
+        config,
+        prepared_ivks,
+        mut decryption_cache,
+        tx_range,
+        expected_positions: _,
+        hasher: _,
+    } = options;
+
+    if let Some(scan_error) = check_hash_continuity(
+        &block,
+        prior_block_metadata.map(|m| (m.block_height(), m.block_hash())),
+        config.require_prev_hash,
+    ) {
+        return Err(scan_error);
+    }
+
+    check_output_count(&block, &config)?;
+
+    let cur_height = block.height();
+    let cur_hash = block.hash();
+    let zip212_enforcement = zip212_enforcement(params, cur_height);
+
+    // The baseline note commitment tree sizes below are determined from the activation heights
+    // of the shielded protocols, which are only meaningful if they are correctly ordered.
+    if let Some(nu5_activation) = params.activation_height(NetworkUpgrade::Nu5) {
+        let sapling_activation = params.activation_height(NetworkUpgrade::Sapling);
+        if sapling_activation.map_or(true, |sapling| nu5_activation < sapling) {
+            return Err(ScanError::InconsistentActivationHeights {
+                at_height: cur_height,
+                sapling_activation,
+                nu5_activation,
+            });
+        }
+    }
+
+    if let Some(prior) = prior_block_metadata {
+        check_prior_pool_metadata(params, prior, &block)?;
+    }
+
+    // A source that does not track note commitment tree sizes may provide chain metadata that
+    // is populated only with default (zero) values. We distinguish this case from the absence
+    // of chain metadata, so that callers can decide whether to fall back to a different source.
+    // Zero tree sizes are legitimate for a block that has no outputs in either pool, so this is
+    // only used to classify the error when the block has outputs that the tree sizes omit.
+    let metadata_defaulted = block.chain_metadata.as_ref().map_or(false, |m| {
+        m.sapling_commitment_tree_size == 0 && m.orchard_commitment_tree_size == 0
+    });
+
+    let sapling_commitment_tree_size = prior_block_metadata
+        .and_then(|m| m.sapling_tree_size())
+        .map_or_else(
+            || {
+                block.chain_metadata.as_ref().map_or_else(
+                    || {
+                        // If we're below Sapling activation, or Sapling activation is not set, the tree size is zero
+                        params
+                            .activation_height(NetworkUpgrade::Sapling)
+                            .map_or_else(
+                                || Ok(0),
+                                |sapling_activation| {
+                                    if cur_height < sapling_activation {
+                                        Ok(0)
+                                    } else {
+                                        Err(ScanError::TreeSizeUnknown {
+                                            protocol: ShieldedProtocol::Sapling,
+                                            at_height: cur_height,
+                                            metadata_absent: true,
+                                        })
+                                    }
+                                },
+                            )
+                    },
+                    |m| {
+                        let sapling_output_count: u32 = block_output_counts(&block)
+                            .0
+                            .try_into()
+                            .map_err(|_| ScanError::OutputCountOverflow {
+                                protocol: ShieldedProtocol::Sapling,
+                                at_height: cur_height,
+                            })?;
+
+                        // The default for m.sapling_commitment_tree_size is zero, so we need to check
+                        // that the subtraction will not underflow; if it would do so, we were given
+                        // invalid chain metadata for a block with Sapling outputs.
+                        m.sapling_commitment_tree_size
+                            .checked_sub(sapling_output_count)
+                            .ok_or(if metadata_defaulted {
+                                ScanError::TreeSizeUnknown {
+                                    protocol: ShieldedProtocol::Sapling,
+                                    at_height: cur_height,
+                                    metadata_absent: false,
+                                }
+                            } else {
+                                ScanError::TreeSizeInvalid {
+                                    protocol: ShieldedProtocol::Sapling,
+                                    at_height: cur_height,
+                                }
+                            })
+                    },
+                )
+            },
+            Ok,
+        )?;
+
+    if let Some(limit) = config.max_sapling_tree_size {
+        let sapling_output_count = block_output_counts(&block).0;
+        if u64::from(sapling_commitment_tree_size).saturating_add(sapling_output_count as u64)
+            > limit
+        {
+            return Err(ScanError::TreeSizeExceeded {
+                protocol: ShieldedProtocol::Sapling,
+                limit,
+                at_height: cur_height,
+            });
+        }
+    }
+
+    #[cfg(feature = "orchard")]
+    let orchard_commitment_tree_size = prior_block_metadata
+        .and_then(|m| m.orchard_tree_size())
+        .map_or_else(
+            || {
+                block.chain_metadata.as_ref().map_or_else(
+                    || {
+                        // If we're below Orchard activation, or Orchard activation is not set, the tree size is zero
+                        params.activation_height(NetworkUpgrade::Nu5).map_or_else(
+                            || Ok(0),
+                            |orchard_activation| {
+                                if cur_height < orchard_activation {
+                                    Ok(0)
+                                } else {
+                                    Err(ScanError::TreeSizeUnknown {
+                                        protocol: ShieldedProtocol::Orchard,
+                                        at_height: cur_height,
+                                        metadata_absent: true,
+                                    })
+                                }
+                            },
+                        )
+                    },
+                    |m| {
+                        let orchard_action_count: u32 = block_output_counts(&block)
+                            .1
+                            .try_into()
+                            .map_err(|_| ScanError::OutputCountOverflow {
+                                protocol: ShieldedProtocol::Orchard,
+                                at_height: cur_height,
+                            })?;
+
+                        // The default for m.orchard_commitment_tree_size is zero, so we need to check
+                        // that the subtraction will not underflow; if it would do so, we were given
+                        // invalid chain metadata for a block with Orchard actions.
+                        m.orchard_commitment_tree_size
+                            .checked_sub(orchard_action_count)
+                            .ok_or(if metadata_defaulted {
+                                ScanError::TreeSizeUnknown {
+                                    protocol: ShieldedProtocol::Orchard,
+                                    at_height: cur_height,
+                                    metadata_absent: false,
+                                }
+                            } else {
+                                ScanError::TreeSizeInvalid {
+                                    protocol: ShieldedProtocol::Orchard,
+                                    at_height: cur_height,
+                                }
+                            })
+                    },
+                )
+            },
+            Ok,
+        )?;
+
+    // The running tree sizes are accumulated as `u64` values, and are only narrowed back to
+    // `u32` where they are compared against (or stored as) chain metadata.
+    let mut sapling_commitment_tree_size = u64::from(sapling_commitment_tree_size);
+    #[cfg(feature = "orchard")]
+    let mut orchard_commitment_tree_size = u64::from(orchard_commitment_tree_size);
+
+    // If the tree sizes were obtained from the prior block's metadata, they may disagree with
+    // the end-of-block sizes given by the block's own chain metadata. Detect this before doing
+    // any trial decryption, since such a block will always fail the final consistency check.
+    if let Some(chain_meta) = block.chain_metadata.as_ref() {
+        let computed = grow_tree_size(
+            sapling_commitment_tree_size,
+            block_output_counts(&block).0,
+            ShieldedProtocol::Sapling,
+            cur_height,
+        )?;
+        if u64::from(chain_meta.sapling_commitment_tree_size) != computed {
+            return Err(ScanError::TreeSizeMismatch {
+                protocol: ShieldedProtocol::Sapling,
+                at_height: cur_height,
+                given: chain_meta.sapling_commitment_tree_size,
+                computed: narrow_tree_size(computed, ShieldedProtocol::Sapling, cur_height)?,
+            });
+        }
+
+        #[cfg(feature = "orchard")]
+        {
+            let computed = grow_tree_size(
+                orchard_commitment_tree_size,
+                block_output_counts(&block).1,
+                ShieldedProtocol::Orchard,
+                cur_height,
+            )?;
+            if u64::from(chain_meta.orchard_commitment_tree_size) != computed {
+                return Err(ScanError::TreeSizeMismatch {
+                    protocol: ShieldedProtocol::Orchard,
+                    at_height: cur_height,
+                    given: chain_meta.orchard_commitment_tree_size,
+                    computed: narrow_tree_size(computed, ShieldedProtocol::Orchard, cur_height)?,
+                });
+            }
+        }
+    }
+
+    let sapling_start_size = sapling_commitment_tree_size;
+    #[cfg(feature = "orchard")]
+    let orchard_start_size = orchard_commitment_tree_size;
+
+    let mut wtxs: Vec<WalletTx<AccountId>> = vec![];
+    let mut sapling_nullifier_map = Vec::with_capacity(block.vtx.len());
+    let mut sapling_note_commitments: Vec<(sapling::Node, Retention<BlockHeight>)> = vec![];
+
+    #[cfg(feature = "orchard")]
+    let mut orchard_nullifier_map = Vec::with_capacity(block.vtx.len());
+    #[cfg(feature = "orchard")]
+    let mut orchard_note_commitments: Vec<(MerkleHashOrchard, Retention<BlockHeight>)> = vec![];
+
+    let find_sapling_account = sapling_account_finder(nullifiers);
+    #[cfg(feature = "orchard")]
+    let find_orchard_account = orchard_account_finder(nullifiers);
+
+    for (tx_idx, tx) in block.vtx.into_iter().enumerate() {
+        let txid = tx.txid();
+
+        // The outputs of transactions outside of the requested range are not trial-decrypted,
+        // but their note commitments must still be appended to the note commitment trees so
+        // that the positions of the notes we do detect are correct.
+        if tx_range
+            .as_ref()
+            .map_or(false, |range| !range.contains(&tx_idx))
+        {
+            if let Some(runners) = batch_runners.as_mut() {
+                // Discard any batch decryption results for the skipped transaction.
+                runners.sapling.collect_results(cur_hash, txid);
+                #[cfg(feature = "orchard")]
+                runners.orchard.collect_results(cur_hash, txid);
+            }
+
+            if config.track_commitments {
+                for (i, output) in tx.outputs.iter().enumerate() {
+                    let cmu = output.cmu().map_err(|_| ScanError::EncodingInvalid {
+                        at_height: cur_height,
+                        txid,
+                        pool_type: ShieldedProtocol::Sapling,
+                        index: i,
+                    })?;
+                    sapling_note_commitments.push((H::sapling_node(&cmu), Retention::Ephemeral));
+                }
+            }
+            sapling_commitment_tree_size = grow_tree_size(
+                sapling_commitment_tree_size,
+                tx.outputs.len(),
+                ShieldedProtocol::Sapling,
+                cur_height,
+            )?;
+
+            #[cfg(feature = "orchard")]
+            {
+                if config.track_commitments {
+                    for (i, action) in tx.actions.iter().enumerate() {
+                        let cmx = action.cmx().map_err(|_| ScanError::EncodingInvalid {
+                            at_height: cur_height,
+                            txid,
+                            pool_type: ShieldedProtocol::Orchard,
+                            index: i,
+                        })?;
+                        orchard_note_commitments
+                            .push((MerkleHashOrchard::from_cmx(&cmx), Retention::Ephemeral));
+                    }
+                }
+                orchard_commitment_tree_size = grow_tree_size(
+                    orchard_commitment_tree_size,
+                    tx.actions.len(),
+                    ShieldedProtocol::Orchard,
+                    cur_height,
+                )?;
+            }
+
+            continue;
+        }
+
+        let tx_index = u16::try_from(tx.index).map_err(|_| ScanError::TxIndexOverflow {
+            at_height: cur_height,
+            txid,
+            index: tx.index,
+        })?;
+        let _span = tracing::trace_span!("scan_tx", txid = %txid, tx_index).entered();
+
+        let (sapling_spends, sapling_unlinked_nullifiers) = find_spent(
+            &tx.spends,
+            &find_sapling_account,
+            |index, spend| {
+                spend.nf().map_err(|_| ScanError::NullifierInvalid {
+                    protocol: ShieldedProtocol::Sapling,
+                    at_height: cur_height,
+                    txid,
+                    index,
+                })
+            },
+            WalletSpend::from_parts,
+        )?;
+
+        if config.reject_duplicate_nullifiers {
+            check_unique_accounts(
+                &nullifiers.sapling,
+                &sapling_spends,
+                ShieldedProtocol::Sapling,
+                cur_height,
+                txid,
+            )?;
+        }
+        sapling_nullifier_map.push((txid, tx_index, sapling_unlinked_nullifiers));
+
+        #[cfg(feature = "orchard")]
+        let orchard_spends = {
+            let (orchard_spends, orchard_unlinked_nullifiers) = find_spent(
+                &tx.actions,
+                &find_orchard_account,
+                |index, spend| {
+                    spend.nf().map_err(|_| ScanError::NullifierInvalid {
+                        protocol: ShieldedProtocol::Orchard,
+                        at_height: cur_height,
+                        txid,
+                        index,
+                    })
+                },
+                WalletSpend::from_parts,
+            )?;
+            if config.reject_duplicate_nullifiers {
+                check_unique_accounts(
+                    &nullifiers.orchard,
+                    &orchard_spends,
+                    ShieldedProtocol::Orchard,
+                    cur_height,
+                    txid,
+                )?;
+            }
+            orchard_nullifier_map.push((txid, tx_index, orchard_unlinked_nullifiers));
+            orchard_spends
+        };
+
+        // Collect the set of accounts that were spent from in this transaction
+        let spent_from_accounts = sapling_spends.iter().map(|spend| spend.account_id());
+        #[cfg(feature = "orchard")]
+        let spent_from_accounts =
+            spent_from_accounts.chain(orchard_spends.iter().map(|spend| spend.account_id()));
+        let spent_from_accounts = spent_from_accounts.copied().collect::<HashSet<_>>();
+
+        let (sapling_outputs, mut sapling_nc) = find_received(
+            ShieldedProtocol::Sapling,
+            cur_height,
+            txid,
+            sapling_commitment_tree_size,
+            &scanning_keys.sapling,
+            &spent_from_accounts,
+            &tx.outputs
+                .iter()
+                .enumerate()
+                .map(|(i, output)| {
+                    Ok((
+                        SaplingDomain::new(zip212_enforcement),
+                        CompactOutputDescription::try_from(output).map_err(|_| {
+                            ScanError::EncodingInvalid {
+                                at_height: cur_height,
+                                txid,
+                                pool_type: ShieldedProtocol::Sapling,
+                                index: i,
+                            }
+                        })?,
+                    ))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            batch_runners
+                .as_mut()
+                .map(|runners| |txid| runners.sapling.collect_results(cur_hash, txid)),
+            prepared_ivks.map(|prepared| &prepared.sapling),
+            decryption_cache.as_mut().map(|cache| &mut cache.sapling),
+            config,
+            |output| H::sapling_node(&output.cmu),
+            |note| note.value().inner(),
+        )?;
+        sapling_note_commitments.append(&mut sapling_nc);
+        let has_sapling = !(sapling_spends.is_empty() && sapling_outputs.is_empty());
+
+        #[cfg(feature = "orchard")]
+        let (orchard_outputs, mut orchard_nc) = find_received(
+            ShieldedProtocol::Orchard,
+            cur_height,
+            txid,
+            orchard_commitment_tree_size,
+            &scanning_keys.orchard,
+            &spent_from_accounts,
+            &tx.actions
+                .iter()
+                .enumerate()
+                .map(|(i, action)| {
+                    let action = CompactAction::try_from(action).map_err(|_| {
+                        ScanError::EncodingInvalid {
+                            at_height: cur_height,
+                            txid,
+                            pool_type: ShieldedProtocol::Orchard,
+                            index: i,
+                        }
+                    })?;
+                    Ok((OrchardDomain::for_nullifier(action.nullifier()), action))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            batch_runners
+                .as_mut()
+                .map(|runners| |txid| runners.orchard.collect_results(cur_hash, txid)),
+            prepared_ivks.map(|prepared| &prepared.orchard),
+            decryption_cache.as_mut().map(|cache| &mut cache.orchard),
+            config,
+            |output| MerkleHashOrchard::from_cmx(&output.cmx()),
+            |note| note.value().inner(),
+        )?;
+        #[cfg(feature = "orchard")]
+        orchard_note_commitments.append(&mut orchard_nc);
+
+        tracing::trace!(
+            sapling_outputs = tx.outputs.len(),
+            sapling_decrypted = sapling_outputs.len(),
+            "Trial-decrypted Sapling outputs"
+        );
+        #[cfg(feature = "orchard")]
+        tracing::trace!(
+            orchard_actions = tx.actions.len(),
+            orchard_decrypted = orchard_outputs.len(),
+            "Trial-decrypted Orchard actions"
+        );
+
+        #[cfg(feature = "orchard")]
+        let has_orchard = !(orchard_spends.is_empty() && orchard_outputs.is_empty());
+        #[cfg(not(feature = "orchard"))]
+        let has_orchard = false;
+
+        let has_sapling_or_orchard_spend = !sapling_spends.is_empty();
+        #[cfg(feature = "orchard")]
+        let has_sapling_or_orchard_spend =
+            has_sapling_or_orchard_spend || !orchard_spends.is_empty();
+
+        #[cfg(feature = "transparent-inputs")]
+        let transparent_outputs =
+            find_received_transparent(cur_height, txid, &tx.vout, &scanning_keys.transparent)?;
+        #[cfg(feature = "transparent-inputs")]
+        let has_transparent = !transparent_outputs.is_empty();
+        #[cfg(not(feature = "transparent-inputs"))]
+        let has_transparent = false;
+
+        // A transaction that spends the wallet's transparent funds and pays to the same account
+        // in a shielded pool is considered to shield those funds.
+        #[cfg(feature = "transparent-inputs")]
+        let is_shielding = {
+            let transparent_spenders = tx
+                .vin
+                .iter()
+                .filter_map(|txin| nullifiers.transparent_spender(txin))
+                .collect::<HashSet<_>>();
+            let is_shielding = sapling_outputs
+                .iter()
+                .any(|output| transparent_spenders.contains(output.account_id()));
+            #[cfg(feature = "orchard")]
+            let is_shielding = is_shielding
+                || orchard_outputs
+                    .iter()
+                    .any(|output| transparent_spenders.contains(output.account_id()));
+            is_shielding
+        };
+
+        // The fee can only be computed if every input to the transaction is a note of known
+        // value, and every output is either a note received by the wallet or a transparent
+        // output included in the compact transaction. An empty `vin` or `vout` only indicates
+        // the absence of transparent inputs or outputs if the source provides transparent data.
+        let all_shielded_known =
+            sapling_spends.len() == tx.spends.len() && sapling_outputs.len() == tx.outputs.len();
+        #[cfg(feature = "orchard")]
+        let all_shielded_known = all_shielded_known
+            && orchard_spends.len() == tx.actions.len()
+            && orchard_outputs.len() == tx.actions.len();
+        let computed_fee = if config.transparent_data_available
+            && all_shielded_known
+            && has_sapling_or_orchard_spend
+            && tx.vin.is_empty()
+        {
+            let spent_values = sapling_spends
+                .iter()
+                .map(|spend| nullifiers.sapling_values.get(&spend.nf().0).copied());
+            #[cfg(feature = "orchard")]
+            let spent_values = spent_values.chain(orchard_spends.iter().map(|spend| {
+                nullifiers
+                    .orchard_values
+                    .get(&spend.nf().to_bytes())
+                    .copied()
+            }));
+
+            let output_values = sapling_outputs
+                .iter()
+                .map(|output| NonNegativeAmount::from_u64(output.note().value().inner()).ok());
+            #[cfg(feature = "orchard")]
+            let output_values = output_values.chain(
+                orchard_outputs
+                    .iter()
+                    .map(|output| NonNegativeAmount::from_u64(output.note().value().inner()).ok()),
+            );
+            let output_values = output_values.chain(
+                tx.vout
+                    .iter()
+                    .map(|output| NonNegativeAmount::from_u64(output.value).ok()),
+            );
+
+            compute_fee(spent_values, output_values)
+        } else {
+            None
+        };
+
+        if has_sapling || has_orchard || has_transparent {
+            let wtx = WalletTx::new(
+                txid,
+                tx_index as usize,
+                sapling_spends,
+                sapling_outputs,
+                #[cfg(feature = "orchard")]
+                orchard_spends,
+                #[cfg(feature = "orchard")]
+                orchard_outputs,
+                #[cfg(feature = "transparent-inputs")]
+                transparent_outputs,
+                computed_fee,
+                logical_actions(&tx),
+                tx.outputs.len() + tx.actions.len(),
+            );
+            #[cfg(feature = "transparent-inputs")]
+            let wtx = wtx.with_shielding(is_shielding);
+            wtxs.push(wtx);
+        }
+
+        sapling_commitment_tree_size = grow_tree_size(
+            sapling_commitment_tree_size,
+            tx.outputs.len(),
+            ShieldedProtocol::Sapling,
+            cur_height,
+        )?;
+        #[cfg(feature = "orchard")]
+        {
+            orchard_commitment_tree_size = grow_tree_size(
+                orchard_commitment_tree_size,
+                tx.actions.len(),
+                ShieldedProtocol::Orchard,
+                cur_height,
+            )?;
+        }
+    }
+
+    checkpoint_last_commitment(&mut sapling_note_commitments, cur_height);
+    #[cfg(feature = "orchard")]
+    checkpoint_last_commitment(&mut orchard_note_commitments, cur_height);
+
+    debug_assert!(positions_are_contiguous(
+        sapling_start_size,
+        sapling_commitment_tree_size,
+        config
+            .track_commitments
+            .then_some(&sapling_note_commitments[..]),
+        wtxs.iter().flat_map(|tx| {
+            tx.sapling_outputs()
+                .iter()
+                .map(|output| output.note_commitment_tree_position())
+        }),
+    ));
+    #[cfg(feature = "orchard")]
+    debug_assert!(positions_are_contiguous(
+        orchard_start_size,
+        orchard_commitment_tree_size,
+        config
+            .track_commitments
+            .then_some(&orchard_note_commitments[..]),
+        wtxs.iter().flat_map(|tx| {
+            tx.orchard_outputs()
+                .iter()
+                .map(|output| output.note_commitment_tree_position())
+        }),
+    ));
+
+    if let Some(chain_meta) = block.chain_metadata {
+        if u64::from(chain_meta.sapling_commitment_tree_size) != sapling_commitment_tree_size {
+            return Err(ScanError::TreeSizeMismatch {
+                protocol: ShieldedProtocol::Sapling,
+                at_height: cur_height,
+                given: chain_meta.sapling_commitment_tree_size,
+                computed: narrow_tree_size(
+                    sapling_commitment_tree_size,
+                    ShieldedProtocol::Sapling,
+                    cur_height,
+                )?,
+            });
+        }
+
+        #[cfg(feature = "orchard")]
+        if u64::from(chain_meta.orchard_commitment_tree_size) != orchard_commitment_tree_size {
+            return Err(ScanError::TreeSizeMismatch {
+                protocol: ShieldedProtocol::Orchard,
+                at_height: cur_height,
+                given: chain_meta.orchard_commitment_tree_size,
+                computed: narrow_tree_size(
+                    orchard_commitment_tree_size,
+                    ShieldedProtocol::Orchard,
+                    cur_height,
+                )?,
+            });
+        }
+    }
+
+    Ok(ScannedBlock::from_parts(
+        cur_height,
+        cur_hash,
+        block.time,
+        wtxs,
+        zip212_enforcement,
+        ScannedBundles::new(
+            narrow_tree_size(
+                sapling_commitment_tree_size,
+                ShieldedProtocol::Sapling,
+                cur_height,
+            )?,
+            sapling_note_commitments,
+            sapling_nullifier_map,
+        ),
+        #[cfg(feature = "orchard")]
+        ScannedBundles::new(
+            narrow_tree_size(
+                orchard_commitment_tree_size,
+                ShieldedProtocol::Orchard,
+                cur_height,
+            )?,
+            orchard_note_commitments,
+            orchard_nullifier_map,
+        ),
+    ))
+}
+
+/// Returns a function that identifies the account (if any) for which the given Sapling
+/// nullifier is tracked.
+#[cfg(not(feature = "fast-nullifier-lookup"))]
+fn sapling_account_finder<AccountId: ConditionallySelectable + Default>(
+    nullifiers: &Nullifiers<AccountId>,
+) -> impl Fn(&sapling::Nullifier) -> Option<AccountId> + '_ {
+    |nf| {
+        if nullifiers
+            .sapling_bloom
+            .as_ref()
+            .map_or(true, |bloom| bloom.may_contain(&nf.0))
+        {
+            ct_find_account(&nullifiers.sapling, nf)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "fast-nullifier-lookup")]
+fn sapling_account_finder<AccountId: Copy>(
+    nullifiers: &Nullifiers<AccountId>,
+) -> impl Fn(&sapling::Nullifier) -> Option<AccountId> + '_ {
+    |nf| {
+        nullifiers
+            .sapling_index
+            .get(&nf.0)
+            .map(|&i| nullifiers.sapling[i].0)
+    }
+}
+
+/// Returns a function that identifies the account (if any) for which the given Orchard
+/// nullifier is tracked.
+#[cfg(all(feature = "orchard", not(feature = "fast-nullifier-lookup")))]
+fn orchard_account_finder<AccountId: ConditionallySelectable + Default>(
+    nullifiers: &Nullifiers<AccountId>,
+) -> impl Fn(&orchard::note::Nullifier) -> Option<AccountId> + '_ {
+    |nf| {
+        if nullifiers
+            .orchard_bloom
+            .as_ref()
+            .map_or(true, |bloom| bloom.may_contain(&nf.to_bytes()))
+        {
+            ct_find_account(&nullifiers.orchard, nf)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(all(feature = "orchard", feature = "fast-nullifier-lookup"))]
+fn orchard_account_finder<AccountId: Copy>(
+    nullifiers: &Nullifiers<AccountId>,
+) -> impl Fn(&orchard::note::Nullifier) -> Option<AccountId> + '_ {
+    |nf| {
+        nullifiers
+            .orchard_index
+            .get(&nf.to_bytes())
+            .map(|&i| nullifiers.orchard[i].0)
+    }
+}
+
+/// Check for spent notes, using `find_account` to identify the account (if any) that owns the
+/// note spent by each nullifier.
+fn find_spent<AccountId, Spend, Nf: Copy, WS>(
+    spends: &[Spend],
+    find_account: impl Fn(&Nf) -> Option<AccountId>,
+    extract_nf: impl Fn(usize, &Spend) -> Result<Nf, ScanError>,
+    construct_wallet_spend: impl Fn(usize, Nf, AccountId) -> WS,
+) -> Result<(Vec<WS>, Vec<(usize, Nf)>), ScanError> {
+    let mut found_spent = vec![];
+    let mut unlinked_nullifiers = Vec::with_capacity(spends.len());
+    for (index, spend) in spends.iter().enumerate() {
+        let spend_nf = extract_nf(index, spend)?;
+
+        if let Some(account) = find_account(&spend_nf) {
+            found_spent.push(construct_wallet_spend(index, spend_nf, account));
+        } else {
+            // This nullifier didn't match any we are currently tracking; save it in
+            // case it matches an earlier block range we haven't scanned yet.
+            unlinked_nullifiers.push((index, spend_nf));
+        }
+    }
+
+    Ok((found_spent, unlinked_nullifiers))
+}
+
+/// Checks that the nullifier revealed by each of the given spends is tracked for only a single
+/// account.
+fn check_unique_accounts<AccountId: Eq, Nf: PartialEq>(
+    tracked: &[(AccountId, Nf)],
+    spends: &[WalletSpend<Nf, AccountId>],
+    protocol: ShieldedProtocol,
+    at_height: BlockHeight,
+    txid: TxId,
+) -> Result<(), ScanError> {
+    for spend in spends {
+        let mut accounts = tracked
+            .iter()
+            .filter(|(_, nf)| nf == spend.nf())
+            .map(|(account, _)| account);
+        if let Some(first) = accounts.next() {
+            if accounts.any(|account| account != first) {
+                return Err(ScanError::DuplicateTrackedNullifier {
+                    protocol,
+                    at_height,
+                    txid,
+                    index: spend.index(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Computes a transaction's fee as the difference between the values of its inputs and its
+/// outputs. Returns `None` if any value is unknown, or if the outputs exceed the inputs.
+fn compute_fee(
+    input_values: impl IntoIterator<Item = Option<NonNegativeAmount>>,
+    output_values: impl IntoIterator<Item = Option<NonNegativeAmount>>,
+) -> Option<NonNegativeAmount> {
+    let inputs = input_values
+        .into_iter()
+        .try_fold(NonNegativeAmount::ZERO, |acc, value| acc + value?)?;
+    let outputs = output_values
+        .into_iter()
+        .try_fold(NonNegativeAmount::ZERO, |acc, value| acc + value?)?;
+    inputs - outputs
+}
+
+/// Returns the account that owns the note with the given nullifier, if it is among the
+/// tracked nullifiers. The comparison against known-unspent nullifiers is done in constant
+/// time.
+#[cfg(not(feature = "fast-nullifier-lookup"))]
+fn ct_find_account<AccountId: ConditionallySelectable + Default, Nf: ConstantTimeEq>(
+    nullifiers: &[(AccountId, Nf)],
+    spend_nf: &Nf,
+) -> Option<AccountId> {
+    #[cfg(test)]
+    CT_FIND_FOLDS.with(|folds| folds.set(folds.get() + nullifiers.len()));
+
+    // TODO: this is O(|nullifiers| * |notes|); does using constant-time operations here really
+    // make sense?
+    nullifiers
+        .iter()
+        .map(|(account, nf)| CtOption::new(*account, nf.ct_eq(spend_nf)))
+        .fold(
+            CtOption::new(AccountId::default(), 0.into()),
+            |first, next| CtOption::conditional_select(&next, &first, first.is_some()),
+        )
+        .into()
+}
+
+/// The number of fold steps (comparisons against a tracked nullifier) performed by
+/// [`ct_find_account`] on the current thread, so that tests can measure the effect of
+/// prefiltering spends.
+#[cfg(all(test, not(feature = "fast-nullifier-lookup")))]
+thread_local! {
+    static CT_FIND_FOLDS: std::cell::Cell<usize> = std::cell::Cell::new(0);
+}
+
+/// Builds an index from the byte encoding of each tracked nullifier to the position of its
+/// entry in `nullifiers`. If a nullifier is tracked for more than one account, the first such
+/// entry is used, matching the behaviour of the constant-time lookup.
+#[cfg(feature = "fast-nullifier-lookup")]
+fn nullifier_index<AccountId, Nf>(
+    nullifiers: &[(AccountId, Nf)],
+    to_bytes: impl Fn(&Nf) -> [u8; 32],
+) -> HashMap<[u8; 32], usize> {
+    let mut index = HashMap::with_capacity(nullifiers.len());
+    extend_nullifier_index(&mut index, nullifiers, 0, to_bytes);
+    index
+}
+
+/// Adds the entries of `nullifiers` from position `start` onwards to the given index.
+#[cfg(feature = "fast-nullifier-lookup")]
+fn extend_nullifier_index<AccountId, Nf>(
+    index: &mut HashMap<[u8; 32], usize>,
+    nullifiers: &[(AccountId, Nf)],
+    start: usize,
+    to_bytes: impl Fn(&Nf) -> [u8; 32],
+) {
+    for (i, (_, nf)) in nullifiers.iter().enumerate().skip(start) {
+        index.entry(to_bytes(nf)).or_insert(i);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[allow(clippy::type_complexity)]
+fn find_received<
+    AccountId: Copy + Eq + Hash,
+    D: BatchDomain<ExtractedCommitmentBytes = [u8; 32]>,
+    Nf,
+    IvkTag: Clone + std::hash::Hash + Eq + Send + 'static,
+    SK: ScanningKeyOps<D, AccountId, Nf>,
+    Output: ShieldedOutput<D, COMPACT_NOTE_SIZE>,
+    NoteCommitment,
+>(
+    protocol: ShieldedProtocol,
+    block_height: BlockHeight,
+    txid: TxId,
+    commitment_tree_size: u64,
+    keys: &HashMap<IvkTag, SK>,
+    spent_from_accounts: &HashSet<AccountId>,
+    decoded: &[(D, Output)],
+    batch_results: Option<
+        impl FnOnce(TxId) -> HashMap<(TxId, usize), DecryptedOutput<IvkTag, D, ()>>,
+    >,
+    prepared_ivks: Option<&(Vec<IvkTag>, Vec<D::IncomingViewingKey>)>,
+    decryption_cache: Option<&mut PoolDecryptionCache<IvkTag>>,
+    config: ScanConfig,
+    extract_note_commitment: impl Fn(&Output) -> NoteCommitment,
+    note_value: impl Fn(&D::Note) -> u64,
+) -> Result<
+    (
+        Vec<WalletOutput<D::Note, Nf, AccountId>>,
+        Vec<(NoteCommitment, Retention<BlockHeight>)>,
+    ),
+    ScanError,
+> {
+    let key_is_valid = |key: &SK| {
+        key.valid_heights()
+            .map_or(true, |range| range.contains(&block_height))
+    };
+
+    let pool_enabled = match protocol {
+        ShieldedProtocol::Sapling => config.scan_sapling,
+        ShieldedProtocol::Orchard => config.scan_orchard,
+    };
+
+    // Check for incoming notes while incrementing tree and witnesses
+    let (decrypted_opts, decrypted_len) = if !pool_enabled {
+        // Discard any batch decryption results for the transaction; the outputs of a disabled
+        // pool are treated as undecryptable, but still occupy positions in the tree.
+        if let Some(collect_results) = batch_results {
+            collect_results(txid);
+        }
+        ((0..decoded.len()).map(|_| None).collect::<Vec<_>>(), 0)
+    } else if let Some(collect_results) = batch_results {
+        let mut decrypted = collect_results(txid);
+        let decrypted_len = decrypted.len();
+        (
+            (0..decoded.len())
+                .map(|i| {
+                    // Batch trial decryption is performed with every key, so notes decrypted
+                    // by keys that are not valid at this height must be discarded here.
+                    decrypted
+                        .remove(&(txid, i))
+                        .filter(|d_out| keys.get(&d_out.ivk_tag).map_or(true, key_is_valid))
+                        .map(|d_out| (d_out.ivk_tag, d_out.note))
+                })
+                .collect::<Vec<_>>(),
+            decrypted_len,
+        )
+    } else {
+        // The prepared keys can only be used if none of the keys need to be excluded at this
+        // height; otherwise, the keys that are valid at this height are prepared here.
+        let all_keys_valid = keys.values().all(key_is_valid);
+        let mut prepared_tags = vec![];
+        let mut prepared = vec![];
+        let (ivk_lookup, ivks): (&[IvkTag], &[D::IncomingViewingKey]) = match prepared_ivks {
+            Some((tags, ivks)) if all_keys_valid => (tags, ivks),
+            _ => {
+                for (key_id, key) in keys.iter().filter(|(_, key)| key_is_valid(key)) {
+                    prepared.push(key.prepare());
+                    prepared_tags.push(key_id.clone());
+                }
+                (&prepared_tags, &prepared)
+            }
+        };
+
+        let batch_decrypt = || {
+            batch::try_compact_note_decryption(ivks, decoded)
+                .into_iter()
+                .map(|v| v.map(|((note, _), ivk_idx)| (note, ivk_idx)))
+                .collect::<Vec<_>>()
+        };
+
+        let decrypted = match decryption_cache {
+            Some(cache) => {
+                let generation = cache.generation(keys.keys());
+                let cache_keys = decoded
+                    .iter()
+                    .map(|(_, output)| (output.ephemeral_key().0, output.cmstar_bytes()))
+                    .collect::<Vec<_>>();
+
+                // Outputs that have previously been found to be undecryptable are only tried
+                // with the keys that have been added since. Batch decryption is used for
+                // transactions in which no output has been seen before.
+                let decrypted = if cache_keys.iter().any(|k| cache.tried_keys(k).is_some()) {
+                    decoded
+                        .iter()
+                        .zip(&cache_keys)
+                        .map(|((domain, output), cache_key)| {
+                            let tried = cache.tried_keys(cache_key);
+                            ivk_lookup
+                                .iter()
+                                .zip(ivks)
+                                .enumerate()
+                                .filter(|(_, (tag, _))| tried.map_or(true, |t| !t.contains(*tag)))
+                                .find_map(|(ivk_idx, (_, ivk))| {
+                                    zcash_note_encryption::try_compact_note_decryption(
+                                        domain, ivk, output,
+                                    )
+                                    .map(|(note, _)| (note, ivk_idx))
+                                })
+                        })
+                        .collect::<Vec<_>>()
+                } else {
+                    batch_decrypt()
+                };
+
+                // A failure can only be attributed to the whole generation of keys if none of
+                // them were excluded at this height.
+                for (cache_key, d) in cache_keys.into_iter().zip(&decrypted) {
+                    if d.is_some() {
+                        cache.undecryptable.remove(&cache_key);
+                    } else if all_keys_valid {
+                        cache.undecryptable.insert(cache_key, generation);
+                    }
+                }
+
+                decrypted
+            }
+            None => batch_decrypt(),
+        };
+
+        let mut decrypted_len = 0;
+        (
+            decrypted
+                .into_iter()
+                .map(|v| {
+                    v.map(|(note, ivk_idx)| {
+                        decrypted_len += 1;
+                        (ivk_lookup[ivk_idx].clone(), note)
+                    })
+                })
+                .collect::<Vec<_>>(),
+            decrypted_len,
+        )
+    };
+
+    let mut shielded_outputs = Vec::with_capacity(decrypted_len);
+    let mut note_commitments = if config.track_commitments {
+        Vec::with_capacity(decoded.len())
+    } else {
+        vec![]
+    };
+    // The outputs of the transaction occupy consecutive positions in the note commitment tree,
+    // beginning at the size of the tree prior to the transaction.
+    let mut note_commitment_tree_position = Position::from(commitment_tree_size);
+    for (output_idx, ((_, output), decrypted_note)) in
+        decoded.iter().zip(decrypted_opts).enumerate()
+    {
+        // Collect block note commitments
+        if config.track_commitments {
+            let node = extract_note_commitment(output);
+            // The checkpoint for the block is added once all of the block's transactions have
+            // been scanned; see `checkpoint_last_commitment`.
+            let retention = if decrypted_note.is_some() {
+                Retention::Marked
+            } else {
+                Retention::Ephemeral
+            };
+            note_commitments.push((node, retention));
+        }
+
+        if let Some((key_id, note)) = decrypted_note {
+            let key = keys.get(&key_id).ok_or(ScanError::UnknownIvkTag {
+                protocol,
+                at_height: block_height,
+                txid,
+                output_index: output_idx,
+            })?;
+
+            // A note is marked as "change" if the account that received it
+            // also spent notes in the same transaction. This will catch,
+            // for instance:
+            // - Change created by spending fractions of notes.
+            // - Notes created by consolidation transactions.
+            // - Notes sent from one account to itself.
+            let is_change = spent_from_accounts.contains(key.account_id());
+            let nf = key.nf(&note, note_commitment_tree_position);
+            let below_dust_threshold = note_value(&note) < u64::from(config.dust_threshold);
+
+            shielded_outputs.push(WalletOutput::from_parts(
+                output_idx,
+                output.ephemeral_key(),
+                note,
+                is_change,
+                below_dust_threshold,
+                note_commitment_tree_position,
+                nf,
+                *key.account_id(),
+                key.key_scope(),
+            ));
+        }
+
+        note_commitment_tree_position = note_commitment_tree_position + 1;
+    }
+
+    Ok((shielded_outputs, note_commitments))
+}
+
+/// Checks the invariants of the note commitment tree positions assigned while scanning a block
+/// in a single shielded pool.
+///
+/// The block's note commitments (if they were tracked) must occupy a contiguous run of positions
+/// beginning at `start_size`, the size of the tree as of the end of the prior block, and ending
+/// at `end_size`. The positions of the received notes, given in the order in which they were
+/// detected, must be strictly increasing, must lie within that run, and must be exactly the
+/// positions of the marked commitments.
+fn positions_are_contiguous<NoteCommitment>(
+    start_size: u64,
+    end_size: u64,
+    commitments: Option<&[(NoteCommitment, Retention<BlockHeight>)]>,
+    received_positions: impl Iterator<Item = Position>,
+) -> bool {
+    let start = Position::from(start_size);
+    let end = Position::from(end_size);
+    let received_positions = received_positions.collect::<Vec<_>>();
+
+    let in_range = received_positions
+        .iter()
+        .all(|pos| start <= *pos && *pos < end);
+    let increasing = received_positions.windows(2).all(|w| w[0] < w[1]);
+    let commitments_consistent = commitments.map_or(true, |commitments| {
+        start_size + commitments.len() as u64 == end_size
+            && commitments
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, retention))| retention.is_marked())
+                .map(|(i, _)| Position::from(start_size + i as u64))
+                .eq(received_positions.iter().copied())
+    });
+
+    start <= end && in_range && increasing && commitments_consistent
+}
+
+/// Makes the last note commitment of a block the checkpoint for that block, preserving whether
+/// it is marked.
+///
+/// Each pool is checkpointed independently: the last commitment in a pool need not belong to the
+/// block's last transaction. If the block contains no commitments in the pool, no checkpoint is
+/// added.
+fn checkpoint_last_commitment<NoteCommitment>(
+    commitments: &mut [(NoteCommitment, Retention<BlockHeight>)],
+    block_height: BlockHeight,
+) {
+    if let Some((_, retention)) = commitments.last_mut() {
+        *retention = Retention::Checkpoint {
+            id: block_height,
+            is_marked: retention.is_marked(),
+        };
+    }
+}
+
+/// Returns the transparent outputs of a transaction that pay to any of the given addresses.
+#[cfg(feature = "transparent-inputs")]
+fn find_received_transparent(
+    block_height: BlockHeight,
+    txid: TxId,
+    vout: &[compact_formats::TxOut],
+    addresses: &HashSet<TransparentAddress>,
+) -> Result<Vec<WalletTransparentOutput>, ScanError> {
+    if addresses.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut received = vec![];
+    for (index, output) in vout.iter().enumerate() {
+        let invalid = || ScanError::TransparentOutputInvalid {
+            at_height: block_height,
+            txid,
+            index,
+        };
+        let txout = TxOut {
+            value: NonNegativeAmount::from_u64(output.value).map_err(|_| invalid())?,
+            script_pubkey: Script(output.script_pub_key.clone()),
+        };
+
+        if txout
+            .recipient_address()
+            .map_or(false, |addr| addresses.contains(&addr))
+        {
+            let outpoint =
+                OutPoint::new(*txid.as_ref(), u32::try_from(index).map_err(|_| invalid())?);
+            received.extend(WalletTransparentOutput::from_parts(
+                outpoint,
+                txout,
+                block_height,
+            ));
+        }
+    }
+
+    Ok(received)
+}
+
+/// Utilities for testing the results of scanning.
+#[cfg(any(test, feature = "test-dependencies"))]
+pub mod testing {
+    use std::fmt::Debug;
+
+    use incrementalmerkletree::Position;
+    use zcash_primitives::transaction::{components::amount::NonNegativeAmount, TxId};
+
+    use crate::{
+        data_api::{ScannedBlock, ScannedBundles},
+        wallet::{WalletOutput, WalletSpend, WalletTx},
+    };
+
+    #[cfg(feature = "transparent-inputs")]
+    use zcash_primitives::{
+        consensus::BlockHeight,
+        transaction::components::{OutPoint, TxOut},
+    };
+
+    /// The comparable contents of a [`WalletSpend`].
+    type SpendSummary<'a, Nf, A> = (usize, &'a Nf, &'a A);
+
+    /// The comparable contents of a [`WalletOutput`], with the ephemeral key and note
+    /// represented by their encodings.
+    type OutputSummary<'a, Nf, A> = (
+        usize,
+        [u8; 32],
+        [u8; 32],
+        bool,
+        bool,
+        Position,
+        Option<&'a Nf>,
+        &'a A,
+        Option<zip32::Scope>,
+    );
+
+    #[derive(Debug, PartialEq)]
+    struct TxSummary<'a, A> {
+        txid: TxId,
+        block_index: usize,
+        sapling_spends: Vec<SpendSummary<'a, sapling::Nullifier, A>>,
+        sapling_outputs: Vec<OutputSummary<'a, sapling::Nullifier, A>>,
+        #[cfg(feature = "orchard")]
+        orchard_spends: Vec<SpendSummary<'a, orchard::note::Nullifier, A>>,
+        #[cfg(feature = "orchard")]
+        orchard_outputs: Vec<OutputSummary<'a, orchard::note::Nullifier, A>>,
+        #[cfg(feature = "transparent-inputs")]
+        transparent_outputs: Vec<(&'a OutPoint, &'a TxOut, BlockHeight)>,
+        computed_fee: Option<NonNegativeAmount>,
+        logical_actions: usize,
+        shielded_output_count: usize,
+    }
+
+    fn summarize_spends<Nf, A>(spends: &[WalletSpend<Nf, A>]) -> Vec<SpendSummary<'_, Nf, A>> {
+        let mut summary = spends
+            .iter()
+            .map(|spend| (spend.index(), spend.nf(), spend.account_id()))
+            .collect::<Vec<_>>();
+        summary.sort_by_key(|(index, _, _)| *index);
+        summary
+    }
+
+    fn summarize_outputs<N, Nf, A>(
+        outputs: &[WalletOutput<N, Nf, A>],
+        note_commitment: impl Fn(&N) -> [u8; 32],
+    ) -> Vec<OutputSummary<'_, Nf, A>> {
+        let mut summary = outputs
+            .iter()
+            .map(|output| {
+                (
+                    output.index(),
+                    output.ephemeral_key().0,
+                    note_commitment(output.note()),
+                    output.is_change(),
+                    output.below_dust_threshold(),
+                    output.note_commitment_tree_position(),
+                    output.nf(),
+                    output.account_id(),
+                    output.recipient_key_scope(),
+                )
+            })
+            .collect::<Vec<_>>();
+        summary.sort_by_key(|output| output.0);
+        summary
+    }
+
+    fn summarize_tx<A>(tx: &WalletTx<A>) -> TxSummary<'_, A> {
+        TxSummary {
+            txid: tx.txid(),
+            block_index: tx.block_index(),
+            sapling_spends: summarize_spends(tx.sapling_spends()),
+            sapling_outputs: summarize_outputs(tx.sapling_outputs(), |note| note.cmu().to_bytes()),
+            #[cfg(feature = "orchard")]
+            orchard_spends: summarize_spends(tx.orchard_spends()),
+            #[cfg(feature = "orchard")]
+            orchard_outputs: summarize_outputs(tx.orchard_outputs(), |note| {
+                orchard::note::ExtractedNoteCommitment::from(note.commitment()).to_bytes()
+            }),
+            #[cfg(feature = "transparent-inputs")]
+            transparent_outputs: {
+                let mut outputs = tx
+                    .transparent_outputs()
+                    .iter()
+                    .map(|output| (output.outpoint(), output.txout(), output.height()))
+                    .collect::<Vec<_>>();
+                outputs.sort_by_key(|(outpoint, _, _)| outpoint.n());
+                outputs
+            },
+            computed_fee: tx.computed_fee(),
+            logical_actions: tx.logical_actions(),
+            shielded_output_count: tx.shielded_output_count(),
+        }
+    }
+
+    fn summarize_txs<A>(block: &ScannedBlock<A>) -> Vec<TxSummary<'_, A>> {
+        let mut txs = block
+            .transactions()
+            .iter()
+            .map(summarize_tx)
+            .collect::<Vec<_>>();
+        txs.sort_by_key(|tx| tx.block_index);
+        txs
+    }
+
+    fn assert_bundles_equivalent<H: Debug + PartialEq, Nf: Debug + PartialEq>(
+        a: &ScannedBundles<H, Nf>,
+        b: &ScannedBundles<H, Nf>,
+    ) {
+        #[allow(clippy::type_complexity)]
+        fn sorted_nullifier_map<H, Nf>(
+            bundles: &ScannedBundles<H, Nf>,
+        ) -> Vec<(u16, TxId, Vec<(usize, &Nf)>)> {
+            let mut map = bundles
+                .nullifier_map()
+                .iter()
+                .map(|(txid, tx_index, nullifiers)| {
+                    let mut nullifiers = nullifiers
+                        .iter()
+                        .map(|(index, nf)| (*index, nf))
+                        .collect::<Vec<_>>();
+                    nullifiers.sort_by_key(|(index, _)| *index);
+                    (*tx_index, *txid, nullifiers)
+                })
+                .collect::<Vec<_>>();
+            map.sort_by_key(|(tx_index, _, _)| *tx_index);
+            map
+        }
+
+        assert_eq!(a.final_tree_size(), b.final_tree_size());
+        // The order of the note commitments is significant, because it determines their
+        // positions in the note commitment tree.
+        assert_eq!(a.commitments(), b.commitments());
+        assert_eq!(sorted_nullifier_map(a), sorted_nullifier_map(b));
+    }
+
+    /// Asserts that two scans of the same block produced equivalent results.
+    ///
+    /// The blocks' metadata, their note commitments (together with their retentions and
+    /// tree positions), and their nullifier maps are compared, as are the contents of the
+    /// [`WalletTx`]s that were detected: their spends, their outputs (including note
+    /// commitment tree positions and nullifiers), and their transparent outputs. The order in
+    /// which transactions, spends, outputs, and nullifiers were recorded is not significant.
+    ///
+    /// This can be used, for example, to check that scanning a block with and without
+    /// multithreaded trial decryption produces the same result.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the scans differ.
+    pub fn assert_scan_equivalent<A: Debug + PartialEq>(a: &ScannedBlock<A>, b: &ScannedBlock<A>) {
+        assert_eq!(a.height(), b.height());
+        assert_eq!(a.block_hash(), b.block_hash());
+        assert_eq!(a.block_time(), b.block_time());
+        assert_eq!(a.zip212_enforcement(), b.zip212_enforcement());
+
+        assert_bundles_equivalent(a.sapling(), b.sapling());
+        #[cfg(feature = "orchard")]
+        assert_bundles_equivalent(a.orchard(), b.orchard());
+
+        assert_eq!(summarize_txs(a), summarize_txs(b));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{
+        collections::{HashMap, HashSet},
+        convert::Infallible,
+        ops::Range,
+    };
+
+    use assert_matches::assert_matches;
+    use group::{
+        ff::{Field, PrimeField},
+        GroupEncoding,
+    };
+    use incrementalmerkletree::{Position, Retention};
+    use rand_core::{OsRng, RngCore, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use sapling::{
+        constants::SPENDING_KEY_GENERATOR,
+        note_encryption::{sapling_note_encryption, SaplingDomain, Zip212Enforcement},
+        util::generate_random_rseed,
+        value::NoteValue,
+        zip32::DiversifiableFullViewingKey,
+        Nullifier,
+    };
+    use zcash_keys::keys::{UnifiedFullViewingKey, UnifiedSpendingKey};
+    use zcash_note_encryption::{Domain, COMPACT_NOTE_SIZE};
+    use zcash_primitives::{
+        block::BlockHash,
+        consensus::{
+            BlockHeight, Network, NetworkType, NetworkUpgrade, Parameters, ZIP212_GRACE_PERIOD,
+        },
+        memo::MemoBytes,
+        transaction::{
+            components::{amount::NonNegativeAmount, sapling::zip212_enforcement},
+            TxId,
+        },
+        zip32::AccountId,
+    };
+
+    use crate::{
+        data_api::{
+            apply_and_root, apply_to_frontier, diff_scanned, BlockMetadata, FrontierError,
+            ScannedBlock, ScannedBundles,
+        },
+        proto::compact_formats::{
+            self as compact, CompactBlock, CompactSaplingOutput, CompactSaplingSpend, CompactTx,
+        },
+        scanning::{testing::assert_scan_equivalent, BatchRunners, ScanningKeys},
+        wallet::WalletOutput,
+        ShieldedProtocol,
+    };
+
+    #[cfg(feature = "orchard")]
+    use super::extract_orchard_nullifier_map;
+    use super::{
+        block_tree_growth, detect_reorg, extract_nullifier_map, grow_tree_size, narrow_tree_size,
+        scan_block, scan_block_detailed, scan_block_explain, scan_block_spends_by_account,
+        scan_block_spends_only, scan_block_with_config, scan_block_with_runners,
+        scan_block_with_shared_secrets, scan_blocks, scan_mempool_tx, validate_chain, BlockScanner,
+        CommitmentHasher, DecryptionCache, HeightBoundedKey, NullifierBloom, Nullifiers,
+        SaplingCommitmentHasher, SaplingOutputDisclosure, ScanConfig, ScanError, ScanOptions,
+        ScanningKeyOps,
+    };
+
+    fn random_compact_tx(mut rng: impl RngCore) -> CompactTx {
+        let fake_nf = {
+            let mut nf = vec![0; 32];
+            rng.fill_bytes(&mut nf);
+            nf
+        };
+        let fake_cmu = {
+            let fake_cmu = bls12_381::Scalar::random(&mut rng);
+            fake_cmu.to_repr().as_ref().to_owned()
+        };
+        let fake_epk = {
+            let mut buffer = [0; 64];
+            rng.fill_bytes(&mut buffer);
+            let fake_esk = jubjub::Fr::from_bytes_wide(&buffer);
+            let fake_epk = SPENDING_KEY_GENERATOR * fake_esk;
+            fake_epk.to_bytes().to_vec()
+        };
+        let cspend = CompactSaplingSpend { nf: fake_nf };
+        let cout = CompactSaplingOutput {
+            cmu: fake_cmu,
+            ephemeral_key: fake_epk,
+            ciphertext: vec![0; COMPACT_NOTE_SIZE],
+        };
+        let mut ctx = CompactTx::default();
+        let mut txid = vec![0; 32];
+        rng.fill_bytes(&mut txid);
+        ctx.hash = txid;
+        ctx.spends.push(cspend);
+        ctx.outputs.push(cout);
+        ctx
+    }
+
+    /// Creates a fake `CompactSaplingOutput` at the given height, paying the given value to
+    /// the default address of `dfvk`, using `rng` to generate the note's randomness.
+    fn fake_compact_output(
+        mut rng: impl RngCore,
+        height: BlockHeight,
+        dfvk: &DiversifiableFullViewingKey,
+        value: NonNegativeAmount,
+    ) -> CompactSaplingOutput {
+        let zip212_enforcement = zip212_enforcement(&Network::TestNetwork, height);
+        let to = dfvk.default_address().1;
+
+        // Create a fake Note for the account
+        let rseed = generate_random_rseed(zip212_enforcement, &mut rng);
+        let note = sapling::Note::from_parts(to, NoteValue::from_raw(value.into()), rseed);
+        let encryptor = sapling_note_encryption(
+            Some(dfvk.fvk().ovk),
+            note.clone(),
+            *MemoBytes::empty().as_array(),
+            &mut rng,
+        );
+        let cmu = note.cmu().to_bytes().to_vec();
+        let ephemeral_key = SaplingDomain::epk_bytes(encryptor.epk()).0.to_vec();
+        let enc_ciphertext = encryptor.encrypt_note_plaintext();
+
+        CompactSaplingOutput {
+            cmu,
+            ephemeral_key,
+            ciphertext: enc_ciphertext.as_ref()[..52].to_vec(),
+        }
+    }
+
+    /// Create a fake CompactBlock at the given height, with a transaction containing a
+    /// single spend of the given nullifier and a single output paying the given address.
+    /// Returns the CompactBlock.
+    ///
+    /// All of the block's random data is drawn from `rng`, so that a block can be reproduced
+    /// by supplying a deterministically seeded RNG.
+    ///
+    /// Set `initial_tree_sizes` to `None` to simulate a `CompactBlock` retrieved
+    /// from a `lightwalletd` that is not currently tracking note commitment tree sizes.
+    fn fake_compact_block(
+        mut rng: impl RngCore,
+        height: BlockHeight,
+        prev_hash: BlockHash,
+        nf: Nullifier,
+        dfvk: &DiversifiableFullViewingKey,
+        value: NonNegativeAmount,
+        tx_after: bool,
+        initial_tree_sizes: Option<(u32, u32)>,
+    ) -> CompactBlock {
         // Create a fake CompactBlock containing the note
         let mut cb = CompactBlock {
             hash: {
@@ -1235,267 +4791,4143 @@ mod tests {
                 rng.fill_bytes(&mut hash);
                 hash
             },
-            prev_hash: prev_hash.0.to_vec(),
+            prev_hash: prev_hash.0.to_vec(),
+            height: height.into(),
+            ..Default::default()
+        };
+
+        // Add a random Sapling tx before ours
+        {
+            let mut tx = random_compact_tx(&mut rng);
+            tx.index = cb.vtx.len() as u64;
+            cb.vtx.push(tx);
+        }
+
+        let cspend = CompactSaplingSpend { nf: nf.0.to_vec() };
+        let cout = fake_compact_output(&mut rng, height, dfvk, value);
+        let mut ctx = CompactTx::default();
+        let mut txid = vec![0; 32];
+        rng.fill_bytes(&mut txid);
+        ctx.hash = txid;
+        ctx.spends.push(cspend);
+        ctx.outputs.push(cout);
+        ctx.index = cb.vtx.len() as u64;
+        cb.vtx.push(ctx);
+
+        // Optionally add another random Sapling tx after ours
+        if tx_after {
+            let mut tx = random_compact_tx(&mut rng);
+            tx.index = cb.vtx.len() as u64;
+            cb.vtx.push(tx);
+        }
+
+        cb.chain_metadata =
+            initial_tree_sizes.map(|(initial_sapling_tree_size, initial_orchard_tree_size)| {
+                compact::ChainMetadata {
+                    sapling_commitment_tree_size: initial_sapling_tree_size
+                        + cb.vtx.iter().map(|tx| tx.outputs.len() as u32).sum::<u32>(),
+                    orchard_commitment_tree_size: initial_orchard_tree_size
+                        + cb.vtx.iter().map(|tx| tx.actions.len() as u32).sum::<u32>(),
+                }
+            });
+
+        cb
+    }
+
+    /// Asserts that the note commitments of each pool in the scanned block occupy a contiguous
+    /// run of tree positions ending at the pool's final tree size, and that the positions of
+    /// the received notes are exactly the positions of the marked commitments.
+    fn assert_contiguous_positions<A>(scanned_block: &ScannedBlock<A>) {
+        fn check<N, Nf>(bundles: &ScannedBundles<N, Nf>, received: Vec<Position>) {
+            let start = u64::from(bundles.final_tree_size())
+                .checked_sub(bundles.commitments().len() as u64)
+                .expect("commitments do not exceed the final tree size");
+            assert!(received.iter().all(|pos| u64::from(*pos) >= start
+                && u64::from(*pos) < u64::from(bundles.final_tree_size())));
+            assert!(received.windows(2).all(|w| w[0] < w[1]));
+            assert_eq!(bundles.marked_positions(), received);
+        }
+
+        check(
+            scanned_block.sapling(),
+            scanned_block
+                .transactions()
+                .iter()
+                .flat_map(|tx| tx.sapling_outputs())
+                .map(|output| output.note_commitment_tree_position())
+                .collect(),
+        );
+        #[cfg(feature = "orchard")]
+        check(
+            scanned_block.orchard(),
+            scanned_block
+                .transactions()
+                .iter()
+                .flat_map(|tx| tx.orchard_outputs())
+                .map(|output| output.note_commitment_tree_position())
+                .collect(),
+        );
+    }
+
+    /// The keys of a test account.
+    struct TestAccount {
+        ufvk: UnifiedFullViewingKey,
+        sapling_dfvk: DiversifiableFullViewingKey,
+        scanning_keys: ScanningKeys<AccountId, (AccountId, zip32::Scope)>,
+    }
+
+    /// Derives the keys of the given account from the all-zeroes seed, along with the
+    /// [`ScanningKeys`] for its UFVK.
+    fn test_account(network: &Network, account: AccountId) -> TestAccount {
+        let usk = UnifiedSpendingKey::from_seed(network, &[0u8; 32], account).expect("Valid USK");
+        let ufvk = usk.to_unified_full_viewing_key();
+        let sapling_dfvk = ufvk.sapling().expect("Sapling key is present").clone();
+        let scanning_keys = ScanningKeys::from_account_ufvks([(account, ufvk.clone())]);
+        TestAccount {
+            ufvk,
+            sapling_dfvk,
+            scanning_keys,
+        }
+    }
+
+    #[test]
+    fn fake_compact_block_is_reproducible_from_seed() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+
+        let block = |seed| {
+            fake_compact_block(
+                XorShiftRng::seed_from_u64(seed),
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                true,
+                Some((0, 0)),
+            )
+        };
+
+        assert_eq!(block(1), block(1));
+        assert_ne!(block(1), block(2));
+    }
+
+    #[test]
+    fn scan_block_with_my_tx() {
+        fn go(scan_multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount {
+                sapling_dfvk,
+                scanning_keys,
+                ..
+            } = test_account(&network, account);
+
+            let cb = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                None,
+            );
+            assert_eq!(cb.vtx.len(), 2);
+
+            let mut batch_runners = if scan_multithreaded {
+                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
+                runners.add_block(&Network::TestNetwork, &cb).unwrap();
+                runners.flush();
+
+                Some(runners)
+            } else {
+                None
+            };
+
+            let scanned_block = scan_block_with_runners(
+                &network,
+                cb,
+                &scanning_keys,
+                &Nullifiers::empty(),
+                Some(&BlockMetadata::from_parts(
+                    BlockHeight::from(0),
+                    BlockHash([0u8; 32]),
+                    Some(0),
+                    #[cfg(feature = "orchard")]
+                    Some(0),
+                )),
+                ScanConfig::default(),
+                batch_runners.as_mut(),
+            )
+            .unwrap();
+            let txs = scanned_block.transactions();
+            assert_eq!(txs.len(), 1);
+            assert_eq!(scanned_block.matched_txids(), vec![txs[0].txid()]);
+
+            let tx = &txs[0];
+            assert_eq!(tx.block_index(), 1);
+            assert_eq!(tx.sapling_spends().len(), 0);
+            assert_eq!(tx.sapling_outputs().len(), 1);
+            assert_eq!(tx.sapling_outputs()[0].index(), 0);
+            assert_eq!(tx.sapling_outputs()[0].account_id(), &account);
+            assert_eq!(tx.sapling_outputs()[0].note().value().inner(), 5);
+            assert_eq!(
+                tx.sapling_outputs()[0].recipient(),
+                sapling_dfvk.default_address().1
+            );
+            // The transaction has one Sapling spend and one Sapling output.
+            assert_eq!(tx.logical_actions(), 1);
+            // Its only shielded output was decrypted by the wallet.
+            assert_eq!(tx.shielded_output_count(), 1);
+            assert!(tx.is_fully_decrypted());
+            // The 5 zatoshi note is below the default dust threshold.
+            assert!(tx.sapling_outputs()[0].below_dust_threshold());
+            assert!(tx.sapling_outputs()[0].is_dust(NonNegativeAmount::const_from_u64(6)));
+            assert!(!tx.sapling_outputs()[0].is_dust(NonNegativeAmount::const_from_u64(5)));
+            assert_eq!(
+                tx.sapling_outputs()[0].note_commitment_tree_position(),
+                Position::from(1)
+            );
+
+            let received = scanned_block.received_by_account().unwrap();
+            assert_eq!(received.len(), 1);
+            assert_eq!(
+                received.get(&account),
+                Some(&NonNegativeAmount::const_from_u64(5))
+            );
+
+            assert_eq!(scanned_block.sapling().final_tree_size(), 2);
+            assert_eq!(
+                scanned_block.sapling().position_range(),
+                Some(Position::from(0)..Position::from(2))
+            );
+            assert_eq!(
+                scanned_block
+                    .sapling()
+                    .commitments()
+                    .iter()
+                    .map(|(_, retention)| *retention)
+                    .collect::<Vec<_>>(),
+                vec![
+                    Retention::Ephemeral,
+                    Retention::Checkpoint {
+                        id: scanned_block.height(),
+                        is_marked: true
+                    }
+                ]
+            );
+
+            assert!(!scanned_block.sapling().is_empty());
+            assert_contiguous_positions(&scanned_block);
+            // The block contains no Orchard actions, so its Orchard bundles are empty.
+            #[cfg(feature = "orchard")]
+            assert!(scanned_block.orchard().is_empty());
+            assert!(ScannedBundles::<sapling::Node, Nullifier>::default().is_empty());
+
+            let metadata = BlockMetadata::from_scanned_block(&scanned_block);
+            assert_eq!(metadata.block_height(), scanned_block.height());
+            assert_eq!(metadata.block_hash(), scanned_block.block_hash());
+            assert_eq!(metadata.sapling_tree_size(), Some(2));
+            #[cfg(feature = "orchard")]
+            assert_eq!(metadata.orchard_tree_size(), Some(0));
+        }
+
+        go(false);
+        go(true);
+    }
+
+    #[test]
+    fn scan_block_with_runners_is_equivalent() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+
+        let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
+        runners.add_block(&network, &cb).unwrap();
+        runners.flush();
+
+        let scan = |batch_runners| {
+            scan_block_with_runners(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &nullifiers,
+                None,
+                ScanConfig::default(),
+                batch_runners,
+            )
+            .unwrap()
+        };
+        let single_threaded = scan(None);
+        let multithreaded = scan(Some(&mut runners));
+
+        assert_eq!(single_threaded.transactions().len(), 1);
+        assert_scan_equivalent(&single_threaded, &multithreaded);
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn feed_blocks_scans_stream() {
+        use futures_util::{stream, StreamExt};
+
+        use super::feed_blocks;
+
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            ufvk, sapling_dfvk, ..
+        } = test_account(&network, account);
+
+        let cb1 = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let cb2 = fake_compact_block(
+            OsRng,
+            2u32.into(),
+            cb1.hash(),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((2, 0)),
+        );
+        // A block that does not connect to the previous one.
+        let disconnected = fake_compact_block(
+            OsRng,
+            3u32.into(),
+            BlockHash([1; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((4, 0)),
+        );
+
+        let make_scanner = move || {
+            BlockScanner::new(
+                network,
+                ScanningKeys::from_account_ufvks([(account, ufvk)]),
+                Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                true,
+            )
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let results = runtime.block_on(
+            feed_blocks(
+                make_scanner,
+                stream::iter(vec![cb1.clone(), cb2.clone(), disconnected, cb2]),
+            )
+            .collect::<Vec<_>>(),
+        );
+
+        // The stream ends after the first error.
+        assert_eq!(results.len(), 3);
+        let scanned = results[..2]
+            .iter()
+            .map(|result| result.as_ref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(scanned[0].height(), BlockHeight::from(1));
+        assert_eq!(scanned[0].block_hash(), cb1.hash());
+        assert_eq!(scanned[1].height(), BlockHeight::from(2));
+        assert!(scanned.iter().all(|block| block.transactions().len() == 1));
+        assert_matches!(results[2], Err(ScanError::PrevHashMismatch { .. }));
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn feed_blocks_reads_ahead() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        use futures_util::{stream, StreamExt};
+
+        use super::feed_blocks;
+
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            ufvk, sapling_dfvk, ..
+        } = test_account(&network, account);
+
+        let mut blocks = vec![];
+        let mut prev_hash = BlockHash([0; 32]);
+        for height in 1..=4u32 {
+            let cb = fake_compact_block(
+                OsRng,
+                height.into(),
+                prev_hash,
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some(((height - 1) * 2, 0)),
+            );
+            prev_hash = cb.hash();
+            blocks.push(cb);
+        }
+
+        let make_scanner = move || {
+            BlockScanner::new(
+                network,
+                ScanningKeys::from_account_ufvks([(account, ufvk)]),
+                Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                false,
+            )
+        };
+
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let blocks = stream::iter(blocks).inspect({
+            let pulled = pulled.clone();
+            move |_| {
+                pulled.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut results = Box::pin(feed_blocks(make_scanner, blocks));
+
+            // While waiting for the first block to be scanned, the remaining blocks are read
+            // from the input stream without waiting for their results to be consumed.
+            let first = results.next().await.unwrap().unwrap();
+            assert_eq!(first.height(), BlockHeight::from(1));
+            assert_eq!(pulled.load(Ordering::SeqCst), 4);
+
+            let rest = results.collect::<Vec<_>>().await;
+            assert_eq!(rest.len(), 3);
+            assert!(rest.iter().all(|result| result.is_ok()));
+        });
+    }
+
+    #[test]
+    fn scan_block_with_txs_after_my_tx() {
+        fn go(scan_multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount {
+                sapling_dfvk,
+                scanning_keys,
+                ..
+            } = test_account(&network, account);
+
+            let cb = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                true,
+                Some((0, 0)),
+            );
+            assert_eq!(cb.vtx.len(), 3);
+
+            let mut batch_runners = if scan_multithreaded {
+                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
+                runners.add_block(&Network::TestNetwork, &cb).unwrap();
+                runners.flush();
+
+                Some(runners)
+            } else {
+                None
+            };
+
+            let scanned_block = scan_block_with_runners(
+                &network,
+                cb,
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                batch_runners.as_mut(),
+            )
+            .unwrap();
+            let txs = scanned_block.transactions();
+            assert_eq!(txs.len(), 1);
+
+            let tx = &txs[0];
+            assert_eq!(tx.block_index(), 1);
+            assert_eq!(tx.sapling_spends().len(), 0);
+            assert_eq!(tx.sapling_outputs().len(), 1);
+            assert_eq!(tx.sapling_outputs()[0].index(), 0);
+            assert_eq!(tx.sapling_outputs()[0].account_id(), &AccountId::ZERO);
+            assert_eq!(tx.sapling_outputs()[0].note().value().inner(), 5);
+
+            assert_eq!(
+                scanned_block
+                    .sapling()
+                    .commitments()
+                    .iter()
+                    .map(|(_, retention)| *retention)
+                    .collect::<Vec<_>>(),
+                vec![
+                    Retention::Ephemeral,
+                    Retention::Marked,
+                    Retention::Checkpoint {
+                        id: scanned_block.height(),
+                        is_marked: false
+                    }
+                ]
+            );
+            assert_eq!(
+                scanned_block.sapling().marked_positions(),
+                vec![Position::from(1)]
+            );
+            // Block 1 precedes Canopy activation, so ZIP 212 is not enforced.
+            assert_eq!(scanned_block.zip212_enforcement(), Zip212Enforcement::Off);
+        }
+
+        go(false);
+        go(true);
+    }
+
+    #[test]
+    fn scan_block_with_runner_key_drift() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys: runner_keys,
+            ..
+        } = test_account(&network, account);
+
+        let other_account = AccountId::try_from(1).unwrap();
+        let other_usk =
+            UnifiedSpendingKey::from_seed(&network, &[0u8; 32], other_account).expect("Valid USK");
+        let scanning_keys = ScanningKeys::from_account_ufvks([(
+            other_account,
+            other_usk.to_unified_full_viewing_key(),
+        )]);
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        // The batch runner is primed with a key that the scanner does not know about.
+        let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &runner_keys);
+        runners.add_block(&network, &cb).unwrap();
+        runners.flush();
+
+        assert_matches!(
+            scan_block_with_runners(
+                &network,
+                cb,
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                Some(&mut runners),
+            )
+            .err(),
+            Some(ScanError::UnknownIvkTag {
+                protocol: ShieldedProtocol::Sapling,
+                output_index: 0,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn scan_block_respects_key_valid_heights() {
+        fn go(scan_multithreaded: bool, valid_heights: Range<BlockHeight>, expected_txs: usize) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+
+            let mut sapling_keys: HashMap<
+                u32,
+                Box<dyn ScanningKeyOps<SaplingDomain, AccountId, Nullifier>>,
+            > = HashMap::new();
+            sapling_keys.insert(
+                0,
+                Box::new(HeightBoundedKey::new(
+                    (account, sapling_dfvk.to_ivk(zip32::Scope::External)),
+                    valid_heights,
+                )),
+            );
+            let scanning_keys = ScanningKeys::new(
+                sapling_keys,
+                #[cfg(feature = "orchard")]
+                HashMap::new(),
+            );
+
+            let cb = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+
+            let mut batch_runners = if scan_multithreaded {
+                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
+                runners.add_block(&network, &cb).unwrap();
+                runners.flush();
+
+                Some(runners)
+            } else {
+                None
+            };
+
+            let scanned_block = scan_block_with_runners(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                batch_runners.as_mut(),
+            )
+            .unwrap();
+            assert_eq!(scanned_block.transactions().len(), expected_txs);
+            // The note commitment tree is updated regardless of whether the key was used.
+            assert_eq!(scanned_block.sapling().final_tree_size(), 2);
+
+            // Keys that were prepared in advance are also restricted to their valid heights.
+            let scanned_block = scan_block_with_config(
+                &network,
+                cb,
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanOptions::new(ScanConfig::default())
+                    .with_prepared_ivks(&scanning_keys.to_prepared_ivks()),
+            )
+            .unwrap();
+            assert_eq!(scanned_block.transactions().len(), expected_txs);
+        }
+
+        for scan_multithreaded in [false, true] {
+            // The key is in use at the height of the block.
+            go(
+                scan_multithreaded,
+                BlockHeight::from(0)..BlockHeight::from(2),
+                1,
+            );
+            // The key was rotated into use after the block.
+            go(
+                scan_multithreaded,
+                BlockHeight::from(2)..BlockHeight::from(10),
+                0,
+            );
+        }
+    }
+
+    #[test]
+    fn scan_block_with_non_copy_ivk_tags() {
+        fn go(scan_multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+
+            // A tag that identifies the wallet as well as the scope of the key.
+            let tag = (String::from("wallet-0"), zip32::Scope::External);
+            let mut sapling_keys: HashMap<
+                (String, zip32::Scope),
+                Box<dyn ScanningKeyOps<SaplingDomain, AccountId, Nullifier>>,
+            > = HashMap::new();
+            sapling_keys.insert(
+                tag.clone(),
+                Box::new((account, sapling_dfvk.to_ivk(zip32::Scope::External))),
+            );
+            let scanning_keys = ScanningKeys::new(
+                sapling_keys,
+                #[cfg(feature = "orchard")]
+                HashMap::new(),
+            );
+
+            let cb = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+
+            let mut batch_runners = if scan_multithreaded {
+                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
+                runners.add_block(&network, &cb).unwrap();
+                runners.flush();
+
+                Some(runners)
+            } else {
+                None
+            };
+
+            let scanned_block = scan_block_with_runners(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanOptions::new(ScanConfig::default())
+                    .with_prepared_ivks(&scanning_keys.to_prepared_ivks()),
+                batch_runners.as_mut(),
+            )
+            .unwrap();
+            assert_eq!(scanned_block.transactions().len(), 1);
+
+            let diagnostics = scan_block_explain(&network, &cb, &scanning_keys);
+            assert_eq!(diagnostics[1].decrypted_by(), Some(&tag));
+        }
+
+        go(false);
+        go(true);
+    }
+
+    /// Constructs a compact Orchard action paying a note of the given value to the default
+    /// address of the given key, using `rng` to generate the note's randomness.
+    #[cfg(feature = "orchard")]
+    fn fake_compact_action(
+        mut rng: impl RngCore,
+        fvk: &orchard::keys::FullViewingKey,
+        scope: zip32::Scope,
+        value: u64,
+    ) -> compact::CompactOrchardAction {
+        let rho = orchard::note::Nullifier::from_bytes(&[0; 32]).unwrap();
+        let rseed = loop {
+            let mut bytes = [0; 32];
+            rng.fill_bytes(&mut bytes);
+            let rseed = orchard::note::RandomSeed::from_bytes(bytes, &rho);
+            if rseed.is_some().into() {
+                break rseed.unwrap();
+            }
+        };
+        let note = orchard::Note::from_parts(
+            fvk.address_at(0u32, scope),
+            orchard::value::NoteValue::from_raw(value),
+            rho,
+            rseed,
+        )
+        .unwrap();
+        let encryptor = orchard::note_encryption::OrchardNoteEncryption::new(
+            Some(fvk.to_ovk(scope)),
+            note,
+            *MemoBytes::empty().as_array(),
+        );
+        compact::CompactOrchardAction {
+            nullifier: rho.to_bytes().to_vec(),
+            cmx: orchard::note::ExtractedNoteCommitment::from(note.commitment())
+                .to_bytes()
+                .to_vec(),
+            ephemeral_key: orchard::note_encryption::OrchardDomain::epk_bytes(encryptor.epk())
+                .0
+                .to_vec(),
+            ciphertext: encryptor.encrypt_note_plaintext().as_ref()[..52].to_vec(),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "orchard")]
+    fn scan_block_cross_pool_change() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount { ufvk, .. } = test_account(&network, account);
+        let orchard_fvk = ufvk.orchard().expect("Orchard key is present").clone();
+
+        // The account spends a previously-received Sapling note.
+        let sapling_nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(vec![(account, sapling_nf)], vec![]);
+
+        // Construct an Orchard action paying change to the account's internal address.
+        let mut rng = OsRng;
+        let action = fake_compact_action(&mut rng, &orchard_fvk, zip32::Scope::Internal, 3);
+
+        let mut ctx = CompactTx::default();
+        let mut txid = vec![0; 32];
+        rng.fill_bytes(&mut txid);
+        ctx.hash = txid;
+        ctx.spends.push(CompactSaplingSpend {
+            nf: sapling_nf.0.to_vec(),
+        });
+        ctx.actions.push(action);
+
+        let cb = CompactBlock {
+            hash: vec![1; 32],
+            prev_hash: vec![0; 32],
+            height: 1,
+            vtx: vec![ctx],
+            chain_metadata: Some(compact::ChainMetadata {
+                sapling_commitment_tree_size: 0,
+                orchard_commitment_tree_size: 1,
+            }),
+            ..Default::default()
+        };
+
+        let scanned_block = scan_block_with_config(
+            &network,
+            cb,
+            &ScanningKeys::from_account_ufvks([(account, ufvk)]),
+            &nullifiers,
+            None,
+            ScanConfig::default(),
+        )
+        .unwrap();
+        let txs = scanned_block.transactions();
+        assert_eq!(txs.len(), 1);
+
+        let tx = &txs[0];
+        assert_eq!(tx.sapling_spends().len(), 1);
+        assert_eq!(tx.sapling_spends()[0].account_id(), &account);
+        assert_eq!(tx.sapling_outputs().len(), 0);
+        assert_eq!(tx.orchard_outputs().len(), 1);
+        assert_eq!(tx.logical_actions(), 2);
+        assert_eq!(tx.shielded_output_count(), 1);
+        assert!(tx.is_fully_decrypted());
+        assert_eq!(tx.orchard_outputs()[0].account_id(), &account);
+        assert_eq!(tx.orchard_outputs()[0].note().value().inner(), 3);
+        assert!(tx.orchard_outputs()[0].is_change());
+        assert_eq!(
+            tx.orchard_outputs()[0].recipient(),
+            orchard_fvk.address_at(0u32, zip32::Scope::Internal)
+        );
+        assert_eq!(
+            scanned_block.received_by_account().unwrap().get(&account),
+            Some(&NonNegativeAmount::const_from_u64(3))
+        );
+        assert_eq!(
+            tx.orchard_outputs()[0].recipient_key_scope(),
+            Some(zip32::Scope::Internal)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "orchard")]
+    fn scan_block_checkpoints_each_pool_independently() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // An Orchard action paying a key that does not belong to the wallet.
+        let other_usk =
+            UnifiedSpendingKey::from_seed(&network, &[1u8; 32], account).expect("Valid USK");
+        let other_fvk = other_usk
+            .to_unified_full_viewing_key()
+            .orchard()
+            .expect("Orchard key is present")
+            .clone();
+        let action = fake_compact_action(OsRng, &other_fvk, zip32::Scope::External, 4);
+
+        // The block's last Sapling output is received by the wallet, but the block's last
+        // transaction contains only an Orchard action.
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            None,
+        );
+        let mut txid = vec![0; 32];
+        OsRng.fill_bytes(&mut txid);
+        cb.vtx.push(CompactTx {
+            index: cb.vtx.len() as u64,
+            hash: txid,
+            actions: vec![action],
+            ..Default::default()
+        });
+        cb.chain_metadata = Some(compact::ChainMetadata {
+            sapling_commitment_tree_size: 2,
+            orchard_commitment_tree_size: 1,
+        });
+
+        let scanned_block =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
+        assert_contiguous_positions(&scanned_block);
+
+        let retentions = |commitments: &[(_, Retention<BlockHeight>)]| {
+            commitments.iter().map(|(_, r)| *r).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            retentions(scanned_block.sapling().commitments()),
+            vec![
+                Retention::Ephemeral,
+                Retention::Checkpoint {
+                    id: scanned_block.height(),
+                    is_marked: true
+                }
+            ]
+        );
+        assert_eq!(
+            retentions(scanned_block.orchard().commitments()),
+            vec![Retention::Checkpoint {
+                id: scanned_block.height(),
+                is_marked: false
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "orchard")]
+    fn scan_block_skips_disabled_pools() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            ufvk,
+            sapling_dfvk,
+            scanning_keys,
+        } = test_account(&network, account);
+        let orchard_fvk = ufvk.orchard().expect("Orchard key is present").clone();
+
+        // The block's first transaction contains an unrelated Sapling output, and its second a
+        // Sapling output paying the wallet. A final transaction pays the wallet in both pools,
+        // with its Sapling output following the Orchard action.
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            None,
+        );
+        let mut txid = vec![0; 32];
+        OsRng.fill_bytes(&mut txid);
+        cb.vtx.push(CompactTx {
+            index: cb.vtx.len() as u64,
+            hash: txid,
+            actions: vec![fake_compact_action(
+                OsRng,
+                &orchard_fvk,
+                zip32::Scope::External,
+                4,
+            )],
+            outputs: vec![fake_compact_output(
+                OsRng,
+                cb.height(),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(6),
+            )],
+            ..Default::default()
+        });
+        cb.chain_metadata = Some(compact::ChainMetadata {
+            sapling_commitment_tree_size: 10 + 3,
+            orchard_commitment_tree_size: 7 + 1,
+        });
+
+        let scan = |scan_sapling, scan_orchard| {
+            scan_block_with_config(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig {
+                    scan_sapling,
+                    scan_orchard,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+        };
+        let sapling_positions = |block: &ScannedBlock<AccountId>| {
+            block
+                .transactions()
+                .iter()
+                .flat_map(|tx| tx.sapling_outputs())
+                .map(|out| out.note_commitment_tree_position())
+                .collect::<Vec<_>>()
+        };
+        let orchard_positions = |block: &ScannedBlock<AccountId>| {
+            block
+                .transactions()
+                .iter()
+                .flat_map(|tx| tx.orchard_outputs())
+                .map(|out| out.note_commitment_tree_position())
+                .collect::<Vec<_>>()
+        };
+
+        // With Orchard disabled, the Orchard tree still grows by the block's action, and the
+        // Sapling outputs are found at the same positions as when both pools are scanned.
+        let both = scan(true, true);
+        let sapling_only = scan(true, false);
+        assert_eq!(
+            sapling_positions(&both),
+            vec![Position::from(11), Position::from(12)]
+        );
+        assert_eq!(sapling_positions(&sapling_only), sapling_positions(&both));
+        assert_eq!(orchard_positions(&both), vec![Position::from(7)]);
+        assert!(orchard_positions(&sapling_only).is_empty());
+        assert_eq!(sapling_only.orchard().final_tree_size(), 8);
+        assert_eq!(sapling_only.sapling().final_tree_size(), 13);
+        assert_eq!(
+            sapling_only.orchard().commitments().len(),
+            both.orchard().commitments().len()
+        );
+        assert_contiguous_positions(&sapling_only);
+
+        let orchard_only = scan(false, true);
+        assert!(sapling_positions(&orchard_only).is_empty());
+        assert_eq!(orchard_positions(&orchard_only), vec![Position::from(7)]);
+        assert_eq!(orchard_only.sapling().final_tree_size(), 13);
+        assert_contiguous_positions(&orchard_only);
+    }
+
+    #[test]
+    fn scan_block_reports_unlinked_nullifier_positions() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+        let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+
+        let nf = Nullifier([7; 32]);
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let txid = cb.vtx[1].txid();
+
+        let scanned_block =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
+        assert!(scanned_block.transactions().is_empty());
+
+        let nullifier_map = scanned_block.sapling().nullifier_map();
+        assert_eq!(nullifier_map.len(), 2);
+        assert_eq!(nullifier_map[1].0, txid);
+        assert_eq!(nullifier_map[1].1, 1);
+        assert_eq!(nullifier_map[1].2, vec![(0, nf)]);
+    }
+
+    #[test]
+    fn scan_block_orders_outputs_across_accounts() {
+        fn go(scan_multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account0 = AccountId::ZERO;
+            let account1 = AccountId::try_from(1).unwrap();
+            let ufvk0 = UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account0)
+                .expect("Valid USK")
+                .to_unified_full_viewing_key();
+            let ufvk1 = UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account1)
+                .expect("Valid USK")
+                .to_unified_full_viewing_key();
+            let dfvk0 = ufvk0.sapling().expect("Sapling key is present").clone();
+            let dfvk1 = ufvk1.sapling().expect("Sapling key is present").clone();
+            let scanning_keys =
+                ScanningKeys::from_account_ufvks([(account0, ufvk0), (account1, ufvk1)]);
+
+            // A single transaction pays account 1, then account 0, then account 1 again.
+            let height = BlockHeight::from(1);
+            let mut ctx = random_compact_tx(OsRng);
+            ctx.spends.clear();
+            ctx.outputs = vec![
+                fake_compact_output(OsRng, height, &dfvk1, NonNegativeAmount::const_from_u64(1)),
+                fake_compact_output(OsRng, height, &dfvk0, NonNegativeAmount::const_from_u64(2)),
+                fake_compact_output(OsRng, height, &dfvk1, NonNegativeAmount::const_from_u64(3)),
+            ];
+            let cb = CompactBlock {
+                hash: vec![1; 32],
+                prev_hash: vec![0; 32],
+                height: height.into(),
+                vtx: vec![ctx],
+                chain_metadata: Some(compact::ChainMetadata {
+                    sapling_commitment_tree_size: 3,
+                    orchard_commitment_tree_size: 0,
+                }),
+                ..Default::default()
+            };
+
+            let mut batch_runners = if scan_multithreaded {
+                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
+                runners.add_block(&network, &cb).unwrap();
+                runners.flush();
+
+                Some(runners)
+            } else {
+                None
+            };
+
+            let scanned_block = scan_block_with_runners(
+                &network,
+                cb,
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                batch_runners.as_mut(),
+            )
+            .unwrap();
+            let txs = scanned_block.transactions();
+            assert_eq!(txs.len(), 1);
+            assert_eq!(
+                txs[0]
+                    .sapling_outputs()
+                    .iter()
+                    .map(|output| (
+                        output.index(),
+                        *output.account_id(),
+                        output.note().value().inner()
+                    ))
+                    .collect::<Vec<_>>(),
+                vec![(0, account1, 1), (1, account0, 2), (2, account1, 3)]
+            );
+        }
+
+        go(false);
+        go(true);
+    }
+
+    #[test]
+    fn scan_block_with_my_spend() {
+        let network = Network::TestNetwork;
+        let account = AccountId::try_from(12).unwrap();
+        let TestAccount { ufvk, .. } = test_account(&network, account);
+        let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+
+        let nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            ufvk.sapling().unwrap(),
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        assert_eq!(cb.vtx.len(), 2);
+
+        let scanned_block = scan_block(&network, cb, &scanning_keys, &nullifiers, None).unwrap();
+        let txs = scanned_block.transactions();
+        assert_eq!(txs.len(), 1);
+
+        let tx = &txs[0];
+        assert_eq!(tx.block_index(), 1);
+        assert_eq!(tx.sapling_spends().len(), 1);
+        assert_eq!(tx.sapling_outputs().len(), 0);
+        assert_eq!(tx.sapling_spends()[0].index(), 0);
+        assert_eq!(tx.sapling_spends()[0].nf(), &nf);
+        assert_eq!(tx.sapling_spends()[0].account_id(), &account);
+        // The value of the spent note is unknown, and the output is not ours.
+        assert_eq!(tx.computed_fee(), None);
+
+        assert_eq!(
+            scanned_block
+                .sapling()
+                .commitments()
+                .iter()
+                .map(|(_, retention)| *retention)
+                .collect::<Vec<_>>(),
+            vec![
+                Retention::Ephemeral,
+                Retention::Checkpoint {
+                    id: scanned_block.height(),
+                    is_marked: false
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_block_with_nullifier_bloom() {
+        let network = Network::TestNetwork;
+        let account = AccountId::try_from(12).unwrap();
+        let TestAccount { ufvk, .. } = test_account(&network, account);
+        let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+
+        let nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        )
+        .with_sapling_bloom(NullifierBloom::new(1));
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            ufvk.sapling().unwrap(),
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+
+        // The spend of the tracked note passes the filter and is detected, while the spends
+        // of the unrelated transactions are not.
+        let scanned_block = scan_block(&network, cb, &scanning_keys, &nullifiers, None).unwrap();
+        let txs = scanned_block.transactions();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].block_index(), 1);
+        assert_eq!(txs[0].sapling_spends().len(), 1);
+        assert_eq!(txs[0].sapling_spends()[0].nf(), &nf);
+        assert_eq!(txs[0].sapling_spends()[0].account_id(), &account);
+    }
+
+    #[test]
+    #[cfg(not(feature = "fast-nullifier-lookup"))]
+    fn nullifier_bloom_reduces_folds() {
+        use super::CT_FIND_FOLDS;
+
+        let network = Network::TestNetwork;
+        let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+        let mut rng = OsRng;
+        let random_nf = |rng: &mut OsRng| {
+            let mut nf = [0u8; 32];
+            rng.fill_bytes(&mut nf);
+            Nullifier(nf)
+        };
+
+        let tracked = (0..100)
+            .map(|_| (AccountId::ZERO, random_nf(&mut rng)))
+            .collect::<Vec<_>>();
+
+        // A block full of spends that are unrelated to the wallet.
+        let spend_count = 1_000;
+        let cb = CompactBlock {
+            height: 1,
+            hash: vec![1; 32],
+            prev_hash: vec![0; 32],
+            vtx: (0..spend_count)
+                .map(|i| {
+                    let mut hash = vec![0; 32];
+                    rng.fill_bytes(&mut hash);
+                    CompactTx {
+                        index: i as u64,
+                        hash,
+                        spends: vec![CompactSaplingSpend {
+                            nf: random_nf(&mut rng).0.to_vec(),
+                        }],
+                        ..Default::default()
+                    }
+                })
+                .collect(),
+            chain_metadata: Some(compact::ChainMetadata {
+                sapling_commitment_tree_size: 0,
+                orchard_commitment_tree_size: 0,
+            }),
+            ..Default::default()
+        };
+
+        let folds = |nullifiers: &Nullifiers<AccountId>| {
+            CT_FIND_FOLDS.with(|folds| folds.set(0));
+            let scanned_block =
+                scan_block(&network, cb.clone(), &scanning_keys, nullifiers, None).unwrap();
+            assert!(scanned_block.transactions().is_empty());
+            CT_FIND_FOLDS.with(|folds| folds.get())
+        };
+
+        let unfiltered = Nullifiers::new(
+            tracked.clone(),
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+        let filtered = unfiltered
+            .clone()
+            .with_sapling_bloom(NullifierBloom::new(tracked.len()));
+
+        // Without the filter, each spend is compared against every tracked nullifier. With the
+        // filter, only the false positives (about 1% of spends) are.
+        let unfiltered_folds = folds(&unfiltered);
+        let filtered_folds = folds(&filtered);
+        assert_eq!(unfiltered_folds, spend_count * tracked.len());
+        assert!(filtered_folds < unfiltered_folds / 30);
+    }
+
+    #[test]
+    #[cfg(feature = "fast-nullifier-lookup")]
+    fn nullifier_index_tracks_updates() {
+        use super::sapling_account_finder;
+
+        let account0 = AccountId::ZERO;
+        let account1 = AccountId::try_from(1).unwrap();
+        let nf = |b: u8| Nullifier([b; 32]);
+
+        let mut nullifiers = Nullifiers::new(
+            vec![(account0, nf(1)), (account1, nf(2)), (account1, nf(3))],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+
+        // Removing an entry shifts the positions of the entries that follow it.
+        nullifiers.retain_sapling(|(_, n)| n != &nf(1));
+        nullifiers.extend_sapling([(account0, nf(4))]);
+
+        let find = sapling_account_finder(&nullifiers);
+        assert_eq!(find(&nf(1)), None);
+        assert_eq!(find(&nf(2)), Some(account1));
+        assert_eq!(find(&nf(3)), Some(account1));
+        assert_eq!(find(&nf(4)), Some(account0));
+    }
+
+    #[test]
+    fn scan_mempool_tx_detects_unmined_notes() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let spent_nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, spent_nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+
+        let target_height = network.activation_height(NetworkUpgrade::Nu5).unwrap() + 1000;
+        let mut cb = fake_compact_block(
+            OsRng,
+            target_height,
+            BlockHash([0; 32]),
+            spent_nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            None,
+        );
+        let our_tx = cb.vtx.pop().unwrap();
+        let unrelated_tx = cb.vtx.pop().unwrap();
+        let txid = our_tx.txid();
+
+        let wtx = scan_mempool_tx(
+            &network,
+            target_height,
+            our_tx,
+            &scanning_keys,
+            &nullifiers,
+            ScanConfig::default(),
+        )
+        .unwrap()
+        .expect("The transaction is relevant to the wallet");
+        assert_eq!(wtx.txid(), txid);
+
+        // The received note is detected, but its position and nullifier are not yet known.
+        assert_eq!(wtx.sapling_outputs().len(), 1);
+        let output = &wtx.sapling_outputs()[0];
+        assert_eq!(output.note().value().inner(), 5);
+        assert_eq!(output.account_id(), &account);
+        assert!(!output.is_mined());
+        assert_eq!(output.nf(), None);
+
+        // No nullifier can be computed for the note until its position is known.
+        let output = WalletOutput::from_parts(
+            output.index(),
+            output.ephemeral_key().clone(),
+            output.note().clone(),
+            output.is_change(),
+            output.below_dust_threshold(),
+            output.note_commitment_tree_position(),
+            None,
+            *output.account_id(),
+            output.recipient_key_scope(),
+        );
+        assert!(output.with_nullifier(&sapling_dfvk.fvk().vk.nk).is_none());
+
+        // The spend of the tracked note is detected.
+        assert_eq!(wtx.sapling_spends().len(), 1);
+        assert_eq!(wtx.sapling_spends()[0].nf(), &spent_nf);
+
+        // Outputs detected when scanning a block are mined.
+        assert!(scan_block(
+            &network,
+            fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            ),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None
+        )
+        .unwrap()
+        .transactions()[0]
+            .sapling_outputs()[0]
+            .is_mined());
+
+        // A transaction that is unrelated to the wallet is not returned.
+        assert!(scan_mempool_tx(
+            &network,
+            target_height,
+            unrelated_tx,
+            &scanning_keys,
+            &nullifiers,
+            ScanConfig::default(),
+        )
+        .unwrap()
+        .is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "transparent-inputs")]
+    fn scan_block_detects_shielding_transaction() {
+        use zcash_primitives::transaction::components::OutPoint;
+
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let nullifiers =
+            Nullifiers::empty().with_transparent_outpoints([(account, OutPoint::new([9; 32], 0))]);
+
+        let scan_with_vin = |prevout_index: u32| {
+            let mut cb = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+            cb.vtx.last_mut().unwrap().vin.push(compact::CompactTxIn {
+                prevout_txid: vec![9; 32],
+                prevout_index,
+            });
+
+            let scanned_block =
+                scan_block(&network, cb, &scanning_keys, &nullifiers, None).unwrap();
+            let txs = scanned_block.transactions();
+            assert_eq!(txs.len(), 1);
+            txs[0].is_shielding()
+        };
+
+        // Spending a tracked transparent output into a note received by the same account is
+        // a shielding transaction.
+        assert!(scan_with_vin(0));
+
+        // Spending an untracked outpoint is not.
+        assert!(!scan_with_vin(1));
+    }
+
+    /// Replaces the last output of the given block with an output of `note`, returning the ID of
+    /// the transaction containing it and a function that computes the shared secret agreed by
+    /// its sender.
+    fn replace_with_disclosable_output(
+        cb: &mut CompactBlock,
+        dfvk: &DiversifiableFullViewingKey,
+        note: sapling::Note,
+    ) -> (TxId, impl Fn() -> sapling::keys::SharedSecret) {
+        let encryptor = sapling_note_encryption(
+            Some(dfvk.fvk().ovk),
+            note.clone(),
+            *MemoBytes::empty().as_array(),
+            &mut OsRng,
+        );
+        let our_tx = cb.vtx.last_mut().unwrap();
+        our_tx.outputs[0] = CompactSaplingOutput {
+            cmu: note.cmu().to_bytes().to_vec(),
+            ephemeral_key: SaplingDomain::epk_bytes(encryptor.epk()).0.to_vec(),
+            ciphertext: encryptor.encrypt_note_plaintext().as_ref()[..COMPACT_NOTE_SIZE].to_vec(),
+        };
+        let recipient = note.recipient();
+        (our_tx.txid(), move || {
+            SaplingDomain::ka_agree_enc(encryptor.esk(), recipient.pk_d())
+        })
+    }
+
+    #[test]
+    fn scan_block_with_shared_secrets_decrypts_disclosed_outputs() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let height = network.activation_height(NetworkUpgrade::Nu5).unwrap() + 1000;
+        let mut cb = fake_compact_block(
+            OsRng,
+            height,
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        let recipient = sapling_dfvk.default_address().1;
+        let rseed = generate_random_rseed(zip212_enforcement(&network, height), &mut OsRng);
+        let (txid, shared_secret) = replace_with_disclosable_output(
+            &mut cb,
+            &sapling_dfvk,
+            sapling::Note::from_parts(recipient, NoteValue::from_raw(5), rseed),
+        );
+        let other_txid = cb.vtx[0].txid();
+        let disclosure = || SaplingOutputDisclosure::new(account, recipient, shared_secret());
+
+        // The disclosed output is decrypted to the same output that trial decryption produces,
+        // except that its nullifier is not known.
+        let disclosed = scan_block_with_shared_secrets(
+            &network,
+            &cb,
+            HashMap::from([((txid, 0), disclosure())]),
+            ScanConfig::default(),
+        )
+        .unwrap();
+        let scanned_block = scan_block(
+            &network,
+            cb.clone(),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+        )
+        .unwrap();
+        let expected = &scanned_block.transactions()[0].sapling_outputs()[0];
+        assert_eq!(disclosed.len(), 1);
+        let (disclosed_txid, output) = &disclosed[0];
+        assert_eq!(disclosed_txid, &txid);
+        assert_eq!(output.index(), expected.index());
+        assert_eq!(output.ephemeral_key(), expected.ephemeral_key());
+        assert_eq!(output.note(), expected.note());
+        assert_eq!(
+            output.note_commitment_tree_position(),
+            expected.note_commitment_tree_position()
+        );
+        assert_eq!(output.account_id(), &account);
+        assert_eq!(output.nf(), None);
+
+        // A secret disclosed for a different output does not decrypt it.
+        assert!(scan_block_with_shared_secrets(
+            &network,
+            &cb,
+            HashMap::from([((other_txid, 0), disclosure())]),
+            ScanConfig::default(),
+        )
+        .unwrap()
+        .is_empty());
+
+        // Chain metadata is required to determine the positions of the decrypted notes.
+        cb.chain_metadata = None;
+        assert_matches!(
+            scan_block_with_shared_secrets(
+                &network,
+                &cb,
+                HashMap::from([((txid, 0), disclosure())]),
+                ScanConfig::default(),
+            ),
+            Err(ScanError::TreeSizeUnknown {
+                metadata_absent: true,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn scan_block_with_shared_secrets_decrypts_pre_zip212_outputs() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+
+        // An output using the original plaintext format, in which `rcm` is encoded directly.
+        let recipient = sapling_dfvk.default_address().1;
+        let rcm = jubjub::Fr::random(&mut OsRng);
+        let note = sapling::Note::from_parts(
+            recipient,
+            NoteValue::from_raw(5),
+            sapling::Rseed::BeforeZip212(rcm),
+        );
+
+        let canopy = network.activation_height(NetworkUpgrade::Canopy).unwrap();
+        let decrypt = |height: BlockHeight| {
+            let mut cb = fake_compact_block(
+                OsRng,
+                height,
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+            let (txid, shared_secret) =
+                replace_with_disclosable_output(&mut cb, &sapling_dfvk, note.clone());
+            scan_block_with_shared_secrets(
+                &network,
+                &cb,
+                HashMap::from([(
+                    (txid, 0),
+                    SaplingOutputDisclosure::new(account, recipient, shared_secret()),
+                )]),
+                ScanConfig::default(),
+            )
+            .unwrap()
+        };
+
+        // Such outputs are accepted during the ZIP 212 grace period.
+        let disclosed = decrypt(canopy + 1);
+        assert_eq!(disclosed.len(), 1);
+        assert_eq!(disclosed[0].1.note(), &note);
+
+        // They are rejected once the grace period has ended.
+        assert!(decrypt(canopy + ZIP212_GRACE_PERIOD).is_empty());
+    }
+
+    #[test]
+    fn scan_block_tree_size_unknown() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+        let sapling_activation = network.activation_height(NetworkUpgrade::Sapling).unwrap();
+
+        let mut cb = fake_compact_block(
+            OsRng,
+            sapling_activation,
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            None,
+        );
+
+        // Without chain metadata, we can't tell where the block's outputs land in the tree.
+        assert_matches!(
+            scan_block(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None
+            )
+            .err(),
+            Some(ScanError::TreeSizeUnknown {
+                protocol: ShieldedProtocol::Sapling,
+                metadata_absent: true,
+                ..
+            })
+        );
+
+        // Chain metadata populated only with default values is distinguishable.
+        cb.chain_metadata = Some(compact::ChainMetadata::default());
+        assert_matches!(
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).err(),
+            Some(ScanError::TreeSizeUnknown {
+                protocol: ShieldedProtocol::Sapling,
+                metadata_absent: false,
+                ..
+            })
+        );
+
+        // Zero tree sizes are correct for a block without shielded outputs.
+        let empty_block = CompactBlock {
+            height: sapling_activation.into(),
+            hash: vec![1; 32],
+            prev_hash: vec![0; 32],
+            chain_metadata: Some(compact::ChainMetadata::default()),
+            ..Default::default()
+        };
+        let scanned_block = scan_block(
+            &network,
+            empty_block,
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(scanned_block.sapling().final_tree_size(), 0);
+    }
+
+    #[test]
+    fn scan_block_tx_index_overflow() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        cb.vtx[1].index = u64::from(u16::MAX) + 1;
+
+        assert_matches!(
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).err(),
+            Some(ScanError::TxIndexOverflow { index, .. }) if index == u64::from(u16::MAX) + 1
+        );
+    }
+
+    #[test]
+    fn scan_block_rejects_blocks_with_too_many_outputs() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // The block contains three Sapling outputs.
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+        let scan_with_limit = |max_outputs_per_block| {
+            scan_block_with_config(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig {
+                    max_outputs_per_block,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert_matches!(
+            scan_with_limit(Some(2)),
+            Err(ScanError::BlockTooLarge {
+                at_height,
+                output_count: 3,
+            }) if at_height == cb.height()
+        );
+        assert!(scan_with_limit(Some(3)).is_ok());
+        assert!(scan_with_limit(None).is_ok());
+        assert!(scan_with_limit(ScanConfig::default().max_outputs_per_block).is_ok());
+    }
+
+    #[test]
+    fn scan_block_enforces_max_sapling_tree_size() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // The block appends three Sapling outputs to a tree of size 5.
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((5, 0)),
+        );
+        let scan_with_limit = |max_sapling_tree_size| {
+            scan_block_with_config(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanConfig {
+                    max_sapling_tree_size,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert_matches!(
+            scan_with_limit(Some(7)),
+            Err(ScanError::TreeSizeExceeded {
+                protocol: ShieldedProtocol::Sapling,
+                limit: 7,
+                at_height,
+            }) if at_height == cb.height()
+        );
+        assert_matches!(
+            scan_with_limit(Some(5)).map_err(|e| e.code()),
+            Err("tree-size-exceeded")
+        );
+        assert!(scan_with_limit(Some(8)).is_ok());
+        assert!(scan_with_limit(None).is_ok());
+    }
+
+    #[test]
+    fn expected_positions_detect_mismatches() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // Our output follows the single output of the block's first transaction, and so is at
+        // position 1 in a tree that was empty prior to this block.
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let txid = cb.vtx[1].txid();
+        let validate = |expected: HashMap<TxId, Vec<(usize, Position)>>| {
+            scan_block_with_config(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanOptions::new(ScanConfig::default()).with_expected_positions(&expected),
+            )
+        };
+
+        let scanned_block = validate(HashMap::from([(txid, vec![(0, Position::from(1))])]))
+            .expect("Positions match");
+        assert_eq!(scanned_block.transactions().len(), 1);
+        assert!(validate(HashMap::new()).is_ok());
+
+        assert_matches!(
+            validate(HashMap::from([(txid, vec![(0, Position::from(0))])])),
+            Err(ScanError::PositionMismatch {
+                txid: t,
+                output_index: 0,
+                expected,
+                computed: Some(computed),
+                ..
+            }) if t == txid && expected == Position::from(0) && computed == Position::from(1)
+        );
+
+        // The first transaction's output was not sent to us, so no position is computed for it.
+        let other_txid = cb.vtx[0].txid();
+        assert_matches!(
+            validate(HashMap::from([(other_txid, vec![(0, Position::from(0))])])),
+            Err(ScanError::PositionMismatch {
+                txid: t,
+                output_index: 0,
+                computed: None,
+                ..
+            }) if t == other_txid
+        );
+    }
+
+    #[test]
+    fn extract_nullifier_map_matches_spends_only_scan() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+
+        let nf = Nullifier([7; 32]);
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+        // A transaction without any spends is still included.
+        cb.vtx[2].spends.clear();
+
+        let nullifier_map = extract_nullifier_map(&cb).unwrap();
+        assert_eq!(nullifier_map.len(), cb.vtx.len());
+        assert_eq!(nullifier_map[1], (cb.vtx[1].txid(), 1, vec![nf]));
+        assert_eq!(nullifier_map[2], (cb.vtx[2].txid(), 2, vec![]));
+
+        // The nullifiers are the same as those reported by a scan that tracks no nullifiers.
+        let scanned = scan_block_spends_only::<AccountId>(&cb, &Nullifiers::empty()).unwrap();
+        for (txid, tx_index, nfs) in scanned.sapling_nullifier_map() {
+            let (_, _, extracted) = nullifier_map
+                .iter()
+                .find(|(t, i, _)| t == txid && i == tx_index)
+                .unwrap();
+            assert_eq!(
+                &nfs.iter().map(|(_, nf)| *nf).collect::<Vec<_>>(),
+                extracted
+            );
+        }
+
+        #[cfg(feature = "orchard")]
+        assert!(extract_orchard_nullifier_map(&cb)
+            .unwrap()
+            .iter()
+            .all(|(_, _, nfs)| nfs.is_empty()));
+
+        cb.vtx[0].index = u64::from(u16::MAX) + 1;
+        assert_matches!(
+            extract_nullifier_map(&cb),
+            Err(ScanError::TxIndexOverflow { .. })
+        );
+
+        cb.vtx[0].index = 0;
+        cb.vtx[1].spends[0].nf.truncate(31);
+        assert_matches!(
+            extract_nullifier_map(&cb),
+            Err(ScanError::NullifierInvalid {
+                protocol: ShieldedProtocol::Sapling,
+                txid,
+                index: 0,
+                ..
+            }) if txid == cb.vtx[1].txid()
+        );
+    }
+
+    #[test]
+    fn scan_error_codes() {
+        let at_height = BlockHeight::from(1);
+        let prev_hash_mismatch = ScanError::PrevHashMismatch { at_height };
+        assert_eq!(prev_hash_mismatch.code(), "prev-hash-mismatch");
+        assert!(prev_hash_mismatch.is_continuity_error());
+
+        let tree_size_unknown = ScanError::TreeSizeUnknown {
+            protocol: ShieldedProtocol::Sapling,
+            at_height,
+            metadata_absent: true,
+        };
+        assert_eq!(tree_size_unknown.code(), "tree-size-unknown");
+        assert!(!tree_size_unknown.is_continuity_error());
+    }
+
+    #[test]
+    fn scan_error_display_with_network() {
+        let reorg_too_deep = ScanError::ReorgTooDeep {
+            at_height: BlockHeight::from(1013),
+            depth: 100,
+        };
+        assert_eq!(
+            reorg_too_deep
+                .display_with_network(NetworkType::Test)
+                .to_string(),
+            "Connecting the block at height 1013 would require rewinding at least 100 blocks on testnet"
+        );
+
+        let prev_hash_mismatch = ScanError::PrevHashMismatch {
+            at_height: BlockHeight::from(1013),
+        };
+        assert_eq!(
+            prev_hash_mismatch
+                .display_with_network(NetworkType::Main)
+                .to_string(),
+            "The parent hash of proposed block does not correspond to the block hash at height 1013 on mainnet"
+        );
+    }
+
+    #[test]
+    fn block_scanner_tracks_state() {
+        fn go(multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount {
+                sapling_dfvk,
+                scanning_keys,
+                ..
+            } = test_account(&network, account);
+
+            let mut scanner = BlockScanner::new(
+                network,
+                scanning_keys,
+                Nullifiers::empty(),
+                None,
+                ScanConfig {
+                    transparent_data_available: true,
+                    ..Default::default()
+                },
+                multithreaded,
+            );
+
+            let cb1 = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+            let cb1_hash = cb1.hash();
+            let scanned_block = scanner.scan_next(cb1).unwrap();
+            let received_nf = *scanned_block.transactions()[0].sapling_outputs()[0]
+                .nf()
+                .unwrap();
+            assert_eq!(scanner.nullifiers().sapling(), &[(account, received_nf)]);
+
+            // A block that does not connect to the previous one is rejected.
+            let disconnected = fake_compact_block(
+                OsRng,
+                2u32.into(),
+                BlockHash([1; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((2, 0)),
+            );
+            assert_matches!(
+                scanner.scan_next(disconnected).err(),
+                Some(ScanError::PrevHashMismatch { .. })
+            );
+            // The rejected block was not enqueued for trial decryption.
+            assert_eq!(scanner.pending_result_count(), 0);
+
+            // A block that fails to scan after being enqueued does not leave its results
+            // pending, and does not change the scanner's state.
+            let mut invalid = fake_compact_block(
+                OsRng,
+                2u32.into(),
+                cb1_hash,
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((2, 0)),
+            );
+            invalid
+                .chain_metadata
+                .as_mut()
+                .unwrap()
+                .sapling_commitment_tree_size = 0;
+            assert_matches!(
+                scanner.scan_next(invalid).err(),
+                Some(ScanError::TreeSizeMismatch { .. })
+            );
+            assert_eq!(scanner.pending_result_count(), 0);
+            assert_eq!(
+                scanner.prior_block_metadata().map(|m| m.block_height()),
+                Some(BlockHeight::from(1))
+            );
+
+            // The note received in the first block is detected as spent in the second.
+            let cb2 = fake_compact_block(
+                OsRng,
+                2u32.into(),
+                cb1_hash,
+                received_nf,
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(3),
+                false,
+                Some((2, 0)),
+            );
+            let scanned_block = scanner.scan_next(cb2).unwrap();
+            let tx = &scanned_block.transactions()[0];
+            assert_eq!(tx.sapling_spends().len(), 1);
+            assert_eq!(tx.sapling_spends()[0].nf(), &received_nf);
+            // The value of the spent note was recorded when it was received, and the
+            // transaction's only output is received by the wallet.
+            assert_eq!(
+                tx.computed_fee(),
+                Some(NonNegativeAmount::const_from_u64(2))
+            );
+            assert_eq!(
+                scanner.prior_block_metadata().map(|m| m.block_height()),
+                Some(BlockHeight::from(2))
+            );
+            assert_eq!(scanner.nullifiers().sapling().len(), 1);
+
+            // The batches for successfully scanned blocks have completed by the time their
+            // results are collected.
+            if multithreaded {
+                assert!(scanner.completed_batches() > 0);
+            } else {
+                assert_eq!(scanner.pending_tasks(), 0);
+                assert_eq!(scanner.completed_batches(), 0);
+            }
+        }
+
+        go(false);
+        go(true);
+    }
+
+    #[test]
+    fn block_scanner_scans_batches() {
+        fn go(multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount {
+                ufvk, sapling_dfvk, ..
+            } = test_account(&network, account);
+            let scanning_keys = || ScanningKeys::from_account_ufvks([(account, ufvk.clone())]);
+
+            let mut scanner = BlockScanner::new(
+                network,
+                scanning_keys(),
+                Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                multithreaded,
+            );
+
+            let cb1 = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+            // The nullifier of the note received in the first block is obtained from a separate
+            // scan, so that it can be spent in the second block of the batch.
+            let received_nf = *scan_block(
+                &network,
+                cb1.clone(),
+                &scanning_keys(),
+                &Nullifiers::empty(),
+                None,
+            )
+            .unwrap()
+            .transactions()[0]
+                .sapling_outputs()[0]
+                .nf()
+                .unwrap();
+            let cb2 = fake_compact_block(
+                OsRng,
+                2u32.into(),
+                cb1.hash(),
+                received_nf,
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(3),
+                false,
+                Some((2, 0)),
+            );
+
+            // If the second block fails to scan, no results are returned for the first, and
+            // the scanner's state is left unchanged.
+            let mut invalid = cb2.clone();
+            invalid
+                .chain_metadata
+                .as_mut()
+                .unwrap()
+                .sapling_commitment_tree_size = 0;
+            assert_matches!(
+                scanner.scan_batch(vec![cb1.clone(), invalid]),
+                Err(ScanError::TreeSizeMismatch { .. })
+            );
+            assert_eq!(scanner.pending_result_count(), 0);
+            assert!(scanner.prior_block_metadata().is_none());
+            assert!(scanner.nullifiers().sapling().is_empty());
+
+            // Blocks that do not form a chain are rejected before any scanning is performed.
+            assert_matches!(
+                scanner.scan_batch(vec![cb2.clone(), cb1.clone()]),
+                Err(ScanError::BlockHeightDiscontinuity { .. })
+            );
+
+            let completed_before = scanner.completed_batches();
+            let scanned_blocks = scanner.scan_batch(vec![cb1, cb2]).unwrap();
+            assert_eq!(scanned_blocks.len(), 2);
+            // The note received in the first block is detected as spent in the second.
+            let spends = scanned_blocks[1].transactions()[0].sapling_spends();
+            assert_eq!(spends.len(), 1);
+            assert_eq!(spends[0].nf(), &received_nf);
+            assert_eq!(
+                scanner.prior_block_metadata().map(|m| m.block_height()),
+                Some(BlockHeight::from(2))
+            );
+            assert_eq!(scanner.nullifiers().sapling().len(), 1);
+
+            // The outputs of both blocks were decrypted in a single batch.
+            if multithreaded {
+                assert_eq!(scanner.completed_batches() - completed_before, 1);
+            }
+        }
+
+        go(false);
+        go(true);
+    }
+
+    #[test]
+    fn scan_block_computes_fee_only_with_transparent_data() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // A note of value 10 is spent, and change of value 3 is returned to the wallet.
+        let nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        )
+        .with_sapling_values([(nf, NonNegativeAmount::const_from_u64(10))]);
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(3),
+            false,
+            Some((0, 0)),
+        );
+
+        let fee = |cb: CompactBlock, transparent_data_available| {
+            scan_block_with_config(
+                &network,
+                cb,
+                &scanning_keys,
+                &nullifiers,
+                None,
+                ScanConfig {
+                    transparent_data_available,
+                    ..Default::default()
+                },
+            )
+            .unwrap()
+            .transactions()[0]
+                .computed_fee()
+        };
+
+        // The transaction also pays 5 to a transparent address, but the source omits
+        // transparent outputs from the compact transaction, so the fee cannot be computed.
+        assert_eq!(fee(cb.clone(), false), None);
+
+        // If the source provides transparent data, the transparent output is accounted for.
+        let mut deshielding = cb;
+        deshielding.vtx[1].vout.push(compact::TxOut {
+            value: 5,
+            script_pub_key: vec![],
+        });
+        assert_eq!(
+            fee(deshielding, true),
+            Some(NonNegativeAmount::const_from_u64(2))
+        );
+    }
+
+    #[test]
+    fn scan_block_detects_spend_within_range() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+        let mut nullifiers = Nullifiers::empty();
+
+        // A note is received in the first block of the range.
+        let cb1 = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let block1 = scan_block(&network, cb1.clone(), &scanning_keys, &nullifiers, None).unwrap();
+        let received_nf = *block1.transactions()[0].sapling_outputs()[0].nf().unwrap();
+        nullifiers.update_for_block(&block1);
+
+        // An unrelated block follows.
+        let cb2 = fake_compact_block(
+            OsRng,
+            2u32.into(),
+            cb1.hash(),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(4),
+            false,
+            Some((2, 0)),
+        );
+        let block2 = scan_block(
+            &network,
+            cb2.clone(),
+            &scanning_keys,
+            &nullifiers,
+            Some(&block1.to_block_metadata()),
+        )
+        .unwrap();
+        assert!(block2.transactions()[0].sapling_spends().is_empty());
+        nullifiers.update_for_block(&block2);
+        assert_eq!(nullifiers.sapling().len(), 2);
+
+        // The note received two blocks earlier is spent in the third block.
+        let cb3 = fake_compact_block(
+            OsRng,
+            3u32.into(),
+            cb2.hash(),
+            received_nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(3),
+            false,
+            Some((4, 0)),
+        );
+        let block3 = scan_block(
+            &network,
+            cb3,
+            &scanning_keys,
+            &nullifiers,
+            Some(&block2.to_block_metadata()),
+        )
+        .unwrap();
+        let spends = block3.transactions()[0].sapling_spends();
+        assert_eq!(spends.len(), 1);
+        assert_eq!(spends[0].nf(), &received_nf);
+        assert_eq!(spends[0].account_id(), &account);
+
+        nullifiers.update_for_block(&block3);
+        assert!(!nullifiers
+            .sapling()
+            .iter()
+            .any(|(_, nf)| nf == &received_nf));
+    }
+
+    #[test]
+    fn scan_blocks_merges_commitments() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+        let mut nullifiers = Nullifiers::empty();
+
+        let cb1 = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let received_nf = *scan_block(&network, cb1.clone(), &scanning_keys, &nullifiers, None)
+            .unwrap()
+            .transactions()[0]
+            .sapling_outputs()[0]
+            .nf()
+            .unwrap();
+
+        let cb2 = fake_compact_block(
+            OsRng,
+            2u32.into(),
+            cb1.hash(),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(4),
+            false,
+            Some((2, 0)),
+        );
+        // The note received in the first block is spent in the third block.
+        let cb3 = fake_compact_block(
+            OsRng,
+            3u32.into(),
+            cb2.hash(),
+            received_nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(3),
+            false,
+            Some((4, 0)),
+        );
+
+        let scanned = scan_blocks(
+            &network,
+            vec![cb1, cb2, cb3],
+            &scanning_keys,
+            &mut nullifiers,
+            None,
+            ScanConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            scanned.block_range(),
+            Some(BlockHeight::from(1)..BlockHeight::from(4))
+        );
+        assert_eq!(scanned.block_metadata().len(), 3);
+        assert_eq!(scanned.transactions().len(), 3);
+        let (spend_height, spend_tx) = &scanned.transactions()[2];
+        assert_eq!(*spend_height, BlockHeight::from(3));
+        assert_eq!(spend_tx.sapling_spends()[0].nf(), &received_nf);
+
+        // The merged stream contains exactly one checkpoint per block, at the last commitment
+        // of each block.
+        let commitments = scanned.sapling_commitments();
+        assert_eq!(commitments.len(), 6);
+        let checkpoints = commitments
+            .iter()
+            .enumerate()
+            .filter_map(|(i, (_, retention))| match retention {
+                Retention::Checkpoint { id, .. } => Some((i, *id)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            checkpoints,
+            vec![
+                (1, BlockHeight::from(1)),
+                (3, BlockHeight::from(2)),
+                (5, BlockHeight::from(3))
+            ]
+        );
+
+        // The stream is partitioned into one segment per block, each ending at its checkpoint.
+        let segments = scanned.sapling_checkpoint_segments();
+        assert_eq!(
+            segments
+                .iter()
+                .map(|segment| segment.len())
+                .collect::<Vec<_>>(),
+            vec![2, 2, 2]
+        );
+        assert!(segments
+            .iter()
+            .flat_map(|segment| segment.iter())
+            .map(|(node, _)| node)
+            .eq(commitments.iter().map(|(node, _)| node)));
+
+        // The nullifier of the spent note is no longer tracked.
+        assert!(!nullifiers
+            .sapling()
+            .iter()
+            .any(|(_, nf)| nf == &received_nf));
+    }
+
+    #[test]
+    fn apply_scanned_bundles_to_frontier() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+        let nullifiers = Nullifiers::empty();
+
+        let cb1 = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let cb2 = fake_compact_block(
+            OsRng,
+            2u32.into(),
+            cb1.hash(),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(4),
+            true,
+            Some((2, 0)),
+        );
+
+        // The expected tree is built directly from the note commitments in the blocks.
+        let mut expected = sapling::CommitmentTree::empty();
+        for cb in [&cb1, &cb2] {
+            for output in cb.vtx.iter().flat_map(|tx| tx.outputs.iter()) {
+                expected
+                    .append(sapling::Node::from_cmu(&output.cmu().unwrap()))
+                    .unwrap();
+            }
+        }
+
+        let scanned1 = scan_block(&network, cb1, &scanning_keys, &nullifiers, None).unwrap();
+        let scanned2 = scan_block(&network, cb2, &scanning_keys, &nullifiers, None).unwrap();
+
+        // Bundles must be applied in order, starting from the matching treestate.
+        let mut tree = sapling::CommitmentTree::empty();
+        assert_eq!(
+            apply_to_frontier(scanned2.sapling(), &mut tree),
+            Err(FrontierError::StartPositionMismatch {
+                tree_size: 0,
+                start_position: 2
+            })
+        );
+        assert_eq!(tree.size(), 0);
+
+        apply_to_frontier(scanned1.sapling(), &mut tree).unwrap();
+        apply_to_frontier(scanned2.sapling(), &mut tree).unwrap();
+        assert_eq!(tree.size(), 5);
+        assert_eq!(tree.root(), expected.root());
+
+        // Bundles that have already been applied are rejected.
+        assert_matches!(
+            apply_to_frontier(scanned2.sapling(), &mut tree),
+            Err(FrontierError::StartPositionMismatch { .. })
+        );
+
+        // The anchor is computed from the tree after each block's commitments are appended.
+        let mut tree = sapling::CommitmentTree::empty();
+        assert_matches!(
+            apply_and_root(scanned2.sapling(), &mut tree),
+            Err(FrontierError::StartPositionMismatch { .. })
+        );
+        let anchor1 = apply_and_root(scanned1.sapling(), &mut tree).unwrap();
+        assert_eq!(tree.size(), 2);
+        assert_eq!(anchor1, sapling::Anchor::from(tree.root()));
+        let anchor2 = apply_and_root(scanned2.sapling(), &mut tree).unwrap();
+        assert_eq!(anchor2, sapling::Anchor::from(expected.root()));
+        assert_ne!(anchor1, anchor2);
+    }
+
+    #[test]
+    fn scan_block_without_commitment_tracking() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((3, 0)),
+        );
+
+        let scanned_block = scan_block_with_config(
+            &network,
+            cb,
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+            ScanConfig {
+                track_commitments: false,
+                dust_threshold: NonNegativeAmount::ZERO,
+                reject_duplicate_nullifiers: false,
+                require_prev_hash: true,
+                source_id: None,
+                max_outputs_per_block: None,
+                track_key_hits: false,
+                max_sapling_tree_size: None,
+                scan_sapling: true,
+                scan_orchard: true,
+                transparent_data_available: false,
+            },
+        )
+        .unwrap();
+
+        // The received note and its position are still reported.
+        let txs = scanned_block.transactions();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].sapling_outputs().len(), 1);
+        assert_eq!(
+            txs[0].sapling_outputs()[0].note_commitment_tree_position(),
+            Position::from(4)
+        );
+        // No note is dust when the dust threshold is zero.
+        assert!(!txs[0].sapling_outputs()[0].below_dust_threshold());
+
+        // No commitments are produced, but the final tree size is still tracked.
+        assert!(scanned_block.sapling().commitments().is_empty());
+        assert_eq!(scanned_block.sapling().final_tree_size(), 6);
+        assert_eq!(scanned_block.sapling().position_range(), None);
+    }
+
+    #[test]
+    fn scan_block_spends_only_matches_tracked_nullifiers() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
+
+        let nf = Nullifier([7; 32]);
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+        let spending_tx = cb
+            .vtx
+            .iter()
+            .find(|tx| tx.spends.iter().any(|spend| spend.nf == nf.0))
+            .unwrap();
+        let (txid, tx_index) = (spending_tx.txid(), spending_tx.index as u16);
+
+        // Without any tracked nullifiers, the spend is only recorded in the nullifier map.
+        let scanned = scan_block_spends_only::<AccountId>(&cb, &Nullifiers::empty()).unwrap();
+        assert_eq!(scanned.block_height(), cb.height());
+        assert_eq!(scanned.block_hash(), cb.hash());
+        assert!(!scanned.has_spends());
+        assert!(scanned
+            .sapling_nullifier_map()
+            .contains(&(txid, tx_index, vec![(0, nf)])));
+
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+        let scanned = scan_block_spends_only(&cb, &nullifiers).unwrap();
+        assert!(scanned.has_spends());
+        assert_eq!(scanned.sapling_spends().len(), 1);
+        let (spend_txid, spend) = &scanned.sapling_spends()[0];
+        assert_eq!(spend_txid, &txid);
+        assert_eq!(spend.index(), 0);
+        assert_eq!(spend.nf(), &nf);
+        assert_eq!(spend.account_id(), &account);
+        assert!(scanned
+            .sapling_nullifier_map()
+            .contains(&(txid, tx_index, vec![])));
+
+        // A spend with an undecodable nullifier is reported as an error rather than a panic,
+        // both here and when scanning the full block.
+        let mut cb = cb;
+        let spending_tx = cb
+            .vtx
+            .iter_mut()
+            .find(|tx| tx.index == u64::from(tx_index))
+            .unwrap();
+        spending_tx.spends[0].nf.truncate(31);
+        assert_matches!(
+            scan_block_spends_only(&cb, &nullifiers),
+            Err(ScanError::NullifierInvalid {
+                protocol: ShieldedProtocol::Sapling,
+                index: 0,
+                ..
+            })
+        );
+        assert_matches!(
+            scan_block(
+                &network,
+                cb,
+                &ScanningKeys::<AccountId, Infallible>::empty(),
+                &nullifiers,
+                None
+            ),
+            Err(ScanError::NullifierInvalid { .. })
+        );
+    }
+
+    #[test]
+    fn scan_block_spends_by_account_isolates_accounts() {
+        let network = Network::TestNetwork;
+        let account0 = AccountId::ZERO;
+        let account1 = AccountId::try_from(1).unwrap();
+        let account2 = AccountId::try_from(2).unwrap();
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account0);
+
+        let nf = Nullifier([7; 32]);
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        // The spent nullifier is tracked by two accounts; the third account tracks only an
+        // unrelated nullifier.
+        let sapling = HashMap::from([
+            (account0, vec![nf]),
+            (account1, vec![Nullifier([8; 32]), nf]),
+            (account2, vec![Nullifier([9; 32])]),
+        ]);
+        let scanned = scan_block_spends_by_account(
+            &cb,
+            &sapling,
+            #[cfg(feature = "orchard")]
+            &HashMap::new(),
+        )
+        .unwrap();
+        assert_eq!(scanned.len(), 3);
+
+        for account in [account0, account1] {
+            let spends = scanned[&account].sapling_spends();
+            assert_eq!(spends.len(), 1);
+            assert_eq!(spends[0].1.nf(), &nf);
+            assert_eq!(spends[0].1.account_id(), &account);
+        }
+        assert!(!scanned[&account2].has_spends());
+    }
+
+    #[test]
+    fn scan_block_reports_spent_from_accounts() {
+        let network = Network::TestNetwork;
+        let account0 = AccountId::ZERO;
+        let account1 = AccountId::try_from(1).unwrap();
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account0);
+
+        let nf0 = Nullifier([7; 32]);
+        let nf1 = Nullifier([8; 32]);
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf0,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        // Only the first account's note is spent.
+        let nullifiers = Nullifiers::new(
+            vec![(account0, nf0), (account1, nf1)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+        let scanned_block =
+            scan_block(&network, cb.clone(), &scanning_keys, &nullifiers, None).unwrap();
+        assert_eq!(
+            scanned_block.transactions()[0].spent_from_accounts(),
+            [account0].into_iter().collect()
+        );
+
+        // Spend the second account's note in the same transaction.
+        cb.vtx
+            .iter_mut()
+            .find(|tx| tx.spends.iter().any(|spend| spend.nf == nf0.0))
+            .unwrap()
+            .spends
+            .push(CompactSaplingSpend { nf: nf1.0.to_vec() });
+        let scanned_block = scan_block(&network, cb, &scanning_keys, &nullifiers, None).unwrap();
+        assert_eq!(
+            scanned_block.transactions()[0].spent_from_accounts(),
+            [account0, account1].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn scan_block_assigns_positions_in_dense_transaction() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let mut rng = OsRng;
+        let height = BlockHeight::from(1);
+        let received = [0, 1, 499, 998, 999];
+        let mut tx = CompactTx::default();
+        tx.hash = vec![7; 32];
+        for output_idx in 0..1000 {
+            tx.outputs.push(if received.contains(&output_idx) {
+                fake_compact_output(
+                    OsRng,
+                    height,
+                    &sapling_dfvk,
+                    NonNegativeAmount::const_from_u64(5),
+                )
+            } else {
+                random_compact_tx(&mut rng).outputs.remove(0)
+            });
+        }
+
+        let initial_sapling_tree_size = 12345;
+        let cb = CompactBlock {
+            hash: vec![1; 32],
+            prev_hash: vec![0; 32],
+            height: height.into(),
+            vtx: vec![tx],
+            chain_metadata: Some(compact::ChainMetadata {
+                sapling_commitment_tree_size: initial_sapling_tree_size + 1000,
+                orchard_commitment_tree_size: 0,
+            }),
+            ..Default::default()
+        };
+
+        let scanned_block =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
+        let txs = scanned_block.transactions();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(
+            txs[0]
+                .sapling_outputs()
+                .iter()
+                .map(|output| (output.index(), output.note_commitment_tree_position()))
+                .collect::<Vec<_>>(),
+            received
+                .iter()
+                .map(|idx| (
+                    *idx,
+                    Position::from(u64::from(initial_sapling_tree_size) + *idx as u64)
+                ))
+                .collect::<Vec<_>>()
+        );
+        assert_contiguous_positions(&scanned_block);
+    }
+
+    #[test]
+    fn ivk_scanned_output_with_nullifier() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            ufvk, sapling_dfvk, ..
+        } = test_account(&network, account);
+
+        let mut sapling_keys: HashMap<
+            u32,
+            Box<dyn ScanningKeyOps<SaplingDomain, AccountId, Nullifier>>,
+        > = HashMap::new();
+        sapling_keys.insert(
+            0,
+            Box::new((account, sapling_dfvk.to_ivk(zip32::Scope::External))),
+        );
+        let ivk_scanning_keys = ScanningKeys::new(
+            sapling_keys,
+            #[cfg(feature = "orchard")]
+            HashMap::new(),
+        );
+        let fvk_scanning_keys = ScanningKeys::from_account_ufvks([(account, ufvk)]);
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        let ivk_scanned = scan_block(
+            &network,
+            cb.clone(),
+            &ivk_scanning_keys,
+            &Nullifiers::empty(),
+            None,
+        )
+        .unwrap();
+        let fvk_scanned =
+            scan_block(&network, cb, &fvk_scanning_keys, &Nullifiers::empty(), None).unwrap();
+
+        let output = &ivk_scanned.transactions()[0].sapling_outputs()[0];
+        assert_eq!(output.nf(), None);
+        let output = WalletOutput::from_parts(
+            output.index(),
+            output.ephemeral_key().clone(),
+            output.note().clone(),
+            output.is_change(),
+            output.below_dust_threshold(),
+            output.note_commitment_tree_position(),
+            None,
+            *output.account_id(),
+            output.recipient_key_scope(),
+        );
+        let expected_nf = fvk_scanned.transactions()[0].sapling_outputs()[0]
+            .nf()
+            .copied();
+        assert!(expected_nf.is_some());
+
+        let output = output.with_nullifier(&sapling_dfvk.fvk().vk.nk).unwrap();
+        assert_eq!(output.nf().copied(), expected_nf);
+    }
+
+    #[test]
+    fn scan_block_without_prev_hash() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let prior_block_metadata = BlockMetadata::from_parts(
+            1u32.into(),
+            BlockHash([1; 32]),
+            Some(0),
+            #[cfg(feature = "orchard")]
+            Some(0),
+        );
+        let config = ScanConfig {
+            require_prev_hash: false,
+            ..Default::default()
+        };
+        let block_without_prev_hash = |height: u32| {
+            let mut cb = fake_compact_block(
+                OsRng,
+                height.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+            cb.prev_hash.clear();
+            cb
+        };
+
+        let scanned_block = scan_block_with_config(
+            &network,
+            block_without_prev_hash(2),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            Some(&prior_block_metadata),
+            config,
+        )
+        .unwrap();
+        assert_eq!(scanned_block.height(), BlockHeight::from(2));
+        assert_eq!(scanned_block.transactions().len(), 1);
+
+        // The height of the block must still follow that of the prior block.
+        assert_matches!(
+            scan_block_with_config(
+                &network,
+                block_without_prev_hash(3),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                Some(&prior_block_metadata),
+                config,
+            )
+            .err(),
+            Some(ScanError::BlockHeightDiscontinuity { .. })
+        );
+    }
+
+    #[test]
+    fn tree_size_accumulation_is_checked() {
+        let at_height = BlockHeight::from(1);
+        let max = u64::from(u32::MAX);
+
+        // The running size may exceed the range of the chain metadata's tree sizes...
+        let grown = grow_tree_size(max, 1, ShieldedProtocol::Sapling, at_height).unwrap();
+        assert_eq!(grown, max + 1);
+
+        // ... but is rejected when it is narrowed for comparison with the chain metadata.
+        assert_matches!(
+            narrow_tree_size(grown, ShieldedProtocol::Sapling, at_height),
+            Err(ScanError::OutputCountOverflow {
+                protocol: ShieldedProtocol::Sapling,
+                ..
+            })
+        );
+        assert_eq!(
+            narrow_tree_size(max, ShieldedProtocol::Sapling, at_height).unwrap(),
+            u32::MAX
+        );
+
+        assert_matches!(
+            grow_tree_size(u64::MAX, 1, ShieldedProtocol::Orchard, at_height),
+            Err(ScanError::OutputCountOverflow {
+                protocol: ShieldedProtocol::Orchard,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn scan_block_with_custom_hasher() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static SAPLING_NODES: AtomicUsize = AtomicUsize::new(0);
+
+        struct CountingHasher;
+        impl CommitmentHasher for CountingHasher {
+            fn sapling_node(cmu: &sapling::note::ExtractedNoteCommitment) -> sapling::Node {
+                SAPLING_NODES.fetch_add(1, Ordering::SeqCst);
+                SaplingCommitmentHasher::sapling_node(cmu)
+            }
+        }
+
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+
+        let scanned_block = scan_block_with_config(
+            &network,
+            cb.clone(),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+            ScanOptions::new(ScanConfig::default()).with_hasher::<CountingHasher>(),
+        )
+        .unwrap();
+
+        // A leaf is constructed for each of the Sapling outputs in the block.
+        assert_eq!(SAPLING_NODES.load(Ordering::SeqCst), 3);
+
+        // The result is identical to that of scanning with the default hasher.
+        let expected =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
+        assert_eq!(
+            scanned_block.sapling().commitments(),
+            expected.sapling().commitments()
+        );
+        assert_eq!(
+            scanned_block.transactions()[0].sapling_outputs()[0].note_commitment_tree_position(),
+            expected.transactions()[0].sapling_outputs()[0].note_commitment_tree_position()
+        );
+    }
+
+    #[test]
+    fn marked_ranges_coalesce_adjacent_outputs() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // The block's Sapling outputs, by position, are: a random output; three consecutive
+        // outputs belonging to the wallet; another random output; and a fourth output belonging
+        // to the wallet.
+        let mut rng = OsRng;
+        let height = BlockHeight::from(1);
+        let owned_tx = CompactTx {
+            hash: vec![1; 32],
+            outputs: [5, 6, 7]
+                .into_iter()
+                .map(|value| {
+                    fake_compact_output(
+                        OsRng,
+                        height,
+                        &sapling_dfvk,
+                        NonNegativeAmount::const_from_u64(value),
+                    )
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let later_tx = CompactTx {
+            hash: vec![2; 32],
+            outputs: vec![fake_compact_output(
+                OsRng,
+                height,
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(8),
+            )],
+            ..Default::default()
+        };
+
+        let mut vtx = vec![
+            random_compact_tx(&mut rng),
+            owned_tx,
+            random_compact_tx(&mut rng),
+            later_tx,
+        ];
+        for (i, tx) in vtx.iter_mut().enumerate() {
+            tx.index = i as u64;
+        }
+        let cb = CompactBlock {
+            hash: vec![3; 32],
+            prev_hash: vec![0; 32],
             height: height.into(),
+            vtx,
+            chain_metadata: Some(compact::ChainMetadata {
+                sapling_commitment_tree_size: 6,
+                orchard_commitment_tree_size: 0,
+            }),
             ..Default::default()
         };
 
-        // Add a random Sapling tx before ours
-        {
-            let mut tx = random_compact_tx(&mut rng);
-            tx.index = cb.vtx.len() as u64;
-            cb.vtx.push(tx);
+        let scanned_block =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
+        let sapling = scanned_block.sapling();
+        assert_eq!(
+            sapling.marked_positions(),
+            vec![
+                Position::from(1),
+                Position::from(2),
+                Position::from(3),
+                Position::from(5)
+            ]
+        );
+        assert_eq!(
+            sapling.marked_ranges(),
+            vec![
+                Position::from(1)..Position::from(4),
+                Position::from(5)..Position::from(6)
+            ]
+        );
+
+        // A block without marked commitments has no marked ranges.
+        assert!(ScannedBundles::<sapling::Node, Nullifier>::default()
+            .marked_ranges()
+            .is_empty());
+    }
+
+    #[test]
+    fn diff_scanned_partitions_known_data() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let scanned_block = scan_block(&network, cb, &scanning_keys, &nullifiers, None).unwrap();
+        let txid = scanned_block.transactions()[0].txid();
+        let cmu = scanned_block.transactions()[0].sapling_outputs()[0]
+            .note()
+            .cmu()
+            .to_bytes();
+
+        // Nothing is known, so everything in the block is new.
+        let diff = diff_scanned(&scanned_block, &HashSet::new(), &HashSet::new());
+        assert!(!diff.is_unchanged());
+        assert_eq!(diff.sapling().new_spends().len(), 1);
+        assert_eq!(diff.sapling().new_spends()[0].0, txid);
+        assert_eq!(diff.sapling().new_spends()[0].1.nf(), &nf);
+        assert_eq!(diff.sapling().new_outputs().len(), 1);
+        assert_eq!(diff.sapling().new_outputs()[0].0, txid);
+        assert!(diff.sapling().known_spends().is_empty());
+        assert!(diff.sapling().known_outputs().is_empty());
+
+        // Once the spend is known, only the received note is new.
+        let known_nullifiers = HashSet::from([nf.0]);
+        let diff = diff_scanned(&scanned_block, &known_nullifiers, &HashSet::new());
+        assert!(!diff.is_unchanged());
+        assert!(diff.sapling().new_spends().is_empty());
+        assert_eq!(diff.sapling().known_spends().len(), 1);
+        assert_eq!(diff.sapling().new_outputs().len(), 1);
+
+        // Once the note commitment is also known, the block contains nothing new.
+        let known_commitments = HashSet::from([cmu]);
+        let diff = diff_scanned(&scanned_block, &known_nullifiers, &known_commitments);
+        assert!(diff.is_unchanged());
+        assert_eq!(diff.sapling().known_spends().len(), 1);
+        assert_eq!(diff.sapling().known_outputs().len(), 1);
+    }
+
+    #[test]
+    fn scan_block_tracks_key_hits() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        // A second output to the same key.
+        cb.vtx.last_mut().unwrap().outputs.push(fake_compact_output(
+            OsRng,
+            1u32.into(),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(6),
+        ));
+        cb.chain_metadata
+            .as_mut()
+            .unwrap()
+            .sapling_commitment_tree_size += 1;
+
+        let scanned_block = scan_block_with_config(
+            &network,
+            cb.clone(),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+            ScanConfig {
+                track_key_hits: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            scanned_block.key_hits(),
+            Some(&HashMap::from([(
+                (account, Some(zip32::Scope::External)),
+                2
+            )]))
+        );
+
+        // Key hits are not counted by default.
+        let scanned_block =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
+        assert_eq!(scanned_block.key_hits(), None);
+    }
+
+    #[test]
+    fn decryption_cache_only_tries_new_keys() {
+        let network = Network::TestNetwork;
+        let ufvk = |i: u32| {
+            let account = AccountId::try_from(i).unwrap();
+            let usk =
+                UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account).expect("Valid USK");
+            (account, usk.to_unified_full_viewing_key())
+        };
+        let (new_account, new_ufvk) = ufvk(3);
+        let sapling_dfvk = new_ufvk.sapling().expect("Sapling key is present").clone();
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let scan = |scanning_keys: &ScanningKeys<AccountId, (AccountId, zip32::Scope)>,
+                    cache: &mut DecryptionCache<(AccountId, zip32::Scope)>| {
+            scan_block_with_config(
+                &network,
+                cb.clone(),
+                scanning_keys,
+                &Nullifiers::empty(),
+                None,
+                ScanOptions::new(ScanConfig::default()).with_decryption_cache(cache),
+            )
+            .unwrap()
+        };
+
+        // Neither of the block's outputs can be decrypted with the wallet's three keys.
+        let mut cache = DecryptionCache::new();
+        let old_keys = ScanningKeys::from_account_ufvks((0..3).map(ufvk));
+        assert!(scan(&old_keys, &mut cache).transactions().is_empty());
+        assert_eq!(cache.len(), 2);
+
+        // After a key is added, the rescan detects the output sent to the new key.
+        let new_keys = ScanningKeys::from_account_ufvks((0..4).map(ufvk));
+        let scanned_block = scan(&new_keys, &mut cache);
+        assert_eq!(scanned_block.transactions().len(), 1);
+        assert_eq!(
+            *scanned_block.transactions()[0].sapling_outputs()[0].account_id(),
+            new_account
+        );
+        assert_eq!(cache.len(), 1);
+
+        // Keys with which an output has already been tried are skipped, so replacing the key
+        // for an existing tag requires the cache to be cleared.
+        let mut cache = DecryptionCache::new();
+        let original_keys = ScanningKeys::from_account_ufvks([ufvk(0)]);
+        let replaced_keys = ScanningKeys::from_account_ufvks([(AccountId::ZERO, new_ufvk.clone())]);
+        assert!(scan(&original_keys, &mut cache).transactions().is_empty());
+        assert!(scan(&replaced_keys, &mut cache).transactions().is_empty());
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(scan(&replaced_keys, &mut cache).transactions().len(), 1);
+
+        // Removing a key invalidates the cache, so that all of the remaining keys are tried.
+        let mut cache = DecryptionCache::new();
+        let two_keys = ScanningKeys::from_account_ufvks([ufvk(0), ufvk(1)]);
+        assert!(scan(&two_keys, &mut cache).transactions().is_empty());
+        assert_eq!(scan(&replaced_keys, &mut cache).transactions().len(), 1);
+    }
+
+    #[test]
+    fn scan_block_records_source_id() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        let config = ScanConfig {
+            source_id: Some(42),
+            ..Default::default()
+        };
+
+        let scanned_block = scan_block_with_config(
+            &network,
+            cb.clone(),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+            config,
+        )
+        .unwrap();
+        assert_eq!(scanned_block.source_id(), Some(42));
+
+        let scanned_block = scan_block(
+            &network,
+            cb.clone(),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(scanned_block.source_id(), None);
+
+        // The prior block's metadata claims a Sapling tree size that is inconsistent with the
+        // block's chain metadata, so the source of the block is reported with the error.
+        let prior_block_metadata = BlockMetadata::from_parts(
+            BlockHeight::from(0),
+            BlockHash([0; 32]),
+            Some(5),
+            #[cfg(feature = "orchard")]
+            Some(0),
+        );
+        let err = scan_block_with_config(
+            &network,
+            cb,
+            &scanning_keys,
+            &Nullifiers::empty(),
+            Some(&prior_block_metadata),
+            config,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.source_id(), Some(42));
+        assert_eq!(err.code(), "tree-size-mismatch");
+        assert!(err.is_continuity_error());
+        assert_eq!(err.at_height(), BlockHeight::from(1));
+        assert_matches!(
+            &err,
+            ScanError::FromSource { error, .. }
+                if matches!(**error, ScanError::TreeSizeMismatch { .. })
+        );
+
+        // Reattributing the error replaces the source rather than nesting it.
+        let err = err.with_source(7);
+        assert_eq!(err.source_id(), Some(7));
+        assert_matches!(
+            &err,
+            ScanError::FromSource { error, .. }
+                if matches!(**error, ScanError::TreeSizeMismatch { .. })
+        );
+    }
+
+    #[test]
+    fn scan_block_explain_reports_decryption_outcomes() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let height = BlockHeight::from(1);
+        let mut cb = fake_compact_block(
+            OsRng,
+            height,
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        // An output using the post-ZIP 212 plaintext lead byte, which is not permitted before
+        // Canopy activation.
+        let mut canopy_output = fake_compact_output(
+            OsRng,
+            network.activation_height(NetworkUpgrade::Canopy).unwrap() + 100_000,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+        );
+        // An output whose ephemeral key is not a valid curve point.
+        let mut invalid_epk_output = canopy_output.clone();
+        invalid_epk_output.ephemeral_key = vec![0xff; 32];
+        // The transaction paying the account is the last in the block.
+        let tx = cb.vtx.last_mut().unwrap();
+        tx.outputs.push(canopy_output.clone());
+        tx.outputs.push(invalid_epk_output);
+        // An output that cannot be parsed.
+        canopy_output.cmu.truncate(16);
+        tx.outputs.push(canopy_output);
+        let txid = tx.txid();
+
+        let diagnostics = scan_block_explain(&network, &cb, &scanning_keys);
+        assert_eq!(
+            diagnostics.len(),
+            cb.vtx.iter().map(|tx| tx.outputs.len()).sum::<usize>()
+        );
+
+        // The random output in the first transaction is well-formed but not decryptable.
+        assert_eq!(diagnostics[0].txid(), cb.vtx[0].txid());
+        assert!(diagnostics[0].encoding_valid() && diagnostics[0].epk_valid());
+        assert_eq!(diagnostics[0].decrypted_by(), None);
+        assert!(!diagnostics[0].zip212_rejected());
+
+        let ours = &diagnostics[1..];
+        assert!(ours.iter().all(|d| d.txid() == txid));
+        assert_eq!(
+            ours.iter().map(|d| d.output_index()).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3]
+        );
+        assert_eq!(
+            ours[0].decrypted_by(),
+            Some(&(account, zip32::Scope::External))
+        );
+        assert!(ours[1].epk_valid());
+        assert_eq!(ours[1].decrypted_by(), None);
+        assert!(ours[1].zip212_rejected());
+        assert!(ours[2].encoding_valid() && !ours[2].epk_valid());
+        assert!(!ours[2].zip212_rejected());
+        assert!(!ours[3].encoding_valid());
+        assert_eq!(ours[3].decrypted_by(), None);
+    }
+
+    #[test]
+    fn scan_block_rejects_truncated_ciphertext() {
+        fn go(multithreaded: bool) {
+            let network = Network::TestNetwork;
+            let account = AccountId::ZERO;
+            let TestAccount {
+                sapling_dfvk,
+                scanning_keys,
+                ..
+            } = test_account(&network, account);
+
+            let mut cb = fake_compact_block(
+                OsRng,
+                1u32.into(),
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                Some((0, 0)),
+            );
+            let tx = cb.vtx.last_mut().unwrap();
+            tx.outputs[0].ciphertext.truncate(COMPACT_NOTE_SIZE - 1);
+            let txid = tx.txid();
+
+            // A malformed output is reported as an error, rather than causing a panic in either
+            // the batch runners or the scanner.
+            let mut scanner = BlockScanner::new(
+                network,
+                scanning_keys,
+                Nullifiers::empty(),
+                None,
+                ScanConfig::default(),
+                multithreaded,
+            );
+            assert_matches!(
+                scanner.scan_next(cb),
+                Err(ScanError::EncodingInvalid {
+                    txid: error_txid,
+                    pool_type: ShieldedProtocol::Sapling,
+                    index: 0,
+                    ..
+                }) if error_txid == txid
+            );
         }
 
-        let cspend = CompactSaplingSpend { nf: nf.0.to_vec() };
-        let cout = CompactSaplingOutput {
-            cmu,
-            ephemeral_key,
-            ciphertext: enc_ciphertext.as_ref()[..52].to_vec(),
+        go(false);
+        go(true);
+    }
+
+    #[test]
+    fn scan_block_rejects_missing_pool_metadata() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let scan_after = |prior_height: BlockHeight,
+                          sapling_size: Option<u32>,
+                          _orchard_size: Option<u32>,
+                          initial_tree_sizes: Option<(u32, u32)>| {
+            let cb = fake_compact_block(
+                OsRng,
+                prior_height + 1,
+                BlockHash([0; 32]),
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
+                initial_tree_sizes,
+            );
+            scan_block(
+                &network,
+                cb,
+                &scanning_keys,
+                &Nullifiers::empty(),
+                Some(&BlockMetadata::from_parts(
+                    prior_height,
+                    BlockHash([0; 32]),
+                    sapling_size,
+                    #[cfg(feature = "orchard")]
+                    _orchard_size,
+                )),
+            )
         };
-        let mut ctx = CompactTx::default();
-        let mut txid = vec![0; 32];
-        rng.fill_bytes(&mut txid);
-        ctx.hash = txid;
-        ctx.spends.push(cspend);
-        ctx.outputs.push(cout);
-        ctx.index = cb.vtx.len() as u64;
-        cb.vtx.push(ctx);
 
-        // Optionally add another random Sapling tx after ours
-        if tx_after {
-            let mut tx = random_compact_tx(&mut rng);
-            tx.index = cb.vtx.len() as u64;
-            cb.vtx.push(tx);
+        let sapling_activation = network.activation_height(NetworkUpgrade::Sapling).unwrap();
+        let nu5_activation = network.activation_height(NetworkUpgrade::Nu5).unwrap();
+
+        // A tree size may be omitted for a pool that was not active as of the prior block.
+        assert_matches!(
+            scan_after(sapling_activation - 1, None, None, Some((0, 0))),
+            Ok(_)
+        );
+
+        assert_matches!(
+            scan_after(sapling_activation, None, Some(0), None),
+            Err(ScanError::MissingPoolMetadata {
+                protocol: ShieldedProtocol::Sapling,
+                at_height,
+            }) if at_height == sapling_activation + 1
+        );
+
+        #[cfg(feature = "orchard")]
+        assert_matches!(
+            scan_after(nu5_activation, Some(0), None, None),
+            Err(ScanError::MissingPoolMetadata {
+                protocol: ShieldedProtocol::Orchard,
+                at_height,
+            }) if at_height == nu5_activation + 1
+        );
+        #[cfg(not(feature = "orchard"))]
+        assert_matches!(scan_after(nu5_activation, Some(0), None, None), Ok(_));
+
+        // Prior block metadata recorded before a pool's tree size was tracked may omit it, in
+        // which case the tree size is obtained from the block's chain metadata.
+        assert_matches!(
+            scan_after(sapling_activation, None, Some(0), Some((0, 0))),
+            Ok(_)
+        );
+        #[cfg(feature = "orchard")]
+        {
+            let scanned = scan_after(nu5_activation, Some(0), None, Some((0, 0)))
+                .expect("Chain metadata provides the missing tree size");
+            assert_eq!(scanned.orchard().final_tree_size(), 0);
         }
+    }
 
-        cb.chain_metadata =
-            initial_tree_sizes.map(|(initial_sapling_tree_size, initial_orchard_tree_size)| {
-                compact::ChainMetadata {
-                    sapling_commitment_tree_size: initial_sapling_tree_size
-                        + cb.vtx.iter().map(|tx| tx.outputs.len() as u32).sum::<u32>(),
-                    orchard_commitment_tree_size: initial_orchard_tree_size
-                        + cb.vtx.iter().map(|tx| tx.actions.len() as u32).sum::<u32>(),
-                }
-            });
+    #[test]
+    fn scan_block_detects_duplicate_tracked_nullifiers() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let other_account = AccountId::try_from(1).unwrap();
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // The same nullifier is tracked for two accounts.
+        let nf = Nullifier([7; 32]);
+        let nullifiers = Nullifiers::new(
+            vec![(account, nf), (other_account, nf)],
+            #[cfg(feature = "orchard")]
+            vec![],
+        );
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            nf,
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        // By default, the spend is attributed to the first matching account.
+        let scanned_block =
+            scan_block(&network, cb.clone(), &scanning_keys, &nullifiers, None).unwrap();
+        let spends = scanned_block.transactions()[0].sapling_spends();
+        assert_eq!(spends.len(), 1);
+        assert_eq!(spends[0].account_id(), &account);
+
+        assert_matches!(
+            scan_block_with_config(
+                &network,
+                cb,
+                &scanning_keys,
+                &nullifiers,
+                None,
+                ScanConfig {
+                    reject_duplicate_nullifiers: true,
+                    ..Default::default()
+                },
+            )
+            .err(),
+            Some(ScanError::DuplicateTrackedNullifier {
+                protocol: ShieldedProtocol::Sapling,
+                index: 0,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn scan_block_rejects_stale_prior_tree_size() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // The block's chain metadata implies a starting Sapling tree size of zero, but the prior
+        // block's metadata claims a tree size of 5.
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+        cb.time = 1_700_000_000;
+        let prior_block_metadata = BlockMetadata::from_parts(
+            BlockHeight::from(0),
+            BlockHash([0; 32]),
+            Some(5),
+            #[cfg(feature = "orchard")]
+            Some(0),
+        );
 
-        cb
+        assert_matches!(
+            scan_block(
+                &network,
+                cb.clone(),
+                &scanning_keys,
+                &Nullifiers::empty(),
+                Some(&prior_block_metadata),
+            ),
+            Err(ScanError::TreeSizeMismatch {
+                protocol: ShieldedProtocol::Sapling,
+                given: 2,
+                computed: 7,
+                ..
+            })
+        );
+
+        // The detailed variant identifies the block that failed to scan.
+        let failure = scan_block_detailed(
+            &network,
+            cb.clone(),
+            &scanning_keys,
+            &Nullifiers::empty(),
+            Some(&prior_block_metadata),
+            ScanConfig::default(),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(failure.block_height(), BlockHeight::from(1));
+        assert_eq!(failure.block_hash(), cb.hash());
+        assert_eq!(failure.block_time(), 1_700_000_000);
+        assert_matches!(failure.error(), ScanError::TreeSizeMismatch { .. });
     }
 
     #[test]
-    fn scan_block_with_my_tx() {
-        fn go(scan_multithreaded: bool) {
-            let network = Network::TestNetwork;
-            let account = AccountId::ZERO;
-            let usk =
-                UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account).expect("Valid USK");
-            let ufvk = usk.to_unified_full_viewing_key();
-            let sapling_dfvk = ufvk.sapling().expect("Sapling key is present").clone();
-            let scanning_keys = ScanningKeys::from_account_ufvks([(account, ufvk)]);
-
-            let cb = fake_compact_block(
-                1u32.into(),
-                BlockHash([0; 32]),
+    fn validate_chain_checks_continuity() {
+        let network = Network::TestNetwork;
+        let usk = UnifiedSpendingKey::from_seed(&network, &[0u8; 32], AccountId::ZERO)
+            .expect("Valid USK");
+        let sapling_dfvk = usk
+            .to_unified_full_viewing_key()
+            .sapling()
+            .expect("Sapling key is present")
+            .clone();
+        let block = |height: u32, prev_hash: BlockHash| {
+            fake_compact_block(
+                OsRng,
+                height.into(),
+                prev_hash,
                 Nullifier([0; 32]),
                 &sapling_dfvk,
                 NonNegativeAmount::const_from_u64(5),
                 false,
                 None,
-            );
-            assert_eq!(cb.vtx.len(), 2);
+            )
+        };
 
-            let mut batch_runners = if scan_multithreaded {
-                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
-                runners
-                    .add_block(&Network::TestNetwork, cb.clone())
-                    .unwrap();
-                runners.flush();
+        let prior = BlockMetadata::from_parts(
+            BlockHeight::from(1),
+            BlockHash([1; 32]),
+            Some(0),
+            #[cfg(feature = "orchard")]
+            Some(0),
+        );
+        let cb2 = block(2, BlockHash([1; 32]));
+        let cb3 = block(3, cb2.hash());
+        let cb4 = block(4, cb3.hash());
+
+        assert_matches!(validate_chain(&[], None), Ok(()));
+        assert_matches!(
+            validate_chain(&[cb2.clone(), cb3.clone(), cb4.clone()], Some(&prior)),
+            Ok(())
+        );
 
-                Some(runners)
-            } else {
-                None
-            };
+        // The first block must follow the prior block.
+        assert_matches!(
+            validate_chain(&[cb3.clone(), cb4.clone()], Some(&prior)),
+            Err(ScanError::BlockHeightDiscontinuity { new_height, .. }) if new_height == BlockHeight::from(3)
+        );
 
-            let scanned_block = scan_block_with_runners(
-                &network,
-                cb,
-                &scanning_keys,
-                &Nullifiers::empty(),
-                Some(&BlockMetadata::from_parts(
-                    BlockHeight::from(0),
-                    BlockHash([0u8; 32]),
-                    Some(0),
-                    #[cfg(feature = "orchard")]
-                    Some(0),
-                )),
-                batch_runners.as_mut(),
-            )
-            .unwrap();
-            let txs = scanned_block.transactions();
-            assert_eq!(txs.len(), 1);
+        // Heights must increase by one.
+        assert_matches!(
+            validate_chain(&[cb2.clone(), cb4.clone()], None),
+            Err(ScanError::BlockHeightDiscontinuity { prev_height, new_height })
+                if prev_height == BlockHeight::from(2) && new_height == BlockHeight::from(4)
+        );
 
-            let tx = &txs[0];
-            assert_eq!(tx.block_index(), 1);
-            assert_eq!(tx.sapling_spends().len(), 0);
-            assert_eq!(tx.sapling_outputs().len(), 1);
-            assert_eq!(tx.sapling_outputs()[0].index(), 0);
-            assert_eq!(tx.sapling_outputs()[0].account_id(), &account);
-            assert_eq!(tx.sapling_outputs()[0].note().value().inner(), 5);
-            assert_eq!(
-                tx.sapling_outputs()[0].note_commitment_tree_position(),
-                Position::from(1)
-            );
+        // Each block must commit to the hash of the block before it.
+        let unlinked = block(4, BlockHash([9; 32]));
+        assert_matches!(
+            validate_chain(&[cb2, cb3, unlinked], None),
+            Err(ScanError::PrevHashMismatch { at_height }) if at_height == BlockHeight::from(4)
+        );
+    }
 
-            assert_eq!(scanned_block.sapling().final_tree_size(), 2);
-            assert_eq!(
-                scanned_block
-                    .sapling()
-                    .commitments()
-                    .iter()
-                    .map(|(_, retention)| *retention)
-                    .collect::<Vec<_>>(),
-                vec![
-                    Retention::Ephemeral,
-                    Retention::Checkpoint {
-                        id: scanned_block.height(),
-                        is_marked: true
-                    }
-                ]
-            );
+    #[test]
+    fn scan_block_rejects_inconsistent_activation_heights() {
+        #[derive(Clone)]
+        struct MisorderedNetwork;
+
+        impl Parameters for MisorderedNetwork {
+            fn network_type(&self) -> NetworkType {
+                NetworkType::Regtest
+            }
+
+            fn activation_height(&self, nu: NetworkUpgrade) -> Option<BlockHeight> {
+                match nu {
+                    NetworkUpgrade::Sapling => Some(BlockHeight::from(100)),
+                    NetworkUpgrade::Nu5 => Some(BlockHeight::from(10)),
+                    _ => None,
+                }
+            }
         }
 
-        go(false);
-        go(true);
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            false,
+            Some((0, 0)),
+        );
+
+        let err = scan_block(
+            &MisorderedNetwork,
+            cb,
+            &scanning_keys,
+            &Nullifiers::empty(),
+            None,
+        )
+        .unwrap_err();
+        assert_matches!(
+            err,
+            ScanError::InconsistentActivationHeights {
+                sapling_activation: Some(sapling),
+                nu5_activation,
+                ..
+            } if sapling == BlockHeight::from(100) && nu5_activation == BlockHeight::from(10)
+        );
+        assert_eq!(err.code(), "inconsistent-activation-heights");
     }
 
     #[test]
-    fn scan_block_with_txs_after_my_tx() {
-        fn go(scan_multithreaded: bool) {
-            let network = Network::TestNetwork;
-            let account = AccountId::ZERO;
-            let usk =
-                UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account).expect("Valid USK");
-            let ufvk = usk.to_unified_full_viewing_key();
-            let sapling_dfvk = ufvk.sapling().expect("Sapling key is present").clone();
-            let scanning_keys = ScanningKeys::from_account_ufvks([(account, ufvk)]);
-
-            let cb = fake_compact_block(
+    fn scan_tx_range_preserves_positions() {
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            sapling_dfvk,
+            scanning_keys,
+            ..
+        } = test_account(&network, account);
+
+        // The block contains a random transaction, a transaction paying the wallet, another
+        // random transaction, and a second transaction paying the wallet.
+        let mut cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            None,
+        );
+        let mut txid = vec![0; 32];
+        OsRng.fill_bytes(&mut txid);
+        cb.vtx.push(CompactTx {
+            index: cb.vtx.len() as u64,
+            hash: txid,
+            outputs: vec![fake_compact_output(
+                OsRng,
                 1u32.into(),
-                BlockHash([0; 32]),
-                Nullifier([0; 32]),
                 &sapling_dfvk,
-                NonNegativeAmount::const_from_u64(5),
-                true,
-                Some((0, 0)),
+                NonNegativeAmount::const_from_u64(7),
+            )],
+            ..Default::default()
+        });
+        cb.chain_metadata = Some(compact::ChainMetadata {
+            sapling_commitment_tree_size: 10 + block_tree_growth(&cb).0,
+            orchard_commitment_tree_size: 0,
+        });
+        let prior_block_metadata = BlockMetadata::from_parts(
+            BlockHeight::from(0),
+            BlockHash([0; 32]),
+            Some(10),
+            #[cfg(feature = "orchard")]
+            Some(0),
+        );
+
+        let positions = |range: Option<std::ops::Range<usize>>| {
+            let scanned_block = match range {
+                Some(range) => scan_block_with_config(
+                    &network,
+                    cb.clone(),
+                    &scanning_keys,
+                    &Nullifiers::empty(),
+                    Some(&prior_block_metadata),
+                    ScanOptions::new(ScanConfig::default()).with_tx_range(range),
+                ),
+                None => scan_block(
+                    &network,
+                    cb.clone(),
+                    &scanning_keys,
+                    &Nullifiers::empty(),
+                    Some(&prior_block_metadata),
+                ),
+            }
+            .unwrap();
+            assert_contiguous_positions(&scanned_block);
+
+            // Every note commitment in the block is reported, regardless of the range.
+            assert_eq!(
+                scanned_block.sapling().commitments().len(),
+                cb.vtx.iter().map(|tx| tx.outputs.len()).sum::<usize>()
             );
-            assert_eq!(cb.vtx.len(), 3);
 
-            let mut batch_runners = if scan_multithreaded {
-                let mut runners = BatchRunners::<_, (), ()>::for_keys(10, &scanning_keys);
-                runners
-                    .add_block(&Network::TestNetwork, cb.clone())
-                    .unwrap();
-                runners.flush();
+            scanned_block
+                .transactions()
+                .iter()
+                .flat_map(|tx| {
+                    tx.sapling_outputs().iter().map(move |output| {
+                        (tx.block_index(), output.note_commitment_tree_position())
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
 
-                Some(runners)
-            } else {
-                None
-            };
+        let full = positions(None);
+        assert_eq!(full.len(), 2);
 
-            let scanned_block = scan_block_with_runners(
-                &network,
-                cb,
-                &scanning_keys,
-                &Nullifiers::empty(),
+        // Scanning only the last transaction finds the note at the same position.
+        assert_eq!(positions(Some(3..4)), full[1..].to_vec());
+        // Scanning only the first two transactions finds just the first note.
+        assert_eq!(positions(Some(0..2)), full[..1].to_vec());
+        // Scanning a range containing none of the wallet's transactions finds nothing.
+        assert_eq!(positions(Some(2..3)), vec![]);
+    }
+
+    #[test]
+    fn detect_reorg_limits_rewind_depth() {
+        let network = Network::TestNetwork;
+        let usk = UnifiedSpendingKey::from_seed(&network, &[0u8; 32], AccountId::ZERO)
+            .expect("Valid USK");
+        let sapling_dfvk = usk
+            .to_unified_full_viewing_key()
+            .sapling()
+            .expect("Sapling key is present")
+            .clone();
+        let block = |height: u32, prev_hash: BlockHash| {
+            fake_compact_block(
+                OsRng,
+                height.into(),
+                prev_hash,
+                Nullifier([0; 32]),
+                &sapling_dfvk,
+                NonNegativeAmount::const_from_u64(5),
+                false,
                 None,
-                batch_runners.as_mut(),
             )
-            .unwrap();
-            let txs = scanned_block.transactions();
-            assert_eq!(txs.len(), 1);
+        };
 
-            let tx = &txs[0];
-            assert_eq!(tx.block_index(), 1);
-            assert_eq!(tx.sapling_spends().len(), 0);
-            assert_eq!(tx.sapling_outputs().len(), 1);
-            assert_eq!(tx.sapling_outputs()[0].index(), 0);
-            assert_eq!(tx.sapling_outputs()[0].account_id(), &AccountId::ZERO);
-            assert_eq!(tx.sapling_outputs()[0].note().value().inner(), 5);
+        // The wallet has scanned blocks 10 through 14.
+        let recent_blocks = (10u8..15)
+            .map(|h| {
+                BlockMetadata::from_parts(
+                    BlockHeight::from(u32::from(h)),
+                    BlockHash([h; 32]),
+                    Some(0),
+                    #[cfg(feature = "orchard")]
+                    Some(0),
+                )
+            })
+            .collect::<Vec<_>>();
 
-            assert_eq!(
-                scanned_block
-                    .sapling()
-                    .commitments()
-                    .iter()
-                    .map(|(_, retention)| *retention)
-                    .collect::<Vec<_>>(),
-                vec![
-                    Retention::Ephemeral,
-                    Retention::Marked,
-                    Retention::Checkpoint {
-                        id: scanned_block.height(),
-                        is_marked: false
-                    }
-                ]
-            );
-        }
+        assert_matches!(
+            detect_reorg(&block(15, BlockHash([14; 32])), &[], 0),
+            Ok(())
+        );
 
-        go(false);
-        go(true);
+        // A block following the chain tip requires no rewinding.
+        assert_matches!(
+            detect_reorg(&block(15, BlockHash([14; 32])), &recent_blocks, 0),
+            Ok(())
+        );
+
+        // A block forking from block 12 requires blocks 13 and 14 to be rewound.
+        let fork = block(13, BlockHash([12; 32]));
+        assert_matches!(detect_reorg(&fork, &recent_blocks, 2), Ok(()));
+        assert_matches!(
+            detect_reorg(&fork, &recent_blocks, 1),
+            Err(ScanError::ReorgTooDeep { depth: 2, .. })
+        );
+
+        // A block that does not connect to block 12 implies a fork point below it.
+        let unconnected = block(13, BlockHash([0xff; 32]));
+        assert_matches!(
+            detect_reorg(&unconnected, &recent_blocks, 5),
+            Err(ScanError::PrevHashMismatch { .. })
+        );
+        assert_matches!(
+            detect_reorg(&unconnected, &recent_blocks, 2),
+            Err(ScanError::ReorgTooDeep { depth: 3, .. })
+        );
+
+        assert_matches!(
+            detect_reorg(&block(16, BlockHash([15; 32])), &recent_blocks, 10),
+            Err(ScanError::BlockHeightDiscontinuity { .. })
+        );
     }
 
     #[test]
-    fn scan_block_with_my_spend() {
+    fn block_tree_growth_counts_outputs() {
         let network = Network::TestNetwork;
-        let account = AccountId::try_from(12).unwrap();
-        let usk = UnifiedSpendingKey::from_seed(&network, &[0u8; 32], account).expect("Valid USK");
-        let ufvk = usk.to_unified_full_viewing_key();
-        let scanning_keys = ScanningKeys::<AccountId, Infallible>::empty();
+        let account = AccountId::ZERO;
+        let TestAccount { sapling_dfvk, .. } = test_account(&network, account);
 
-        let nf = Nullifier([7; 32]);
-        let nullifiers = Nullifiers::new(
-            vec![(account, nf)],
-            #[cfg(feature = "orchard")]
-            vec![],
+        let cb = fake_compact_block(
+            OsRng,
+            1u32.into(),
+            BlockHash([0; 32]),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
+            NonNegativeAmount::const_from_u64(5),
+            true,
+            Some((0, 0)),
+        );
+        assert_eq!(block_tree_growth(&cb), (3, 0));
+        assert_eq!(
+            cb.chain_metadata
+                .as_ref()
+                .map(|m| m.sapling_commitment_tree_size),
+            Some(3)
         );
+    }
 
-        let cb = fake_compact_block(
+    #[test]
+    #[cfg(feature = "transparent-inputs")]
+    fn scan_block_with_transparent_outputs() {
+        use zcash_primitives::legacy::TransparentAddress;
+
+        let network = Network::TestNetwork;
+        let account = AccountId::ZERO;
+        let TestAccount {
+            ufvk, sapling_dfvk, ..
+        } = test_account(&network, account);
+
+        let taddr = TransparentAddress::PublicKeyHash([7; 20]);
+        let other_taddr = TransparentAddress::PublicKeyHash([8; 20]);
+        let scanning_keys =
+            ScanningKeys::from_account_ufvks([(account, ufvk)]).with_transparent_addresses([taddr]);
+
+        let mut cb = fake_compact_block(
+            OsRng,
             1u32.into(),
             BlockHash([0; 32]),
-            nf,
-            ufvk.sapling().unwrap(),
+            Nullifier([0; 32]),
+            &sapling_dfvk,
             NonNegativeAmount::const_from_u64(5),
             false,
             Some((0, 0)),
         );
-        assert_eq!(cb.vtx.len(), 2);
+        // The first (random) transaction pays to the wallet's transparent address, along with
+        // an address that is not being tracked.
+        for addr in [other_taddr, taddr] {
+            cb.vtx[0].vout.push(compact::TxOut {
+                value: 10000,
+                script_pub_key: addr.script().0,
+            });
+        }
+        let txid = cb.vtx[0].txid();
 
-        let scanned_block = scan_block(&network, cb, &scanning_keys, &nullifiers, None).unwrap();
+        let scanned_block =
+            scan_block(&network, cb, &scanning_keys, &Nullifiers::empty(), None).unwrap();
         let txs = scanned_block.transactions();
-        assert_eq!(txs.len(), 1);
+        assert_eq!(txs.len(), 2);
 
         let tx = &txs[0];
-        assert_eq!(tx.block_index(), 1);
-        assert_eq!(tx.sapling_spends().len(), 1);
+        assert_eq!(tx.txid(), txid);
         assert_eq!(tx.sapling_outputs().len(), 0);
-        assert_eq!(tx.sapling_spends()[0].index(), 0);
-        assert_eq!(tx.sapling_spends()[0].nf(), &nf);
-        assert_eq!(tx.sapling_spends()[0].account_id(), &account);
+        assert_eq!(tx.transparent_outputs().len(), 1);
 
-        assert_eq!(
-            scanned_block
-                .sapling()
-                .commitments()
-                .iter()
-                .map(|(_, retention)| *retention)
-                .collect::<Vec<_>>(),
-            vec![
-                Retention::Ephemeral,
-                Retention::Checkpoint {
-                    id: scanned_block.height(),
-                    is_marked: false
-                }
-            ]
-        );
+        let output = &tx.transparent_outputs()[0];
+        assert_eq!(output.recipient_address(), &taddr);
+        assert_eq!(output.outpoint().n(), 1);
+        assert_eq!(output.height(), BlockHeight::from(1));
+        assert_eq!(output.value(), NonNegativeAmount::const_from_u64(10000));
+
+        assert_eq!(txs[1].transparent_outputs().len(), 0);
     }
 }
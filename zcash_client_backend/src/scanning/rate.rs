@@ -0,0 +1,187 @@
+//! Estimation of the time required to complete a scan from recent scanning throughput.
+
+use std::time::Duration;
+
+/// Estimates the time remaining to scan a number of blocks, based on the throughput observed
+/// while scanning previous blocks.
+///
+/// The estimator maintains an exponential moving average of the time taken to scan a single
+/// block. Each sample recorded via [`ScanRateEstimator::record`] contributes to the average in
+/// proportion to the smoothing factor, so that the estimate tracks changes in throughput (for
+/// example, as the density of shielded outputs in the chain changes) without being dominated
+/// by the most recent sample.
+///
+/// The time taken to scan a block varies greatly with the number of outputs it contains. To
+/// prevent a single unusually large or empty range of blocks from distorting the estimate, the
+/// per-block time of each sample is clamped to within a fixed factor of the current average
+/// before it is incorporated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanRateEstimator {
+    smoothing_factor: f64,
+    outlier_factor: f64,
+    secs_per_block: Option<f64>,
+}
+
+impl ScanRateEstimator {
+    /// The default weight given to each new sample; see [`ScanRateEstimator::new`].
+    pub const DEFAULT_SMOOTHING_FACTOR: f64 = 0.2;
+
+    /// The default bound on the influence of outlier samples; see [`ScanRateEstimator::new`].
+    pub const DEFAULT_OUTLIER_FACTOR: f64 = 4.0;
+
+    /// Constructs a new estimator that has not yet recorded any samples.
+    ///
+    /// `smoothing_factor` is the weight given to each new sample relative to the current
+    /// average, and must be in the range `(0, 1]`. `outlier_factor` bounds the influence of
+    /// each sample: a sample's per-block time is clamped to between the current average divided
+    /// by this factor and the current average multiplied by it. It must be at least 1.
+    ///
+    /// Returns `None` if either parameter is out of range.
+    pub fn new(smoothing_factor: f64, outlier_factor: f64) -> Option<Self> {
+        (smoothing_factor > 0.0 && smoothing_factor <= 1.0 && outlier_factor >= 1.0).then_some(
+            ScanRateEstimator {
+                smoothing_factor,
+                outlier_factor,
+                secs_per_block: None,
+            },
+        )
+    }
+
+    /// Records that `blocks_scanned` blocks were scanned in the given duration.
+    ///
+    /// Samples in which no blocks were scanned are ignored.
+    pub fn record(&mut self, blocks_scanned: usize, duration: Duration) {
+        if blocks_scanned == 0 {
+            return;
+        }
+
+        let sample = duration.as_secs_f64() / blocks_scanned as f64;
+        self.secs_per_block = Some(match self.secs_per_block {
+            // The first sample is taken as-is, as there is no average against which to judge it.
+            // This is also the case if all samples so far have taken no measurable time.
+            None => sample,
+            Some(average) if average == 0.0 => sample,
+            Some(average) => {
+                let clamped =
+                    sample.clamp(average / self.outlier_factor, average * self.outlier_factor);
+                self.smoothing_factor * clamped + (1.0 - self.smoothing_factor) * average
+            }
+        });
+    }
+
+    /// Returns the current estimate of the time taken to scan a single block, or `None` if no
+    /// samples have been recorded.
+    pub fn time_per_block(&self) -> Option<Duration> {
+        self.secs_per_block.map(duration_from_secs)
+    }
+
+    /// Returns the estimated time required to scan `blocks_left` blocks at the current rate, or
+    /// `None` if no samples have been recorded.
+    pub fn estimated_remaining(&self, blocks_left: usize) -> Option<Duration> {
+        self.secs_per_block
+            .map(|secs| duration_from_secs(secs * blocks_left as f64))
+    }
+
+    /// Discards all recorded samples, for example when scanning resumes after an interruption
+    /// during which the throughput may have changed.
+    pub fn reset(&mut self) {
+        self.secs_per_block = None;
+    }
+}
+
+impl Default for ScanRateEstimator {
+    fn default() -> Self {
+        ScanRateEstimator {
+            smoothing_factor: Self::DEFAULT_SMOOTHING_FACTOR,
+            outlier_factor: Self::DEFAULT_OUTLIER_FACTOR,
+            secs_per_block: None,
+        }
+    }
+}
+
+/// Converts a non-negative number of seconds to a [`Duration`], saturating at
+/// [`Duration::MAX`].
+fn duration_from_secs(secs: f64) -> Duration {
+    if secs.is_finite() && secs < u64::MAX as f64 {
+        Duration::from_secs_f64(secs.max(0.0))
+    } else {
+        Duration::MAX
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ScanRateEstimator;
+
+    fn assert_close(actual: Option<Duration>, expected_secs: f64) {
+        let actual = actual.expect("An estimate is available").as_secs_f64();
+        assert!(
+            (actual - expected_secs).abs() < 1e-6,
+            "expected {}s, got {}s",
+            expected_secs,
+            actual
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert!(ScanRateEstimator::new(0.0, 4.0).is_none());
+        assert!(ScanRateEstimator::new(1.5, 4.0).is_none());
+        assert!(ScanRateEstimator::new(0.5, 0.5).is_none());
+        assert!(ScanRateEstimator::new(1.0, 1.0).is_some());
+    }
+
+    #[test]
+    fn estimates_from_steady_throughput() {
+        let mut estimator = ScanRateEstimator::default();
+        assert_eq!(estimator.estimated_remaining(100), None);
+
+        // Empty samples are ignored.
+        estimator.record(0, Duration::from_secs(10));
+        assert_eq!(estimator.time_per_block(), None);
+
+        for _ in 0..10 {
+            estimator.record(100, Duration::from_secs(2));
+        }
+        assert_close(estimator.time_per_block(), 0.02);
+        assert_close(estimator.estimated_remaining(12_000), 240.0);
+        assert_eq!(estimator.estimated_remaining(0), Some(Duration::ZERO));
+
+        estimator.reset();
+        assert_eq!(estimator.estimated_remaining(100), None);
+    }
+
+    #[test]
+    fn clamps_outlier_samples() {
+        let mut estimator = ScanRateEstimator::new(0.5, 2.0).unwrap();
+        estimator.record(10, Duration::from_secs(10));
+        assert_close(estimator.time_per_block(), 1.0);
+
+        // A block that took 100 seconds to scan is treated as though it took 2 seconds.
+        estimator.record(1, Duration::from_secs(100));
+        assert_close(estimator.time_per_block(), 1.5);
+
+        // A range of blocks that took no time to scan is treated as though each block took
+        // half of the average time.
+        estimator.record(1000, Duration::ZERO);
+        assert_close(estimator.time_per_block(), 1.125);
+
+        // The estimate converges on a sustained change in throughput.
+        for _ in 0..50 {
+            estimator.record(1, Duration::from_secs(5));
+        }
+        assert_close(estimator.time_per_block(), 5.0);
+    }
+
+    #[test]
+    fn saturates_large_estimates() {
+        let mut estimator = ScanRateEstimator::default();
+        estimator.record(1, Duration::MAX);
+        assert_eq!(
+            estimator.estimated_remaining(usize::MAX),
+            Some(Duration::MAX)
+        );
+    }
+}
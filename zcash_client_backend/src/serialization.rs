@@ -1 +1,2 @@
+pub mod scanned_block;
 pub mod shardtree;
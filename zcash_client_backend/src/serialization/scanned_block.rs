@@ -0,0 +1,950 @@
+//! Serialization of the results of scanning a block.
+//!
+//! This allows a [`ScannedBlock`] to be cached, or to be transferred from the device that
+//! scanned it to a different device that persists the results to its wallet database. All
+//! integers are encoded in little-endian byte order. Vectors and optional values are encoded
+//! as by [`Vector`] and [`Optional`], and account identifiers are encoded as by their
+//! [`AccountIdSer`] implementation.
+//!
+//! A [`ScannedBlock`] is encoded as:
+//! - a 1-byte format version (currently 2);
+//! - a 1-byte set of flags recording which feature-dependent components are present:
+//!   `0x01` for Orchard data (the `orchard` feature) and `0x02` for transparent outputs (the
+//!   `transparent-inputs` feature). Data that does not match the features enabled for the
+//!   reader is rejected;
+//! - the block height (`u32`), block hash (32 bytes), and block time (`u32`);
+//! - the ZIP 212 enforcement state (`u8`: 0 = off, 1 = grace period, 2 = on);
+//! - a vector of [`WalletTx`]s;
+//! - the Sapling [`ScannedBundles`], followed by the Orchard [`ScannedBundles`] if present;
+//! - the optional identifier of the source of the block (`u64`);
+//! - the optional key hit counts (see [`ScannedBlock::key_hits`]), as a vector of entries
+//!   each consisting of the account ID, the key scope (`u8`, encoded as for a shielded
+//!   output's recipient key scope), and the count (`u32`). Entries are ordered by their
+//!   encodings, so that the encoding is deterministic.
+//!
+//! Version 1 of the format is identical, except that it ends after the [`ScannedBundles`];
+//! blocks read from it have neither a source identifier nor key hit counts.
+//!
+//! A [`ScannedBundles`] is encoded as the final note commitment tree size (`u32`), a vector of
+//! note commitments each followed by its retention, and a vector of nullifier map entries.
+//! Retention is encoded as a `u8` tag: 0 = ephemeral; 1 = checkpoint, followed by the
+//! checkpoint height (`u32`) and whether the commitment is marked (`u8`); 2 = marked. Each
+//! nullifier map entry is encoded as the txid, the transaction's index in the block (`u16`),
+//! and a vector of (`u32` index, 32-byte nullifier) pairs.
+//!
+//! A [`WalletTx`] is encoded as its txid, its index in the block (`u32`), vectors of its
+//! Sapling spends and outputs, vectors of its Orchard spends and outputs (if present), a
+//! vector of its transparent outputs followed by whether it is a shielding transaction (`u8`)
+//! (if present), its computed fee as an optional `u64`, its
+//! number of logical actions (`u32`), and its total number of shielded outputs (`u32`).
+//! - A spend is encoded as its index (`u32`), its 32-byte nullifier, and its account ID.
+//! - A shielded output is encoded as its index (`u32`), its ephemeral key (32 bytes), the
+//!   note, a `u8` of flags (`0x01` = change, `0x02` = below the dust threshold), the note
+//!   commitment tree position (`u64`), the optional 32-byte nullifier, the account ID, and
+//!   the recipient key scope (`u8`: 0 = unknown, 1 = external, 2 = internal).
+//! - A Sapling note is encoded as the 43-byte recipient address, the value (`u64`), and the
+//!   rseed as a `u8` tag (1 = pre-ZIP 212 `rcm`, 2 = post-ZIP 212 seed) followed by 32 bytes.
+//! - An Orchard note is encoded as the 43-byte raw recipient address, the value (`u64`), the
+//!   32-byte `rho`, and the 32-byte rseed.
+//! - A transparent output is encoded as its outpoint, the output itself, and the height at
+//!   which it was mined (`u32`).
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use group::ff::PrimeField;
+use incrementalmerkletree::{Position, Retention};
+use sapling::{note_encryption::Zip212Enforcement, Rseed};
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use zcash_encoding::{Optional, Vector};
+use zcash_note_encryption::EphemeralKeyBytes;
+use zcash_primitives::{
+    block::BlockHash,
+    consensus::BlockHeight,
+    merkle_tree::HashSer,
+    transaction::{components::amount::NonNegativeAmount, TxId},
+};
+
+use crate::{
+    data_api::{ScannedBlock, ScannedBundles},
+    wallet::{WalletOutput, WalletSpend, WalletTx},
+};
+
+#[cfg(feature = "orchard")]
+use orchard::tree::MerkleHashOrchard;
+
+#[cfg(feature = "transparent-inputs")]
+use {
+    crate::wallet::WalletTransparentOutput,
+    zcash_primitives::transaction::components::{OutPoint, TxOut},
+};
+
+const SER_V1: u8 = 1;
+const SER_V2: u8 = 2;
+
+const FLAG_ORCHARD: u8 = 0b01;
+const FLAG_TRANSPARENT: u8 = 0b10;
+
+const RETENTION_EPHEMERAL: u8 = 0;
+const RETENTION_CHECKPOINT: u8 = 1;
+const RETENTION_MARKED: u8 = 2;
+
+const RSEED_BEFORE_ZIP212: u8 = 1;
+const RSEED_AFTER_ZIP212: u8 = 2;
+
+const OUTPUT_FLAG_CHANGE: u8 = 0b01;
+const OUTPUT_FLAG_DUST: u8 = 0b10;
+
+/// The set of feature-dependent components that are included in the serialized form of a
+/// [`ScannedBlock`] by this build.
+const SUPPORTED_FLAGS: u8 = {
+    let mut flags = 0;
+    if cfg!(feature = "orchard") {
+        flags |= FLAG_ORCHARD;
+    }
+    if cfg!(feature = "transparent-inputs") {
+        flags |= FLAG_TRANSPARENT;
+    }
+    flags
+};
+
+/// A trait for account identifiers that can be serialized as part of a [`ScannedBlock`].
+pub trait AccountIdSer: Sized {
+    /// Reads an account identifier from the provided [`Read`] instance.
+    fn read<R: Read>(reader: R) -> io::Result<Self>;
+
+    /// Writes this account identifier to the provided [`Write`] instance.
+    fn write<W: Write>(&self, writer: W) -> io::Result<()>;
+}
+
+impl AccountIdSer for zip32::AccountId {
+    fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let account = reader.read_u32::<LittleEndian>()?;
+        zip32::AccountId::try_from(account)
+            .map_err(|_| invalid_data(format!("Account index {} is out of range", account)))
+    }
+
+    fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(u32::from(*self))
+    }
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_index<R: Read>(mut reader: R) -> io::Result<usize> {
+    reader
+        .read_u32::<LittleEndian>()
+        .map(|index| index as usize)
+}
+
+fn write_index<W: Write>(mut writer: W, index: usize) -> io::Result<()> {
+    let index = u32::try_from(index)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Index out of range"))?;
+    writer.write_u32::<LittleEndian>(index)
+}
+
+fn read_bytes<R: Read, const N: usize>(mut reader: R) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_height<R: Read>(mut reader: R) -> io::Result<BlockHeight> {
+    reader.read_u32::<LittleEndian>().map(BlockHeight::from)
+}
+
+fn read_retention<R: Read>(mut reader: R) -> io::Result<Retention<BlockHeight>> {
+    match reader.read_u8()? {
+        RETENTION_EPHEMERAL => Ok(Retention::Ephemeral),
+        RETENTION_CHECKPOINT => {
+            let id = read_height(&mut reader)?;
+            let is_marked = match reader.read_u8()? {
+                0 => false,
+                1 => true,
+                other => {
+                    return Err(invalid_data(format!(
+                        "Checkpoint marking flag not recognized: {}",
+                        other
+                    )))
+                }
+            };
+            Ok(Retention::Checkpoint { id, is_marked })
+        }
+        RETENTION_MARKED => Ok(Retention::Marked),
+        other => Err(invalid_data(format!(
+            "Retention tag not recognized: {}",
+            other
+        ))),
+    }
+}
+
+fn write_retention<W: Write>(mut writer: W, retention: &Retention<BlockHeight>) -> io::Result<()> {
+    match retention {
+        Retention::Ephemeral => writer.write_u8(RETENTION_EPHEMERAL),
+        Retention::Checkpoint { id, is_marked } => {
+            writer.write_u8(RETENTION_CHECKPOINT)?;
+            writer.write_u32::<LittleEndian>(u32::from(*id))?;
+            writer.write_u8(u8::from(*is_marked))
+        }
+        Retention::Marked => writer.write_u8(RETENTION_MARKED),
+    }
+}
+
+fn read_scope<R: Read>(mut reader: R) -> io::Result<Option<zip32::Scope>> {
+    match reader.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(zip32::Scope::External)),
+        2 => Ok(Some(zip32::Scope::Internal)),
+        other => Err(invalid_data(format!("Key scope not recognized: {}", other))),
+    }
+}
+
+fn write_scope<W: Write>(mut writer: W, scope: Option<zip32::Scope>) -> io::Result<()> {
+    writer.write_u8(match scope {
+        None => 0,
+        Some(zip32::Scope::External) => 1,
+        Some(zip32::Scope::Internal) => 2,
+    })
+}
+
+/// Reads the components of [`ScannedBundles`], using `read_nf` to decode each nullifier.
+#[allow(clippy::type_complexity)]
+fn read_bundles_parts<R: Read, H: HashSer, NF>(
+    mut reader: R,
+    read_nf: impl Fn(&mut R) -> io::Result<NF>,
+) -> io::Result<(
+    u32,
+    Vec<(H, Retention<BlockHeight>)>,
+    Vec<(TxId, u16, Vec<(usize, NF)>)>,
+)> {
+    let final_tree_size = reader.read_u32::<LittleEndian>()?;
+    let commitments = Vector::read(&mut reader, |r| {
+        let node = H::read(&mut *r)?;
+        let retention = read_retention(r)?;
+        Ok((node, retention))
+    })?;
+    // The commitments are the last leaves of the tree, so there cannot be more of them than
+    // there are leaves in the tree.
+    if u64::try_from(commitments.len()).map_or(true, |len| len > u64::from(final_tree_size)) {
+        return Err(invalid_data(format!(
+            "{} note commitments cannot be contained in a tree of size {}",
+            commitments.len(),
+            final_tree_size
+        )));
+    }
+    let nullifier_map = Vector::read(&mut reader, |r| {
+        let txid = TxId::read(&mut *r)?;
+        let tx_index = r.read_u16::<LittleEndian>()?;
+        let nullifiers = Vector::read(&mut **r, |r| {
+            let index = read_index(&mut *r)?;
+            let nf = read_nf(&mut **r)?;
+            Ok((index, nf))
+        })?;
+        Ok((txid, tx_index, nullifiers))
+    })?;
+
+    Ok((final_tree_size, commitments, nullifier_map))
+}
+
+/// Writes the components of [`ScannedBundles`], using `write_nf` to encode each nullifier.
+fn write_bundles<W: Write, H: HashSer, NF>(
+    mut writer: W,
+    bundles: &ScannedBundles<H, NF>,
+    write_nf: impl Fn(&mut W, &NF) -> io::Result<()>,
+) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(bundles.final_tree_size())?;
+    Vector::write(
+        &mut writer,
+        bundles.commitments(),
+        |w, (node, retention)| {
+            node.write(&mut *w)?;
+            write_retention(w, retention)
+        },
+    )?;
+    Vector::write(
+        &mut writer,
+        bundles.nullifier_map(),
+        |w, (txid, tx_index, nullifiers)| {
+            txid.write(&mut **w)?;
+            w.write_u16::<LittleEndian>(*tx_index)?;
+            Vector::write(&mut **w, nullifiers, |w, (index, nf)| {
+                write_index(&mut *w, *index)?;
+                write_nf(&mut **w, nf)
+            })
+        },
+    )
+}
+
+impl ScannedBundles<sapling::Node, sapling::Nullifier> {
+    /// Reads the Sapling components of a [`ScannedBlock`] from the provided [`Read`] instance.
+    pub fn read<R: Read>(reader: R) -> io::Result<Self> {
+        let (final_tree_size, commitments, nullifier_map) =
+            read_bundles_parts(reader, |r| read_bytes(r).map(sapling::Nullifier))?;
+        Ok(ScannedBundles::new(
+            final_tree_size,
+            commitments,
+            nullifier_map,
+        ))
+    }
+
+    /// Writes the Sapling components of a [`ScannedBlock`] to the provided [`Write`] instance.
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        write_bundles(writer, self, |w, nf| w.write_all(&nf.0))
+    }
+}
+
+#[cfg(feature = "orchard")]
+impl ScannedBundles<MerkleHashOrchard, orchard::note::Nullifier> {
+    /// Reads the Orchard components of a [`ScannedBlock`] from the provided [`Read`] instance.
+    pub fn read<R: Read>(reader: R) -> io::Result<Self> {
+        let (final_tree_size, commitments, nullifier_map) =
+            read_bundles_parts(reader, |r| read_orchard_nullifier(r))?;
+        Ok(ScannedBundles::new(
+            final_tree_size,
+            commitments,
+            nullifier_map,
+        ))
+    }
+
+    /// Writes the Orchard components of a [`ScannedBlock`] to the provided [`Write`] instance.
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        write_bundles(writer, self, |w, nf| w.write_all(&nf.to_bytes()))
+    }
+}
+
+#[cfg(feature = "orchard")]
+fn read_orchard_nullifier<R: Read>(reader: R) -> io::Result<orchard::note::Nullifier> {
+    Option::from(orchard::note::Nullifier::from_bytes(&read_bytes(reader)?))
+        .ok_or_else(|| invalid_data("Invalid Orchard nullifier"))
+}
+
+fn read_spend<R: Read, NF, A: AccountIdSer>(
+    mut reader: R,
+    read_nf: impl Fn(&mut R) -> io::Result<NF>,
+) -> io::Result<WalletSpend<NF, A>> {
+    let index = read_index(&mut reader)?;
+    let nf = read_nf(&mut reader)?;
+    let account_id = A::read(&mut reader)?;
+    Ok(WalletSpend::from_parts(index, nf, account_id))
+}
+
+fn write_spend<W: Write, NF, A: AccountIdSer>(
+    mut writer: W,
+    spend: &WalletSpend<NF, A>,
+    write_nf: impl Fn(&mut W, &NF) -> io::Result<()>,
+) -> io::Result<()> {
+    write_index(&mut writer, spend.index())?;
+    write_nf(&mut writer, spend.nf())?;
+    spend.account_id().write(&mut writer)
+}
+
+fn read_output<R: Read, N, NF, A: AccountIdSer>(
+    mut reader: R,
+    read_note: impl Fn(&mut R) -> io::Result<N>,
+    read_nf: impl Fn(&mut R) -> io::Result<NF>,
+) -> io::Result<WalletOutput<N, NF, A>> {
+    let index = read_index(&mut reader)?;
+    let ephemeral_key = EphemeralKeyBytes(read_bytes(&mut reader)?);
+    let note = read_note(&mut reader)?;
+    let flags = reader.read_u8()?;
+    if flags & !(OUTPUT_FLAG_CHANGE | OUTPUT_FLAG_DUST) != 0 {
+        return Err(invalid_data(format!(
+            "Output flags not recognized: {}",
+            flags
+        )));
+    }
+    let position = Position::from(reader.read_u64::<LittleEndian>()?);
+    let nf = Optional::read(&mut reader, |r| read_nf(r))?;
+    let account_id = A::read(&mut reader)?;
+    let recipient_key_scope = read_scope(&mut reader)?;
+
+    Ok(WalletOutput::from_parts(
+        index,
+        ephemeral_key,
+        note,
+        flags & OUTPUT_FLAG_CHANGE != 0,
+        flags & OUTPUT_FLAG_DUST != 0,
+        position,
+        nf,
+        account_id,
+        recipient_key_scope,
+    ))
+}
+
+fn write_output<W: Write, N, NF, A: AccountIdSer>(
+    mut writer: W,
+    output: &WalletOutput<N, NF, A>,
+    write_note: impl Fn(&mut W, &N) -> io::Result<()>,
+    write_nf: impl Fn(&mut W, &NF) -> io::Result<()>,
+) -> io::Result<()> {
+    write_index(&mut writer, output.index())?;
+    writer.write_all(&output.ephemeral_key().0)?;
+    write_note(&mut writer, output.note())?;
+    let mut flags = 0;
+    if output.is_change() {
+        flags |= OUTPUT_FLAG_CHANGE;
+    }
+    if output.below_dust_threshold() {
+        flags |= OUTPUT_FLAG_DUST;
+    }
+    writer.write_u8(flags)?;
+    writer.write_u64::<LittleEndian>(output.note_commitment_tree_position().into())?;
+    Optional::write(&mut writer, output.nf(), |w, nf| write_nf(w, nf))?;
+    output.account_id().write(&mut writer)?;
+    write_scope(&mut writer, output.recipient_key_scope())
+}
+
+fn read_sapling_note<R: Read>(mut reader: R) -> io::Result<sapling::Note> {
+    let recipient = sapling::PaymentAddress::from_bytes(&read_bytes(&mut reader)?)
+        .ok_or_else(|| invalid_data("Invalid Sapling payment address"))?;
+    let value = sapling::value::NoteValue::from_raw(reader.read_u64::<LittleEndian>()?);
+    let rseed = match reader.read_u8()? {
+        RSEED_BEFORE_ZIP212 => Rseed::BeforeZip212(
+            Option::from(jubjub::Fr::from_repr(read_bytes(&mut reader)?))
+                .ok_or_else(|| invalid_data("Invalid Sapling note commitment randomness"))?,
+        ),
+        RSEED_AFTER_ZIP212 => Rseed::AfterZip212(read_bytes(&mut reader)?),
+        other => {
+            return Err(invalid_data(format!(
+                "Sapling rseed tag not recognized: {}",
+                other
+            )))
+        }
+    };
+    Ok(sapling::Note::from_parts(recipient, value, rseed))
+}
+
+fn write_sapling_note<W: Write>(mut writer: W, note: &sapling::Note) -> io::Result<()> {
+    writer.write_all(&note.recipient().to_bytes())?;
+    writer.write_u64::<LittleEndian>(note.value().inner())?;
+    match note.rseed() {
+        Rseed::BeforeZip212(rcm) => {
+            writer.write_u8(RSEED_BEFORE_ZIP212)?;
+            writer.write_all(&rcm.to_repr())
+        }
+        Rseed::AfterZip212(rseed) => {
+            writer.write_u8(RSEED_AFTER_ZIP212)?;
+            writer.write_all(rseed)
+        }
+    }
+}
+
+#[cfg(feature = "orchard")]
+fn read_orchard_note<R: Read>(mut reader: R) -> io::Result<orchard::Note> {
+    let recipient = Option::from(orchard::Address::from_raw_address_bytes(&read_bytes(
+        &mut reader,
+    )?))
+    .ok_or_else(|| invalid_data("Invalid Orchard address"))?;
+    let value = orchard::value::NoteValue::from_raw(reader.read_u64::<LittleEndian>()?);
+    let rho = read_orchard_nullifier(&mut reader)?;
+    let rseed = Option::from(orchard::note::RandomSeed::from_bytes(
+        read_bytes(&mut reader)?,
+        &rho,
+    ))
+    .ok_or_else(|| invalid_data("Invalid Orchard note rseed"))?;
+    Option::from(orchard::Note::from_parts(recipient, value, rho, rseed))
+        .ok_or_else(|| invalid_data("Invalid Orchard note"))
+}
+
+#[cfg(feature = "orchard")]
+fn write_orchard_note<W: Write>(mut writer: W, note: &orchard::Note) -> io::Result<()> {
+    writer.write_all(&note.recipient().to_raw_address_bytes())?;
+    writer.write_u64::<LittleEndian>(note.value().inner())?;
+    writer.write_all(&note.rho().to_bytes())?;
+    writer.write_all(note.rseed().as_bytes())
+}
+
+#[cfg(feature = "transparent-inputs")]
+fn read_transparent_output<R: Read>(mut reader: R) -> io::Result<WalletTransparentOutput> {
+    let outpoint = OutPoint::read(&mut reader)?;
+    let txout = TxOut::read(&mut reader)?;
+    let height = read_height(&mut reader)?;
+    WalletTransparentOutput::from_parts(outpoint, txout, height)
+        .ok_or_else(|| invalid_data("Transparent output has an unrecognized recipient"))
+}
+
+#[cfg(feature = "transparent-inputs")]
+fn write_transparent_output<W: Write>(
+    mut writer: W,
+    output: &WalletTransparentOutput,
+) -> io::Result<()> {
+    output.outpoint().write(&mut writer)?;
+    output.txout().write(&mut writer)?;
+    writer.write_u32::<LittleEndian>(output.height().into())
+}
+
+impl<A: AccountIdSer> WalletTx<A> {
+    /// Reads a [`WalletTx`] from the provided [`Read`] instance.
+    ///
+    /// See the [module documentation](self) for a description of the format.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let txid = TxId::read(&mut reader)?;
+        let block_index = read_index(&mut reader)?;
+        let sapling_spends = Vector::read(&mut reader, |r| {
+            read_spend(r, |r| read_bytes(r).map(sapling::Nullifier))
+        })?;
+        let sapling_outputs = Vector::read(&mut reader, |r| {
+            read_output(
+                r,
+                |r| read_sapling_note(r),
+                |r| read_bytes(r).map(sapling::Nullifier),
+            )
+        })?;
+        #[cfg(feature = "orchard")]
+        let orchard_spends = Vector::read(&mut reader, |r| {
+            read_spend(r, |r| read_orchard_nullifier(r))
+        })?;
+        #[cfg(feature = "orchard")]
+        let orchard_outputs = Vector::read(&mut reader, |r| {
+            read_output(r, |r| read_orchard_note(r), |r| read_orchard_nullifier(r))
+        })?;
+        #[cfg(feature = "transparent-inputs")]
+        let transparent_outputs = Vector::read(&mut reader, |r| read_transparent_output(r))?;
+        #[cfg(feature = "transparent-inputs")]
+        let is_shielding = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            other => {
+                return Err(invalid_data(format!(
+                    "Shielding flag not recognized: {}",
+                    other
+                )))
+            }
+        };
+        let computed_fee = Optional::read(&mut reader, |r| {
+            let value = r.read_u64::<LittleEndian>()?;
+            NonNegativeAmount::from_u64(value)
+                .map_err(|_| invalid_data(format!("Invalid fee: {}", value)))
+        })?;
+        let logical_actions = read_index(&mut reader)?;
+        let shielded_output_count = read_index(&mut reader)?;
+
+        let tx = WalletTx::new(
+            txid,
+            block_index,
+            sapling_spends,
+            sapling_outputs,
+            #[cfg(feature = "orchard")]
+            orchard_spends,
+            #[cfg(feature = "orchard")]
+            orchard_outputs,
+            #[cfg(feature = "transparent-inputs")]
+            transparent_outputs,
+            computed_fee,
+            logical_actions,
+            shielded_output_count,
+        );
+        #[cfg(feature = "transparent-inputs")]
+        let tx = tx.with_shielding(is_shielding);
+
+        Ok(tx)
+    }
+
+    /// Writes this [`WalletTx`] to the provided [`Write`] instance.
+    ///
+    /// See the [module documentation](self) for a description of the format.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.txid().write(&mut writer)?;
+        write_index(&mut writer, self.block_index())?;
+        Vector::write(&mut writer, self.sapling_spends(), |w, spend| {
+            write_spend(w, spend, |w, nf| w.write_all(&nf.0))
+        })?;
+        Vector::write(&mut writer, self.sapling_outputs(), |w, output| {
+            write_output(
+                w,
+                output,
+                |w, note| write_sapling_note(w, note),
+                |w, nf| w.write_all(&nf.0),
+            )
+        })?;
+        #[cfg(feature = "orchard")]
+        {
+            Vector::write(&mut writer, self.orchard_spends(), |w, spend| {
+                write_spend(w, spend, |w, nf| w.write_all(&nf.to_bytes()))
+            })?;
+            Vector::write(&mut writer, self.orchard_outputs(), |w, output| {
+                write_output(
+                    w,
+                    output,
+                    |w, note| write_orchard_note(w, note),
+                    |w, nf| w.write_all(&nf.to_bytes()),
+                )
+            })?;
+        }
+        #[cfg(feature = "transparent-inputs")]
+        {
+            Vector::write(&mut writer, self.transparent_outputs(), |w, output| {
+                write_transparent_output(w, output)
+            })?;
+            writer.write_u8(u8::from(self.is_shielding()))?;
+        }
+        Optional::write(&mut writer, self.computed_fee(), |w, fee| {
+            w.write_u64::<LittleEndian>(fee.into())
+        })?;
+        write_index(&mut writer, self.logical_actions())?;
+        write_index(&mut writer, self.shielded_output_count())
+    }
+}
+
+impl<A: AccountIdSer> ScannedBlock<A> {
+    /// Reads a [`ScannedBlock`] from the provided [`Read`] instance.
+    ///
+    /// Returns an [`io::ErrorKind::InvalidData`] error if the format version is not
+    /// recognized, or if the serialized block's Orchard or transparent components do not
+    /// correspond to the features enabled for this crate. See the
+    /// [module documentation](self) for a description of the format.
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self>
+    where
+        A: Eq + Hash,
+    {
+        let version = reader.read_u8()?;
+        match version {
+            SER_V1 | SER_V2 => {}
+            other => {
+                return Err(invalid_data(format!(
+                    "Scanned block serialization version not recognized: {}",
+                    other
+                )))
+            }
+        }
+        let flags = reader.read_u8()?;
+        if flags != SUPPORTED_FLAGS {
+            return Err(invalid_data(format!(
+                "Scanned block component flags {:#04x} do not match the enabled features ({:#04x})",
+                flags, SUPPORTED_FLAGS
+            )));
+        }
+
+        let block_height = read_height(&mut reader)?;
+        let block_hash = BlockHash(read_bytes(&mut reader)?);
+        let block_time = reader.read_u32::<LittleEndian>()?;
+        let zip212_enforcement = match reader.read_u8()? {
+            0 => Zip212Enforcement::Off,
+            1 => Zip212Enforcement::GracePeriod,
+            2 => Zip212Enforcement::On,
+            other => {
+                return Err(invalid_data(format!(
+                    "ZIP 212 enforcement state not recognized: {}",
+                    other
+                )))
+            }
+        };
+        let transactions = Vector::read(&mut reader, |r| WalletTx::read(r))?;
+        let sapling = ScannedBundles::<sapling::Node, sapling::Nullifier>::read(&mut reader)?;
+        #[cfg(feature = "orchard")]
+        let orchard =
+            ScannedBundles::<MerkleHashOrchard, orchard::note::Nullifier>::read(&mut reader)?;
+
+        let block = ScannedBlock::from_parts(
+            block_height,
+            block_hash,
+            block_time,
+            transactions,
+            zip212_enforcement,
+            sapling,
+            #[cfg(feature = "orchard")]
+            orchard,
+        );
+        if version == SER_V1 {
+            return Ok(block);
+        }
+
+        let source_id = Optional::read(&mut reader, |r| r.read_u64::<LittleEndian>())?;
+        let key_hits = Optional::read(&mut reader, |r| {
+            Vector::read(r, |r| {
+                let account_id = A::read(&mut *r)?;
+                let scope = read_scope(&mut *r)?;
+                let count = read_index(r)?;
+                Ok(((account_id, scope), count))
+            })
+        })?;
+
+        let block = match source_id {
+            Some(source_id) => block.with_source_id(source_id),
+            None => block,
+        };
+        Ok(match key_hits {
+            Some(key_hits) => block.with_key_hit_counts(key_hits.into_iter().collect()),
+            None => block,
+        })
+    }
+
+    /// Writes this [`ScannedBlock`] to the provided [`Write`] instance.
+    ///
+    /// See the [module documentation](self) for a description of the format.
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u8(SER_V2)?;
+        writer.write_u8(SUPPORTED_FLAGS)?;
+        writer.write_u32::<LittleEndian>(self.height().into())?;
+        writer.write_all(&self.block_hash().0)?;
+        writer.write_u32::<LittleEndian>(self.block_time())?;
+        writer.write_u8(match self.zip212_enforcement() {
+            Zip212Enforcement::Off => 0,
+            Zip212Enforcement::GracePeriod => 1,
+            Zip212Enforcement::On => 2,
+        })?;
+        Vector::write(&mut writer, self.transactions(), |w, tx| tx.write(w))?;
+        self.sapling().write(&mut writer)?;
+        #[cfg(feature = "orchard")]
+        self.orchard().write(&mut writer)?;
+        Optional::write(&mut writer, self.source_id(), |w, source_id| {
+            w.write_u64::<LittleEndian>(source_id)
+        })?;
+
+        // The key hit counts are held in a map, so they are sorted by their encodings in order
+        // for the encoding of the block to be deterministic.
+        let key_hits = self
+            .key_hits()
+            .map(|key_hits| {
+                let mut entries = key_hits
+                    .iter()
+                    .map(|((account_id, scope), count)| {
+                        let mut entry = vec![];
+                        account_id.write(&mut entry)?;
+                        write_scope(&mut entry, *scope)?;
+                        write_index(&mut entry, *count)?;
+                        Ok(entry)
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                entries.sort();
+                Ok::<_, io::Error>(entries)
+            })
+            .transpose()?;
+        Optional::write(&mut writer, key_hits.as_ref(), |w, entries| {
+            Vector::write(w, entries, |w, entry| w.write_all(entry))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use incrementalmerkletree::{Hashable, Position, Retention};
+    use rand_core::{OsRng, RngCore};
+    use sapling::{
+        note_encryption::Zip212Enforcement, value::NoteValue, zip32::ExtendedSpendingKey, Rseed,
+    };
+    use zcash_note_encryption::EphemeralKeyBytes;
+    use zcash_primitives::{block::BlockHash, transaction::TxId};
+
+    use crate::{
+        data_api::{ScannedBlock, ScannedBundles},
+        wallet::{WalletOutput, WalletSpend, WalletTx},
+    };
+
+    fn roundtrip(block: &ScannedBlock<zip32::AccountId>) -> ScannedBlock<zip32::AccountId> {
+        let mut data = vec![];
+        block.write(&mut data).unwrap();
+        let result = ScannedBlock::read(&data[..]).unwrap();
+
+        // Serialization must be deterministic.
+        let mut reserialized = vec![];
+        result.write(&mut reserialized).unwrap();
+        assert_eq!(data, reserialized);
+
+        result
+    }
+
+    #[test]
+    fn scanned_block_roundtrip() {
+        let account = zip32::AccountId::ZERO;
+        let extsk = ExtendedSpendingKey::master(&[0; 32]);
+        let recipient = extsk
+            .to_diversifiable_full_viewing_key()
+            .default_address()
+            .1;
+
+        let mut rseed = [0; 32];
+        OsRng.fill_bytes(&mut rseed);
+        let note = sapling::Note::from_parts(
+            recipient,
+            NoteValue::from_raw(5000),
+            Rseed::AfterZip212(rseed),
+        );
+        let node = sapling::Node::from_cmu(&note.cmu());
+
+        let txid = TxId::from_bytes([1; 32]);
+        let spent_nf = sapling::Nullifier([2; 32]);
+        let received_nf = sapling::Nullifier([3; 32]);
+        let unlinked_nf = sapling::Nullifier([4; 32]);
+
+        let tx = WalletTx::new(
+            txid,
+            3,
+            vec![WalletSpend::from_parts(0, spent_nf, account)],
+            vec![WalletOutput::from_parts(
+                1,
+                EphemeralKeyBytes([5; 32]),
+                note.clone(),
+                true,
+                false,
+                Position::from(17),
+                Some(received_nf),
+                account,
+                Some(zip32::Scope::Internal),
+            )],
+            #[cfg(feature = "orchard")]
+            vec![],
+            #[cfg(feature = "orchard")]
+            vec![],
+            #[cfg(feature = "transparent-inputs")]
+            vec![],
+            None,
+            2,
+            3,
+        );
+
+        let height = 100u32.into();
+        let commitments = vec![
+            (node, Retention::Ephemeral),
+            (node, Retention::Marked),
+            (
+                node,
+                Retention::Checkpoint {
+                    id: height,
+                    is_marked: true,
+                },
+            ),
+        ];
+        let nullifier_map = vec![(txid, 3, vec![(1, unlinked_nf)])];
+
+        let block = ScannedBlock::from_parts(
+            height,
+            BlockHash([6; 32]),
+            1_700_000_000,
+            vec![tx],
+            Zip212Enforcement::On,
+            ScannedBundles::new(20, commitments.clone(), nullifier_map.clone()),
+            #[cfg(feature = "orchard")]
+            ScannedBundles::new(0, vec![], vec![]),
+        );
+
+        let result = roundtrip(&block);
+        assert_eq!(result.source_id(), None);
+        assert_eq!(result.key_hits(), None);
+
+        let block = block.with_source_id(9).with_key_hits();
+        let result = roundtrip(&block);
+        assert_eq!(result.source_id(), Some(9));
+        assert_eq!(
+            result.key_hits(),
+            Some(&HashMap::from([(
+                (account, Some(zip32::Scope::Internal)),
+                1
+            )]))
+        );
+        assert_eq!(result.height(), height);
+        assert_eq!(result.block_hash(), BlockHash([6; 32]));
+        assert_eq!(result.block_time(), 1_700_000_000);
+        assert_eq!(result.zip212_enforcement(), Zip212Enforcement::On);
+        assert_eq!(result.sapling().final_tree_size(), 20);
+        assert_eq!(result.sapling().commitments(), &commitments[..]);
+        assert_eq!(result.sapling().nullifier_map(), &nullifier_map[..]);
+
+        assert_eq!(result.transactions().len(), 1);
+        let tx = &result.transactions()[0];
+        assert_eq!(tx.txid(), txid);
+        assert_eq!(tx.block_index(), 3);
+        assert_eq!(tx.logical_actions(), 2);
+        assert_eq!(tx.shielded_output_count(), 3);
+        assert!(!tx.is_fully_decrypted());
+        assert_eq!(tx.computed_fee(), None);
+
+        assert_eq!(tx.sapling_spends().len(), 1);
+        let spend = &tx.sapling_spends()[0];
+        assert_eq!(spend.index(), 0);
+        assert_eq!(spend.nf(), &spent_nf);
+        assert_eq!(spend.account_id(), &account);
+
+        assert_eq!(tx.sapling_outputs().len(), 1);
+        let output = &tx.sapling_outputs()[0];
+        assert_eq!(output.index(), 1);
+        assert_eq!(output.ephemeral_key().0, [5; 32]);
+        assert_eq!(output.note().cmu(), note.cmu());
+        assert!(output.is_change());
+        assert!(!output.below_dust_threshold());
+        assert_eq!(output.note_commitment_tree_position(), Position::from(17));
+        assert_eq!(output.nf(), Some(&received_nf));
+        assert_eq!(output.account_id(), &account);
+        assert_eq!(output.recipient_key_scope(), Some(zip32::Scope::Internal));
+    }
+
+    #[test]
+    fn scanned_block_reads_v1() {
+        let block = ScannedBlock::<zip32::AccountId>::from_parts(
+            1u32.into(),
+            BlockHash([0; 32]),
+            0,
+            vec![],
+            Zip212Enforcement::Off,
+            ScannedBundles::new(0, vec![], vec![]),
+            #[cfg(feature = "orchard")]
+            ScannedBundles::new(0, vec![], vec![]),
+        );
+        let mut data = vec![];
+        block.write(&mut data).unwrap();
+
+        // The version 1 encoding omits the trailing (absent) source ID and key hit counts.
+        assert_eq!(data.split_off(data.len() - 2), vec![0, 0]);
+        data[0] = 1;
+        let result = ScannedBlock::<zip32::AccountId>::read(&data[..]).unwrap();
+        assert_eq!(result.height(), block.height());
+        assert_eq!(result.source_id(), None);
+        assert_eq!(result.key_hits(), None);
+    }
+
+    #[test]
+    fn scanned_block_rejects_excess_commitments() {
+        let node = sapling::Node::empty_leaf();
+        let block = ScannedBlock::<zip32::AccountId>::from_parts(
+            1u32.into(),
+            BlockHash([0; 32]),
+            0,
+            vec![],
+            Zip212Enforcement::Off,
+            // A tree of size 1 cannot end with two note commitments.
+            ScannedBundles::new(
+                1,
+                vec![(node, Retention::Ephemeral), (node, Retention::Marked)],
+                vec![],
+            ),
+            #[cfg(feature = "orchard")]
+            ScannedBundles::new(0, vec![], vec![]),
+        );
+        let mut data = vec![];
+        block.write(&mut data).unwrap();
+        assert_eq!(
+            ScannedBlock::<zip32::AccountId>::read(&data[..])
+                .err()
+                .map(|e| e.kind()),
+            Some(std::io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[test]
+    fn scanned_block_rejects_unknown_version() {
+        let block = ScannedBlock::<zip32::AccountId>::from_parts(
+            1u32.into(),
+            BlockHash([0; 32]),
+            0,
+            vec![],
+            Zip212Enforcement::Off,
+            ScannedBundles::new(0, vec![], vec![]),
+            #[cfg(feature = "orchard")]
+            ScannedBundles::new(0, vec![], vec![]),
+        );
+        let mut data = vec![];
+        block.write(&mut data).unwrap();
+        assert_eq!(roundtrip(&block).height(), block.height());
+
+        data[0] = 3;
+        assert_eq!(
+            ScannedBlock::<zip32::AccountId>::read(&data[..])
+                .err()
+                .map(|e| e.kind()),
+            Some(std::io::ErrorKind::InvalidData)
+        );
+    }
+}
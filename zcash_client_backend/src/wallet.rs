@@ -1,6 +1,8 @@
 //! Structs representing transaction data scanned from the block chain by a wallet or
 //! light client.
 
+use std::{collections::HashSet, hash::Hash};
+
 use incrementalmerkletree::Position;
 use zcash_note_encryption::EphemeralKeyBytes;
 use zcash_primitives::{
@@ -94,7 +96,7 @@ impl<AccountId, N> Recipient<AccountId, Option<N>> {
     }
 }
 
-/// The shielded subset of a [`Transaction`]'s data that is relevant to a particular wallet.
+/// The subset of a [`Transaction`]'s data that is relevant to a particular wallet.
 ///
 /// [`Transaction`]: zcash_primitives::transaction::Transaction
 pub struct WalletTx<AccountId> {
@@ -106,6 +108,13 @@ pub struct WalletTx<AccountId> {
     orchard_spends: Vec<WalletOrchardSpend<AccountId>>,
     #[cfg(feature = "orchard")]
     orchard_outputs: Vec<WalletOrchardOutput<AccountId>>,
+    #[cfg(feature = "transparent-inputs")]
+    transparent_outputs: Vec<WalletTransparentOutput>,
+    #[cfg(feature = "transparent-inputs")]
+    is_shielding: bool,
+    computed_fee: Option<NonNegativeAmount>,
+    logical_actions: usize,
+    shielded_output_count: usize,
 }
 
 impl<AccountId> WalletTx<AccountId> {
@@ -119,6 +128,10 @@ impl<AccountId> WalletTx<AccountId> {
             WalletSpend<orchard::note::Nullifier, AccountId>,
         >,
         #[cfg(feature = "orchard")] orchard_outputs: Vec<WalletOrchardOutput<AccountId>>,
+        #[cfg(feature = "transparent-inputs")] transparent_outputs: Vec<WalletTransparentOutput>,
+        computed_fee: Option<NonNegativeAmount>,
+        logical_actions: usize,
+        shielded_output_count: usize,
     ) -> Self {
         Self {
             txid,
@@ -129,9 +142,24 @@ impl<AccountId> WalletTx<AccountId> {
             orchard_spends,
             #[cfg(feature = "orchard")]
             orchard_outputs,
+            #[cfg(feature = "transparent-inputs")]
+            transparent_outputs,
+            #[cfg(feature = "transparent-inputs")]
+            is_shielding: false,
+            computed_fee,
+            logical_actions,
+            shielded_output_count,
         }
     }
 
+    /// Sets whether the transaction shields the wallet's transparent funds; see
+    /// [`Self::is_shielding`].
+    #[cfg(feature = "transparent-inputs")]
+    pub(crate) fn with_shielding(mut self, is_shielding: bool) -> Self {
+        self.is_shielding = is_shielding;
+        self
+    }
+
     /// Returns the [`TxId`] for the corresponding [`Transaction`].
     ///
     /// [`Transaction`]: zcash_primitives::transaction::Transaction
@@ -144,6 +172,19 @@ impl<AccountId> WalletTx<AccountId> {
         self.block_index
     }
 
+    /// Marks the shielded outputs of this transaction as belonging to a transaction that has not
+    /// been mined; see [`WalletOutput::is_mined`].
+    pub(crate) fn into_unmined(mut self) -> Self {
+        for output in &mut self.sapling_outputs {
+            output.mark_unmined();
+        }
+        #[cfg(feature = "orchard")]
+        for output in &mut self.orchard_outputs {
+            output.mark_unmined();
+        }
+        self
+    }
+
     /// Returns a record for each Sapling note belonging to the wallet that was spent in the
     /// transaction.
     pub fn sapling_spends(&self) -> &[WalletSaplingSpend<AccountId>] {
@@ -152,6 +193,9 @@ impl<AccountId> WalletTx<AccountId> {
 
     /// Returns a record for each Sapling note received or produced by the wallet in the
     /// transaction.
+    ///
+    /// When produced by scanning, the records are ordered by their output index within the
+    /// transaction, regardless of which accounts received the notes.
     pub fn sapling_outputs(&self) -> &[WalletSaplingOutput<AccountId>] {
         self.sapling_outputs.as_ref()
     }
@@ -165,10 +209,101 @@ impl<AccountId> WalletTx<AccountId> {
 
     /// Returns a record for each Orchard note received or produced by the wallet in the
     /// transaction.
+    ///
+    /// When produced by scanning, the records are ordered by their action index within the
+    /// transaction, regardless of which accounts received the notes.
     #[cfg(feature = "orchard")]
     pub fn orchard_outputs(&self) -> &[WalletOrchardOutput<AccountId>] {
         self.orchard_outputs.as_ref()
     }
+
+    /// Returns a record for each transparent output received by the wallet in the
+    /// transaction.
+    ///
+    /// This is only populated when scanning compact blocks whose transactions include
+    /// transparent outputs, and only for outputs that pay to one of the transparent addresses
+    /// provided to the scanner.
+    #[cfg(feature = "transparent-inputs")]
+    pub fn transparent_outputs(&self) -> &[WalletTransparentOutput] {
+        self.transparent_outputs.as_ref()
+    }
+
+    /// Returns `true` if the transaction was detected during scanning as shielding the
+    /// wallet's transparent funds: that is, it spends at least one transparent output received
+    /// by an account, and pays at least one shielded output to that same account.
+    ///
+    /// Transparent spends are only detected for the outpoints provided to the scanner via
+    /// [`Nullifiers::with_transparent_outpoints`]; this is a heuristic intended for labelling
+    /// transactions in a wallet's history.
+    ///
+    /// [`Nullifiers::with_transparent_outpoints`]: crate::scanning::Nullifiers::with_transparent_outpoints
+    #[cfg(feature = "transparent-inputs")]
+    pub fn is_shielding(&self) -> bool {
+        self.is_shielding
+    }
+
+    /// Returns the fee paid by the transaction, if it could be determined during scanning.
+    ///
+    /// The fee is computed only if every input to the transaction spends a note whose value
+    /// was provided to the scanner, the transaction has no transparent inputs, and every
+    /// shielded output of the transaction was received by the wallet. Compact transactions do
+    /// not indicate whether transparent data has been omitted, so the fee is also only computed
+    /// if the scanner was configured to expect transparent data (see
+    /// [`ScanConfig::transparent_data_available`]).
+    ///
+    /// [`ScanConfig::transparent_data_available`]: crate::scanning::ScanConfig::transparent_data_available
+    pub fn computed_fee(&self) -> Option<NonNegativeAmount> {
+        self.computed_fee
+    }
+
+    /// Returns the number of [ZIP 317] logical actions in the transaction, as determined
+    /// during scanning.
+    ///
+    /// Each transparent input and output included in the compact transaction is assumed to be
+    /// a standard P2PKH input or output; transparent data omitted from the compact transaction
+    /// is not counted. This may be used together with [`classify_fee`] to determine whether
+    /// the transaction paid the conventional fee.
+    ///
+    /// [ZIP 317]: https://zips.z.cash/zip-0317
+    /// [`classify_fee`]: crate::fees::zip317::classify_fee
+    pub fn logical_actions(&self) -> usize {
+        self.logical_actions
+    }
+
+    /// Returns the total number of shielded outputs in the transaction (its Sapling outputs
+    /// and Orchard actions), including those that were not decrypted by the wallet.
+    pub fn shielded_output_count(&self) -> usize {
+        self.shielded_output_count
+    }
+
+    /// Returns `true` if the transaction has at least one shielded output, and every one of
+    /// its shielded outputs was decrypted by the wallet.
+    ///
+    /// Such a transaction is a transfer between (or within) the wallet's accounts, such as a
+    /// shielding transaction sending funds to the wallet itself. Transparent outputs are not
+    /// considered. When the `orchard` feature is disabled, a transaction that has Orchard
+    /// actions is never considered to be fully decrypted.
+    pub fn is_fully_decrypted(&self) -> bool {
+        let decrypted = self.sapling_outputs.len();
+        #[cfg(feature = "orchard")]
+        let decrypted = decrypted + self.orchard_outputs.len();
+        self.shielded_output_count > 0 && decrypted == self.shielded_output_count
+    }
+
+    /// Returns the set of accounts whose notes were spent in the transaction, across all
+    /// shielded protocols.
+    ///
+    /// A transaction that spends from more than one account is unusual, and may indicate a
+    /// consolidation of funds across accounts that should be brought to the user's attention.
+    pub fn spent_from_accounts(&self) -> HashSet<AccountId>
+    where
+        AccountId: Copy + Eq + Hash,
+    {
+        let accounts = self.sapling_spends.iter().map(|spend| *spend.account_id());
+        #[cfg(feature = "orchard")]
+        let accounts = accounts.chain(self.orchard_spends.iter().map(|spend| *spend.account_id()));
+        accounts.collect()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -270,6 +405,7 @@ pub struct WalletOutput<Note, Nullifier, AccountId> {
     ephemeral_key: EphemeralKeyBytes,
     note: Note,
     is_change: bool,
+    below_dust_threshold: bool,
     note_commitment_tree_position: Position,
     nf: Option<Nullifier>,
     account_id: AccountId,
@@ -284,6 +420,7 @@ impl<Note, Nullifier, AccountId> WalletOutput<Note, Nullifier, AccountId> {
         ephemeral_key: EphemeralKeyBytes,
         note: Note,
         is_change: bool,
+        below_dust_threshold: bool,
         note_commitment_tree_position: Position,
         nf: Option<Nullifier>,
         account_id: AccountId,
@@ -294,6 +431,7 @@ impl<Note, Nullifier, AccountId> WalletOutput<Note, Nullifier, AccountId> {
             ephemeral_key,
             note,
             is_change,
+            below_dust_threshold,
             note_commitment_tree_position,
             nf,
             account_id,
@@ -318,10 +456,34 @@ impl<Note, Nullifier, AccountId> WalletOutput<Note, Nullifier, AccountId> {
     pub fn is_change(&self) -> bool {
         self.is_change
     }
+    /// A flag indicating whether the value of the note was below the dust threshold that was
+    /// in effect when the output was scanned (see [`ScanConfig::dust_threshold`]).
+    ///
+    /// [`ScanConfig::dust_threshold`]: crate::scanning::ScanConfig::dust_threshold
+    pub fn below_dust_threshold(&self) -> bool {
+        self.below_dust_threshold
+    }
     /// The position of the note in the global note commitment tree.
+    ///
+    /// For an output of a transaction that has not been mined (see [`Self::is_mined`]), this is
+    /// a sentinel value that lies beyond the capacity of the note commitment tree.
     pub fn note_commitment_tree_position(&self) -> Position {
         self.note_commitment_tree_position
     }
+    /// Returns `false` if this output belongs to a transaction that had not been mined when it
+    /// was scanned (for example, by [`scan_mempool_tx`]), in which case the position of the note
+    /// in the note commitment tree and its nullifier are not yet known.
+    ///
+    /// [`scan_mempool_tx`]: crate::scanning::scan_mempool_tx
+    pub fn is_mined(&self) -> bool {
+        u64::from(self.note_commitment_tree_position) != UNMINED_POSITION
+    }
+    /// Marks this output as belonging to a transaction that has not been mined, discarding its
+    /// note commitment tree position and nullifier.
+    pub(crate) fn mark_unmined(&mut self) {
+        self.note_commitment_tree_position = Position::from(UNMINED_POSITION);
+        self.nf = None;
+    }
     /// The nullifier for the note, if the key used to decrypt the note was able to compute it.
     pub fn nf(&self) -> Option<&Nullifier> {
         self.nf.as_ref()
@@ -337,12 +499,63 @@ impl<Note, Nullifier, AccountId> WalletOutput<Note, Nullifier, AccountId> {
     }
 }
 
+/// The sentinel note commitment tree position of the outputs of unmined transactions.
+///
+/// No note can occupy this position, because it exceeds the capacity of the note commitment
+/// trees of all shielded protocols.
+const UNMINED_POSITION: u64 = u64::MAX;
+
 /// A subset of an [`OutputDescription`] relevant to wallets and light clients.
 ///
 /// [`OutputDescription`]: sapling::bundle::OutputDescription
 pub type WalletSaplingOutput<AccountId> =
     WalletOutput<sapling::Note, sapling::Nullifier, AccountId>;
 
+impl<AccountId> WalletSaplingOutput<AccountId> {
+    /// The diversified payment address to which the note was sent.
+    ///
+    /// This allows received funds to be attributed to a specific diversified address of the
+    /// receiving account.
+    pub fn recipient(&self) -> sapling::PaymentAddress {
+        self.note.recipient()
+    }
+
+    /// Returns whether the value of the note is below the given dust threshold, in which case
+    /// it would cost more to spend than it is worth.
+    pub fn is_dust(&self, threshold: NonNegativeAmount) -> bool {
+        self.note.value().inner() < u64::from(threshold)
+    }
+
+    /// Attaches the nullifier of the note, computed using the given nullifier deriving key.
+    ///
+    /// This allows an output detected by a scan with an incoming viewing key, for which no
+    /// nullifier could be computed, to be upgraded once the full viewing key of the receiving
+    /// account is available, without re-scanning the block. Any existing nullifier is replaced.
+    ///
+    /// Returns `None` if the output belongs to a transaction that has not been mined (see
+    /// [`Self::is_mined`]), as the nullifier depends upon the position of the note in the note
+    /// commitment tree.
+    pub fn with_nullifier(self, nk: &sapling::NullifierDerivingKey) -> Option<Self> {
+        if !self.is_mined() {
+            return None;
+        }
+        let nf = compute_nullifier(nk, &self.note, self.note_commitment_tree_position);
+        Some(Self {
+            nf: Some(nf),
+            ..self
+        })
+    }
+}
+
+/// Computes the nullifier of a Sapling note at the given position in the note commitment tree.
+pub fn compute_nullifier(
+    nk: &sapling::NullifierDerivingKey,
+    note: &sapling::Note,
+    position: Position,
+) -> sapling::Nullifier {
+    note.nf(nk, position.into())
+}
+
 /// The output part of an Orchard [`Action`] that was decrypted in the process of scanning.
 ///
 /// [`Action`]: orchard::Action
@@ -350,6 +563,23 @@ pub type WalletSaplingOutput<AccountId> =
 pub type WalletOrchardOutput<AccountId> =
     WalletOutput<orchard::note::Note, orchard::note::Nullifier, AccountId>;
 
+#[cfg(feature = "orchard")]
+impl<AccountId> WalletOrchardOutput<AccountId> {
+    /// The diversified address to which the note was sent.
+    ///
+    /// This allows received funds to be attributed to a specific diversified address of the
+    /// receiving account.
+    pub fn recipient(&self) -> orchard::Address {
+        self.note.recipient()
+    }
+
+    /// Returns whether the value of the note is below the given dust threshold, in which case
+    /// it would cost more to spend than it is worth.
+    pub fn is_dust(&self, threshold: NonNegativeAmount) -> bool {
+        self.note.value().inner() < u64::from(threshold)
+    }
+}
+
 /// An enumeration of supported shielded note types for use in [`ReceivedNote`]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Note {
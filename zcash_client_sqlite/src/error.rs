@@ -10,7 +10,10 @@ use zcash_client_backend::{
 };
 use zcash_keys::keys::AddressGenerationError;
 use zcash_primitives::zip32;
-use zcash_primitives::{consensus::BlockHeight, transaction::components::amount::BalanceError};
+use zcash_primitives::{
+    consensus::BlockHeight,
+    transaction::{components::amount::BalanceError, TxId},
+};
 
 use crate::wallet::commitment_tree;
 use crate::PRUNING_DEPTH;
@@ -114,6 +117,10 @@ pub enum SqliteClientError {
 
     /// An error occurred in computing wallet balance
     BalanceError(BalanceError),
+
+    /// The raw data for the transaction with the given ID is not stored in the wallet, so the
+    /// requested information could not be reconstructed.
+    TransactionDataUnavailable(TxId),
 }
 
 impl error::Error for SqliteClientError {
@@ -166,6 +173,7 @@ impl fmt::Display for SqliteClientError {
             SqliteClientError::ChainHeightUnknown => write!(f, "Chain height unknown; please call `update_chain_tip`"),
             SqliteClientError::UnsupportedPoolType(t) => write!(f, "Pool type is not currently supported: {}", t),
             SqliteClientError::BalanceError(e) => write!(f, "Balance error: {}", e),
+            SqliteClientError::TransactionDataUnavailable(txid) => write!(f, "Raw data for transaction {} is not available in the wallet.", txid),
         }
     }
 }
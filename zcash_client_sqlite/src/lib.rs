@@ -169,6 +169,7 @@ impl<P: consensus::Parameters + Clone> WalletDb<Connection, P> {
     pub fn for_path<F: AsRef<Path>>(path: F, params: P) -> Result<Self, rusqlite::Error> {
         Connection::open(path).and_then(move |conn| {
             rusqlite::vtab::array::load_module(&conn)?;
+            wallet::register_functions(&conn)?;
             Ok(WalletDb { conn, params })
         })
     }
@@ -540,6 +541,10 @@ impl<P: consensus::Parameters> WalletWrite for WalletDb<rusqlite::Connection, P>
                     block.block_time(),
                     block.sapling().final_tree_size(),
                     block.sapling().commitments().len().try_into().unwrap(),
+                    #[cfg(feature = "orchard")]
+                    block.orchard().final_tree_size(),
+                    #[cfg(feature = "orchard")]
+                    block.orchard().commitments().len().try_into().unwrap(),
                 )?;
 
                 for tx in block.transactions() {
@@ -567,6 +572,12 @@ impl<P: consensus::Parameters> WalletWrite for WalletDb<rusqlite::Connection, P>
 
                         wallet::sapling::put_received_note(wdb.conn.0, output, tx_row, spent_in)?;
                     }
+
+                    // Record any transparent outputs that were detected during scanning.
+                    #[cfg(feature = "transparent-inputs")]
+                    for output in tx.transparent_outputs() {
+                        wallet::put_received_transparent_utxo(wdb.conn.0, &wdb.params, output)?;
+                    }
                 }
 
                 // Insert the new nullifiers from this block into the nullifier map.
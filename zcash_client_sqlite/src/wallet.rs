@@ -65,7 +65,7 @@
 //! - `memo` the shielded memo associated with the output, if any.
 
 use incrementalmerkletree::Retention;
-use rusqlite::{self, named_params, params, OptionalExtension};
+use rusqlite::{self, functions::FunctionFlags, named_params, params, OptionalExtension};
 use shardtree::{error::ShardTreeError, store::ShardStore, ShardTree};
 use std::borrow::Borrow;
 use std::collections::HashMap;
@@ -230,6 +230,29 @@ impl Account {
     }
 }
 
+/// Registers the custom SQL functions used by the wallet's queries and views on the given
+/// connection.
+///
+/// - `confirmations(mined_height)` returns the number of confirmations of a transaction mined
+///   at `mined_height`, relative to the maximum height in the `blocks` table. Unmined
+///   transactions (for which `mined_height` is `NULL`) have zero confirmations.
+pub(crate) fn register_functions(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    conn.create_scalar_function("confirmations", 1, FunctionFlags::SQLITE_UTF8, |ctx| {
+        let mined_height = match ctx.get::<Option<u32>>(0)? {
+            Some(h) => h,
+            None => return Ok(0u32),
+        };
+
+        // SAFETY: the connection is only used to perform a read-only query for the duration
+        // of this call, and is not retained.
+        let conn = unsafe { ctx.get_connection()? };
+        let chain_tip: Option<u32> =
+            conn.query_row("SELECT MAX(height) FROM blocks", [], |row| row.get(0))?;
+
+        Ok(chain_tip.map_or(0, |tip| (tip + 1).saturating_sub(mined_height)))
+    })
+}
+
 pub(crate) fn pool_code(pool_type: PoolType) -> i64 {
     // These constants are *incidentally* shared with the typecodes
     // for unified addresses, but this is exclusively an internal
@@ -256,6 +279,33 @@ pub(crate) fn parse_scope(code: i64) -> Option<Scope> {
     }
 }
 
+/// Returns the identifier of the viewing key with the given scope for the given account, adding
+/// the key to the `viewing_keys` table if it has not previously been recorded.
+pub(crate) fn viewing_key_id(
+    conn: &rusqlite::Connection,
+    account: AccountId,
+    scope: Scope,
+) -> Result<i64, SqliteClientError> {
+    let key_scope = scope_code(scope);
+    let key_args = named_params![
+        ":account_id": account.0,
+        ":key_scope": key_scope,
+    ];
+    conn.prepare_cached(
+        "INSERT INTO viewing_keys (account_id, key_scope)
+        VALUES (:account_id, :key_scope)
+        ON CONFLICT (account_id, key_scope) DO NOTHING",
+    )?
+    .execute(key_args)?;
+
+    conn.prepare_cached(
+        "SELECT id FROM viewing_keys
+        WHERE account_id = :account_id AND key_scope = :key_scope",
+    )?
+    .query_row(key_args, |row| row.get(0))
+    .map_err(SqliteClientError::from)
+}
+
 pub(crate) fn memo_repr(memo: Option<&MemoBytes>) -> Option<&[u8]> {
     memo.map(|m| {
         if m == &MemoBytes::empty() {
@@ -267,6 +317,12 @@ pub(crate) fn memo_repr(memo: Option<&MemoBytes>) -> Option<&[u8]> {
     })
 }
 
+/// Returns the value to be stored in the `has_memo` column for a note with the given memo, or
+/// `None` if the memo is not known.
+pub(crate) fn has_memo(memo: Option<&MemoBytes>) -> Option<bool> {
+    memo.map(|m| m != &MemoBytes::empty())
+}
+
 // Returns the highest used account index for a given seed.
 pub(crate) fn max_zip32_account_index(
     conn: &rusqlite::Connection,
@@ -1012,6 +1068,93 @@ pub(crate) fn get_received_memo(
         .transpose()
 }
 
+/// Reconstructs the wallet's view of the transaction with the given ID from the data stored in
+/// the wallet database, in the form produced by scanning the block containing it.
+///
+/// The raw transaction data must be available in the wallet, as the ephemeral keys of its
+/// outputs and the indices of its spends are not otherwise stored. Only the transaction's
+/// Sapling spends and outputs are reconstructed; the returned value does not include Orchard
+/// spends and outputs or transparent outputs. The block index of a transaction that has not
+/// been mined is reported as zero.
+///
+/// Returns `Ok(None)` if the transaction is not known to the wallet.
+pub fn load_wallet_tx<C: Borrow<rusqlite::Connection>, P: consensus::Parameters>(
+    wdb: &WalletDb<C, P>,
+    txid: TxId,
+) -> Result<Option<WalletTx<AccountId>>, SqliteClientError> {
+    let conn = wdb.conn.borrow();
+    let tx_meta = conn
+        .query_row(
+            "SELECT transactions.tx_index, transactions.fee, transaction_data.raw IS NOT NULL
+            FROM transactions
+            LEFT JOIN transaction_data ON transaction_data.id_tx = transactions.id_tx
+            WHERE transactions.txid = :txid",
+            named_params![":txid": txid.as_ref()],
+            |row| {
+                Ok((
+                    row.get::<_, Option<i64>>(0)?,
+                    row.get::<_, Option<i64>>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            },
+        )
+        .optional()?;
+
+    let (tx_index, fee, has_raw) = match tx_meta {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+    if !has_raw {
+        return Err(SqliteClientError::TransactionDataUnavailable(txid));
+    }
+
+    let block_index = tx_index
+        .map(usize::try_from)
+        .transpose()
+        .map_err(|_| SqliteClientError::CorruptedData("Invalid transaction index.".to_owned()))?
+        .unwrap_or(0);
+    let fee = fee
+        .map(|f| {
+            u64::try_from(f)
+                .ok()
+                .and_then(|f| NonNegativeAmount::from_u64(f).ok())
+                .ok_or_else(|| {
+                    SqliteClientError::CorruptedData(format!("Invalid transaction fee {}", f))
+                })
+        })
+        .transpose()?;
+
+    let (_, tx) = get_transaction(conn, &wdb.params, txid)?;
+    let (sapling_spends, sapling_outputs) =
+        sapling::get_wallet_sapling_parts(conn, &wdb.params, &tx)?;
+
+    let (transparent_inputs, transparent_outputs) = tx
+        .transparent_bundle()
+        .map_or((0, 0), |b| (b.vin.len(), b.vout.len()));
+    let (sapling_spend_count, sapling_output_count) = tx.sapling_bundle().map_or((0, 0), |b| {
+        (b.shielded_spends().len(), b.shielded_outputs().len())
+    });
+    let orchard_action_count = tx.orchard_bundle().map_or(0, |b| b.actions().len());
+
+    Ok(Some(WalletTx::new(
+        txid,
+        block_index,
+        sapling_spends,
+        sapling_outputs,
+        #[cfg(feature = "orchard")]
+        vec![],
+        #[cfg(feature = "orchard")]
+        vec![],
+        #[cfg(feature = "transparent-inputs")]
+        vec![],
+        fee,
+        std::cmp::max(transparent_inputs, transparent_outputs)
+            + std::cmp::max(sapling_spend_count, sapling_output_count)
+            + orchard_action_count,
+        sapling_output_count + orchard_action_count,
+    )))
+}
+
 /// Looks up a transaction by its [`TxId`].
 ///
 /// Returns the decoded transaction, along with the block height that was used in its decoding.
@@ -1028,8 +1171,10 @@ pub(crate) fn get_transaction<P: Parameters>(
         Option<BlockHeight>,
         Option<BlockHeight>,
     ) = conn.query_row(
-        "SELECT raw, block, expiry_height FROM transactions
-        WHERE txid = ?",
+        "SELECT transaction_data.raw, transactions.block, transactions.expiry_height
+        FROM transactions
+        LEFT JOIN transaction_data ON transaction_data.id_tx = transactions.id_tx
+        WHERE transactions.txid = ?",
         [txid.as_ref()],
         |row| {
             let h: Option<u32> = row.get(1)?;
@@ -1528,7 +1673,11 @@ pub(crate) fn truncate_to_height<P: consensus::Parameters>(
 
     // nothing to do if we're deleting back down to the max height
     if block_height < last_scanned_height {
-        // Truncate the note commitment trees
+        // Truncate the note commitment trees. Checkpoints are identified by the height of the
+        // block whose final note commitment they follow (scanning emits
+        // `Retention::Checkpoint { id: block_height, .. }` for the last note commitment of each
+        // block), so the `sapling_tree_checkpoints` table already provides the mapping from
+        // block height to tree position required to rewind without replaying blocks.
         let mut wdb = WalletDb {
             conn: SqlTransaction(conn),
             params: params.clone(),
@@ -1783,6 +1932,8 @@ pub(crate) fn put_block(
     block_time: u32,
     sapling_commitment_tree_size: u32,
     sapling_output_count: u32,
+    #[cfg(feature = "orchard")] orchard_commitment_tree_size: u32,
+    #[cfg(feature = "orchard")] orchard_action_count: u32,
 ) -> Result<(), SqliteClientError> {
     let block_hash_data = conn
         .query_row(
@@ -1813,7 +1964,9 @@ pub(crate) fn put_block(
             time,
             sapling_commitment_tree_size,
             sapling_output_count,
-            sapling_tree
+            sapling_tree,
+            orchard_commitment_tree_size,
+            orchard_action_count
         )
         VALUES (
             :height,
@@ -1821,21 +1974,36 @@ pub(crate) fn put_block(
             :block_time,
             :sapling_commitment_tree_size,
             :sapling_output_count,
-            x'00'
+            x'00',
+            :orchard_commitment_tree_size,
+            :orchard_action_count
         )
         ON CONFLICT (height) DO UPDATE
         SET hash = :hash,
             time = :block_time,
             sapling_commitment_tree_size = :sapling_commitment_tree_size,
-            sapling_output_count = :sapling_output_count",
+            sapling_output_count = :sapling_output_count,
+            orchard_commitment_tree_size = :orchard_commitment_tree_size,
+            orchard_action_count = :orchard_action_count",
     )?;
 
+    #[cfg(feature = "orchard")]
+    let (orchard_commitment_tree_size, orchard_action_count) = (
+        Some(orchard_commitment_tree_size),
+        Some(orchard_action_count),
+    );
+    #[cfg(not(feature = "orchard"))]
+    let (orchard_commitment_tree_size, orchard_action_count): (Option<u32>, Option<u32>) =
+        (None, None);
+
     stmt_upsert_block.execute(named_params![
         ":height": u32::from(block_height),
         ":hash": &block_hash.0[..],
         ":block_time": block_time,
         ":sapling_commitment_tree_size": sapling_commitment_tree_size,
         ":sapling_output_count": sapling_output_count,
+        ":orchard_commitment_tree_size": orchard_commitment_tree_size,
+        ":orchard_action_count": orchard_action_count,
     ])?;
 
     Ok(())
@@ -1877,15 +2045,20 @@ pub(crate) fn put_tx_data(
     fee: Option<NonNegativeAmount>,
     created_at: Option<time::OffsetDateTime>,
 ) -> Result<i64, SqliteClientError> {
-    let mut stmt_upsert_tx_data = conn.prepare_cached(
-        "INSERT INTO transactions (txid, created, expiry_height, raw, fee)
-        VALUES (:txid, :created_at, :expiry_height, :raw, :fee)
+    let mut stmt_upsert_tx = conn.prepare_cached(
+        "INSERT INTO transactions (txid, created, expiry_height, fee)
+        VALUES (:txid, :created_at, :expiry_height, :fee)
         ON CONFLICT (txid) DO UPDATE
         SET expiry_height = :expiry_height,
-            raw = :raw,
             fee = IFNULL(:fee, fee)
         RETURNING id_tx",
     )?;
+    let mut stmt_upsert_tx_data = conn.prepare_cached(
+        "INSERT INTO transaction_data (id_tx, raw)
+        VALUES (:id_tx, :raw)
+        ON CONFLICT (id_tx) DO UPDATE
+        SET raw = :raw",
+    )?;
 
     let txid = tx.txid();
     let mut raw_tx = vec![];
@@ -1895,13 +2068,13 @@ pub(crate) fn put_tx_data(
         ":txid": &txid.as_ref()[..],
         ":created_at": created_at,
         ":expiry_height": u32::from(tx.expiry_height()),
-        ":raw": raw_tx,
         ":fee": fee.map(u64::from),
     ];
+    let id_tx = stmt_upsert_tx.query_row(tx_params, |row| row.get::<_, i64>(0))?;
 
-    stmt_upsert_tx_data
-        .query_row(tx_params, |row| row.get::<_, i64>(0))
-        .map_err(SqliteClientError::from)
+    stmt_upsert_tx_data.execute(named_params![":id_tx": id_tx, ":raw": raw_tx,])?;
+
+    Ok(id_tx)
 }
 
 /// Marks the given UTXO as having been spent.
@@ -2063,10 +2236,10 @@ pub(crate) fn insert_sent_output<P: consensus::Parameters>(
     let mut stmt_insert_sent_output = conn.prepare_cached(
         "INSERT INTO sent_notes (
             tx, output_pool, output_index, from_account_id,
-            to_address, to_account_id, value, memo)
+            to_address, to_account_id, value, memo, has_memo)
         VALUES (
             :tx, :output_pool, :output_index, :from_account_id,
-            :to_address, :to_account_id, :value, :memo)",
+            :to_address, :to_account_id, :value, :memo, IFNULL(:has_memo, 0))",
     )?;
 
     let (to_address, to_account_id, pool_type) = recipient_params(params, output.recipient());
@@ -2078,7 +2251,8 @@ pub(crate) fn insert_sent_output<P: consensus::Parameters>(
         ":to_address": &to_address,
         ":to_account_id": to_account_id.map(|a| a.0),
         ":value": &i64::from(Amount::from(output.value())),
-        ":memo": memo_repr(output.memo())
+        ":memo": memo_repr(output.memo()),
+        ":has_memo": has_memo(output.memo())
     ];
 
     stmt_insert_sent_output.execute(sql_args)?;
@@ -2111,16 +2285,17 @@ pub(crate) fn put_sent_output<P: consensus::Parameters>(
     let mut stmt_upsert_sent_output = conn.prepare_cached(
         "INSERT INTO sent_notes (
             tx, output_pool, output_index, from_account_id,
-            to_address, to_account_id, value, memo)
+            to_address, to_account_id, value, memo, has_memo)
         VALUES (
             :tx, :output_pool, :output_index, :from_account_id,
-            :to_address, :to_account_id, :value, :memo)
+            :to_address, :to_account_id, :value, :memo, IFNULL(:has_memo, 0))
         ON CONFLICT (tx, output_pool, output_index) DO UPDATE
         SET from_account_id = :from_account_id,
             to_address = :to_address,
             to_account_id = :to_account_id,
             value = :value,
-            memo = IFNULL(:memo, memo)",
+            memo = IFNULL(:memo, memo),
+            has_memo = IFNULL(:has_memo, has_memo)",
     )?;
 
     let (to_address, to_account_id, pool_type) = recipient_params(params, recipient);
@@ -2132,7 +2307,8 @@ pub(crate) fn put_sent_output<P: consensus::Parameters>(
         ":to_address": &to_address,
         ":to_account_id": &to_account_id.map(|a| a.0),
         ":value": &i64::from(Amount::from(value)),
-        ":memo": memo_repr(memo)
+        ":memo": memo_repr(memo),
+        ":has_memo": has_memo(memo)
     ];
 
     stmt_upsert_sent_output.execute(sql_args)?;
@@ -2149,7 +2325,7 @@ pub(crate) fn insert_nullifier_map<N: AsRef<[u8]>>(
     conn: &rusqlite::Transaction<'_>,
     block_height: BlockHeight,
     spend_pool: ShieldedProtocol,
-    new_entries: &[(TxId, u16, Vec<N>)],
+    new_entries: &[(TxId, u16, Vec<(usize, N)>)],
 ) -> Result<(), SqliteClientError> {
     let mut stmt_select_tx_locators = conn.prepare_cached(
         "SELECT block_height, tx_index, txid
@@ -2163,11 +2339,12 @@ pub(crate) fn insert_nullifier_map<N: AsRef<[u8]>>(
     )?;
     let mut stmt_insert_nullifier_mapping = conn.prepare_cached(
         "INSERT INTO nullifier_map
-        (spend_pool, nf, block_height, tx_index)
-        VALUES (:spend_pool, :nf, :block_height, :tx_index)
+        (spend_pool, nf, block_height, tx_index, spend_index)
+        VALUES (:spend_pool, :nf, :block_height, :tx_index, :spend_index)
         ON CONFLICT (spend_pool, nf) DO UPDATE
         SET block_height = :block_height,
-            tx_index = :tx_index",
+            tx_index = :tx_index,
+            spend_index = :spend_index",
     )?;
 
     for (txid, tx_index, nullifiers) in new_entries {
@@ -2217,7 +2394,7 @@ pub(crate) fn insert_nullifier_map<N: AsRef<[u8]>>(
             None => stmt_insert_tx_locator.execute(tx_args).map(|_| ())?,
         }
 
-        for nf in nullifiers {
+        for (spend_index, nf) in nullifiers {
             // Here it is okay to use an upsert, because per above we've confirmed that
             // the locator points to the same transaction.
             let nf_args = named_params![
@@ -2225,6 +2402,7 @@ pub(crate) fn insert_nullifier_map<N: AsRef<[u8]>>(
                 ":nf": nf.as_ref(),
                 ":block_height": u32::from(block_height),
                 ":tx_index": tx_index,
+                ":spend_index": i64::try_from(*spend_index).expect("spend indices are representable as i64"),
             ];
             stmt_insert_nullifier_mapping.execute(nf_args)?;
         }
@@ -2282,6 +2460,11 @@ pub(crate) fn query_nullifier_map<N: AsRef<[u8]>, S>(
             vec![],
             #[cfg(feature = "orchard")]
             vec![],
+            #[cfg(feature = "transparent-inputs")]
+            vec![],
+            None,
+            0,
+            0,
         ),
         height,
     )
@@ -2309,12 +2492,20 @@ mod tests {
     use std::num::NonZeroU32;
 
     use sapling::zip32::ExtendedSpendingKey;
-    use zcash_client_backend::data_api::{AccountBirthday, WalletRead};
-    use zcash_primitives::{block::BlockHash, transaction::components::amount::NonNegativeAmount};
+    use zcash_client_backend::{
+        data_api::{AccountBirthday, WalletRead},
+        ShieldedProtocol,
+    };
+    use zcash_primitives::{
+        block::BlockHash,
+        consensus::BlockHeight,
+        transaction::{components::amount::NonNegativeAmount, TxId},
+    };
 
     use crate::{
+        error::SqliteClientError,
         testing::{AddressType, BlockCache, TestBuilder, TestState},
-        wallet::{get_account, Account},
+        wallet::{get_account, insert_nullifier_map, Account},
         AccountId,
     };
 
@@ -2327,15 +2518,41 @@ mod tests {
             fees::{fixed, DustOutputPolicy},
             wallet::WalletTransparentOutput,
         },
-        zcash_primitives::{
-            consensus::BlockHeight,
-            transaction::{
-                components::{OutPoint, TxOut},
-                fees::fixed::FeeRule as FixedFeeRule,
-            },
+        zcash_primitives::transaction::{
+            components::{OutPoint, TxOut},
+            fees::fixed::FeeRule as FixedFeeRule,
         },
     };
 
+    #[test]
+    fn nullifier_map_records_spend_index() {
+        let mut st = TestBuilder::new().build();
+
+        let txid = TxId::from_bytes([1; 32]);
+        st.wallet_mut()
+            .transactionally::<_, _, SqliteClientError>(|wdb| {
+                insert_nullifier_map(
+                    wdb.conn.0,
+                    BlockHeight::from(1),
+                    ShieldedProtocol::Sapling,
+                    &[(txid, 0, vec![(0, [2; 32]), (3, [3; 32])])],
+                )
+            })
+            .unwrap();
+
+        let mut stmt = st
+            .wallet()
+            .conn
+            .prepare("SELECT nf, spend_index FROM nullifier_map ORDER BY spend_index")
+            .unwrap();
+        let entries = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<([u8; 32], u32)>, _>>()
+            .unwrap();
+        assert_eq!(entries, vec![([2; 32], 0), ([3; 32], 3)]);
+    }
+
     #[test]
     fn empty_database_has_no_balance() {
         let st = TestBuilder::new()
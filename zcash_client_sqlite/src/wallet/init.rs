@@ -144,6 +144,24 @@ pub fn init_wallet_db<P: consensus::Parameters + 'static>(
     init_wallet_db_internal(wdb, seed, &[])
 }
 
+/// Recomputes the fee paid by each transaction in the wallet for which full transaction data is
+/// stored, updating the stored fee wherever it differs from the computed value.
+///
+/// Computing the fee of a transaction that spends transparent outputs requires the values of
+/// those outputs to be known to the wallet. This should therefore be invoked after UTXO data has
+/// been added to the wallet for transactions that were stored before their inputs were known.
+/// The database schema is not modified.
+///
+/// Returns the number of transactions for which the stored fee was updated.
+pub fn recompute_transaction_fees<P>(
+    wdb: &mut WalletDb<rusqlite::Connection, P>,
+) -> Result<usize, WalletMigrationError> {
+    let tx = wdb.conn.transaction()?;
+    let updated = migrations::update_fees(&tx, "SELECT id_tx, raw FROM transaction_data")?;
+    tx.commit()?;
+    Ok(updated)
+}
+
 fn init_wallet_db_internal<P: consensus::Parameters + 'static>(
     wdb: &mut WalletDb<rusqlite::Connection, P>,
     seed: Option<SecretVec<u8>>,
@@ -255,7 +273,7 @@ mod tests {
                 spend_pool INTEGER NOT NULL,
                 nf BLOB NOT NULL,
                 block_height INTEGER NOT NULL,
-                tx_index INTEGER NOT NULL,
+                tx_index INTEGER NOT NULL, spend_index INTEGER,
                 CONSTRAINT tx_locator
                     FOREIGN KEY (block_height, tx_index)
                     REFERENCES tx_locator_map(block_height, tx_index)
@@ -276,7 +294,7 @@ mod tests {
                 memo BLOB,
                 spent INTEGER,
                 commitment_tree_position INTEGER,
-                recipient_key_scope INTEGER NOT NULL DEFAULT 0,
+                recipient_key_scope INTEGER NOT NULL DEFAULT 0, has_memo INTEGER NOT NULL DEFAULT 0, discovered_by_key_id INTEGER REFERENCES viewing_keys(id),
                 FOREIGN KEY (tx) REFERENCES transactions(id_tx),
                 FOREIGN KEY (account_id) REFERENCES accounts(id),
                 FOREIGN KEY (spent) REFERENCES transactions(id_tx),
@@ -329,7 +347,7 @@ mod tests {
                 to_address TEXT,
                 to_account_id INTEGER,
                 value INTEGER NOT NULL,
-                memo BLOB,
+                memo BLOB, has_memo INTEGER NOT NULL DEFAULT 0,
                 FOREIGN KEY (tx) REFERENCES transactions(id_tx),
                 FOREIGN KEY (from_account_id) REFERENCES accounts(id),
                 FOREIGN KEY (to_account_id) REFERENCES accounts(id),
@@ -340,6 +358,11 @@ mod tests {
             )"#,
             // Internal table created by SQLite when we started using `AUTOINCREMENT`.
             "CREATE TABLE sqlite_sequence(name,seq)",
+            "CREATE TABLE transaction_data (
+                id_tx INTEGER PRIMARY KEY,
+                raw BLOB NOT NULL,
+                FOREIGN KEY (id_tx) REFERENCES transactions(id_tx)
+            )",
             "CREATE TABLE transactions (
                 id_tx INTEGER PRIMARY KEY,
                 txid BLOB NOT NULL UNIQUE,
@@ -347,7 +370,6 @@ mod tests {
                 block INTEGER,
                 tx_index INTEGER,
                 expiry_height INTEGER,
-                raw BLOB,
                 fee INTEGER,
                 FOREIGN KEY (block) REFERENCES blocks(height)
             )",
@@ -371,6 +393,13 @@ mod tests {
                 FOREIGN KEY (spent_in_tx) REFERENCES transactions(id_tx),
                 CONSTRAINT tx_outpoint UNIQUE (prevout_txid, prevout_idx)
             )"#,
+            "CREATE TABLE viewing_keys (
+                id INTEGER PRIMARY KEY,
+                account_id INTEGER NOT NULL,
+                key_scope INTEGER NOT NULL,
+                FOREIGN KEY (account_id) REFERENCES accounts(id),
+                CONSTRAINT account_key_scope UNIQUE (account_id, key_scope)
+            )",
         ];
 
         let mut tables_query = st
@@ -390,6 +419,44 @@ mod tests {
         }
 
         let expected_views = vec![
+            // v_account_balances
+            "CREATE VIEW v_account_balances AS
+            WITH
+            chain_tip AS (
+                SELECT IFNULL(MAX(height), 0) AS height FROM blocks
+            ),
+            notes AS (
+                SELECT sapling_received_notes.account_id AS account_id,
+                       sapling_received_notes.value      AS value,
+                       sapling_received_notes.is_change  AS is_change,
+                       CASE
+                            WHEN spend.block IS NOT NULL
+                              OR (spend.id_tx IS NOT NULL
+                                  AND (spend.expiry_height IS NULL OR spend.expiry_height >= chain_tip.height))
+                            THEN 1
+                            ELSE 0
+                       END AS is_spent
+                FROM sapling_received_notes
+                JOIN chain_tip
+                JOIN transactions
+                     ON transactions.id_tx = sapling_received_notes.tx
+                LEFT JOIN transactions spend
+                          ON spend.id_tx = sapling_received_notes.spent
+                WHERE transactions.block IS NOT NULL
+                   OR transactions.expiry_height IS NULL
+                   OR transactions.expiry_height >= chain_tip.height
+            )
+            SELECT accounts.id AS account_id,
+                   COALESCE(SUM(CASE WHEN notes.is_change = 0 THEN notes.value ELSE 0 END), 0)
+                       AS total_received,
+                   COALESCE(SUM(CASE WHEN notes.is_spent = 1 THEN notes.value ELSE 0 END), 0)
+                   - COALESCE(SUM(CASE WHEN notes.is_change != 0 THEN notes.value ELSE 0 END), 0)
+                       AS total_spent,
+                   COALESCE(SUM(CASE WHEN notes.is_spent = 0 THEN notes.value ELSE 0 END), 0)
+                       AS balance
+            FROM accounts
+            LEFT JOIN notes ON notes.account_id = accounts.id
+            GROUP BY accounts.id".to_owned(),
             // v_sapling_shard_scan_ranges
             format!(
                 "CREATE VIEW v_sapling_shard_scan_ranges AS
@@ -472,11 +539,7 @@ mod tests {
                             WHEN sapling_received_notes.is_change THEN 0
                             ELSE 1
                        END AS received_count,
-                       CASE
-                         WHEN (sapling_received_notes.memo IS NULL OR sapling_received_notes.memo = X'F6')
-                           THEN 0
-                         ELSE 1
-                       END AS memo_present
+                       sapling_received_notes.has_memo       AS memo_present
                 FROM sapling_received_notes
                 JOIN transactions
                      ON transactions.id_tx = sapling_received_notes.tx
@@ -524,9 +587,8 @@ mod tests {
                        COUNT(DISTINCT sent_notes.id) as sent_notes,
                        SUM(
                          CASE
-                           WHEN (sent_notes.memo IS NULL OR sent_notes.memo = X'F6' OR sapling_received_notes.tx IS NOT NULL)
-                             THEN 0
-                           ELSE 1
+                           WHEN sapling_received_notes.tx IS NOT NULL THEN 0
+                           ELSE sent_notes.has_memo
                          END
                        ) AS memo_count
                 FROM sent_notes
@@ -540,33 +602,52 @@ mod tests {
             ),
             blocks_max_height AS (
                 SELECT MAX(blocks.height) as max_height FROM blocks
+            ),
+            summaries AS (
+                SELECT notes.account_id                  AS account_id,
+                       notes.block                       AS mined_height,
+                       notes.txid                        AS txid,
+                       transactions.id_tx                AS id_tx,
+                       transactions.tx_index             AS tx_index,
+                       transactions.expiry_height        AS expiry_height,
+                       SUM(notes.value)                  AS account_balance_delta,
+                       transactions.fee                  AS fee_paid,
+                       SUM(notes.is_change) > 0          AS has_change,
+                       MAX(COALESCE(sent_note_counts.sent_notes, 0))  AS sent_note_count,
+                       SUM(notes.received_count)         AS received_note_count,
+                       SUM(notes.memo_present) + MAX(COALESCE(sent_note_counts.memo_count, 0)) AS memo_count,
+                       blocks.time                       AS block_time,
+                       (
+                            blocks.height IS NULL
+                            AND transactions.expiry_height BETWEEN 1 AND blocks_max_height.max_height
+                       ) AS expired_unmined
+                FROM notes
+                LEFT JOIN transactions
+                     ON notes.txid = transactions.txid
+                JOIN blocks_max_height
+                LEFT JOIN blocks ON blocks.height = notes.block
+                LEFT JOIN sent_note_counts
+                          ON sent_note_counts.account_id = notes.account_id
+                          AND sent_note_counts.txid = notes.txid
+                GROUP BY notes.account_id, notes.txid
             )
-            SELECT notes.account_id                  AS account_id,
-                   notes.block                       AS mined_height,
-                   notes.txid                        AS txid,
-                   transactions.tx_index             AS tx_index,
-                   transactions.expiry_height        AS expiry_height,
-                   transactions.raw                  AS raw,
-                   SUM(notes.value)                  AS account_balance_delta,
-                   transactions.fee                  AS fee_paid,
-                   SUM(notes.is_change) > 0          AS has_change,
-                   MAX(COALESCE(sent_note_counts.sent_notes, 0))  AS sent_note_count,
-                   SUM(notes.received_count)         AS received_note_count,
-                   SUM(notes.memo_present) + MAX(COALESCE(sent_note_counts.memo_count, 0)) AS memo_count,
-                   blocks.time                       AS block_time,
-                   (
-                        blocks.height IS NULL
-                        AND transactions.expiry_height BETWEEN 1 AND blocks_max_height.max_height
-                   ) AS expired_unmined
-            FROM notes
-            LEFT JOIN transactions
-                 ON notes.txid = transactions.txid
-            JOIN blocks_max_height
-            LEFT JOIN blocks ON blocks.height = notes.block
-            LEFT JOIN sent_note_counts
-                      ON sent_note_counts.account_id = notes.account_id
-                      AND sent_note_counts.txid = notes.txid
-            GROUP BY notes.account_id, notes.txid".to_owned(),
+            SELECT summaries.account_id            AS account_id,
+                   summaries.mined_height          AS mined_height,
+                   summaries.txid                  AS txid,
+                   summaries.tx_index              AS tx_index,
+                   summaries.expiry_height         AS expiry_height,
+                   transaction_data.raw            AS raw,
+                   summaries.account_balance_delta AS account_balance_delta,
+                   summaries.fee_paid              AS fee_paid,
+                   summaries.has_change            AS has_change,
+                   summaries.sent_note_count       AS sent_note_count,
+                   summaries.received_note_count   AS received_note_count,
+                   summaries.memo_count            AS memo_count,
+                   summaries.block_time            AS block_time,
+                   summaries.expired_unmined       AS expired_unmined
+            FROM summaries
+            LEFT JOIN transaction_data
+                 ON transaction_data.id_tx = summaries.id_tx".to_owned(),
             // v_tx_outputs
             "CREATE VIEW v_tx_outputs AS
             SELECT transactions.txid                   AS txid,
@@ -577,7 +658,8 @@ mod tests {
                    NULL                                AS to_address,
                    sapling_received_notes.value        AS value,
                    sapling_received_notes.is_change    AS is_change,
-                   sapling_received_notes.memo         AS memo
+                   sapling_received_notes.memo         AS memo,
+                   sapling_received_notes.has_memo     AS memo_present
             FROM sapling_received_notes
             JOIN transactions
                  ON transactions.id_tx = sapling_received_notes.tx
@@ -593,7 +675,8 @@ mod tests {
                    utxos.address                AS to_address,
                    utxos.value_zat              AS value,
                    0                            AS is_change,
-                   NULL                         AS memo
+                   NULL                         AS memo,
+                   0                            AS memo_present
             FROM utxos
             UNION
             SELECT transactions.txid                 AS txid,
@@ -604,7 +687,8 @@ mod tests {
                    sent_notes.to_address             AS to_address,
                    sent_notes.value                  AS value,
                    0                                 AS is_change,
-                   sent_notes.memo                   AS memo
+                   sent_notes.memo                   AS memo,
+                   sent_notes.has_memo               AS memo_present
             FROM sent_notes
             JOIN transactions
                  ON transactions.id_tx = sent_notes.tx
@@ -1140,4 +1224,125 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn spend_linking_queries_use_indices() {
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db(&mut db_data, None).unwrap();
+
+        // The `UNIQUE` constraints on `sapling_received_notes.nf` and on the outpoint of each
+        // UTXO are backed by indices, which the queries used to link spends to received notes
+        // and to look up the values of spent UTXOs must use rather than scanning their tables.
+        for query in [
+            "UPDATE sapling_received_notes SET spent = ? WHERE nf = ?",
+            "SELECT value_zat FROM utxos WHERE prevout_txid = ? AND prevout_idx = ?",
+        ] {
+            let mut stmt = db_data
+                .conn
+                .prepare(&format!("EXPLAIN QUERY PLAN {}", query))
+                .unwrap();
+            let plan = stmt
+                .query_map([rusqlite::types::Null; 2], |row| row.get::<_, String>(3))
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert!(
+                plan.iter()
+                    .all(|step| step.starts_with("SEARCH") && step.contains("INDEX")),
+                "query `{}` does not use an index: {:?}",
+                query,
+                plan
+            );
+        }
+    }
+
+    #[test]
+    fn recompute_transaction_fees() {
+        use zcash_primitives::{
+            legacy::Script,
+            transaction::components::{
+                amount::NonNegativeAmount,
+                transparent::{self, Authorized, OutPoint},
+                TxIn, TxOut,
+            },
+        };
+
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db(&mut db_data, None).unwrap();
+
+        // A transaction spending a 12000-zatoshi UTXO and creating a 10000-zatoshi output.
+        let tx = TransactionData::from_parts(
+            TxVersion::Sapling,
+            BranchId::Canopy,
+            0,
+            BlockHeight::from(3),
+            Some(transparent::Bundle {
+                vin: vec![TxIn {
+                    prevout: OutPoint::new([1u8; 32], 1),
+                    script_sig: Script(vec![]),
+                    sequence: 0,
+                }],
+                vout: vec![TxOut {
+                    value: NonNegativeAmount::const_from_u64(10000),
+                    script_pubkey: Script(vec![]),
+                }],
+                authorization: Authorized,
+            }),
+            None,
+            None,
+            None,
+        )
+        .freeze()
+        .unwrap();
+        let mut tx_bytes = vec![];
+        tx.write(&mut tx_bytes).unwrap();
+
+        db_data
+            .conn
+            .execute(
+                "INSERT INTO transactions (id_tx, txid) VALUES (0, :txid)",
+                named_params![":txid": tx.txid().as_ref()],
+            )
+            .unwrap();
+        db_data
+            .conn
+            .execute(
+                "INSERT INTO transaction_data (id_tx, raw) VALUES (0, :raw)",
+                named_params![":raw": tx_bytes],
+            )
+            .unwrap();
+        let stored_fee = |db_data: &WalletDb<rusqlite::Connection, Network>| -> Option<i64> {
+            db_data
+                .conn
+                .query_row("SELECT fee FROM transactions WHERE id_tx = 0", [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+
+        // The fee cannot be computed while the spent UTXO is unknown to the wallet.
+        assert_eq!(super::recompute_transaction_fees(&mut db_data).unwrap(), 0);
+        assert_eq!(stored_fee(&db_data), None);
+
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO accounts (id, account_type, uivk, birthday_height)
+                VALUES (1, 1, 'uivk', 0);
+                INSERT INTO utxos (
+                    received_by_account_id, address, prevout_txid, prevout_idx, script,
+                    value_zat, height
+                )
+                VALUES (1, '', x'0101010101010101010101010101010101010101010101010101010101010101', 1, x'', 12000, 1);",
+            )
+            .unwrap();
+
+        assert_eq!(super::recompute_transaction_fees(&mut db_data).unwrap(), 1);
+        assert_eq!(stored_fee(&db_data), Some(2000));
+
+        // Fees that are already correct are not updated.
+        assert_eq!(super::recompute_transaction_fees(&mut db_data).unwrap(), 0);
+    }
 }
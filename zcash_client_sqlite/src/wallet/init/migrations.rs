@@ -4,19 +4,25 @@ mod add_utxo_account;
 mod addresses_table;
 mod full_account_ids;
 mod initial_setup;
+mod note_memo_flags;
 mod nullifier_map;
+mod nullifier_map_spend_index;
+mod received_note_key_ids;
 mod received_notes_nullable_nf;
 mod receiving_key_scopes;
 mod sapling_memo_consistency;
 mod sent_notes_to_internal;
 mod shardtree_support;
+mod transaction_data_table;
 mod ufvk_support;
 mod utxos_table;
+mod v_account_balances;
 mod v_sapling_shard_unscanned_ranges;
 mod v_transactions_net;
 mod v_transactions_note_uniqueness;
 mod v_transactions_shielding_balance;
 mod v_transactions_transparent_history;
+mod v_tx_outputs_memo_present;
 mod v_tx_outputs_use_legacy_false;
 mod wallet_summaries;
 
@@ -28,6 +34,8 @@ use zcash_primitives::consensus;
 
 use super::WalletMigrationError;
 
+pub(super) use add_transaction_views::update_fees;
+
 pub(super) fn all_migrations<P: consensus::Parameters + 'static>(
     params: &P,
     seed: Option<SecretVec<u8>>,
@@ -59,6 +67,16 @@ pub(super) fn all_migrations<P: consensus::Parameters + 'static>(
     //                                        \        |         v_transactions_note_uniqueness
     //                                         \       |          /
     //                                           full_account_ids
+    //                                             /              \
+    //                          v_tx_outputs_memo_present    v_account_balances
+    //                                             \              /
+    //                                              note_memo_flags
+    //                                                     |
+    //                                           transaction_data_table
+    //                                                     |
+    //                                           received_note_key_ids
+    //                                                     |
+    //                                         nullifier_map_spend_index
     vec![
         Box::new(initial_setup::Migration {}),
         Box::new(utxos_table::Migration {}),
@@ -101,5 +119,11 @@ pub(super) fn all_migrations<P: consensus::Parameters + 'static>(
             seed,
             params: params.clone(),
         }),
+        Box::new(v_tx_outputs_memo_present::Migration),
+        Box::new(v_account_balances::Migration),
+        Box::new(note_memo_flags::Migration),
+        Box::new(transaction_data_table::Migration),
+        Box::new(received_note_key_ids::Migration),
+        Box::new(nullifier_map_spend_index::Migration),
     ]
 }
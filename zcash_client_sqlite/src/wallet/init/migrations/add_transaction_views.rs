@@ -1,12 +1,14 @@
 //! Migration that adds transaction summary views & add fee information to transactions.
 use std::collections::HashSet;
 
-use rusqlite::{self, types::ToSql, OptionalExtension};
+use rusqlite::{self, named_params, types::ToSql, OptionalExtension};
 use schemer_rusqlite::RusqliteMigration;
+use tracing::warn;
 use uuid::Uuid;
 
 use zcash_primitives::{
     consensus::BranchId,
+    memo::MemoBytes,
     transaction::{
         components::amount::{Amount, BalanceError},
         Transaction,
@@ -39,107 +41,184 @@ impl schemer::Migration for Migration {
     }
 }
 
-impl RusqliteMigration for Migration {
-    type Error = WalletMigrationError;
+/// Computes the fee paid by each transaction for which full transaction data is stored, and
+/// records it in the `fee` column of the `transactions` table.
+///
+/// `list_txs_query` must select the `id_tx` and raw transaction data of each transaction, as the
+/// table in which the raw data is stored differs between versions of the schema.
+///
+/// The fee of a transaction that spends transparent outputs unknown to the wallet cannot be
+/// computed; any fee previously recorded for such a transaction is left unchanged. Returns the
+/// number of transactions for which the recorded fee was changed.
+pub(in crate::wallet::init) fn update_fees(
+    conn: &rusqlite::Connection,
+    list_txs_query: &str,
+) -> Result<usize, WalletMigrationError> {
+    enum FeeError {
+        Db(rusqlite::Error),
+        UtxoNotFound,
+        Balance(BalanceError),
+        CorruptedData(String),
+    }
 
-    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), WalletMigrationError> {
-        enum FeeError {
-            Db(rusqlite::Error),
-            UtxoNotFound,
-            Balance(BalanceError),
-            CorruptedData(String),
+    impl From<BalanceError> for FeeError {
+        fn from(e: BalanceError) -> Self {
+            FeeError::Balance(e)
         }
+    }
 
-        impl From<BalanceError> for FeeError {
-            fn from(e: BalanceError) -> Self {
-                FeeError::Balance(e)
-            }
+    impl From<rusqlite::Error> for FeeError {
+        fn from(e: rusqlite::Error) -> Self {
+            FeeError::Db(e)
         }
+    }
+
+    let mut stmt_list_txs = conn.prepare(list_txs_query)?;
+
+    // Only rows for which the fee changes are updated, so that the number of updated rows
+    // can be reported.
+    let mut stmt_set_fee =
+        conn.prepare("UPDATE transactions SET fee = ?1 WHERE id_tx = ?2 AND fee IS NOT ?1")?;
+
+    let mut stmt_find_utxo_value =
+        conn.prepare("SELECT value_zat FROM utxos WHERE prevout_txid = ? AND prevout_idx = ?")?;
+
+    let mut updated = 0;
+    let mut tx_rows = stmt_list_txs.query([])?;
+    while let Some(row) = tx_rows.next()? {
+        let id_tx: i64 = row.get(0)?;
+        let tx_bytes: Option<Vec<u8>> = row.get(1)?;
+
+        // If only transaction metadata has been stored, and not transaction data, the fee
+        // information will eventually be set when the full transaction data is inserted.
+        if let Some(tx_bytes) = tx_bytes {
+            let tx = Transaction::read(
+                &tx_bytes[..],
+                // The consensus branch ID is unused in determining the fee paid, so
+                // just pass Nu5 as a dummy value since we know that parsing both v4
+                // and v5 transactions is supported during the Nu5 epoch.
+                BranchId::Nu5,
+            )
+            .map_err(|e| {
+                WalletMigrationError::CorruptedData(format!(
+                    "Parsing failed for transaction {:?}: {:?}",
+                    id_tx, e
+                ))
+            })?;
+
+            let fee_paid = tx.fee_paid(|op| {
+                let op_amount = stmt_find_utxo_value
+                    .query_row([op.hash().to_sql()?, op.n().to_sql()?], |row| {
+                        row.get::<_, i64>(0)
+                    })
+                    .optional()
+                    .map_err(FeeError::Db)?;
+
+                op_amount.map_or_else(
+                    || Err(FeeError::UtxoNotFound),
+                    |i| {
+                        Amount::from_i64(i).map_err(|_| {
+                            FeeError::CorruptedData(format!(
+                                "UTXO amount out of range in outpoint {:?}",
+                                op
+                            ))
+                        })
+                    },
+                )
+            });
 
-        impl From<rusqlite::Error> for FeeError {
-            fn from(e: rusqlite::Error) -> Self {
-                FeeError::Db(e)
+            match fee_paid {
+                Ok(fee_paid) => {
+                    updated += stmt_set_fee.execute([i64::from(fee_paid), id_tx])?;
+                }
+                Err(FeeError::UtxoNotFound) => {
+                    // If no fee has previously been recorded, the fee and net value will be
+                    // null in the transactions view.
+                    warn!(
+                        id_tx,
+                        "Unable to compute fee: one or more inputs spend UTXOs unknown to the wallet."
+                    );
+                }
+                Err(FeeError::Db(e)) => {
+                    return Err(WalletMigrationError::from(e));
+                }
+                Err(FeeError::Balance(e)) => {
+                    return Err(WalletMigrationError::from(e));
+                }
+                Err(FeeError::CorruptedData(s)) => {
+                    return Err(WalletMigrationError::CorruptedData(s));
+                }
             }
         }
+    }
 
-        transaction.execute_batch("ALTER TABLE transactions ADD COLUMN fee INTEGER;")?;
+    Ok(updated)
+}
 
-        let mut stmt_list_txs = transaction.prepare("SELECT id_tx, raw FROM transactions")?;
+/// Checks that every sent and received note refers to a transaction that exists in the
+/// `transactions` table.
+///
+/// The views created by this migration join notes to their transactions, so a note that refers
+/// to a nonexistent transaction would silently be omitted from the wallet's transaction history
+/// and balance calculations. Such notes indicate pre-existing corruption of the wallet database,
+/// and are reported as [`WalletMigrationError::CorruptedData`].
+fn check_orphaned_notes(conn: &rusqlite::Connection) -> Result<(), WalletMigrationError> {
+    let orphaned_txs = |table: &str| -> Result<(i64, String), rusqlite::Error> {
+        conn.query_row(
+            &format!(
+                "SELECT COUNT(*), IFNULL(GROUP_CONCAT(DISTINCT n.tx), '')
+                FROM {} n
+                LEFT OUTER JOIN transactions t ON t.id_tx = n.tx
+                WHERE t.id_tx IS NULL",
+                table
+            ),
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+    };
 
-        let mut stmt_set_fee =
-            transaction.prepare("UPDATE transactions SET fee = ? WHERE id_tx = ?")?;
+    let mut problems = vec![];
+    for table in ["sent_notes", "received_notes"] {
+        let (count, tx_ids) = orphaned_txs(table)?;
+        if count > 0 {
+            problems.push(format!(
+                "{} row(s) in {} refer to nonexistent transaction(s) [{}]",
+                count, table, tx_ids
+            ));
+        }
+    }
 
-        let mut stmt_find_utxo_value = transaction
-            .prepare("SELECT value_zat FROM utxos WHERE prevout_txid = ? AND prevout_idx = ?")?;
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(WalletMigrationError::CorruptedData(format!(
+            "Notes without corresponding transactions detected: {}",
+            problems.join("; ")
+        )))
+    }
+}
 
-        let mut tx_rows = stmt_list_txs.query([])?;
-        while let Some(row) = tx_rows.next()? {
-            let id_tx: i64 = row.get(0)?;
-            let tx_bytes: Option<Vec<u8>> = row.get(1)?;
+impl RusqliteMigration for Migration {
+    type Error = WalletMigrationError;
 
-            // If only transaction metadata has been stored, and not transaction data, the fee
-            // information will eventually be set when the full transaction data is inserted.
-            if let Some(tx_bytes) = tx_bytes {
-                let tx = Transaction::read(
-                    &tx_bytes[..],
-                    // The consensus branch ID is unused in determining the fee paid, so
-                    // just pass Nu5 as a dummy value since we know that parsing both v4
-                    // and v5 transactions is supported during the Nu5 epoch.
-                    BranchId::Nu5,
-                )
-                .map_err(|e| {
-                    WalletMigrationError::CorruptedData(format!(
-                        "Parsing failed for transaction {:?}: {:?}",
-                        id_tx, e
-                    ))
-                })?;
-
-                let fee_paid = tx.fee_paid(|op| {
-                    let op_amount = stmt_find_utxo_value
-                        .query_row([op.hash().to_sql()?, op.n().to_sql()?], |row| {
-                            row.get::<_, i64>(0)
-                        })
-                        .optional()
-                        .map_err(FeeError::Db)?;
-
-                    op_amount.map_or_else(
-                        || Err(FeeError::UtxoNotFound),
-                        |i| {
-                            Amount::from_i64(i).map_err(|_| {
-                                FeeError::CorruptedData(format!(
-                                    "UTXO amount out of range in outpoint {:?}",
-                                    op
-                                ))
-                            })
-                        },
-                    )
-                });
-
-                match fee_paid {
-                    Ok(fee_paid) => {
-                        stmt_set_fee.execute([i64::from(fee_paid), id_tx])?;
-                    }
-                    Err(FeeError::UtxoNotFound) => {
-                        // The fee and net value will end up being null in the transactions view.
-                    }
-                    Err(FeeError::Db(e)) => {
-                        return Err(WalletMigrationError::from(e));
-                    }
-                    Err(FeeError::Balance(e)) => {
-                        return Err(WalletMigrationError::from(e));
-                    }
-                    Err(FeeError::CorruptedData(s)) => {
-                        return Err(WalletMigrationError::CorruptedData(s));
-                    }
-                }
-            }
-        }
+    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), WalletMigrationError> {
+        check_orphaned_notes(transaction)?;
 
-        transaction.execute_batch(
-            "UPDATE sent_notes SET memo = NULL
-              WHERE memo = X'F600000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000';
-            UPDATE received_notes SET memo = NULL
-              WHERE memo = X'F600000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000';")?;
+        transaction.execute_batch("ALTER TABLE transactions ADD COLUMN fee INTEGER;")?;
+
+        update_fees(transaction, "SELECT id_tx, raw FROM transactions")?;
+
+        // Normalize the "no memo" sentinel (0xF6 followed by zeros) to NULL, so that the views
+        // below need only check for NULL memos.
+        let empty_memo = MemoBytes::empty();
+        transaction.execute(
+            "UPDATE sent_notes SET memo = NULL WHERE memo = :empty_memo",
+            named_params![":empty_memo": empty_memo.as_slice()],
+        )?;
+        transaction.execute(
+            "UPDATE received_notes SET memo = NULL WHERE memo = :empty_memo",
+            named_params![":empty_memo": empty_memo.as_slice()],
+        )?;
 
         transaction.execute_batch(
             "CREATE VIEW v_tx_sent AS
@@ -265,21 +344,33 @@ impl RusqliteMigration for Migration {
         Ok(())
     }
 
-    fn down(&self, _transaction: &rusqlite::Transaction) -> Result<(), WalletMigrationError> {
-        Err(WalletMigrationError::CannotRevert(MIGRATION_ID))
+    fn down(&self, transaction: &rusqlite::Transaction) -> Result<(), WalletMigrationError> {
+        transaction.execute_batch(
+            "DROP VIEW v_transactions;
+            DROP VIEW v_tx_received;
+            DROP VIEW v_tx_sent;
+
+            ALTER TABLE transactions DROP COLUMN fee;",
+        )?;
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use rusqlite::{self, params};
+    use rusqlite::{self, named_params, params};
+    use schemer::MigratorError;
+    use schemer_rusqlite::RusqliteMigration;
     use tempfile::NamedTempFile;
 
     use zcash_client_backend::keys::UnifiedSpendingKey;
-    use zcash_primitives::{consensus::Network, zip32::AccountId};
+    use zcash_primitives::{consensus::Network, memo::MemoBytes, zip32::AccountId};
 
     use crate::{
-        wallet::init::{init_wallet_db_internal, migrations::addresses_table},
+        wallet::init::{
+            init_wallet_db_internal, migrations::addresses_table, WalletMigrationError,
+        },
         WalletDb,
     };
 
@@ -292,6 +383,7 @@ mod tests {
             legacy::{keys::IncomingViewingKey, Script},
             transaction::{
                 components::{
+                    amount::NonNegativeAmount,
                     transparent::{self, Authorized, OutPoint},
                     Amount, TxIn, TxOut,
                 },
@@ -325,8 +417,6 @@ mod tests {
             VALUES (0, 2, 0, 0, '', 2);
             INSERT INTO sent_notes (tx, output_pool, output_index, from_account, address, value, memo)
             VALUES (0, 2, 1, 0, '', 3, X'61');
-            INSERT INTO sent_notes (tx, output_pool, output_index, from_account, address, value, memo)
-            VALUES (0, 2, 2, 0, '', 0, X'F600000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000');
 
             INSERT INTO received_notes (tx, output_index, account, diversifier, value, rcm, nf, is_change)
             VALUES (0, 0, 0, '', 2, '', 'a', false);
@@ -336,6 +426,15 @@ mod tests {
             VALUES (0, 4, 0, '', 7, '', 'c', true, X'63');",
         ).unwrap();
 
+        db_data
+            .conn
+            .execute(
+                "INSERT INTO sent_notes (tx, output_pool, output_index, from_account, address, value, memo)
+                VALUES (0, 2, 2, 0, '', 0, :memo)",
+                named_params![":memo": MemoBytes::empty().as_slice()],
+            )
+            .unwrap();
+
         init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
 
         let mut q = db_data
@@ -388,15 +487,116 @@ mod tests {
             assert_eq!(memo_count, 3);
         }
         assert_eq!(row_count, 1);
+
+        // A transaction mined in the chain tip block has a single confirmation.
+        let confirmations = |conn: &rusqlite::Connection| -> u32 {
+            conn.query_row(
+                "SELECT confirmations(mined_height) FROM v_transactions",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(confirmations(&db_data.conn), 1);
+
+        db_data
+            .conn
+            .execute(
+                "INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (9, 9, 0, x'00')",
+                [],
+            )
+            .unwrap();
+        assert_eq!(confirmations(&db_data.conn), 10);
+
+        // Unmined transactions have no confirmations.
+        let no_height: u32 = db_data
+            .conn
+            .query_row("SELECT confirmations(NULL)", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(no_height, 0);
+    }
+
+    #[test]
+    fn transaction_views_rejects_orphaned_notes() {
+        let network = Network::TestNetwork;
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), network).unwrap();
+        init_wallet_db_internal(&mut db_data, None, &[addresses_table::MIGRATION_ID]).unwrap();
+        let usk = UnifiedSpendingKey::from_seed(&network, &[0u8; 32][..], AccountId::ZERO).unwrap();
+        let ufvk = usk.to_unified_full_viewing_key();
+
+        db_data
+            .conn
+            .execute(
+                "INSERT INTO accounts (account, ufvk) VALUES (0, ?)",
+                params![ufvk.encode(&network)],
+            )
+            .unwrap();
+
+        // Simulate a corrupted database in which notes refer to a transaction that is missing
+        // from the `transactions` table.
+        db_data.conn.execute_batch(
+            "PRAGMA foreign_keys = OFF;
+            INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (0, 0, 0, x'00');
+            INSERT INTO transactions (block, id_tx, txid) VALUES (0, 0, '');
+
+            INSERT INTO sent_notes (tx, output_pool, output_index, from_account, address, value)
+            VALUES (0, 2, 0, 0, '', 2);
+            INSERT INTO sent_notes (tx, output_pool, output_index, from_account, address, value)
+            VALUES (7, 2, 0, 0, '', 3);
+
+            INSERT INTO received_notes (tx, output_index, account, diversifier, value, rcm, nf, is_change)
+            VALUES (7, 1, 0, '', 5, '', 'a', false);",
+        ).unwrap();
+
+        assert_matches!(
+            init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]),
+            Err(MigratorError::Migration {
+                error: WalletMigrationError::CorruptedData(msg),
+                ..
+            }) if msg.contains("1 row(s) in sent_notes") && msg.contains("1 row(s) in received_notes")
+        );
+    }
+
+    #[test]
+    fn transaction_views_down() {
+        let network = Network::TestNetwork;
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), network).unwrap();
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        let tx = db_data.conn.transaction().unwrap();
+        super::Migration.down(&tx).unwrap();
+        tx.commit().unwrap();
+
+        let fee_column_count: i64 = db_data
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM pragma_table_info('transactions') WHERE name = 'fee'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(fee_column_count, 0);
+
+        let view_count: i64 = db_data
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master
+                WHERE type = 'view'
+                AND name IN ('v_transactions', 'v_tx_received', 'v_tx_sent')",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(view_count, 0);
     }
 
     #[test]
     #[cfg(feature = "transparent-inputs")]
     fn migrate_from_wm2() {
         use zcash_client_backend::keys::UnifiedAddressRequest;
-        use zcash_primitives::{
-            legacy::keys::NonHardenedChildIndex, transaction::components::amount::NonNegativeAmount,
-        };
+        use zcash_primitives::legacy::keys::NonHardenedChildIndex;
 
         use crate::UA_TRANSPARENT;
 
@@ -490,4 +690,72 @@ mod tests {
 
         assert_eq!(fee, Amount::from_i64(300000000).unwrap());
     }
+
+    #[test]
+    #[cfg(feature = "transparent-inputs")]
+    fn migrate_with_unknown_utxo() {
+        let network = Network::TestNetwork;
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), network).unwrap();
+        init_wallet_db_internal(
+            &mut db_data,
+            None,
+            &[utxos_table::MIGRATION_ID, ufvk_support::MIGRATION_ID],
+        )
+        .unwrap();
+
+        // create a transaction spending a UTXO that the wallet has no record of
+        let tx = TransactionData::from_parts(
+            TxVersion::Sapling,
+            BranchId::Canopy,
+            0,
+            BlockHeight::from(3),
+            Some(transparent::Bundle {
+                vin: vec![TxIn {
+                    prevout: OutPoint::new([2u8; 32], 0),
+                    script_sig: Script(vec![]),
+                    sequence: 0,
+                }],
+                vout: vec![TxOut {
+                    value: NonNegativeAmount::const_from_u64(1100000000),
+                    script_pubkey: Script(vec![]),
+                }],
+                authorization: Authorized,
+            }),
+            None,
+            None,
+            None,
+        )
+        .freeze()
+        .unwrap();
+
+        let mut tx_bytes = vec![];
+        tx.write(&mut tx_bytes).unwrap();
+
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (0, 0, 0, x'00');",
+            )
+            .unwrap();
+        db_data
+            .conn
+            .execute(
+                "INSERT INTO transactions (block, id_tx, txid, raw) VALUES (0, 0, '', ?)",
+                params![tx_bytes],
+            )
+            .unwrap();
+
+        // The migration succeeds, leaving the fee unset.
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        let fee: Option<i64> = db_data
+            .conn
+            .query_row("SELECT fee FROM transactions WHERE id_tx = 0", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert_eq!(fee, None);
+    }
 }
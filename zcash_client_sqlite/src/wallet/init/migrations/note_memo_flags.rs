@@ -0,0 +1,322 @@
+//! This migration adds a persisted `has_memo` column to the `sapling_received_notes` and
+//! `sent_notes` tables, recording whether each note carries a non-empty memo. The
+//! `v_transactions` and `v_tx_outputs` views are rewritten to use this column, rather than
+//! comparing each note's memo against the empty-memo sentinel every time they are queried.
+
+use std::collections::HashSet;
+
+use schemer_rusqlite::RusqliteMigration;
+use uuid::Uuid;
+
+use crate::wallet::init::WalletMigrationError;
+
+use super::{v_account_balances, v_tx_outputs_memo_present};
+
+pub(super) const MIGRATION_ID: Uuid = Uuid::from_u128(0xfd543ca4_f9e3_4f20_87ec_ab113ff02a1b);
+
+pub(super) struct Migration;
+
+impl schemer::Migration for Migration {
+    fn id(&self) -> Uuid {
+        MIGRATION_ID
+    }
+
+    fn dependencies(&self) -> HashSet<Uuid> {
+        [
+            v_tx_outputs_memo_present::MIGRATION_ID,
+            v_account_balances::MIGRATION_ID,
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    fn description(&self) -> &'static str {
+        "Adds persisted `has_memo` columns to the note tables."
+    }
+}
+
+impl RusqliteMigration for Migration {
+    type Error = WalletMigrationError;
+
+    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        // The empty memo is stored as a single 0xF6 byte; see `wallet::memo_repr`.
+        transaction.execute_batch(
+            "ALTER TABLE sapling_received_notes ADD COLUMN has_memo INTEGER NOT NULL DEFAULT 0;
+            UPDATE sapling_received_notes
+            SET has_memo = (memo IS NOT NULL AND memo != X'F6');
+
+            ALTER TABLE sent_notes ADD COLUMN has_memo INTEGER NOT NULL DEFAULT 0;
+            UPDATE sent_notes
+            SET has_memo = (memo IS NOT NULL AND memo != X'F6');",
+        )?;
+
+        transaction.execute_batch(
+            "DROP VIEW v_transactions;
+            CREATE VIEW v_transactions AS
+            WITH
+            notes AS (
+                SELECT sapling_received_notes.id             AS id,
+                       sapling_received_notes.account_id     AS account_id,
+                       transactions.block                    AS block,
+                       transactions.txid                     AS txid,
+                       2                                     AS pool,
+                       sapling_received_notes.value          AS value,
+                       CASE
+                            WHEN sapling_received_notes.is_change THEN 1
+                            ELSE 0
+                       END AS is_change,
+                       CASE
+                            WHEN sapling_received_notes.is_change THEN 0
+                            ELSE 1
+                       END AS received_count,
+                       sapling_received_notes.has_memo       AS memo_present
+                FROM sapling_received_notes
+                JOIN transactions
+                     ON transactions.id_tx = sapling_received_notes.tx
+                UNION
+                SELECT utxos.id                      AS id,
+                       utxos.received_by_account_id  AS account_id,
+                       utxos.height                  AS block,
+                       utxos.prevout_txid            AS txid,
+                       0                             AS pool,
+                       utxos.value_zat               AS value,
+                       0                             AS is_change,
+                       1                             AS received_count,
+                       0                             AS memo_present
+                FROM utxos
+                UNION
+                SELECT sapling_received_notes.id             AS id,
+                       sapling_received_notes.account_id     AS account_id,
+                       transactions.block                    AS block,
+                       transactions.txid                     AS txid,
+                       2                                     AS pool,
+                       -sapling_received_notes.value         AS value,
+                       0                             AS is_change,
+                       0                             AS received_count,
+                       0                             AS memo_present
+                FROM sapling_received_notes
+                JOIN transactions
+                     ON transactions.id_tx = sapling_received_notes.spent
+                UNION
+                SELECT utxos.id                      AS id,
+                       utxos.received_by_account_id  AS account_id,
+                       transactions.block            AS block,
+                       transactions.txid             AS txid,
+                       0                             AS pool,
+                       -utxos.value_zat              AS value,
+                       0                             AS is_change,
+                       0                             AS received_count,
+                       0                             AS memo_present
+                FROM utxos
+                JOIN transactions
+                     ON transactions.id_tx = utxos.spent_in_tx
+            ),
+            sent_note_counts AS (
+                SELECT sent_notes.from_account_id AS account_id,
+                       transactions.txid       AS txid,
+                       COUNT(DISTINCT sent_notes.id) as sent_notes,
+                       SUM(
+                         CASE
+                           WHEN sapling_received_notes.tx IS NOT NULL THEN 0
+                           ELSE sent_notes.has_memo
+                         END
+                       ) AS memo_count
+                FROM sent_notes
+                JOIN transactions
+                     ON transactions.id_tx = sent_notes.tx
+                LEFT JOIN sapling_received_notes
+                          ON (sent_notes.tx, sent_notes.output_pool, sent_notes.output_index) =
+                             (sapling_received_notes.tx, 2, sapling_received_notes.output_index)
+                WHERE COALESCE(sapling_received_notes.is_change, 0) = 0
+                GROUP BY account_id, txid
+            ),
+            blocks_max_height AS (
+                SELECT MAX(blocks.height) as max_height FROM blocks
+            )
+            SELECT notes.account_id                  AS account_id,
+                   notes.block                       AS mined_height,
+                   notes.txid                        AS txid,
+                   transactions.tx_index             AS tx_index,
+                   transactions.expiry_height        AS expiry_height,
+                   transactions.raw                  AS raw,
+                   SUM(notes.value)                  AS account_balance_delta,
+                   transactions.fee                  AS fee_paid,
+                   SUM(notes.is_change) > 0          AS has_change,
+                   MAX(COALESCE(sent_note_counts.sent_notes, 0))  AS sent_note_count,
+                   SUM(notes.received_count)         AS received_note_count,
+                   SUM(notes.memo_present) + MAX(COALESCE(sent_note_counts.memo_count, 0)) AS memo_count,
+                   blocks.time                       AS block_time,
+                   (
+                        blocks.height IS NULL
+                        AND transactions.expiry_height BETWEEN 1 AND blocks_max_height.max_height
+                   ) AS expired_unmined
+            FROM notes
+            LEFT JOIN transactions
+                 ON notes.txid = transactions.txid
+            JOIN blocks_max_height
+            LEFT JOIN blocks ON blocks.height = notes.block
+            LEFT JOIN sent_note_counts
+                      ON sent_note_counts.account_id = notes.account_id
+                      AND sent_note_counts.txid = notes.txid
+            GROUP BY notes.account_id, notes.txid;
+
+            DROP VIEW v_tx_outputs;
+            CREATE VIEW v_tx_outputs AS
+            SELECT transactions.txid                   AS txid,
+                   2                                   AS output_pool,
+                   sapling_received_notes.output_index AS output_index,
+                   sent_notes.from_account_id          AS from_account_id,
+                   sapling_received_notes.account_id   AS to_account_id,
+                   NULL                                AS to_address,
+                   sapling_received_notes.value        AS value,
+                   sapling_received_notes.is_change    AS is_change,
+                   sapling_received_notes.memo         AS memo,
+                   sapling_received_notes.has_memo     AS memo_present
+            FROM sapling_received_notes
+            JOIN transactions
+                 ON transactions.id_tx = sapling_received_notes.tx
+            LEFT JOIN sent_notes
+                      ON (sent_notes.tx, sent_notes.output_pool, sent_notes.output_index) =
+                         (sapling_received_notes.tx, 2, sent_notes.output_index)
+            UNION
+            SELECT utxos.prevout_txid           AS txid,
+                   0                            AS output_pool,
+                   utxos.prevout_idx            AS output_index,
+                   NULL                         AS from_account_id,
+                   utxos.received_by_account_id AS to_account_id,
+                   utxos.address                AS to_address,
+                   utxos.value_zat              AS value,
+                   0                            AS is_change,
+                   NULL                         AS memo,
+                   0                            AS memo_present
+            FROM utxos
+            UNION
+            SELECT transactions.txid                 AS txid,
+                   sent_notes.output_pool            AS output_pool,
+                   sent_notes.output_index           AS output_index,
+                   sent_notes.from_account_id        AS from_account_id,
+                   sapling_received_notes.account_id AS to_account_id,
+                   sent_notes.to_address             AS to_address,
+                   sent_notes.value                  AS value,
+                   0                                 AS is_change,
+                   sent_notes.memo                   AS memo,
+                   sent_notes.has_memo               AS memo_present
+            FROM sent_notes
+            JOIN transactions
+                 ON transactions.id_tx = sent_notes.tx
+            LEFT JOIN sapling_received_notes
+                      ON (sent_notes.tx, sent_notes.output_pool, sent_notes.output_index) =
+                         (sapling_received_notes.tx, 2, sapling_received_notes.output_index)
+            WHERE COALESCE(sapling_received_notes.is_change, 0) = 0;",
+        )?;
+
+        Ok(())
+    }
+
+    fn down(&self, _transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        Err(WalletMigrationError::CannotRevert(MIGRATION_ID))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use zcash_primitives::consensus::Network;
+
+    use crate::{
+        wallet::init::{
+            init_wallet_db_internal,
+            migrations::{v_account_balances, v_tx_outputs_memo_present},
+        },
+        WalletDb,
+    };
+
+    fn memo_counts(conn: &rusqlite::Connection) -> Vec<(String, i64)> {
+        let mut stmt = conn
+            .prepare(
+                "SELECT CAST(txid AS TEXT), memo_count
+                FROM v_transactions
+                ORDER BY txid",
+            )
+            .unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn note_memo_flags() {
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db_internal(
+            &mut db_data,
+            None,
+            &[
+                v_tx_outputs_memo_present::MIGRATION_ID,
+                v_account_balances::MIGRATION_ID,
+            ],
+        )
+        .unwrap();
+
+        // Transaction 'tx0' receives two notes with memos, one note without a memo, and a change
+        // note with a memo, and sends a note with a memo and a note with the empty memo.
+        // Transaction 'tx1' receives a note with an unknown memo.
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO accounts (id, account_type, uivk, birthday_height)
+                VALUES (1, 1, 'uivk1', 0);
+
+                INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (0, 0, 0, x'00');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 0, 'tx0');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 1, 'tx1');
+
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, memo)
+                VALUES (0, 0, 1, '', 2, '', 'a', 0, X'61');
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, memo)
+                VALUES (0, 1, 1, '', 3, '', 'b', 0, X'62');
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, memo)
+                VALUES (0, 2, 1, '', 5, '', 'c', 0, X'F6');
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, memo)
+                VALUES (0, 3, 1, '', 7, '', 'd', 1, X'63');
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change)
+                VALUES (1, 0, 1, '', 11, '', 'e', 0);
+
+                INSERT INTO sent_notes (tx, output_pool, output_index, from_account_id, to_address, value, memo)
+                VALUES (0, 2, 4, 1, 'addr', 13, X'64');
+                INSERT INTO sent_notes (tx, output_pool, output_index, from_account_id, to_address, value, memo)
+                VALUES (0, 2, 5, 1, 'addr', 17, X'F6');",
+            )
+            .unwrap();
+
+        let expected_counts = vec![("tx0".to_owned(), 4), ("tx1".to_owned(), 0)];
+        assert_eq!(memo_counts(&db_data.conn), expected_counts);
+
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        // The memo counts computed from the persisted flags match those computed from the memos.
+        assert_eq!(memo_counts(&db_data.conn), expected_counts);
+
+        let received_flags = db_data
+            .conn
+            .prepare("SELECT has_memo FROM sapling_received_notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get::<_, bool>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(received_flags, vec![true, true, false, true, false]);
+
+        let sent_flags = db_data
+            .conn
+            .prepare("SELECT has_memo FROM sent_notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get::<_, bool>(0))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(sent_flags, vec![true, false]);
+    }
+}
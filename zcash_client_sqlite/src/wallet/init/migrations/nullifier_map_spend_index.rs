@@ -0,0 +1,90 @@
+//! This migration adds a nullable `spend_index` column to the `nullifier_map` table, which
+//! records the index of the spend (for Sapling) or action (for Orchard) within its transaction
+//! that revealed each nullifier.
+//!
+//! The index of an existing entry cannot be recovered without rescanning the block in which it
+//! was revealed, so the column is left null for entries that were stored before this migration.
+
+use std::collections::HashSet;
+
+use schemer_rusqlite::RusqliteMigration;
+use uuid::Uuid;
+
+use crate::wallet::init::WalletMigrationError;
+
+use super::received_note_key_ids;
+
+pub(super) const MIGRATION_ID: Uuid = Uuid::from_u128(0x3c1a6e0b_5f27_4b8e_9d3a_71e2c04f8b56);
+
+pub(super) struct Migration;
+
+impl schemer::Migration for Migration {
+    fn id(&self) -> Uuid {
+        MIGRATION_ID
+    }
+
+    fn dependencies(&self) -> HashSet<Uuid> {
+        [received_note_key_ids::MIGRATION_ID].into_iter().collect()
+    }
+
+    fn description(&self) -> &'static str {
+        "Records the index within its transaction of the spend that revealed each mapped nullifier."
+    }
+}
+
+impl RusqliteMigration for Migration {
+    type Error = WalletMigrationError;
+
+    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        transaction.execute_batch("ALTER TABLE nullifier_map ADD COLUMN spend_index INTEGER;")?;
+
+        Ok(())
+    }
+
+    fn down(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        transaction.execute_batch("ALTER TABLE nullifier_map DROP COLUMN spend_index;")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use zcash_primitives::consensus::Network;
+
+    use crate::{
+        wallet::init::{init_wallet_db_internal, migrations::received_note_key_ids},
+        WalletDb,
+    };
+
+    #[test]
+    fn nullifier_map_spend_index() {
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db_internal(&mut db_data, None, &[received_note_key_ids::MIGRATION_ID])
+            .unwrap();
+
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO tx_locator_map (block_height, tx_index, txid) VALUES (1, 0, 'tx0');
+                INSERT INTO nullifier_map (spend_pool, nf, block_height, tx_index)
+                VALUES (2, 'a', 1, 0);",
+            )
+            .unwrap();
+
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        // The index of an existing entry is unknown.
+        let spend_index: Option<u32> = db_data
+            .conn
+            .query_row(
+                "SELECT spend_index FROM nullifier_map WHERE nf = 'a'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(spend_index, None);
+    }
+}
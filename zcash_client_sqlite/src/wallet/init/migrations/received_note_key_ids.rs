@@ -0,0 +1,145 @@
+//! This migration adds a `viewing_keys` table, which assigns an identifier to each viewing key
+//! (identified by its account and key scope) that has detected a note, and a nullable
+//! `discovered_by_key_id` column to the `sapling_received_notes` table that records the viewing
+//! key that first detected each note.
+//!
+//! The column adds no information that was not already stored: the key that detected a note is
+//! determined by the note's `account_id` and `recipient_key_scope`. The column is therefore
+//! populated for existing notes from those columns, after recording the viewing keys that they
+//! identify.
+
+use std::collections::HashSet;
+
+use schemer_rusqlite::RusqliteMigration;
+use uuid::Uuid;
+
+use crate::wallet::init::WalletMigrationError;
+
+use super::transaction_data_table;
+
+pub(super) const MIGRATION_ID: Uuid = Uuid::from_u128(0x9faf1c32_d82b_4251_a33e_29c793eedda6);
+
+pub(super) struct Migration;
+
+impl schemer::Migration for Migration {
+    fn id(&self) -> Uuid {
+        MIGRATION_ID
+    }
+
+    fn dependencies(&self) -> HashSet<Uuid> {
+        [transaction_data_table::MIGRATION_ID].into_iter().collect()
+    }
+
+    fn description(&self) -> &'static str {
+        "Records the viewing key that detected each received note."
+    }
+}
+
+impl RusqliteMigration for Migration {
+    type Error = WalletMigrationError;
+
+    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        transaction.execute_batch(
+            "CREATE TABLE viewing_keys (
+                id INTEGER PRIMARY KEY,
+                account_id INTEGER NOT NULL,
+                key_scope INTEGER NOT NULL,
+                FOREIGN KEY (account_id) REFERENCES accounts(id),
+                CONSTRAINT account_key_scope UNIQUE (account_id, key_scope)
+            );
+            ALTER TABLE sapling_received_notes
+            ADD COLUMN discovered_by_key_id INTEGER REFERENCES viewing_keys(id);
+
+            INSERT INTO viewing_keys (account_id, key_scope)
+            SELECT DISTINCT account_id, recipient_key_scope
+            FROM sapling_received_notes;
+
+            UPDATE sapling_received_notes
+            SET discovered_by_key_id = (
+                SELECT id FROM viewing_keys
+                WHERE viewing_keys.account_id = sapling_received_notes.account_id
+                AND viewing_keys.key_scope = sapling_received_notes.recipient_key_scope
+            );",
+        )?;
+
+        Ok(())
+    }
+
+    fn down(&self, _transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        Err(WalletMigrationError::CannotRevert(MIGRATION_ID))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use zcash_primitives::consensus::Network;
+
+    use crate::{
+        wallet::init::{init_wallet_db_internal, migrations::transaction_data_table},
+        WalletDb,
+    };
+
+    #[test]
+    fn received_note_key_ids() {
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db_internal(&mut db_data, None, &[transaction_data_table::MIGRATION_ID])
+            .unwrap();
+
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO accounts (id, account_type, uivk, birthday_height)
+                VALUES (1, 1, 'uivk1', 0);
+
+                INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (0, 0, 0, x'00');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 0, 'tx0');
+
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, recipient_key_scope)
+                VALUES (0, 0, 1, '', 2, '', 'a', 0, 0);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, recipient_key_scope)
+                VALUES (0, 1, 1, '', 3, '', 'b', 1, 1);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, recipient_key_scope)
+                VALUES (0, 2, 1, '', 4, '', 'c', 0, 0);",
+            )
+            .unwrap();
+
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        // A viewing key is recorded for each account and scope with which a note was received.
+        let key_count: i64 = db_data
+            .conn
+            .query_row("SELECT COUNT(*) FROM viewing_keys", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(key_count, 2);
+
+        // Existing notes refer to the viewing key identified by their account and scope.
+        let mut stmt = db_data
+            .conn
+            .prepare(
+                "SELECT sapling_received_notes.output_index,
+                    viewing_keys.account_id, viewing_keys.key_scope
+                FROM sapling_received_notes
+                JOIN viewing_keys ON viewing_keys.id = sapling_received_notes.discovered_by_key_id
+                ORDER BY sapling_received_notes.output_index",
+            )
+            .unwrap();
+        let keys = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(i64, i64, i64)>, _>>()
+            .unwrap();
+        assert_eq!(keys, vec![(0, 1, 0), (1, 1, 1), (2, 1, 0)]);
+
+        // Each viewing key is recorded at most once.
+        assert!(db_data
+            .conn
+            .execute(
+                "INSERT INTO viewing_keys (account_id, key_scope) VALUES (1, 0)",
+                [],
+            )
+            .is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! This migration moves the raw transaction data out of the `transactions` table and into a
+//! separate `transaction_data` table, so that queries over transaction metadata (including
+//! the aggregations performed by the `v_transactions` view) do not need to read past the
+//! large `raw` blobs. The `v_transactions` view is rewritten to join `transaction_data` only
+//! after its per-transaction summaries have been computed, so that SQLite omits the join
+//! entirely for queries that do not select the `raw` column.
+
+use std::collections::HashSet;
+
+use schemer_rusqlite::RusqliteMigration;
+use uuid::Uuid;
+
+use crate::wallet::init::WalletMigrationError;
+
+use super::note_memo_flags;
+
+pub(super) const MIGRATION_ID: Uuid = Uuid::from_u128(0xa5b907af_b7d9_4eab_930a_da0b5e500cd9);
+
+pub(super) struct Migration;
+
+impl schemer::Migration for Migration {
+    fn id(&self) -> Uuid {
+        MIGRATION_ID
+    }
+
+    fn dependencies(&self) -> HashSet<Uuid> {
+        [note_memo_flags::MIGRATION_ID].into_iter().collect()
+    }
+
+    fn description(&self) -> &'static str {
+        "Moves raw transaction data into a separate `transaction_data` table."
+    }
+}
+
+impl RusqliteMigration for Migration {
+    type Error = WalletMigrationError;
+
+    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        transaction.execute_batch(
+            "CREATE TABLE transaction_data (
+                id_tx INTEGER PRIMARY KEY,
+                raw BLOB NOT NULL,
+                FOREIGN KEY (id_tx) REFERENCES transactions(id_tx)
+            );
+            INSERT INTO transaction_data (id_tx, raw)
+            SELECT id_tx, raw
+            FROM transactions
+            WHERE raw IS NOT NULL;
+
+            DROP VIEW v_transactions;
+
+            ALTER TABLE transactions DROP COLUMN raw;",
+        )?;
+
+        transaction.execute_batch(
+            "CREATE VIEW v_transactions AS
+            WITH
+            notes AS (
+                SELECT sapling_received_notes.id             AS id,
+                       sapling_received_notes.account_id     AS account_id,
+                       transactions.block                    AS block,
+                       transactions.txid                     AS txid,
+                       2                                     AS pool,
+                       sapling_received_notes.value          AS value,
+                       CASE
+                            WHEN sapling_received_notes.is_change THEN 1
+                            ELSE 0
+                       END AS is_change,
+                       CASE
+                            WHEN sapling_received_notes.is_change THEN 0
+                            ELSE 1
+                       END AS received_count,
+                       sapling_received_notes.has_memo       AS memo_present
+                FROM sapling_received_notes
+                JOIN transactions
+                     ON transactions.id_tx = sapling_received_notes.tx
+                UNION
+                SELECT utxos.id                      AS id,
+                       utxos.received_by_account_id  AS account_id,
+                       utxos.height                  AS block,
+                       utxos.prevout_txid            AS txid,
+                       0                             AS pool,
+                       utxos.value_zat               AS value,
+                       0                             AS is_change,
+                       1                             AS received_count,
+                       0                             AS memo_present
+                FROM utxos
+                UNION
+                SELECT sapling_received_notes.id             AS id,
+                       sapling_received_notes.account_id     AS account_id,
+                       transactions.block                    AS block,
+                       transactions.txid                     AS txid,
+                       2                                     AS pool,
+                       -sapling_received_notes.value         AS value,
+                       0                             AS is_change,
+                       0                             AS received_count,
+                       0                             AS memo_present
+                FROM sapling_received_notes
+                JOIN transactions
+                     ON transactions.id_tx = sapling_received_notes.spent
+                UNION
+                SELECT utxos.id                      AS id,
+                       utxos.received_by_account_id  AS account_id,
+                       transactions.block            AS block,
+                       transactions.txid             AS txid,
+                       0                             AS pool,
+                       -utxos.value_zat              AS value,
+                       0                             AS is_change,
+                       0                             AS received_count,
+                       0                             AS memo_present
+                FROM utxos
+                JOIN transactions
+                     ON transactions.id_tx = utxos.spent_in_tx
+            ),
+            sent_note_counts AS (
+                SELECT sent_notes.from_account_id AS account_id,
+                       transactions.txid       AS txid,
+                       COUNT(DISTINCT sent_notes.id) as sent_notes,
+                       SUM(
+                         CASE
+                           WHEN sapling_received_notes.tx IS NOT NULL THEN 0
+                           ELSE sent_notes.has_memo
+                         END
+                       ) AS memo_count
+                FROM sent_notes
+                JOIN transactions
+                     ON transactions.id_tx = sent_notes.tx
+                LEFT JOIN sapling_received_notes
+                          ON (sent_notes.tx, sent_notes.output_pool, sent_notes.output_index) =
+                             (sapling_received_notes.tx, 2, sapling_received_notes.output_index)
+                WHERE COALESCE(sapling_received_notes.is_change, 0) = 0
+                GROUP BY account_id, txid
+            ),
+            blocks_max_height AS (
+                SELECT MAX(blocks.height) as max_height FROM blocks
+            ),
+            summaries AS (
+                SELECT notes.account_id                  AS account_id,
+                       notes.block                       AS mined_height,
+                       notes.txid                        AS txid,
+                       transactions.id_tx                AS id_tx,
+                       transactions.tx_index             AS tx_index,
+                       transactions.expiry_height        AS expiry_height,
+                       SUM(notes.value)                  AS account_balance_delta,
+                       transactions.fee                  AS fee_paid,
+                       SUM(notes.is_change) > 0          AS has_change,
+                       MAX(COALESCE(sent_note_counts.sent_notes, 0))  AS sent_note_count,
+                       SUM(notes.received_count)         AS received_note_count,
+                       SUM(notes.memo_present) + MAX(COALESCE(sent_note_counts.memo_count, 0)) AS memo_count,
+                       blocks.time                       AS block_time,
+                       (
+                            blocks.height IS NULL
+                            AND transactions.expiry_height BETWEEN 1 AND blocks_max_height.max_height
+                       ) AS expired_unmined
+                FROM notes
+                LEFT JOIN transactions
+                     ON notes.txid = transactions.txid
+                JOIN blocks_max_height
+                LEFT JOIN blocks ON blocks.height = notes.block
+                LEFT JOIN sent_note_counts
+                          ON sent_note_counts.account_id = notes.account_id
+                          AND sent_note_counts.txid = notes.txid
+                GROUP BY notes.account_id, notes.txid
+            )
+            SELECT summaries.account_id            AS account_id,
+                   summaries.mined_height          AS mined_height,
+                   summaries.txid                  AS txid,
+                   summaries.tx_index              AS tx_index,
+                   summaries.expiry_height         AS expiry_height,
+                   transaction_data.raw            AS raw,
+                   summaries.account_balance_delta AS account_balance_delta,
+                   summaries.fee_paid              AS fee_paid,
+                   summaries.has_change            AS has_change,
+                   summaries.sent_note_count       AS sent_note_count,
+                   summaries.received_note_count   AS received_note_count,
+                   summaries.memo_count            AS memo_count,
+                   summaries.block_time            AS block_time,
+                   summaries.expired_unmined       AS expired_unmined
+            FROM summaries
+            LEFT JOIN transaction_data
+                 ON transaction_data.id_tx = summaries.id_tx;",
+        )?;
+
+        Ok(())
+    }
+
+    fn down(&self, _transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        Err(WalletMigrationError::CannotRevert(MIGRATION_ID))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use zcash_primitives::consensus::Network;
+
+    use crate::{
+        wallet::init::{init_wallet_db_internal, migrations::note_memo_flags},
+        WalletDb,
+    };
+
+    fn query_plan(conn: &rusqlite::Connection, query: &str) -> Vec<String> {
+        conn.prepare(&format!("EXPLAIN QUERY PLAN {}", query))
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(3))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn transaction_data_table() {
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db_internal(&mut db_data, None, &[note_memo_flags::MIGRATION_ID]).unwrap();
+
+        // Transaction 'tx0' has full transaction data; transaction 'tx1' has only metadata.
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO accounts (id, account_type, uivk, birthday_height)
+                VALUES (1, 1, 'uivk1', 0);
+
+                INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (0, 0, 0, x'00');
+                INSERT INTO transactions (block, id_tx, txid, raw) VALUES (0, 0, 'tx0', X'0102');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 1, 'tx1');
+
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change)
+                VALUES (0, 0, 1, '', 2, '', 'a', 0);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change)
+                VALUES (1, 0, 1, '', 3, '', 'b', 0);",
+            )
+            .unwrap();
+
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        let stored_data = db_data
+            .conn
+            .prepare("SELECT id_tx, raw FROM transaction_data ORDER BY id_tx")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(stored_data, vec![(0, vec![1, 2])]);
+
+        // The view still reports the raw transaction data, where it is known.
+        let summaries = db_data
+            .conn
+            .prepare(
+                "SELECT CAST(txid AS TEXT), raw, account_balance_delta
+                FROM v_transactions
+                ORDER BY txid",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<Result<Vec<(String, Option<Vec<u8>>, i64)>, _>>()
+            .unwrap();
+        assert_eq!(
+            summaries,
+            vec![
+                ("tx0".to_owned(), Some(vec![1, 2]), 2),
+                ("tx1".to_owned(), None, 3),
+            ]
+        );
+
+        // Only queries that select the raw transaction data read from `transaction_data`.
+        let touches_data = |query: &str| {
+            query_plan(&db_data.conn, query)
+                .iter()
+                .any(|step| step.contains("transaction_data"))
+        };
+        assert!(!touches_data(
+            "SELECT account_balance_delta FROM v_transactions"
+        ));
+        assert!(touches_data("SELECT raw FROM v_transactions"));
+    }
+}
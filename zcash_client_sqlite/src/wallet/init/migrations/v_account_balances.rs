@@ -0,0 +1,165 @@
+//! This migration adds the `v_account_balances` view, which summarizes the value received,
+//! spent, and still held in the Sapling pool by each account in the wallet.
+
+use std::collections::HashSet;
+
+use schemer_rusqlite::RusqliteMigration;
+use uuid::Uuid;
+
+use crate::wallet::init::WalletMigrationError;
+
+use super::full_account_ids;
+
+pub(super) const MIGRATION_ID: Uuid = Uuid::from_u128(0x4f1c3b0e_7d62_4a8e_9b35_c2d6e1a08f47);
+
+pub(super) struct Migration;
+
+impl schemer::Migration for Migration {
+    fn id(&self) -> Uuid {
+        MIGRATION_ID
+    }
+
+    fn dependencies(&self) -> HashSet<Uuid> {
+        [full_account_ids::MIGRATION_ID].into_iter().collect()
+    }
+
+    fn description(&self) -> &'static str {
+        "Adds the `v_account_balances` view."
+    }
+}
+
+impl RusqliteMigration for Migration {
+    type Error = WalletMigrationError;
+
+    fn up(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        // Change notes are value that the wallet sent to itself, so they are counted neither as
+        // received nor as spent; `balance` is therefore `total_received - total_spent`.
+        //
+        // As in `get_wallet_summary`, a note is only counted if the transaction that received
+        // it is mined or has not yet expired, and is only treated as spent if the transaction
+        // that spent it is mined or has not yet expired. A transaction that expired without
+        // being mined therefore neither contributes received notes nor spends any.
+        transaction.execute_batch(
+            "CREATE VIEW v_account_balances AS
+            WITH
+            chain_tip AS (
+                SELECT IFNULL(MAX(height), 0) AS height FROM blocks
+            ),
+            notes AS (
+                SELECT sapling_received_notes.account_id AS account_id,
+                       sapling_received_notes.value      AS value,
+                       sapling_received_notes.is_change  AS is_change,
+                       CASE
+                            WHEN spend.block IS NOT NULL
+                              OR (spend.id_tx IS NOT NULL
+                                  AND (spend.expiry_height IS NULL OR spend.expiry_height >= chain_tip.height))
+                            THEN 1
+                            ELSE 0
+                       END AS is_spent
+                FROM sapling_received_notes
+                JOIN chain_tip
+                JOIN transactions
+                     ON transactions.id_tx = sapling_received_notes.tx
+                LEFT JOIN transactions spend
+                          ON spend.id_tx = sapling_received_notes.spent
+                WHERE transactions.block IS NOT NULL
+                   OR transactions.expiry_height IS NULL
+                   OR transactions.expiry_height >= chain_tip.height
+            )
+            SELECT accounts.id AS account_id,
+                   COALESCE(SUM(CASE WHEN notes.is_change = 0 THEN notes.value ELSE 0 END), 0)
+                       AS total_received,
+                   COALESCE(SUM(CASE WHEN notes.is_spent = 1 THEN notes.value ELSE 0 END), 0)
+                   - COALESCE(SUM(CASE WHEN notes.is_change != 0 THEN notes.value ELSE 0 END), 0)
+                       AS total_spent,
+                   COALESCE(SUM(CASE WHEN notes.is_spent = 0 THEN notes.value ELSE 0 END), 0)
+                       AS balance
+            FROM accounts
+            LEFT JOIN notes ON notes.account_id = accounts.id
+            GROUP BY accounts.id;",
+        )?;
+
+        Ok(())
+    }
+
+    fn down(&self, transaction: &rusqlite::Transaction) -> Result<(), Self::Error> {
+        transaction.execute_batch("DROP VIEW v_account_balances;")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::NamedTempFile;
+
+    use zcash_primitives::consensus::Network;
+
+    use crate::{wallet::init::init_wallet_db_internal, WalletDb};
+
+    #[test]
+    fn v_account_balances() {
+        let data_file = NamedTempFile::new().unwrap();
+        let mut db_data = WalletDb::for_path(data_file.path(), Network::TestNetwork).unwrap();
+        init_wallet_db_internal(&mut db_data, None, &[super::MIGRATION_ID]).unwrap();
+
+        // Account 1 receives 10 and 5 zatoshis, then spends the 10 zatoshi note in tx 2,
+        // receiving 3 zatoshis in change. Account 2 receives 7 zatoshis and spends them in tx 3,
+        // receiving 2 zatoshis in change, but tx 3 expires without being mined. Account 3
+        // receives 4 zatoshis in tx 4, which is unmined but has not yet expired, and 6 zatoshis
+        // in tx 5, which has expired. The chain tip is at height 10.
+        db_data
+            .conn
+            .execute_batch(
+                "INSERT INTO accounts (id, account_type, uivk, birthday_height)
+                VALUES (1, 1, 'uivk1', 0), (2, 1, 'uivk2', 0), (3, 1, 'uivk3', 0);
+
+                INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (0, 0, 0, x'00');
+                INSERT INTO blocks (height, hash, time, sapling_tree) VALUES (10, 10, 0, x'00');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 0, 'tx0');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 1, 'tx1');
+                INSERT INTO transactions (block, id_tx, txid) VALUES (0, 2, 'tx2');
+                INSERT INTO transactions (block, id_tx, txid, expiry_height) VALUES (NULL, 3, 'tx3', 5);
+                INSERT INTO transactions (block, id_tx, txid, expiry_height) VALUES (NULL, 4, 'tx4', 20);
+                INSERT INTO transactions (block, id_tx, txid, expiry_height) VALUES (NULL, 5, 'tx5', 5);
+
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (0, 0, 1, '', 10, '', 'nf_a', 0, 2);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (1, 0, 1, '', 5, '', 'nf_b', 0, NULL);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (1, 1, 2, '', 7, '', 'nf_c', 0, 3);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (2, 0, 1, '', 3, '', 'nf_d', 1, NULL);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (3, 0, 2, '', 2, '', 'nf_e', 1, NULL);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (4, 0, 3, '', 4, '', 'nf_f', 0, NULL);
+                INSERT INTO sapling_received_notes (tx, output_index, account_id, diversifier, value, rcm, nf, is_change, spent)
+                VALUES (5, 0, 3, '', 6, '', 'nf_g', 0, NULL);",
+            )
+            .unwrap();
+
+        let mut stmt = db_data
+            .conn
+            .prepare(
+                "SELECT account_id, total_received, total_spent, balance
+                FROM v_account_balances
+                ORDER BY account_id",
+            )
+            .unwrap();
+        let balances = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, i64>(2)?,
+                    row.get::<_, i64>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(balances, vec![(1, 15, 7, 8), (2, 7, 0, 7), (3, 4, 0, 4)]);
+    }
+}
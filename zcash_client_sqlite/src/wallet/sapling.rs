@@ -9,10 +9,11 @@ use sapling::{self, Diversifier, Nullifier, Rseed};
 use zcash_client_backend::{
     data_api::NullifierQuery,
     keys::UnifiedFullViewingKey,
-    wallet::{Note, ReceivedNote, WalletSaplingOutput},
+    scanning::ScanConfig,
+    wallet::{Note, ReceivedNote, WalletSaplingOutput, WalletSaplingSpend},
     DecryptedOutput, ShieldedProtocol, TransferType,
 };
-use zcash_primitives::transaction::{components::amount::NonNegativeAmount, TxId};
+use zcash_primitives::transaction::{components::amount::NonNegativeAmount, Transaction, TxId};
 use zcash_protocol::{
     consensus::{self, BlockHeight},
     memo::MemoBytes,
@@ -21,7 +22,7 @@ use zip32::Scope;
 
 use crate::{error::SqliteClientError, AccountId, ReceivedNoteId};
 
-use super::{memo_repr, parse_scope, scope_code, wallet_birthday};
+use super::{has_memo, memo_repr, parse_scope, scope_code, viewing_key_id, wallet_birthday};
 
 /// This trait provides a generalization over shielded output representations.
 pub(crate) trait ReceivedSaplingOutput {
@@ -55,7 +56,8 @@ impl ReceivedSaplingOutput for WalletSaplingOutput<AccountId> {
         self.nf()
     }
     fn note_commitment_tree_position(&self) -> Option<Position> {
-        Some(WalletSaplingOutput::note_commitment_tree_position(self))
+        self.is_mined()
+            .then(|| WalletSaplingOutput::note_commitment_tree_position(self))
     }
     fn recipient_key_scope(&self) -> Option<Scope> {
         self.recipient_key_scope()
@@ -93,59 +95,46 @@ impl ReceivedSaplingOutput for DecryptedOutput<sapling::Note, AccountId> {
     }
 }
 
-fn to_spendable_note<P: consensus::Parameters>(
+/// Reconstructs a Sapling note from the values stored for it in the `sapling_received_notes`
+/// table, along with the encoded UFVK of the receiving account.
+fn decode_sapling_note<P: consensus::Parameters>(
     params: &P,
-    row: &Row,
-) -> Result<ReceivedNote<ReceivedNoteId, Note>, SqliteClientError> {
-    let note_id = ReceivedNoteId(ShieldedProtocol::Sapling, row.get(0)?);
-    let txid = row.get::<_, [u8; 32]>(1).map(TxId::from_bytes)?;
-    let output_index = row.get(2)?;
+    diversifier: &[u8],
+    value: i64,
+    rcm: &[u8],
+    ufvk_str: &str,
+    scope: Scope,
+) -> Result<sapling::Note, SqliteClientError> {
     let diversifier = {
-        let d: Vec<_> = row.get(3)?;
-        if d.len() != 11 {
+        if diversifier.len() != 11 {
             return Err(SqliteClientError::CorruptedData(
                 "Invalid diversifier length".to_string(),
             ));
         }
         let mut tmp = [0; 11];
-        tmp.copy_from_slice(&d);
+        tmp.copy_from_slice(diversifier);
         Diversifier(tmp)
     };
 
-    let note_value: u64 = row.get::<_, i64>(4)?.try_into().map_err(|_e| {
+    let note_value: u64 = value.try_into().map_err(|_e| {
         SqliteClientError::CorruptedData("Note values must be nonnegative".to_string())
     })?;
 
     let rseed = {
-        let rcm_bytes: Vec<_> = row.get(5)?;
-
         // We store rcm directly in the data DB, regardless of whether the note
         // used a v1 or v2 note plaintext, so for the purposes of spending let's
         // pretend this is a pre-ZIP 212 note.
         let rcm = Option::from(jubjub::Fr::from_repr(
-            rcm_bytes[..]
-                .try_into()
-                .map_err(|_| SqliteClientError::InvalidNote)?,
+            rcm.try_into().map_err(|_| SqliteClientError::InvalidNote)?,
         ))
         .ok_or(SqliteClientError::InvalidNote)?;
         Rseed::BeforeZip212(rcm)
     };
 
-    let note_commitment_tree_position =
-        Position::from(u64::try_from(row.get::<_, i64>(6)?).map_err(|_| {
-            SqliteClientError::CorruptedData("Note commitment tree position invalid.".to_string())
-        })?);
-
-    let ufvk_str: String = row.get(7)?;
-    let ufvk = UnifiedFullViewingKey::decode(params, &ufvk_str)
+    let ufvk = UnifiedFullViewingKey::decode(params, ufvk_str)
         .map_err(SqliteClientError::CorruptedData)?;
 
-    let scope_code: i64 = row.get(8)?;
-    let spending_key_scope = parse_scope(scope_code).ok_or_else(|| {
-        SqliteClientError::CorruptedData(format!("Invalid key scope code {}", scope_code))
-    })?;
-
-    let recipient = match spending_key_scope {
+    let recipient = match scope {
         Scope::Internal => ufvk
             .sapling()
             .and_then(|dfvk| dfvk.diversified_change_address(diversifier)),
@@ -155,15 +144,45 @@ fn to_spendable_note<P: consensus::Parameters>(
     }
     .ok_or_else(|| SqliteClientError::CorruptedData("Diversifier invalid.".to_owned()))?;
 
+    Ok(sapling::Note::from_parts(
+        recipient,
+        sapling::value::NoteValue::from_raw(note_value),
+        rseed,
+    ))
+}
+
+fn to_spendable_note<P: consensus::Parameters>(
+    params: &P,
+    row: &Row,
+) -> Result<ReceivedNote<ReceivedNoteId, Note>, SqliteClientError> {
+    let note_id = ReceivedNoteId(ShieldedProtocol::Sapling, row.get(0)?);
+    let txid = row.get::<_, [u8; 32]>(1).map(TxId::from_bytes)?;
+    let output_index = row.get(2)?;
+
+    let note_commitment_tree_position =
+        Position::from(u64::try_from(row.get::<_, i64>(6)?).map_err(|_| {
+            SqliteClientError::CorruptedData("Note commitment tree position invalid.".to_string())
+        })?);
+
+    let scope_code: i64 = row.get(8)?;
+    let spending_key_scope = parse_scope(scope_code).ok_or_else(|| {
+        SqliteClientError::CorruptedData(format!("Invalid key scope code {}", scope_code))
+    })?;
+
+    let note = decode_sapling_note(
+        params,
+        &row.get::<_, Vec<u8>>(3)?,
+        row.get(4)?,
+        &row.get::<_, Vec<u8>>(5)?,
+        &row.get::<_, String>(7)?,
+        spending_key_scope,
+    )?;
+
     Ok(ReceivedNote::from_parts(
         note_id,
         txid,
         output_index,
-        Note::Sapling(sapling::Note::from_parts(
-            recipient,
-            sapling::value::NoteValue::from_raw(note_value),
-            rseed,
-        )),
+        Note::Sapling(note),
         spending_key_scope,
         note_commitment_tree_position,
     ))
@@ -353,6 +372,140 @@ pub(crate) fn get_sapling_nullifiers(
     Ok(res)
 }
 
+/// Returns the wallet's view of the Sapling spends and outputs of the given transaction,
+/// reconstructed from the rows stored in the `sapling_received_notes` table.
+///
+/// The transaction's raw data is required in order to recover the index of each spend and the
+/// ephemeral key of each output, neither of which is stored in the database.
+#[allow(clippy::type_complexity)]
+pub(crate) fn get_wallet_sapling_parts<P: consensus::Parameters>(
+    conn: &Connection,
+    params: &P,
+    tx: &Transaction,
+) -> Result<
+    (
+        Vec<WalletSaplingSpend<AccountId>>,
+        Vec<WalletSaplingOutput<AccountId>>,
+    ),
+    SqliteClientError,
+> {
+    let txid = tx.txid();
+    let (shielded_spends, shielded_outputs) = tx.sapling_bundle().map_or((&[][..], &[][..]), |b| {
+        (b.shielded_spends(), b.shielded_outputs())
+    });
+
+    let mut stmt_spends = conn.prepare_cached(
+        "SELECT rn.account_id, rn.nf
+         FROM sapling_received_notes rn
+         INNER JOIN transactions ON transactions.id_tx = rn.spent
+         WHERE transactions.txid = :txid
+         AND rn.nf IS NOT NULL",
+    )?;
+    let mut spends = stmt_spends
+        .query_and_then(named_params![":txid": txid.as_ref()], |row| {
+            let account_id = AccountId(row.get(0)?);
+            let nf_bytes: Vec<u8> = row.get(1)?;
+            let nf = sapling::Nullifier::from_slice(&nf_bytes).map_err(|_| {
+                SqliteClientError::CorruptedData("Invalid Sapling nullifier.".to_owned())
+            })?;
+            let index = shielded_spends
+                .iter()
+                .position(|spend| spend.nullifier() == &nf)
+                .ok_or_else(|| {
+                    SqliteClientError::CorruptedData(format!(
+                        "Transaction {} does not reveal a nullifier recorded as spent in it.",
+                        txid
+                    ))
+                })?;
+            Ok(WalletSaplingSpend::from_parts(index, nf, account_id))
+        })?
+        .collect::<Result<Vec<_>, SqliteClientError>>()?;
+    spends.sort_by_key(|spend| spend.index());
+
+    let dust_threshold = ScanConfig::default().dust_threshold;
+    let mut stmt_outputs = conn.prepare_cached(
+        "SELECT rn.output_index, rn.account_id, rn.diversifier, rn.value, rn.rcm, rn.nf,
+                rn.is_change, rn.commitment_tree_position, rn.recipient_key_scope, accounts.ufvk
+         FROM sapling_received_notes rn
+         INNER JOIN accounts ON accounts.id = rn.account_id
+         INNER JOIN transactions ON transactions.id_tx = rn.tx
+         WHERE transactions.txid = :txid
+         ORDER BY rn.output_index",
+    )?;
+    let outputs = stmt_outputs
+        .query_and_then(named_params![":txid": txid.as_ref()], |row| {
+            let index = usize::try_from(row.get::<_, i64>(0)?).map_err(|_| {
+                SqliteClientError::CorruptedData("Invalid output index.".to_owned())
+            })?;
+            let ephemeral_key = shielded_outputs
+                .get(index)
+                .map(|output| output.ephemeral_key().clone())
+                .ok_or_else(|| {
+                    SqliteClientError::CorruptedData(format!(
+                        "Transaction {} has no Sapling output at index {}.",
+                        txid, index
+                    ))
+                })?;
+            let account_id = AccountId(row.get(1)?);
+
+            let scope_code: i64 = row.get(8)?;
+            let recipient_key_scope = parse_scope(scope_code).ok_or_else(|| {
+                SqliteClientError::CorruptedData(format!("Invalid key scope code {}", scope_code))
+            })?;
+            let ufvk_str: Option<String> = row.get(9)?;
+            let ufvk_str = ufvk_str.ok_or_else(|| {
+                SqliteClientError::CorruptedData(
+                    "Notes received by incoming-only accounts cannot be reconstructed.".to_owned(),
+                )
+            })?;
+            let note = decode_sapling_note(
+                params,
+                &row.get::<_, Vec<u8>>(2)?,
+                row.get(3)?,
+                &row.get::<_, Vec<u8>>(4)?,
+                &ufvk_str,
+                recipient_key_scope,
+            )?;
+
+            let nf = row
+                .get::<_, Option<Vec<u8>>>(5)?
+                .map(|nf_bytes| {
+                    sapling::Nullifier::from_slice(&nf_bytes).map_err(|_| {
+                        SqliteClientError::CorruptedData("Invalid Sapling nullifier.".to_owned())
+                    })
+                })
+                .transpose()?;
+
+            let position = row
+                .get::<_, Option<i64>>(7)?
+                .and_then(|p| u64::try_from(p).ok())
+                .map(Position::from)
+                .ok_or_else(|| {
+                    SqliteClientError::CorruptedData(format!(
+                        "Note commitment tree position unknown for output {} of transaction {}.",
+                        index, txid
+                    ))
+                })?;
+
+            let below_dust_threshold = note.value().inner() < u64::from(dust_threshold);
+
+            Ok(WalletSaplingOutput::from_parts(
+                index,
+                ephemeral_key,
+                note,
+                row.get(6)?,
+                below_dust_threshold,
+                position,
+                nf,
+                account_id,
+                Some(recipient_key_scope),
+            ))
+        })?
+        .collect::<Result<Vec<_>, SqliteClientError>>()?;
+
+    Ok((spends, outputs))
+}
+
 /// Marks a given nullifier as having been revealed in the construction
 /// of the specified transaction.
 ///
@@ -386,9 +539,9 @@ pub(crate) fn put_received_note<T: ReceivedSaplingOutput>(
 ) -> Result<(), SqliteClientError> {
     let mut stmt_upsert_received_note = conn.prepare_cached(
         "INSERT INTO sapling_received_notes
-        (tx, output_index, account_id, diversifier, value, rcm, memo, nf,
+        (tx, output_index, account_id, diversifier, value, rcm, memo, has_memo, nf,
          is_change, spent, commitment_tree_position,
-         recipient_key_scope)
+         recipient_key_scope, discovered_by_key_id)
         VALUES (
             :tx,
             :output_index,
@@ -397,11 +550,13 @@ pub(crate) fn put_received_note<T: ReceivedSaplingOutput>(
             :value,
             :rcm,
             :memo,
+            IFNULL(:has_memo, 0),
             :nf,
             :is_change,
             :spent,
             :commitment_tree_position,
-            :recipient_key_scope
+            :recipient_key_scope,
+            :discovered_by_key_id
         )
         ON CONFLICT (tx, output_index) DO UPDATE
         SET account_id = :account_id,
@@ -410,10 +565,12 @@ pub(crate) fn put_received_note<T: ReceivedSaplingOutput>(
             rcm = :rcm,
             nf = IFNULL(:nf, nf),
             memo = IFNULL(:memo, memo),
+            has_memo = IFNULL(:has_memo, has_memo),
             is_change = IFNULL(:is_change, is_change),
             spent = IFNULL(:spent, spent),
             commitment_tree_position = IFNULL(:commitment_tree_position, commitment_tree_position),
-            recipient_key_scope = :recipient_key_scope",
+            recipient_key_scope = :recipient_key_scope,
+            discovered_by_key_id = IFNULL(discovered_by_key_id, :discovered_by_key_id)",
     )?;
 
     let rcm = output.note().rcm().to_repr();
@@ -426,6 +583,9 @@ pub(crate) fn put_received_note<T: ReceivedSaplingOutput>(
         .recipient_key_scope()
         .expect("Key import is not yet supported.");
 
+    // The note is attributed to the viewing key that first detected it.
+    let key_id = viewing_key_id(conn, output.account_id(), scope)?;
+
     let sql_args = named_params![
         ":tx": &tx_ref,
         ":output_index": i64::try_from(output.index()).expect("output indices are representable as i64"),
@@ -435,10 +595,12 @@ pub(crate) fn put_received_note<T: ReceivedSaplingOutput>(
         ":rcm": &rcm.as_ref(),
         ":nf": output.nullifier().map(|nf| nf.0.as_ref()),
         ":memo": memo_repr(output.memo()),
+        ":has_memo": has_memo(output.memo()),
         ":is_change": output.is_change(),
         ":spent": spent_in,
         ":commitment_tree_position": output.note_commitment_tree_position().map(u64::from),
         ":recipient_key_scope": scope_code(scope),
+        ":discovered_by_key_id": key_id,
     ];
 
     stmt_upsert_received_note
@@ -474,7 +636,7 @@ pub(crate) mod tests {
             fees::{
                 fixed::FeeRule as FixedFeeRule, zip317::FeeError as Zip317FeeError, StandardFeeRule,
             },
-            Transaction,
+            Transaction, TxId,
         },
         zip32::Scope,
     };
@@ -500,10 +662,10 @@ pub(crate) mod tests {
         error::SqliteClientError,
         testing::{input_selector, AddressType, BlockCache, TestBuilder, TestState},
         wallet::{
-            block_max_scanned, commitment_tree, parse_scope,
+            block_max_scanned, commitment_tree, load_wallet_tx, parse_scope,
             sapling::select_spendable_sapling_notes, scanning::tests::test_with_canopy_birthday,
         },
-        NoteId, ReceivedNoteId,
+        AccountId, NoteId, ReceivedNoteId,
     };
 
     #[cfg(feature = "transparent-inputs")]
@@ -883,6 +1045,109 @@ pub(crate) mod tests {
         );
     }
 
+    #[test]
+    fn load_wallet_tx_reconstructs_mined_transaction() {
+        let mut st = TestBuilder::new()
+            .with_block_cache()
+            .with_test_account(AccountBirthday::from_sapling_activation)
+            .build();
+
+        let (account, usk, _) = st.test_account().unwrap();
+        let dfvk = st.test_account_sapling().unwrap();
+
+        // Add funds to the wallet in a single note
+        let value = NonNegativeAmount::const_from_u64(60000);
+        let (h, _, _) = st.generate_next_block(&dfvk, AddressType::DefaultExternal, value);
+        st.scan_cached_blocks(h, 1);
+
+        // The raw data for a transaction discovered via compact block scanning is not stored.
+        let received_txid = st
+            .wallet()
+            .conn
+            .query_row("SELECT txid FROM transactions", [], |row| {
+                row.get::<_, [u8; 32]>(0).map(TxId::from_bytes)
+            })
+            .unwrap();
+        assert_matches!(
+            load_wallet_tx(st.wallet(), received_txid),
+            Err(SqliteClientError::TransactionDataUnavailable(txid)) if txid == received_txid
+        );
+
+        // Unknown transactions are not found.
+        assert_matches!(
+            load_wallet_tx(st.wallet(), TxId::from_bytes([0xff; 32])),
+            Ok(None)
+        );
+
+        let to_extsk = ExtendedSpendingKey::master(&[]);
+        let to: Address = to_extsk.default_address().1.into();
+
+        // TODO: This test was originally written to use the pre-zip-313 fee rule
+        // and has not yet been updated.
+        #[allow(deprecated)]
+        let fee_rule = StandardFeeRule::PreZip313;
+
+        let proposal = st
+            .propose_standard_transfer::<Infallible>(
+                account,
+                fee_rule,
+                NonZeroU32::new(1).unwrap(),
+                &to,
+                NonNegativeAmount::const_from_u64(10000),
+                None,
+                None,
+                ShieldedProtocol::Sapling,
+            )
+            .unwrap();
+        let create_proposed_result =
+            st.create_proposed_transactions::<Infallible, _>(&usk, OvkPolicy::Sender, &proposal);
+        assert_matches!(&create_proposed_result, Ok(txids) if txids.len() == 1);
+        let sent_txid = create_proposed_result.unwrap()[0];
+
+        // Mine the transaction so that the position of the change note becomes known.
+        let tx = st.wallet().get_transaction(sent_txid).unwrap();
+        let (h, _) = st.generate_next_block_from_tx(1, &tx);
+        st.scan_cached_blocks(h, 1);
+
+        let wallet_tx = load_wallet_tx(st.wallet(), sent_txid).unwrap().unwrap();
+        assert_eq!(wallet_tx.txid(), sent_txid);
+        assert_eq!(wallet_tx.block_index(), 1);
+        assert_eq!(
+            wallet_tx.computed_fee(),
+            Some(NonNegativeAmount::const_from_u64(10000))
+        );
+        assert_eq!(wallet_tx.logical_actions(), 2);
+        assert_eq!(wallet_tx.shielded_output_count(), 2);
+
+        let bundle = tx.sapling_bundle().unwrap();
+        assert_eq!(wallet_tx.sapling_spends().len(), 1);
+        let spend = &wallet_tx.sapling_spends()[0];
+        assert_eq!(spend.account_id(), &account);
+        assert_eq!(
+            bundle.shielded_spends()[spend.index()].nullifier(),
+            spend.nf()
+        );
+
+        // Only the change output was received by the wallet.
+        assert_eq!(wallet_tx.sapling_outputs().len(), 1);
+        let output = &wallet_tx.sapling_outputs()[0];
+        assert_eq!(output.account_id(), &account);
+        assert!(output.is_change());
+        assert!(!output.below_dust_threshold());
+        assert_eq!(output.recipient_key_scope(), Some(Scope::Internal));
+        assert_eq!(output.note().value().inner(), 40000);
+        assert_eq!(
+            output.note().recipient(),
+            dfvk.diversified_change_address(*output.note().recipient().diversifier())
+                .unwrap()
+        );
+        assert_eq!(
+            output.ephemeral_key(),
+            bundle.shielded_outputs()[output.index()].ephemeral_key()
+        );
+        assert!(output.nf().is_some());
+    }
+
     #[test]
     fn spend_fails_on_unverified_notes() {
         let mut st = TestBuilder::new()
@@ -1230,8 +1495,10 @@ pub(crate) mod tests {
                 .wallet()
                 .conn
                 .query_row(
-                    "SELECT raw FROM transactions
-                    WHERE txid = ?",
+                    "SELECT transaction_data.raw
+                    FROM transactions
+                    JOIN transaction_data ON transaction_data.id_tx = transactions.id_tx
+                    WHERE transactions.txid = ?",
                     [txid.as_ref()],
                     |row| row.get(0),
                 )
@@ -1396,6 +1663,59 @@ pub(crate) mod tests {
         );
     }
 
+    #[test]
+    fn received_notes_record_discovering_key() {
+        let mut st = TestBuilder::new()
+            .with_block_cache()
+            .with_test_account(AccountBirthday::from_sapling_activation)
+            .build();
+
+        let (account, _, _) = st.test_account().unwrap();
+        let dfvk = st.test_account_sapling().unwrap();
+
+        // Receive a note at the external address and another at the internal address.
+        let (h, _, _) = st.generate_next_block(
+            &dfvk,
+            AddressType::DefaultExternal,
+            NonNegativeAmount::const_from_u64(50000),
+        );
+        st.generate_next_block(
+            &dfvk,
+            AddressType::Internal,
+            NonNegativeAmount::const_from_u64(60000),
+        );
+        st.scan_cached_blocks(h, 2);
+
+        // Each note is attributed to the viewing key of the scope at which it was received.
+        let discovering_keys = st
+            .wallet()
+            .conn
+            .prepare(
+                "SELECT rn.value, viewing_keys.account_id, viewing_keys.key_scope
+                FROM sapling_received_notes rn
+                JOIN viewing_keys ON viewing_keys.id = rn.discovered_by_key_id
+                ORDER BY rn.value",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u64>(0)?,
+                    AccountId(row.get(1)?),
+                    parse_scope(row.get(2)?),
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            discovering_keys,
+            vec![
+                (50000, account, Some(Scope::External)),
+                (60000, account, Some(Scope::Internal)),
+            ]
+        );
+    }
+
     #[test]
     fn external_address_change_spends_detected_in_restore_from_seed() {
         let mut st = TestBuilder::new().with_block_cache().build();